@@ -25,11 +25,35 @@ pub fn increment(counter: &mut Counter) -> u64 {
     counter.increment()
 }
 
-#[update]
+/// Only allow the steps of at most 10.
+fn is_valid_step(n: &u8) -> Result<(), String> {
+    if *n > 10 {
+        return Err(format!("The step {} is larger than 10.", n));
+    }
+
+    Ok(())
+}
+
+#[update(arg_guard = "is_valid_step")]
 pub fn increment_by(counter: &mut Counter, n: u8) -> u64 {
     counter.increment_by(n)
 }
 
+#[update(manual_reply = true, reply_type = "u64")]
+pub fn increment_manual(counter: &mut Counter) {
+    ic::reply((counter.increment(),));
+}
+
+#[global_timer]
+pub fn tick(counter: &mut Counter) {
+    counter.increment();
+}
+
+#[on_low_wasm_memory]
+pub fn release_memory(counter: &mut Counter) {
+    counter.number = 0;
+}
+
 #[query]
 pub fn get_counter(counter: &Counter) -> u64 {
     counter.number
@@ -84,6 +108,98 @@ mod tests {
         );
     }
 
+    #[kit_test]
+    async fn test_update_query(replica: Replica) {
+        let c = replica.add_canister(CounterCanister::anonymous());
+        let caller = Principal::anonymous();
+
+        for i in 1..=3 {
+            let r = replica.update(c.id(), caller, "increment", ()).await;
+            assert_eq!(r.decode_one::<u64>().unwrap(), i);
+        }
+
+        let r = replica.query(c.id(), caller, "get_counter", ()).await;
+        assert_eq!(r.decode_one::<u64>().unwrap(), 3);
+    }
+
+    #[kit_test]
+    async fn test_install(replica: Replica) {
+        let c = replica
+            .install_canister(CounterCanister::anonymous(), ())
+            .await;
+
+        let r = replica
+            .query(c.id(), Principal::anonymous(), "get_counter", ())
+            .await;
+        assert_eq!(r.decode_one::<u64>().unwrap(), 0);
+
+        let r = c.new_call("increment").perform().await;
+        assert_eq!(r.decode_one::<u64>().unwrap(), 1);
+    }
+
+    #[kit_test]
+    async fn test_deadline(replica: Replica) {
+        let c = replica.add_canister(CounterCanister::anonymous());
+        replica.set_time(1_000);
+
+        c.new_call("increment")
+            .with_deadline(2_000)
+            .perform()
+            .await
+            .assert_ok();
+
+        // The message is rejected once the deadline has passed, and it is never executed.
+        replica.set_time(3_000);
+        let r = c.new_call("increment").with_deadline(2_000).perform().await;
+        assert!(matches!(r.rejection_code(), ic::RejectionCode::SysUnknown));
+
+        assert_eq!(
+            c.new_call("get_counter")
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap(),
+            1
+        );
+    }
+
+    #[kit_test]
+    async fn test_increment_manual(replica: Replica) {
+        let c = replica.add_canister(CounterCanister::anonymous());
+
+        for expected in 1..=2u64 {
+            let r = c
+                .new_call("increment_manual")
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap();
+
+            assert_eq!(r, expected);
+        }
+    }
+
+    #[kit_test]
+    async fn test_lifecycle_hooks(replica: Replica) {
+        let c = replica.add_canister(CounterCanister::anonymous());
+        let get_counter = || async {
+            c.new_call("get_counter")
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap()
+        };
+
+        replica.trigger_global_timer(Principal::anonymous()).await;
+        replica.trigger_global_timer(Principal::anonymous()).await;
+        assert_eq!(get_counter().await, 2);
+
+        replica
+            .trigger_low_wasm_memory(Principal::anonymous())
+            .await;
+        assert_eq!(get_counter().await, 0);
+    }
+
     #[kit_test]
     async fn test_increment_by(replica: Replica) {
         let c = replica.add_canister(CounterCanister::anonymous());
@@ -97,4 +213,30 @@ mod tests {
             2
         );
     }
+
+    #[kit_test]
+    async fn test_increment_by_guard(replica: Replica) {
+        let c = replica.add_canister(CounterCanister::anonymous());
+
+        let r = c.new_call("increment_by").with_arg(11u8).perform().await;
+        assert!(matches!(
+            r.rejection_code(),
+            ic::RejectionCode::CanisterReject
+        ));
+        assert_eq!(
+            r.rejection_message(),
+            Some("The step 11 is larger than 10.")
+        );
+
+        // The rejected call did not change the counter.
+        assert_eq!(
+            c.new_call("increment_by")
+                .with_arg(10u8)
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap(),
+            10
+        );
+    }
 }
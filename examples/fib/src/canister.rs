@@ -49,4 +49,47 @@ mod test {
 
         assert_eq!(fib_6, 8);
     }
+
+    #[kit_test]
+    async fn fib_trap(replica: Replica) {
+        let canister = replica.add_canister(FibCanister::anonymous());
+
+        let r = canister.new_call("fib").with_arg(21u64).perform().await;
+        assert!(matches!(
+            r.rejection_code(),
+            ic::RejectionCode::CanisterError
+        ));
+        assert_eq!(r.rejection_message(), Some("Let's not kill IC."));
+    }
+
+    #[kit_test]
+    async fn fib_report(replica: Replica) {
+        use ic_kit::rt::types::{Env, Message, RequestId};
+
+        replica.add_canister(FibCanister::anonymous());
+
+        let message = |n: u64| Message::Request {
+            request_id: RequestId::new(),
+            env: Env::update("fib").with_arg(n),
+        };
+
+        // The first execution only makes the call to compute fib(1).
+        let report = replica
+            .run_message_detailed(Principal::anonymous(), message(2))
+            .await;
+
+        assert!(!report.trapped);
+        assert!(report.instructions > 0);
+        assert!(report.reply.is_none());
+        assert_eq!(report.outgoing_calls.len(), 1);
+        assert_eq!(report.outgoing_calls[0].method, "fib");
+
+        let report = replica
+            .run_message_detailed(Principal::anonymous(), message(21))
+            .await;
+
+        assert!(report.trapped);
+        assert!(report.outgoing_calls.is_empty());
+        assert!(report.reply.unwrap().is_error());
+    }
 }
@@ -25,6 +25,25 @@ fn add_counter(counters: &mut MultiCounter, canister_id: Principal) {
     counters.canister_ids.insert(canister_id);
 }
 
+/// Return the sum of all of the counters, the counters that can not be reached are ignored.
+#[update]
+async fn sum() -> u64 {
+    let canister_ids = ic::with(|counters: &MultiCounter| counters.canister_ids.clone());
+    let mut sum = 0;
+
+    for canister_id in canister_ids {
+        match CallBuilder::new(canister_id, "get_counter")
+            .perform_one::<u64>()
+            .await
+        {
+            Ok(n) => sum += n,
+            Err(e) => ic::print(format!("Could not reach {}: {:?}", canister_id, e)),
+        }
+    }
+
+    sum
+}
+
 #[derive(KitCanister)]
 #[candid_path("candid.did")]
 pub struct MultiCounterCanister;
@@ -84,4 +103,56 @@ mod tests {
 
         println!("{:#?}", x);
     }
+
+    #[kit_test]
+    async fn test_stopped_counter(replica: Replica) {
+        let counter1_id = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        let counter2_id = Principal::from_text("lj532-6iaaa-aaaah-qcc7a-cai").unwrap();
+
+        let canister = replica.add_canister(MultiCounterCanister::anonymous());
+        let counter1 = replica.add_canister(CounterCanister::build(counter1_id));
+        let counter2 = replica.add_canister(CounterCanister::build(counter2_id));
+
+        counter1.new_call("increment").perform().await.assert_ok();
+        counter2
+            .new_call("increment_by")
+            .with_arg(2u8)
+            .perform()
+            .await
+            .assert_ok();
+
+        for id in [counter1_id, counter2_id] {
+            canister
+                .new_call("add_counter")
+                .with_arg(id)
+                .perform()
+                .await
+                .assert_ok();
+        }
+
+        let sum = || async {
+            canister
+                .new_call("sum")
+                .perform()
+                .await
+                .decode_one::<u64>()
+                .unwrap()
+        };
+
+        assert_eq!(sum().await, 3);
+
+        // The call to the stopped counter is rejected and the counter is skipped.
+        counter2.stop();
+        assert_eq!(sum().await, 1);
+
+        let r = counter2.new_call("get_counter").perform().await;
+        assert!(matches!(
+            r.rejection_code(),
+            ic::RejectionCode::CanisterError
+        ));
+        assert!(r.rejection_message().unwrap().contains("is stopped"));
+
+        counter2.start();
+        assert_eq!(sum().await, 3);
+    }
 }
@@ -17,11 +17,70 @@ fn register(registry: &mut Registry, stats: &mut Stats, name: String) {
     registry.names.insert(caller(), name);
 }
 
+fn is_not_anonymous() -> Result<(), String> {
+    if caller() == Principal::anonymous() {
+        return Err("The anonymous principal is not allowed.".to_string());
+    }
+
+    Ok(())
+}
+
+fn is_registered() -> Result<(), String> {
+    if !ic::with(|registry: &Registry| registry.names.contains_key(&caller())) {
+        return Err("The caller is not registered.".to_string());
+    }
+
+    Ok(())
+}
+
+#[update(guard = ["is_not_anonymous", "is_registered"])]
+fn unregister(registry: &mut Registry) {
+    registry.names.remove(&caller());
+}
+
+/// The canister that keeps the list of the principals that are allowed to administer the registry.
+fn allowlist() -> Principal {
+    Principal::from_slice(&[0x0a, 0x11])
+}
+
+/// Ask the allowlist canister whether the caller is an administrator.
+async fn is_admin() -> Result<(), String> {
+    let allowed: bool = CallBuilder::new(allowlist(), "is_allowed")
+        .with_arg(caller())
+        .perform_one()
+        .await
+        .map_err(|e| format!("Could not reach the allowlist: {:?}", e))?;
+
+    if !allowed {
+        return Err("The caller is not an administrator.".to_string());
+    }
+
+    Ok(())
+}
+
+#[update(async_guard = "is_admin")]
+fn reset(registry: &mut Registry) {
+    registry.names.clear();
+}
+
 #[query]
 fn get_name(registry: &Registry, user: Principal) -> Option<&String> {
     registry.names.get(&user)
 }
 
+/// The argument of the `lookup` method, the field names follow the Candid interface already used
+/// by the clients rather than the Rust naming conventions.
+#[derive(CandidType, Deserialize)]
+struct Lookup {
+    #[serde(rename = "userId")]
+    user_id: Principal,
+}
+
+#[query]
+fn lookup(registry: &Registry, query: Lookup) -> Option<&String> {
+    registry.names.get(&query.user_id)
+}
+
 #[derive(KitCanister)]
 #[candid_path("candid.did")]
 pub struct NamingSystemCanister;
@@ -68,4 +127,192 @@ mod tests {
 
         assert_eq!(bob_name, Some("Bob".to_string()));
     }
+
+    #[kit_test]
+    async fn unregister_guards(replica: Replica) {
+        let ns = replica.add_canister(NamingSystemCanister::anonymous());
+
+        let r = ns.new_call("unregister").perform().await;
+        assert_eq!(
+            r.rejection_message(),
+            Some("The anonymous principal is not allowed.")
+        );
+
+        // The first guard passes, but the second one rejects the call.
+        let r = ns
+            .new_call("unregister")
+            .with_caller(*users::ALICE)
+            .perform()
+            .await;
+        assert!(matches!(
+            r.rejection_code(),
+            ic::RejectionCode::CanisterReject
+        ));
+        assert_eq!(r.rejection_message(), Some("The caller is not registered."));
+
+        ns.new_call("register")
+            .with_caller(*users::ALICE)
+            .with_arg("Alice")
+            .perform()
+            .await
+            .assert_ok();
+
+        ns.new_call("unregister")
+            .with_caller(*users::ALICE)
+            .perform()
+            .await
+            .assert_ok();
+
+        let alice_name = ns
+            .new_call("get_name")
+            .with_arg(*users::ALICE)
+            .perform()
+            .await
+            .decode_one::<Option<String>>()
+            .unwrap();
+
+        assert_eq!(alice_name, None);
+    }
+
+    #[kit_test]
+    async fn reset_async_guard(replica: Replica) {
+        // Only Alice is on the allowlist.
+        let replica = replica.with_handler(allowlist(), "is_allowed", |arg| {
+            let user: Principal = ic_kit::candid::decode_one(arg).unwrap();
+            Ok(ic_kit::candid::encode_one(user == *users::ALICE).unwrap())
+        });
+        let ns = replica.add_canister(NamingSystemCanister::anonymous());
+
+        ns.new_call("register")
+            .with_caller(*users::BOB)
+            .with_arg("Bob")
+            .perform()
+            .await
+            .assert_ok();
+
+        let r = ns
+            .new_call("reset")
+            .with_caller(*users::BOB)
+            .perform()
+            .await;
+        assert_eq!(r.rejection_code(), ic::RejectionCode::CanisterReject);
+        assert_eq!(
+            r.rejection_message(),
+            Some("The caller is not an administrator.")
+        );
+
+        ns.new_call("reset")
+            .with_caller(*users::ALICE)
+            .perform()
+            .await
+            .assert_ok();
+
+        let bob_name = ns
+            .new_call("get_name")
+            .with_arg(*users::BOB)
+            .perform()
+            .await
+            .decode_one::<Option<String>>()
+            .unwrap();
+
+        assert_eq!(bob_name, None);
+    }
+
+    #[test]
+    fn candid_arg_names() {
+        assert_eq!(
+            NamingSystemCanister::candid_arg_names(),
+            vec![
+                ("get_name".to_string(), vec!["user".to_string()]),
+                ("lookup".to_string(), vec!["query".to_string()]),
+                ("register".to_string(), vec!["name".to_string()]),
+                ("reset".to_string(), vec![]),
+                ("unregister".to_string(), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn candid_interface() {
+        let candid = ic_kit::candid_interface::<NamingSystemCanister>();
+        assert!(candid.contains("register : (text) -> ();"));
+        assert!(candid.contains("get_name : (principal) -> (opt text) query;"));
+    }
+
+    #[kit_test]
+    async fn decode_errors(replica: Replica) {
+        let ns = replica.add_canister(NamingSystemCanister::anonymous());
+
+        // Cut the message in the middle of its header.
+        let bytes = ic_kit::candid::encode_one("Alice").unwrap();
+        let truncated = ns
+            .new_call("register")
+            .with_arg_raw(&bytes[..5])
+            .perform()
+            .await;
+
+        assert!(truncated
+            .rejection_message()
+            .unwrap()
+            .contains("truncated data"));
+
+        let wrong_type = ns.new_call("register").with_arg(42u64).perform().await;
+
+        assert!(wrong_type
+            .rejection_message()
+            .unwrap()
+            .contains("wrong type"));
+
+        let missing = ns.new_call("register").perform().await;
+
+        assert!(missing
+            .rejection_message()
+            .unwrap()
+            .starts_with("Could not decode arguments, expected 1 argument (truncated data)"));
+    }
+
+    #[kit_test]
+    async fn lookup_renamed_fields(replica: Replica) {
+        // Mirrors the wire format of `Lookup`, the field is named after the Candid interface.
+        #[derive(CandidType)]
+        #[allow(non_snake_case)]
+        struct WireLookup {
+            userId: Principal,
+        }
+
+        let ns = replica.add_canister(NamingSystemCanister::anonymous());
+
+        ns.new_call("register")
+            .with_caller(*users::ALICE)
+            .with_arg("Alice")
+            .perform()
+            .await
+            .assert_ok();
+
+        let alice_name = ns
+            .new_call("lookup")
+            .with_arg(WireLookup {
+                userId: *users::ALICE,
+            })
+            .perform()
+            .await
+            .decode_one::<Option<String>>()
+            .unwrap();
+
+        assert_eq!(alice_name, Some("Alice".to_string()));
+
+        // Using the Rust field name on the wire should not decode.
+        #[derive(CandidType)]
+        struct RustLookup {
+            user_id: Principal,
+        }
+
+        ns.new_call("lookup")
+            .with_arg(RustLookup {
+                user_id: *users::ALICE,
+            })
+            .perform()
+            .await
+            .assert_error();
+    }
 }
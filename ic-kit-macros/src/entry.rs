@@ -2,7 +2,7 @@
 //!
 //! [1]: <https://internetcomputer.org/docs/current/references/ic-interface-spec/#entry-points>
 
-use crate::export_service::declare;
+use crate::export_service::{candid_arg_name, declare, return_types};
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::quote;
 use serde::Deserialize;
@@ -17,6 +17,8 @@ pub enum EntryPoint {
     PostUpgrade,
     InspectMessage,
     Heartbeat,
+    GlobalTimer,
+    OnLowWasmMemory,
     Update,
     Query,
 }
@@ -29,6 +31,8 @@ impl std::fmt::Display for EntryPoint {
             EntryPoint::PostUpgrade => f.write_str("post_upgrade"),
             EntryPoint::InspectMessage => f.write_str("inspect_message"),
             EntryPoint::Heartbeat => f.write_str("heartbeat"),
+            EntryPoint::GlobalTimer => f.write_str("global_timer"),
+            EntryPoint::OnLowWasmMemory => f.write_str("on_low_wasm_memory"),
             EntryPoint::Update => f.write_str("update"),
             EntryPoint::Query => f.write_str("query"),
         }
@@ -51,11 +55,33 @@ impl EntryPoint {
     }
 }
 
+/// One or several guard functions, the guards are checked in the given order.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Guards {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Guards {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Guards::One(guard) => vec![guard],
+            Guards::Many(guards) => guards,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct Config {
     name: Option<String>,
-    guard: Option<String>,
+    guard: Option<Guards>,
+    arg_guard: Option<Guards>,
+    async_guard: Option<Guards>,
     hidden: Option<bool>,
+    manual_reply: Option<bool>,
+    reply_type: Option<String>,
+    composite: Option<bool>,
 }
 
 /// Process a rust syntax and generate the code for processing it.
@@ -76,6 +102,7 @@ pub fn gen_entry_point_code(
     let generics = &signature.generics;
     let is_async = signature.asyncness.is_some();
     let name = &signature.ident;
+    let manual_reply = attrs.manual_reply.unwrap_or(false);
 
     let return_length = match &signature.output {
         syn::ReturnType::Default => 0,
@@ -128,7 +155,7 @@ pub fn gen_entry_point_code(
             ));
         }
 
-        if attrs.guard.is_some() {
+        if attrs.guard.is_some() || attrs.arg_guard.is_some() || attrs.async_guard.is_some() {
             return Err(Error::new(
                 Span::call_site(),
                 format!("#[{}] function cannot have a guard", entry_point),
@@ -141,30 +168,120 @@ pub fn gen_entry_point_code(
                 format!("#[{}] function cannot be async.", entry_point),
             ));
         }
+
+        if attrs.manual_reply.is_some() {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] function cannot have a manual reply.", entry_point),
+            ));
+        }
+    }
+
+    if attrs.composite.is_some() && entry_point != EntryPoint::Query {
+        return Err(Error::new(
+            Span::call_site(),
+            format!("#[{}] function cannot be a composite query.", entry_point),
+        ));
+    }
+
+    // The async guards need to perform calls, which the plain queries can not do.
+    if attrs.async_guard.is_some()
+        && entry_point == EntryPoint::Query
+        && !attrs.composite.unwrap_or(false)
+    {
+        return Err(Error::new(
+            Span::call_site(),
+            format!(
+                "#[{}] function can only have an async guard if it is a composite query.",
+                entry_point
+            ),
+        ));
     }
 
+    if manual_reply && return_length > 0 {
+        return Err(Error::new(
+            signature.output.span(),
+            format!(
+                "#[{}(manual_reply = true)] function cannot have a return value.",
+                entry_point
+            ),
+        ));
+    }
+
+    if attrs.reply_type.is_some() && !manual_reply {
+        return Err(Error::new(
+            Span::call_site(),
+            format!(
+                "#[{}] function can only have a reply_type with manual_reply = true.",
+                entry_point
+            ),
+        ));
+    }
+
+    // A method with a manual reply declares the type of its reply in the candid interface with
+    // the reply_type, since it does not return it.
+    let output = match &attrs.reply_type {
+        Some(reply_type) => {
+            let ty = syn::parse_str::<syn::Type>(reply_type).map_err(|e| {
+                Error::new(
+                    Span::call_site(),
+                    format!("Invalid reply_type '{}'.\n{}", reply_type, e),
+                )
+            })?;
+            syn::ReturnType::Type(Default::default(), Box::new(ty))
+        }
+        None => signature.output.clone(),
+    };
+
     let outer_function_ident = Ident::new(
         &format!("_ic_kit_canister_{}_{}", entry_point, name),
         Span::call_site(),
     );
 
-    let guard = if let Some(guard_name) = attrs.guard {
-        let guard_ident = Ident::new(&guard_name, Span::call_site());
-
-        quote! {
-            let r: Result<(), String> = #guard_ident ();
-            if let Err(e) = r {
-                ic_kit::utils::reject(&e);
-                return;
+    // The message is rejected by the first guard that returns an error.
+    let guards = attrs
+        .guard
+        .map(Guards::into_vec)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|guard_name| {
+            let guard_ident = Ident::new(&guard_name, Span::call_site());
+
+            quote! {
+                if let Err(e) = #guard_ident () {
+                    ic_kit::utils::GuardError::reject(e);
+                    return;
+                }
             }
-        }
-    } else {
-        quote! {}
-    };
+        });
+    let guard = quote! { #(#guards)* };
+
+    // The async guards are awaited in the spawned future once the arguments are decoded, since
+    // the argument data of the call is no longer available after the first await.
+    let async_guards = attrs
+        .async_guard
+        .map(Guards::into_vec)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|guard_name| {
+            let guard_ident = Ident::new(&guard_name, Span::call_site());
+
+            quote! {
+                if let Err(e) = #guard_ident ().await {
+                    ic_kit::utils::GuardError::reject(e);
+                    return;
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let has_async_guard = !async_guards.is_empty();
+    let async_guard = quote! { #(#async_guards)* };
 
     let candid_name = attrs.name.unwrap_or_else(|| name.to_string());
     let export_name = if entry_point.is_lifecycle() {
         format!("canister_{}", entry_point)
+    } else if attrs.composite.unwrap_or(false) {
+        format!("canister_composite_query {}", candid_name)
     } else {
         format!("canister_{0} {1}", entry_point, candid_name)
     };
@@ -176,21 +293,43 @@ pub fn gen_entry_point_code(
     let (imu_args, imu_types): (Vec<_>, Vec<_>) = tmp.imu_args.into_iter().unzip();
     let (mut_args, mut_types): (Vec<_>, Vec<_>) = tmp.mut_args.into_iter().unzip();
 
+    // The argument guards are called with a reference to each of the decoded arguments.
+    let arg_guards = attrs
+        .arg_guard
+        .map(Guards::into_vec)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|guard_name| {
+            let guard_ident = Ident::new(&guard_name, Span::call_site());
+
+            quote! {
+                if let Err(e) = #guard_ident ( #(&#can_args),* ) {
+                    ic_kit::utils::GuardError::reject(e);
+                    return;
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
     // If the method does not accept any arguments, don't even read the msg_data, and if the
     // deserialization fails, just reject the message, which is cheaper than trap.
     let arg_decode = if can_args.len() == 0 {
-        quote! {}
+        quote! {
+            #(#arg_guards)*
+        }
     } else {
+        let expected = can_args.len();
         quote! {
             let bytes = ic_kit::utils::arg_data_raw();
-            let args = match ic_kit::candid::decode_args(&bytes) {
+            let args = match ic_kit::ic::decode(&bytes) {
                 Ok(v) => v,
-                Err(_) => {
-                    ic_kit::utils::reject("Could not decode arguments.");
+                Err(e) => {
+                    ic_kit::utils::reject_decode_error(#expected, &e);
                     return;
                 },
             };
             let ( #( #can_args, )* ) = args;
+            #(#arg_guards)*
         }
     };
 
@@ -203,6 +342,11 @@ pub fn gen_entry_point_code(
         }
     } else if entry_point.is_lifecycle() {
         quote! {}
+    } else if manual_reply {
+        // The method replies by itself.
+        quote! {
+            let _ = result;
+        }
     } else {
         match return_length {
             0 => quote! {
@@ -216,7 +360,7 @@ pub fn gen_entry_point_code(
                 ic_kit::utils::reply(&bytes);
             },
             _ => quote! {
-                let bytes = ic_kit::candid::encode_args(result)
+                let bytes = ic_kit::ic::encode(result)
                     .expect("Could not encode canister's response.");
                 ic_kit::utils::reply(&bytes);
             },
@@ -257,15 +401,24 @@ pub fn gen_entry_point_code(
         },
     };
 
-    // only spawn for async methods.
+    // only spawn for async methods, and for the sync methods that have to wait for a guard.
     let body = if is_async {
         quote! {
             ic_kit::ic::spawn(async {
                 #arg_decode
+                #async_guard
                 let result = #name ( #(#args),* ).await;
                 #return_encode
             });
         }
+    } else if has_async_guard {
+        quote! {
+            ic_kit::ic::spawn(async {
+                #arg_decode
+                #async_guard
+                #sync_result;
+            });
+        }
     } else {
         quote! {
             #arg_decode
@@ -273,15 +426,48 @@ pub fn gen_entry_point_code(
         }
     };
 
+    // The timers set with `ic::set_timer` are multiplexed on the global timer, so they run before
+    // the canister's own global timer hook.
+    let body = if entry_point == EntryPoint::GlobalTimer {
+        quote! {
+            ic_kit::ic::run_timers();
+            #body
+        }
+    } else {
+        body
+    };
+
+    let ret_types = return_types(&output);
+    let arg_names = can_args.iter().map(candid_arg_name);
+    let candid_signature = quote! {
+        #[cfg(not(target_family = "wasm"))]
+        impl ic_kit::CandidMethod for #name {
+            fn candid_signature() -> (
+                Vec<ic_kit::candid::types::Type>,
+                Vec<ic_kit::candid::types::Type>,
+            ) {
+                (
+                    vec![#( <#can_types as ic_kit::candid::CandidType>::ty() ),*],
+                    vec![#( <#ret_types as ic_kit::candid::CandidType>::ty() ),*],
+                )
+            }
+
+            fn candid_arg_names() -> Vec<&'static str> {
+                vec![#( #arg_names ),*]
+            }
+        }
+    };
+
     // only declare candid if hide is false
     declare(
         entry_point,
         name.clone(),
         candid_name,
         attrs.hidden.unwrap_or(false),
+        attrs.composite.unwrap_or(false),
         can_args,
         can_types,
-        &signature.output,
+        &output,
     )?;
 
     Ok(quote! {
@@ -297,8 +483,14 @@ pub fn gen_entry_point_code(
             fn exported_method() {
                 #outer_function_ident()
             }
+
+            fn arg_types() -> Option<Vec<ic_kit::candid::types::Type>> {
+                Some(<Self as ic_kit::CandidMethod>::candid_signature().0)
+            }
         }
 
+        #candid_signature
+
         #[cfg(target_family = "wasm")]
         #[doc(hidden)]
         #[export_name = #export_name]
@@ -409,3 +601,76 @@ fn collect_args(
 
     Ok(args)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(entry_point: EntryPoint, attr: TokenStream, item: TokenStream) -> String {
+        gen_entry_point_code(entry_point, attr, item)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn query() {
+        let code = expand(
+            EntryPoint::Query,
+            quote! {},
+            quote! { fn plain_query() -> u64 { 0 } },
+        );
+        assert!(code.contains("\"canister_query plain_query\""));
+        assert!(!code.contains("canister_composite_query"));
+    }
+
+    #[test]
+    fn composite_query() {
+        let code = expand(
+            EntryPoint::Query,
+            quote! { composite = true },
+            quote! { async fn composite_query() -> u64 { 0 } },
+        );
+        assert!(code.contains("\"canister_composite_query composite_query\""));
+    }
+
+    #[test]
+    fn composite_update() {
+        let error = gen_entry_point_code(
+            EntryPoint::Update,
+            quote! { composite = true },
+            quote! { fn composite_update() {} },
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "#[update] function cannot be a composite query."
+        );
+    }
+
+    #[test]
+    fn async_guard() {
+        let code = expand(
+            EntryPoint::Update,
+            quote! { guard = "is_owner", async_guard = "is_allowed" },
+            quote! { fn guarded() {} },
+        );
+
+        // The sync guard runs first, then the async guard is awaited in the spawned future.
+        let guard = code.find("is_owner ()").unwrap();
+        let spawn = code.find("ic_kit :: ic :: spawn").unwrap();
+        let async_guard = code.find("is_allowed () . await").unwrap();
+        let method = code.find("let result = guarded ()").unwrap();
+        assert!(guard < spawn && spawn < async_guard && async_guard < method);
+
+        let error = gen_entry_point_code(
+            EntryPoint::Query,
+            quote! { async_guard = "is_allowed" },
+            quote! { fn guarded_query() {} },
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "#[query] function can only have an async guard if it is a composite query."
+        );
+    }
+}
@@ -4,13 +4,14 @@ use proc_macro2::{Ident, Span, TokenStream};
 use quote::{quote, ToTokens};
 use std::collections::BTreeMap;
 use std::sync::Mutex;
-use syn::{DeriveInput, Error};
+use syn::{ext::IdentExt, DeriveInput, Error};
 
 struct Method {
     hidden: bool,
     mode: EntryPoint,
+    composite: bool,
     rust_name: String,
-    _arg_names: Vec<String>,
+    arg_names: Vec<String>,
     arg_types: Vec<String>,
     rets: Vec<String>,
 }
@@ -20,33 +21,35 @@ lazy_static! {
     static ref LIFE_CYCLES: Mutex<BTreeMap<EntryPoint, Method>> = Mutex::new(Default::default());
 }
 
+/// Return the name of an argument in the candid interface, the leading underscores that mark an
+/// unused argument in Rust are not part of the name.
+pub(crate) fn candid_arg_name(ident: &Ident) -> String {
+    let name = ident.unraw().to_string();
+
+    match name.trim_start_matches('_') {
+        "" => name,
+        trimmed => trimmed.to_string(),
+    }
+}
+
 pub(crate) fn declare(
     entry_point: EntryPoint,
     rust_name: Ident,
     name: String,
     hidden: bool,
+    composite: bool,
     can_args: Vec<Ident>,
     can_types: Vec<syn::Type>,
     rt: &syn::ReturnType,
 ) -> Result<(), Error> {
-    let rets = match rt {
-        syn::ReturnType::Default => Vec::new(),
-        syn::ReturnType::Type(_, ty) => match ty.as_ref() {
-            syn::Type::Tuple(tuple) => tuple
-                .elems
-                .iter()
-                .cloned()
-                .map(remove_reference_recursive)
-                .collect(),
-            _ => vec![remove_reference_recursive(ty.as_ref().clone())],
-        },
-    };
+    let rets = return_types(rt);
 
     let method = Method {
         hidden,
         mode: entry_point,
+        composite,
         rust_name: rust_name.to_string(),
-        _arg_names: can_args.iter().map(|i| i.to_string()).collect(),
+        arg_names: can_args.iter().map(candid_arg_name).collect(),
         arg_types: can_types
             .iter()
             .map(|t| format!("{}", t.to_token_stream()))
@@ -86,6 +89,11 @@ pub(crate) fn declare(
     Ok(())
 }
 
+/// Implement `KitCanister` for the canister using the methods declared so far.
+///
+/// With a `save_candid_path`, this also generates a test that writes the candid interface of the
+/// canister to that path. When the `IC_KIT_CHECK_CANDID` environment variable is set the test does
+/// not write the file, and fails instead if the file does not match the interface.
 pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>) -> TokenStream {
     let methods = {
         let mut map = METHODS.lock().unwrap();
@@ -109,6 +117,25 @@ pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>)
             .map(|m| Ident::new(m.rust_name.as_str(), Span::call_site())),
     );
 
+    // Without a global timer hook, the canister gets one that only runs the timers set with
+    // `ic::set_timer`.
+    let (timers_method, timers_export) = if life_cycles.contains_key(&EntryPoint::GlobalTimer) {
+        (quote! {}, quote! {})
+    } else {
+        (
+            quote! { .with_method::<ic_kit::ic::TimersMethod>() },
+            quote! {
+                #[cfg(target_family = "wasm")]
+                #[doc(hidden)]
+                #[export_name = "canister_global_timer"]
+                fn _ic_kit_canister_global_timer() {
+                    ic_kit::setup_hooks();
+                    ic_kit::ic::run_timers();
+                }
+            },
+        )
+    };
+
     let gen_tys = methods.iter().map(
         |(
             name,
@@ -116,6 +143,7 @@ pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>)
                 arg_types,
                 rets,
                 mode,
+                composite,
                 hidden,
                 ..
             },
@@ -132,6 +160,9 @@ pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>)
 
             let modes = match mode {
                 EntryPoint::Update => quote! { vec![] },
+                EntryPoint::Query if *composite => {
+                    quote! { vec![ic_kit::candid::parser::types::FuncMode::CompositeQuery] }
+                }
                 EntryPoint::Query => {
                     quote! { vec![ic_kit::candid::parser::types::FuncMode::Query] }
                 }
@@ -155,6 +186,14 @@ pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>)
         },
     );
 
+    let arg_names = methods.iter().filter(|(_, method)| !method.hidden).map(
+        |(name, Method { arg_names, .. })| {
+            quote! {
+                (#name.to_string(), vec![#( #arg_names.to_string() ),*])
+            }
+        },
+    );
+
     let service = quote! {
         use ic_kit::candid::types::{CandidType, Function, Type};
         let mut service = Vec::<(String, Type)>::new();
@@ -189,31 +228,11 @@ pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>)
             fn ic_kit_save_candid() {
                 use ic_kit::KitCanister;
                 use std::env;
-                use std::fs;
                 use std::path::PathBuf;
 
-                let candid = #name::candid();
                 let mut path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
                 path.push(#path);
-                let dir = path.parent().unwrap();
-
-                fs::create_dir_all(dir).unwrap_or_else(|e| {
-                    panic!(
-                        "Failed to create the directory '{}': {}",
-                        dir.as_os_str().to_string_lossy(),
-                        e
-                    )
-                });
-
-                fs::write(&path, candid).unwrap_or_else(|e| {
-                    panic!(
-                        "Failed to write to the file '{}': {}",
-                        path.as_os_str().to_string_lossy(),
-                        e
-                    )
-                });
-
-                println!("Saved candid to: {}", path.as_os_str().to_string_lossy());
+                ic_kit::utils::save_candid(&path, &#name::candid());
             }
         }
     } else {
@@ -228,6 +247,7 @@ pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>)
                 #(
                     .with_method::<#rust_methods>()
                 )*
+                #timers_method
             }
 
             fn candid() -> String {
@@ -236,6 +256,10 @@ pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>)
                 let result = ic_kit::candid::bindings::candid::compile(&env.env, &actor);
                 format!("{}", result)
             }
+
+            fn candid_arg_names() -> Vec<(String, Vec<String>)> {
+                vec![#( #arg_names ),*]
+            }
         }
 
         #[cfg(target_family = "wasm")]
@@ -248,6 +272,8 @@ pub fn export_service(input: DeriveInput, save_candid_path: Option<syn::LitStr>)
             ic_kit::utils::reply(&bytes);
         }
 
+        #timers_export
+
         #save_candid
     }
 }
@@ -259,6 +285,22 @@ fn generate_arg(name: TokenStream, ty: &str) -> TokenStream {
     }
 }
 
+/// Return the owned types of the values returned by a method.
+pub(crate) fn return_types(rt: &syn::ReturnType) -> Vec<syn::Type> {
+    match rt {
+        syn::ReturnType::Default => Vec::new(),
+        syn::ReturnType::Type(_, ty) => match ty.as_ref() {
+            syn::Type::Tuple(tuple) => tuple
+                .elems
+                .iter()
+                .cloned()
+                .map(remove_reference_recursive)
+                .collect(),
+            _ => vec![remove_reference_recursive(ty.as_ref().clone())],
+        },
+    }
+}
+
 /// Remove the references in a type and makes it an owned type, this is used to parse the return
 /// type when it's using Kit's DI.
 fn remove_reference_recursive(ty: syn::Type) -> syn::Type {
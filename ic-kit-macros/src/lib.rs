@@ -47,13 +47,42 @@ pub fn heartbeat(attr: TokenStream, item: TokenStream) -> TokenStream {
     process_entry_point(EntryPoint::Heartbeat, attr, item)
 }
 
+/// Export the function as the global timer hook of the canister, the due timers set with
+/// `ic::set_timer` and `ic::set_timer_interval` run before the function.
+#[proc_macro_attribute]
+pub fn global_timer(attr: TokenStream, item: TokenStream) -> TokenStream {
+    process_entry_point(EntryPoint::GlobalTimer, attr, item)
+}
+
+/// Export the function as the hook of the canister that runs when the canister's wasm memory is
+/// about to run out.
+#[proc_macro_attribute]
+pub fn on_low_wasm_memory(attr: TokenStream, item: TokenStream) -> TokenStream {
+    process_entry_point(EntryPoint::OnLowWasmMemory, attr, item)
+}
+
 /// Export an update method for the canister.
+///
+/// The calls can be checked before the method runs with `guard`, the functions that return a
+/// `Result<(), E>` where `E` implements `ic_kit::utils::GuardError`, with `arg_guard` which
+/// also receive a reference to each of the decoded arguments, and with `async_guard`, the
+/// `async` functions that can call other canisters before allowing the call.
+///
+/// The async guards are awaited after the arguments are decoded, and just like in an `async`
+/// method, `ic::caller` can not be used once the guard performed a call, so a method with an
+/// async guard should not depend on the caller.
+///
+/// A method with `manual_reply = true` replies by itself with `ic::reply` instead of returning
+/// its result, the type of its reply in the candid interface is set with `reply_type`, for
+/// example `#[update(manual_reply = true, reply_type = "u64")]`.
 #[proc_macro_attribute]
 pub fn update(attr: TokenStream, item: TokenStream) -> TokenStream {
     process_entry_point(EntryPoint::Update, attr, item)
 }
 
-/// Export a query method for the canister.
+/// Export a query method for the canister, use `#[query(composite = true)]` to export it as a
+/// composite query that can call the query methods of other canisters. Only the composite queries
+/// can have an `async_guard`, see [`macro@update`].
 #[proc_macro_attribute]
 pub fn query(attr: TokenStream, item: TokenStream) -> TokenStream {
     process_entry_point(EntryPoint::Query, attr, item)
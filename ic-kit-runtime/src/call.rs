@@ -15,10 +15,11 @@ pub struct CallBuilder<'a> {
     sender: Principal,
     payment: u128,
     arg: Option<Vec<u8>>,
+    deadline: Option<u64>,
 }
 
 /// A reply by the canister.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum CallReply {
     Reply {
         data: Vec<u8>,
@@ -37,10 +38,11 @@ impl<'a> CallBuilder<'a> {
         Self {
             replica,
             canister_id,
-            sender: Principal::anonymous(),
+            sender: replica.default_caller(),
             method_name,
             payment: 0,
             arg: None,
+            deadline: None,
         }
     }
 
@@ -87,6 +89,13 @@ impl<'a> CallBuilder<'a> {
         self
     }
 
+    /// Make this a best-effort call with the given deadline in nanoseconds, if the replica's clock
+    /// passes the deadline before the canister replies, the call is rejected with `SysUnknown`.
+    pub fn with_deadline(mut self, deadline: u64) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
     /// Make the call from this sender.
     pub fn with_caller<I: Into<Principal>>(mut self, caller: I) -> Self {
         self.sender = caller.into();
@@ -120,10 +129,8 @@ impl CallReply {
             } => Message::Reply {
                 reply_to,
                 env: Env::default()
-                    .with_entry_mode(EntryMode::RejectCallback)
                     .with_cycles_refunded(cycles_refunded)
-                    .with_rejection_code(rejection_code)
-                    .with_rejection_message(rejection_message),
+                    .with_rejection(rejection_code, rejection_message),
             },
         }
     }
@@ -240,6 +247,57 @@ impl<'a> From<&'a CallBuilder<'a>> for CanisterCall {
                 .arg
                 .clone()
                 .unwrap_or_else(|| CANDID_EMPTY_ARG.to_vec()),
+            deadline: builder.deadline,
+            one_way: false,
+            query: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(data: Vec<u8>) -> CallReply {
+        CallReply::Reply {
+            data,
+            cycles_refunded: 0,
         }
     }
+
+    #[test]
+    fn decode() {
+        let r = reply(encode_args((17u64, String::from("Hello"))).unwrap());
+        let (n, s): (u64, String) = r.decode().unwrap();
+        assert_eq!(n, 17);
+        assert_eq!(s, "Hello");
+
+        let r = reply(encode_one(true).unwrap());
+        assert!(r.decode_one::<bool>().unwrap());
+
+        // The data is not a valid Candid message.
+        let r = reply(vec![0, 1, 2]);
+        assert!(matches!(
+            r.decode_one::<bool>(),
+            Err(CallError::ResponseDeserializationError(data)) if data == vec![0, 1, 2]
+        ));
+    }
+
+    #[test]
+    fn decode_reject() {
+        let r = CallReply::Reject {
+            rejection_code: RejectionCode::CanisterReject,
+            rejection_message: "Nope.".into(),
+            cycles_refunded: 0,
+        };
+
+        assert!(matches!(
+            r.decode::<(u64,)>(),
+            Err(CallError::Rejected(RejectionCode::CanisterReject, m)) if m == "Nope."
+        ));
+        assert!(matches!(
+            r.decode_one::<u64>(),
+            Err(CallError::Rejected(RejectionCode::CanisterReject, _))
+        ));
+    }
 }
@@ -1,15 +1,20 @@
 use crate::call::CallReply;
-use crate::stable::{HeapStableMemory, StableMemoryBackend};
+use crate::stable::{HeapStableMemory, StableMemoryBackend, TransactionalStableMemory};
 use crate::types::*;
+use candid::types::Type;
+use candid::{IDLArgs, TypeEnv};
 use futures::executor::block_on;
 use ic_kit_sys::ic0;
+use ic_kit_sys::ic0::copy_to_canister;
 use ic_kit_sys::ic0::runtime;
 use ic_kit_sys::ic0::runtime::Ic0CallHandlerProxy;
-use ic_kit_sys::types::RejectionCode;
+use ic_kit_sys::types::{RejectionCode, SystemApiError};
 use ic_types::Principal;
 use std::any::Any;
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
-use std::panic::catch_unwind;
+use std::panic::{catch_unwind, RefUnwindSafe, UnwindSafe};
+use std::sync::Arc;
 use std::thread::JoinHandle;
 use thread_local_panic_hook::set_hook;
 use tokio::select;
@@ -17,6 +22,18 @@ use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::oneshot;
 
 const MAX_CYCLES_PER_RESPONSE: u128 = 12;
+/// The maximum timeout of a best-effort call in seconds.
+const MAX_CALL_TIMEOUT: u32 = 300;
+/// The maximum number of pages that are addressable using the 32-bit stable memory API.
+const MAX_STABLE32_PAGES: u64 = 1 << 16;
+/// The instructions charged for each system call when there is no instruction model.
+const SYSTEM_CALL_INSTRUCTIONS: u64 = 100;
+/// The maximum size of an ingress or an inter-canister message on the IC, in bytes.
+const MAX_MESSAGE_SIZE: usize = 2 * 1024 * 1024;
+/// The cycle balance of a new canister.
+const INITIAL_BALANCE: u128 = 100_000_000_000_000;
+/// The maximum size of the data certified by a canister, in bytes.
+const MAX_CERTIFIED_DATA_SIZE: usize = 32;
 
 /// A canister that is being executed.
 pub struct Canister {
@@ -24,6 +41,14 @@ pub struct Canister {
     canister_id: Principal,
     /// Maps the name of each of exported methods to the task function.
     symbol_table: HashMap<String, fn()>,
+    /// The Candid types of the arguments of the exported methods, for the methods that provide
+    /// them.
+    arg_types: HashMap<String, Vec<Type>>,
+    /// Whether the arguments of the incoming messages are checked against the Candid types of
+    /// the method before it is executed.
+    strict_typing: bool,
+    /// The maximum size of the argument of a message or of a reply in bytes.
+    max_message_size: usize,
     /// The data reply that is being built for the current message. An interesting thing about the
     /// IC that I did not expect: The reply data is not preserved throughout the async context.
     /// And the reply is the first call to msg_reply that is inside a non-trapping task.
@@ -36,9 +61,24 @@ pub struct Canister {
     msg_reply: Option<CallReply>,
     /// The amount of available cycles for each incoming request. This is only used
     /// for recovering self.env state for reply callbacks.
-    cycles_available_store: HashMap<IncomingRequestId, u128>,
+    cycles_available_store: HashMap<IncomingRequestId, Cycles>,
+    /// The cycle balance of the canister, not including the cycles accepted during the current
+    /// message.
+    balance: Cycles,
+    /// The balance below which the canister is frozen and rejects the update calls.
+    freezing_threshold: u128,
+    /// The controllers the canister is added to a replica with, from then on the replica keeps
+    /// track of them.
+    controllers: Vec<Principal>,
     /// Amount of cycles accept during this message process.
-    cycles_accepted: u128,
+    cycles_accepted: Cycles,
+    /// The cycles accepted during the last message that was executed.
+    last_cycles_accepted: Cycles,
+    /// The cycles accepted by each call context that is not finished yet, including the cycles
+    /// accepted by its callbacks.
+    call_context_cycles_accepted: HashMap<IncomingRequestId, Cycles>,
+    /// Whether the current message is accepted by inspect_message.
+    message_accepted: bool,
     /// Pending outgoing requests that have not been resolved yet. This is used so we know when
     /// an incoming request is finally finished so we can send the last trapping message as the
     /// response.
@@ -48,18 +88,45 @@ pub struct Canister {
     outgoing_calls: HashMap<OutgoingRequestId, RequestCallbacks>,
     /// The canister execution environment.
     env: Env,
+    /// The environment used for the custom tasks run by [`Canister::custom`].
+    default_env: Env,
+    /// The instructions charged for each system call, see [`InstructionModel`].
+    instruction_model: Option<InstructionModel>,
+    /// The cycles charged for each inter-canister call.
+    call_cost: CallCost,
+    /// The instructions executed during the current message.
+    instructions: u64,
+    /// The instructions executed by each call context that is not finished yet.
+    call_context_instructions: HashMap<IncomingRequestId, u64>,
+    /// The call contexts of the composite queries that are not finished yet, the callbacks of
+    /// their calls are executed as a part of the query.
+    composite_queries: HashSet<IncomingRequestId>,
+    /// The virtual clock of the canister, when set it overrides the time of every message that is
+    /// executed on this canister.
+    time: Option<u64>,
+    /// The time at which the global timer of the canister fires, zero if the timer is not set.
+    global_timer: u64,
+    /// The data certified by the canister, at most 32 bytes long.
+    certified_data: Vec<u8>,
+    /// The status of the canister.
+    status: CanisterStatus,
+    /// The report of the last executed message.
+    report: ExecutionReport,
+    /// Every debug message printed by the canister that is not drained yet.
+    debug_log: Vec<String>,
     /// The stable storage backend for this canister.
-    stable: Box<dyn StableMemoryBackend + Send>,
+    stable: TransactionalStableMemory,
     /// The request id of the current incoming message.
     request_id: Option<IncomingRequestId>,
     /// The calls that are finalized and should be sent after this entry point's successful
     /// execution.
-    call_queue: Vec<(Principal, String, RequestCallbacks, u128, Vec<u8>)>,
+    call_queue: Vec<PendingCall>,
     /// The current call under construction, once call_perform is called, this will go into
     /// the call_queue to be performed later on.
-    pending_call: Option<(Principal, String, RequestCallbacks, u128, Vec<u8>)>,
-    /// The thread in which the canister is being executed at.
-    _execution_thread_handle: JoinHandle<()>,
+    pending_call: Option<PendingCall>,
+    /// The thread in which the canister is being executed at, it is joined when the canister is
+    /// dropped.
+    execution_thread_handle: Option<JoinHandle<()>>,
     /// The communication channel to send tasks to the execution thread.
     task_tx: Sender<TaskFn>,
     /// Emits when the task we just sent has returned.
@@ -70,6 +137,11 @@ pub struct Canister {
     request_rx: Receiver<runtime::Request>,
 }
 
+thread_local! {
+    /// The location of the last panic in the current execution thread.
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
 #[derive(Debug)]
 enum Completion {
     Ok,
@@ -79,9 +151,23 @@ enum Completion {
 /// Any of the reply, reject or clean up callbacks.
 /// (callback_fun, callback_env)
 ///
-/// The callback_fun can be set to -1 for one-way calls.
+/// The callback_fun can be set to -1 when there is no callback, a call with neither a reply nor
+/// a reject callback is a one-way call and the caller never receives its response.
 type Callback = (isize, isize);
 
+/// An outgoing call made by the canister.
+/// (callee, method_name, callbacks, payment, arg, deadline)
+///
+/// The deadline is only set for best-effort calls.
+type PendingCall = (
+    Principal,
+    String,
+    RequestCallbacks,
+    u128,
+    Vec<u8>,
+    Option<u64>,
+);
+
 /// The callbacks
 struct RequestCallbacks {
     /// The original top-level message which caused this inter-canister call, this is used so
@@ -95,6 +181,13 @@ struct RequestCallbacks {
     cleanup: Option<Callback>,
 }
 
+impl RequestCallbacks {
+    /// Whether the call is a one-way call, that is when it has no reply and no reject callback.
+    fn is_one_way(&self) -> bool {
+        self.reply.0 == -1 && self.reject.0 == -1
+    }
+}
+
 /// A method exported by the canister.
 pub trait CanisterMethod {
     /// The export name of this method, this is the name that the method is
@@ -112,6 +205,13 @@ pub trait CanisterMethod {
     /// we perform the serialization/deserialization of arguments/responses, using the runtime
     /// primitives.
     fn exported_method();
+
+    /// The Candid types of the arguments of this method when they are known, in the strict
+    /// typing mode the arguments of the incoming messages are checked against these types before
+    /// the method is executed, see [`Canister::with_strict_typing`].
+    fn arg_types() -> Option<Vec<Type>> {
+        None
+    }
 }
 
 impl Canister {
@@ -131,15 +231,28 @@ impl Canister {
 
             // set the custom panic hook for this thread, this will give us:
             // - No message such as "thread panic during test" in the terminal.
-            // - TODO: Capture the panic location.
-            // let panic_hook_tx = task_completion_tx.clone();
-            set_hook(Box::new(|_| {}));
+            // - The location of the panic, so it can be reported along with the message.
+            set_hook(Box::new(|info| {
+                let location = info
+                    .location()
+                    .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()));
+                PANIC_LOCATION.with(|c| *c.borrow_mut() = location);
+            }));
 
             while let Some(task) = block_on(task_rx.recv()) {
+                PANIC_LOCATION.with(|c| c.borrow_mut().take());
+
                 let c = if let Err(payload) = catch_unwind(|| {
                     task();
                 }) {
-                    Completion::Panicked(downcast_panic_payload(&payload))
+                    let message = downcast_panic_payload(&payload);
+                    // Traps unwind without invoking the hook, so they don't have a location.
+                    match PANIC_LOCATION.with(|c| c.borrow_mut().take()) {
+                        Some(location) => {
+                            Completion::Panicked(format!("panicked at {}: {}", location, message))
+                        }
+                        None => Completion::Panicked(message),
+                    }
                 } else {
                     Completion::Ok
                 };
@@ -156,19 +269,40 @@ impl Canister {
         Self {
             canister_id: canister_id.into(),
             symbol_table: HashMap::new(),
+            arg_types: HashMap::new(),
+            strict_typing: false,
+            max_message_size: MAX_MESSAGE_SIZE,
             msg_reply_data: Vec::new(),
             msg_reply_senders: HashMap::new(),
             msg_reply: None,
             cycles_available_store: HashMap::new(),
-            cycles_accepted: 0,
+            balance: Cycles(INITIAL_BALANCE),
+            freezing_threshold: 0,
+            controllers: Vec::new(),
+            cycles_accepted: Cycles::default(),
+            last_cycles_accepted: Cycles::default(),
+            call_context_cycles_accepted: HashMap::new(),
+            message_accepted: false,
             pending_outgoing_requests: HashMap::new(),
             outgoing_calls: HashMap::new(),
             env: Env::default(),
-            stable: Box::new(HeapStableMemory::default()),
+            default_env: Env::default(),
+            instruction_model: None,
+            call_cost: CallCost::default(),
+            instructions: 0,
+            call_context_instructions: HashMap::new(),
+            composite_queries: HashSet::new(),
+            time: None,
+            global_timer: 0,
+            certified_data: Vec::new(),
+            status: CanisterStatus::Running,
+            report: ExecutionReport::default(),
+            debug_log: Vec::new(),
+            stable: TransactionalStableMemory::new(Box::new(HeapStableMemory::default())),
             request_id: None,
             call_queue: Vec::with_capacity(8),
             pending_call: None,
-            _execution_thread_handle: execution_thread_handle,
+            execution_thread_handle: Some(execution_thread_handle),
             task_tx,
             task_completion_rx,
             reply_tx,
@@ -181,6 +315,41 @@ impl Canister {
         self.canister_id
     }
 
+    /// Change the id of the canister, this is only allowed before the canister is added to a
+    /// replica.
+    pub(crate) fn set_id(&mut self, canister_id: Principal) {
+        self.canister_id = canister_id;
+    }
+
+    /// Replace this instance of the canister with the given one, just like installing a new
+    /// code does. The new instance takes over the id, the cycle balance, the clock, the status,
+    /// the debug log, the stable memory and the certified data of the canister, everything else
+    /// including the heap memory and the global timer is dropped along with the old instance.
+    pub(crate) fn upgrade(&mut self, mut canister: Canister) -> Canister {
+        std::mem::swap(&mut canister.stable, &mut self.stable);
+        std::mem::swap(&mut canister.certified_data, &mut self.certified_data);
+        self.reinstall(canister)
+    }
+
+    /// Replace this instance of the canister with the given one just like [`Canister::upgrade`],
+    /// except that the stable memory and the certified data of the new instance are used as
+    /// well.
+    ///
+    /// Return the replaced instance, which can be put back the same way.
+    pub(crate) fn reinstall(&mut self, mut canister: Canister) -> Canister {
+        canister.canister_id = self.canister_id;
+        canister.balance = self.balance;
+        canister.freezing_threshold = self.freezing_threshold;
+        canister.time = self.time;
+        canister.status = self.status;
+        canister.instruction_model = self.instruction_model.take();
+        canister.call_cost = self.call_cost;
+        canister.strict_typing = self.strict_typing;
+        canister.max_message_size = self.max_message_size;
+        canister.debug_log = std::mem::take(&mut self.debug_log);
+        std::mem::replace(self, canister)
+    }
+
     /// Provide the canister with the definition of the given method.
     pub fn with_method<M: CanisterMethod + 'static>(mut self) -> Self {
         let method_name = String::from(M::EXPORT_NAME);
@@ -190,16 +359,313 @@ impl Canister {
             panic!("The canister already has a '{}' method.", method_name);
         }
 
+        if let Some(types) = M::arg_types() {
+            self.arg_types.insert(method_name.clone(), types);
+        }
+
         self.symbol_table.insert(method_name, task_fn);
         self
     }
 
+    /// Return true if the canister exports a method with the given name, for example
+    /// `canister_heartbeat` or `canister_update increment`.
+    pub fn has_method(&self, export_name: &str) -> bool {
+        self.symbol_table.contains_key(export_name)
+    }
+
+    /// Set the cycle balance of the canister.
+    pub fn with_balance(mut self, balance: u128) -> Self {
+        self.balance = balance.into();
+        self
+    }
+
+    /// Return the cycle balance of the canister.
+    pub fn balance(&self) -> u128 {
+        self.balance.get()
+    }
+
+    /// Add the given cycles to the balance of the canister, fails if the balance would not fit
+    /// in `u128`.
+    pub fn add_cycles(&mut self, cycles: u128) -> Result<(), SystemApiError> {
+        self.balance = self
+            .balance
+            .checked_add(cycles)
+            .map_err(|_| cycles_overflow("cycle balance"))?;
+        Ok(())
+    }
+
+    /// Freeze the canister while its balance is below the given threshold, a frozen canister
+    /// rejects the update calls with an out of cycles rejection but still answers the queries.
+    pub fn with_freezing_threshold(mut self, cycles: u128) -> Self {
+        self.set_freezing_threshold(cycles);
+        self
+    }
+
+    /// Set the freezing threshold of the canister in cycles.
+    pub fn set_freezing_threshold(&mut self, cycles: u128) {
+        self.freezing_threshold = cycles;
+    }
+
+    /// Return the freezing threshold of the canister in cycles.
+    pub fn freezing_threshold(&self) -> u128 {
+        self.freezing_threshold
+    }
+
+    /// Return whether the balance of the canister is below its freezing threshold.
+    pub fn is_frozen(&self) -> bool {
+        self.balance.get() < self.freezing_threshold
+    }
+
+    /// Set the controllers of the canister, only the controllers can stop, upgrade or delete the
+    /// canister through the management canister. A canister has no controllers by default.
+    ///
+    /// Once the canister is added to a replica use [`Replica::set_controllers`] instead.
+    ///
+    /// [`Replica::set_controllers`]: crate::Replica::set_controllers
+    pub fn with_controllers(mut self, controllers: Vec<Principal>) -> Self {
+        self.controllers = controllers;
+        self
+    }
+
+    /// Take the controllers the canister is created with, so the replica can own them.
+    pub(crate) fn take_controllers(&mut self) -> Vec<Principal> {
+        std::mem::take(&mut self.controllers)
+    }
+
+    /// Return the cycles accepted during the last message executed by the canister.
+    pub fn last_message_cycles_accepted(&self) -> u128 {
+        self.last_cycles_accepted.get()
+    }
+
+    /// Use the given environment for the custom tasks run by [`Canister::custom`], this does not
+    /// affect any other message processed by the canister.
+    pub fn with_env(mut self, env: Env) -> Self {
+        self.default_env = env;
+        self
+    }
+
+    /// Set the caller of the custom tasks run by [`Canister::custom`].
+    pub fn with_caller<I: Into<Principal>>(mut self, caller: I) -> Self {
+        self.default_env.sender = caller.into();
+        self
+    }
+
+    /// Set the cycles sent with the custom tasks run by [`Canister::custom`].
+    pub fn with_cycles(mut self, cycles: u128) -> Self {
+        self.default_env.cycles_available = cycles.into();
+        self
+    }
+
     /// Provide the canister with this stable storage backend.
     pub fn with_stable(mut self, stable: Box<dyn StableMemoryBackend + Send>) -> Self {
-        self.stable = stable;
+        self.stable = TransactionalStableMemory::new(stable);
+        self
+    }
+
+    /// Use the given model to count the instructions executed by the canister, the model
+    /// returns the instructions charged for each system call.
+    pub fn with_instruction_model<F: Fn(&runtime::Request) -> u64 + Send + Sync + 'static>(
+        mut self,
+        model: F,
+    ) -> Self {
+        self.set_instruction_model(Arc::new(model));
+        self
+    }
+
+    /// Set the model used to count the instructions executed by the canister.
+    pub fn set_instruction_model(&mut self, model: InstructionModel) {
+        self.instruction_model = Some(model);
+    }
+
+    /// Charge the canister a base fee plus a fee for each byte of the method name and the
+    /// argument for every inter-canister call it performs.
+    pub fn with_call_cost(mut self, base: u128, per_byte: u128) -> Self {
+        self.set_call_cost(CallCost { base, per_byte });
+        self
+    }
+
+    /// Set the cycles charged for each inter-canister call performed by the canister.
+    pub fn set_call_cost(&mut self, cost: CallCost) {
+        self.call_cost = cost;
+    }
+
+    /// Check the arguments of every incoming message against the Candid types of the method
+    /// before executing it, and reject the messages whose arguments do not match, see
+    /// [`CanisterMethod::arg_types`]. The methods without known types are not checked.
+    pub fn with_strict_typing(mut self, strict: bool) -> Self {
+        self.set_strict_typing(strict);
+        self
+    }
+
+    /// Set whether the arguments of the incoming messages are checked against the Candid types
+    /// of the method.
+    pub fn set_strict_typing(&mut self, strict: bool) {
+        self.strict_typing = strict;
+    }
+
+    /// Set the maximum size in bytes of the incoming messages, the calls made by the canister
+    /// and its replies, the larger messages are rejected and the canister traps when it builds
+    /// a larger call or reply. The default is the limit of the IC which is 2MiB.
+    pub fn with_max_message_size(mut self, bytes: usize) -> Self {
+        self.set_max_message_size(bytes);
+        self
+    }
+
+    /// Set the maximum size of the messages in bytes.
+    pub fn set_max_message_size(&mut self, bytes: usize) {
+        self.max_message_size = bytes;
+    }
+
+    /// Set the canister's clock to the given time in nanoseconds since the UNIX epoch, the clock
+    /// does not move on its own after this, and all the messages observe this exact time.
+    pub fn with_time(mut self, time: u64) -> Self {
+        self.set_time(time);
         self
     }
 
+    /// Set the canister's clock to the given time in nanoseconds since the UNIX epoch.
+    ///
+    /// Moving the clock backward is allowed for testing purposes, even though the time on the IC
+    /// is guaranteed to be monotonic, so a warning is printed when that happens.
+    pub fn set_time(&mut self, time: u64) {
+        if let Some(current) = self.time {
+            if time < current {
+                eprintln!(
+                    "ic-kit-runtime: Moving the clock of canister '{}' backward from {} to {}.",
+                    self.canister_id, current, time
+                );
+            }
+        }
+
+        self.time = Some(time);
+    }
+
+    /// Return the time at which the global timer of the canister fires, zero means the timer is
+    /// not set.
+    pub fn global_timer(&self) -> u64 {
+        self.global_timer
+    }
+
+    /// Return the data certified by the canister with `certified_data_set`.
+    pub fn certified_data(&self) -> &[u8] {
+        &self.certified_data
+    }
+
+    /// Take a snapshot of the state of the canister, the canister can be reverted to this state
+    /// later using [`Canister::restore`].
+    ///
+    /// The snapshot contains the cycle balance, the global timer, the certified data and the
+    /// stable memory of the canister. The heap memory lives in the execution thread of the
+    /// canister and is not part of the snapshot, so the canisters that need their state to be
+    /// restored should keep it in the stable memory.
+    pub fn snapshot(&mut self) -> CanisterSnapshot {
+        CanisterSnapshot {
+            balance: self.balance.get(),
+            global_timer: self.global_timer,
+            certified_data: self.certified_data.clone(),
+            stable_memory: self.stable.read_all(),
+        }
+    }
+
+    /// Revert the canister to the state captured by the given snapshot, see
+    /// [`Canister::snapshot`]. The calls that are in flight are not affected.
+    pub fn restore(&mut self, snapshot: &CanisterSnapshot) {
+        self.balance = snapshot.balance.into();
+        self.global_timer = snapshot.global_timer;
+        self.certified_data = snapshot.certified_data.clone();
+        self.stable.restore(&snapshot.stable_memory);
+    }
+
+    /// Return the current status of the canister.
+    pub fn status(&self) -> CanisterStatus {
+        self.status
+    }
+
+    /// Change the status of the canister, a canister that is not running rejects all of the
+    /// incoming calls.
+    pub fn set_status(&mut self, status: CanisterStatus) {
+        self.status = status;
+    }
+
+    /// Return the entry mode of the message that is being executed on the canister, or of the
+    /// last executed message.
+    pub fn current_entry_mode(&self) -> EntryMode {
+        self.env.entry_mode
+    }
+
+    /// Return true if the current message is a query call, or a callback of the calls made by a
+    /// composite query, see [`Env::is_query_context`].
+    pub fn is_query_context(&self) -> bool {
+        self.env.is_query_context()
+            || self
+                .request_id
+                .is_some_and(|id| self.composite_queries.contains(&id))
+    }
+
+    /// Return the reply data appended by the given incoming message during the last execution,
+    /// or `None` if the last execution was for another message, or it did not append any data or
+    /// responded to the message. The data is dropped once the next execution starts.
+    pub fn peek_reply_data(&self, id: IncomingRequestId) -> Option<&[u8]> {
+        if self.request_id != Some(id) || self.msg_reply_data.is_empty() {
+            return None;
+        }
+
+        Some(self.msg_reply_data.as_slice())
+    }
+
+    /// Return the report of the last message executed on this canister.
+    pub fn execution_report(&self) -> &ExecutionReport {
+        &self.report
+    }
+
+    /// Take every debug message printed by this canister since the last call to this method,
+    /// including the ones printed in the callbacks of inter-canister calls.
+    pub fn drain_debug_log(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.debug_log)
+    }
+
+    /// Run the given function as a custom task with the environment provided by
+    /// [`Canister::with_env`], and return the reply.
+    ///
+    /// This is meant for testing a canister on its own, the calls made by the task are not
+    /// delivered, use a [`Replica`](crate::Replica) for that.
+    pub async fn custom<F: FnOnce() + Send + RefUnwindSafe + UnwindSafe + 'static>(
+        &mut self,
+        f: F,
+    ) -> CallReply {
+        let message = Message::CustomTask {
+            request_id: RequestId::new(),
+            task: Box::new(f),
+            env: self.default_env.clone(),
+        };
+
+        self.process_message_and_wait(message).await
+    }
+
+    /// Process the given request or custom task and return the reply of the canister.
+    ///
+    /// # Panics
+    ///
+    /// If the message is a reply to an outgoing call, or if the canister is still waiting for
+    /// a response to the calls it made, those are only delivered by a [`Replica`](crate::Replica).
+    pub async fn process_message_and_wait(&mut self, message: Message) -> CallReply {
+        assert!(
+            !matches!(message, Message::Reply { .. }),
+            "ic-kit-runtime: Only requests and custom tasks have a reply."
+        );
+
+        let (tx, mut rx) = oneshot::channel();
+        self.process_message(message, Some(tx)).await;
+        rx.try_recv().expect(
+            "ic-kit-runtime: The canister did not reply, because it is waiting for its outgoing calls.",
+        )
+    }
+
+    /// Take the report of the last message executed on this canister.
+    pub(crate) fn take_execution_report(&mut self) -> ExecutionReport {
+        std::mem::take(&mut self.report)
+    }
+
     pub async fn process_message(
         &mut self,
         message: Message,
@@ -209,7 +675,11 @@ impl Canister {
         self.discard_pending_call();
         self.discard_call_queue();
         self.request_id = None;
-        self.cycles_accepted = 0;
+        self.msg_reply_data.clear();
+        self.cycles_accepted = Cycles::default();
+        self.last_cycles_accepted = Cycles::default();
+        self.instructions = 0;
+        self.report = ExecutionReport::default();
 
         // Assign the request_id for this message.
         let (request_id, env, task) = match message {
@@ -243,6 +713,65 @@ impl Canister {
                         && env.entry_mode != EntryMode::CustomTask
                 );
 
+                // A query to a method that is exported as a composite query is executed as one.
+                let mut env = env;
+                if env.entry_mode == EntryMode::Query {
+                    let composite_name = format!(
+                        "canister_composite_query {}",
+                        env.method_name.as_deref().unwrap_or_default()
+                    );
+
+                    if self.symbol_table.contains_key(&composite_name) {
+                        env.entry_mode = EntryMode::CompositeQuery;
+                    }
+                }
+
+                // The timer is deactivated before the global timer runs, so it only fires once.
+                if env.entry_mode == EntryMode::GlobalTimer {
+                    self.global_timer = 0;
+                }
+
+                // Queries are still executed on a canister that is not running.
+                if self.status != CanisterStatus::Running
+                    && !matches!(env.entry_mode, EntryMode::Query | EntryMode::CompositeQuery)
+                {
+                    let rejection_message = match self.status {
+                        CanisterStatus::Stopping => format!("Canister {} is stopping", self.id()),
+                        _ => format!("Canister {} is stopped", self.id()),
+                    };
+
+                    self.send_reply(
+                        reply_sender.unwrap(),
+                        CallReply::Reject {
+                            rejection_code: RejectionCode::CanisterError,
+                            rejection_message,
+                            cycles_refunded: env.cycles_available.get(),
+                        },
+                    );
+
+                    return Vec::new();
+                }
+
+                if self.is_frozen() && env.entry_mode == EntryMode::Update {
+                    let rejection_message = format!(
+                        "Canister {} is out of cycles: its balance {} is below the freezing threshold {}",
+                        self.id(),
+                        self.balance.get(),
+                        self.freezing_threshold
+                    );
+
+                    self.send_reply(
+                        reply_sender.unwrap(),
+                        CallReply::Reject {
+                            rejection_code: RejectionCode::SysTransient,
+                            rejection_message,
+                            cycles_refunded: env.cycles_available.get(),
+                        },
+                    );
+
+                    return Vec::new();
+                }
+
                 let entry_point_name = env.get_entry_point_name();
                 let task = self
                     .symbol_table
@@ -283,8 +812,12 @@ impl Canister {
                     _ => unreachable!(),
                 };
 
+                // The cycles reserved for processing the response are not used by the runtime, so
+                // they are all returned along with the refunded cycles.
+                self.balance = self.balance.saturating_add(MAX_CYCLES_PER_RESPONSE);
+
                 let task = Box::new(move || unsafe {
-                    // -1 is used by a one-way call.
+                    // -1 is used when there is no callback.
                     if fun != -1 {
                         let fun = std::mem::transmute::<isize, fn(isize)>(fun);
                         fun(fun_env);
@@ -304,69 +837,164 @@ impl Canister {
                     "Canister does not have a '{}' method.",
                     env.method_name.unwrap_or_default()
                 ),
-                cycles_refunded: env.cycles_available,
+                cycles_refunded: env.cycles_available.get(),
             };
 
-            chan.send(reply)
-                .expect("ic-kit-runtime: Could not send the message reply.");
+            self.send_reply(chan, reply);
 
             return Vec::new();
         }
 
+        if env.args.len() > self.max_message_size {
+            if let Some(reply_sender) = reply_sender {
+                let reply = CallReply::Reject {
+                    rejection_code: RejectionCode::SysFatal,
+                    rejection_message: format!(
+                        "The message of {} bytes exceeds the limit of {} bytes.",
+                        env.args.len(),
+                        self.max_message_size
+                    ),
+                    cycles_refunded: env.cycles_available.get(),
+                };
+
+                self.send_reply(reply_sender, reply);
+                return Vec::new();
+            }
+        }
+
+        if self.strict_typing && reply_sender.is_some() {
+            if let Err(rejection_message) = self.check_arg_types(&env) {
+                if let Some(reply_sender) = reply_sender {
+                    let reply = CallReply::Reject {
+                        rejection_code: RejectionCode::CanisterReject,
+                        rejection_message,
+                        cycles_refunded: env.cycles_available.get(),
+                    };
+
+                    self.send_reply(reply_sender, reply);
+                }
+
+                return Vec::new();
+            }
+        }
+
+        if env.ingress && env.entry_mode == EntryMode::Update {
+            if let Err(reply) = self.inspect_message(&env).await {
+                self.send_reply(reply_sender.unwrap(), reply);
+                return Vec::new();
+            }
+        }
+
         self.request_id = Some(request_id);
         self.env = env;
+        if self.env.entry_mode == EntryMode::CompositeQuery {
+            self.composite_queries.insert(request_id);
+        }
+        if let Some(time) = self.time {
+            self.env.time = time;
+        }
         self.env.cycles_available = *self
             .cycles_available_store
             .entry(request_id)
             .or_insert(self.env.cycles_available);
-        self.env.balance += self.env.cycles_refunded;
+        if let Some(balance) = self.env.balance {
+            self.balance = balance.into();
+        }
+        // The message traps if the refunded cycles do not fit in the balance.
+        let task = match self.balance.checked_add(self.env.cycles_refunded) {
+            Ok(balance) => {
+                self.balance = balance;
+                task
+            }
+            Err(_) => {
+                let error = cycles_overflow("cycle balance");
+                Some(Box::new(move || panic!("{}", error)) as TaskFn)
+            }
+        };
+        self.report.balance_before = self.balance.get();
+        self.report.cycles_refunded = self.env.cycles_refunded.get();
 
         if let Some(sender) = reply_sender {
             self.msg_reply_senders
                 .insert(self.request_id.unwrap(), sender);
         }
 
+        let global_timer = self.global_timer;
+        let certified_data = self.certified_data.clone();
         let completion = self.perform(task.unwrap()).await;
 
         match completion {
             Completion::Panicked(m) => {
+                self.report.trapped = true;
+                self.report.trap_message = Some(m.clone());
+                // Roll back the changes made by the trapping message.
+                self.stable.rollback();
+                self.global_timer = global_timer;
+                self.certified_data = certified_data;
+                // The response is discarded, so are the cycles it refunded.
+                let refunded = self
+                    .msg_reply
+                    .take()
+                    .map(|reply| reply.cycles_refunded())
+                    .unwrap_or(0);
+                self.msg_reply_data.clear();
                 // We panicked, so we don't want to send any of the outgoing messages.
                 self.discard_call_queue();
                 // return the cycles available in this call.
-                self.env.cycles_available += self.cycles_accepted;
-                self.cycles_accepted = 0;
+                self.env.cycles_available = self
+                    .env
+                    .cycles_available
+                    .saturating_add(self.cycles_accepted)
+                    .saturating_add(refunded);
+                self.cycles_accepted = Cycles::default();
                 self.cycles_available_store
                     .insert(self.request_id.unwrap(), self.env.cycles_available);
-                self.maybe_final_reply(Some(m), self.env.cycles_available);
+                self.maybe_final_reply(Some(m), self.env.cycles_available.get());
             }
             Completion::Ok => {
+                // The changes made by a query are discarded, only the stable memory can be rolled
+                // back, the heap memory lives in the execution thread.
+                if self.is_query_context() {
+                    self.stable.rollback();
+                } else {
+                    self.stable.commit();
+                }
+
                 if let Some(reply) = self.msg_reply.take() {
                     let chan = self
                         .msg_reply_senders
                         .remove(&self.request_id.unwrap())
                         .expect("ic-kit-runtime: Response channel not found for request.");
 
-                    chan.send(reply)
-                        .expect("ic-kit-runtime: Could not send the message reply.")
+                    self.send_reply(chan, reply);
                 }
 
-                self.maybe_final_reply(None, self.env.cycles_available);
+                self.maybe_final_reply(None, self.env.cycles_available.get());
             }
         };
 
         let queue = std::mem::replace(&mut self.call_queue, Vec::new());
         let mut tmp = Vec::<CanisterCall>::with_capacity(queue.len());
-        for (callee, method, cb, payment, arg) in queue {
+        // The calls made by a composite query can only call the query methods.
+        let query = self.composite_queries.contains(&self.request_id.unwrap());
+        for (callee, method, cb, payment, arg, deadline) in queue {
             let request_id = RequestId::new();
-
-            // Insert the pending request id for the current call.
-            self.pending_outgoing_requests
-                .entry(self.request_id.unwrap())
-                .or_default()
-                .insert(request_id);
-
-            // Store the callbacks to wake up the caller.
-            self.outgoing_calls.insert(request_id, cb);
+            let one_way = cb.is_one_way();
+
+            if one_way {
+                // The response of a one-way call is never processed, so the cycles reserved for
+                // it are returned right away.
+                self.balance = self.balance.saturating_add(MAX_CYCLES_PER_RESPONSE);
+            } else {
+                // Insert the pending request id for the current call.
+                self.pending_outgoing_requests
+                    .entry(self.request_id.unwrap())
+                    .or_default()
+                    .insert(request_id);
+
+                // Store the callbacks to wake up the caller.
+                self.outgoing_calls.insert(request_id, cb);
+            }
 
             tmp.push(CanisterCall {
                 sender: self.id(),
@@ -375,9 +1003,35 @@ impl Canister {
                 method,
                 payment,
                 arg,
+                deadline,
+                one_way,
+                query,
             });
         }
 
+        let request_id = self.request_id.unwrap();
+        let call_context_cycles_accepted = self
+            .call_context_cycles_accepted
+            .entry(request_id)
+            .or_default();
+        *call_context_cycles_accepted =
+            call_context_cycles_accepted.saturating_add(self.cycles_accepted);
+        self.report.call_context_cycles_accepted = call_context_cycles_accepted.get();
+
+        // The call context is finished once there are no more pending calls.
+        if !self.pending_outgoing_requests.contains_key(&request_id) {
+            self.call_context_instructions.remove(&request_id);
+            self.call_context_cycles_accepted.remove(&request_id);
+            self.composite_queries.remove(&request_id);
+        }
+
+        self.balance = self.balance.saturating_add(self.cycles_accepted);
+        self.last_cycles_accepted = self.cycles_accepted;
+        self.report.cycles_accepted = self.cycles_accepted.get();
+        self.report.balance_after = self.balance.get();
+        self.report.instructions = self.instructions;
+        self.report.outgoing_calls = tmp.clone();
+
         tmp
     }
 
@@ -399,6 +1053,7 @@ impl Canister {
                     break c;
                 },
                 Some(req) = self.request_rx.recv() => {
+                    self.count_instructions(&req);
                     let res = req.proxy(self);
                     self.reply_tx
                         .send(res)
@@ -414,6 +1069,61 @@ impl Canister {
         completion
     }
 
+    /// Run the inspect_message of the canister for the given ingress update call, and return the
+    /// rejection for the caller if the message is not accepted. A canister with no
+    /// inspect_message accepts every message.
+    async fn inspect_message(&mut self, env: &Env) -> Result<(), CallReply> {
+        let f = match self.symbol_table.get("canister_inspect_message") {
+            Some(f) => *f,
+            None => return Ok(()),
+        };
+
+        self.env = Env::inspect_message(env.method_name.clone().unwrap_or_default())
+            .with_sender(env.sender)
+            .with_raw_args(env.args.clone())
+            .with_time(self.time.unwrap_or(env.time));
+        self.message_accepted = false;
+
+        let completion = self.perform(Box::new(f)).await;
+
+        // The changes made by inspect_message are always discarded.
+        self.stable.rollback();
+        self.instructions = 0;
+        self.report = ExecutionReport::default();
+
+        let (rejection_code, rejection_message) = match completion {
+            Completion::Ok if self.message_accepted => return Ok(()),
+            Completion::Ok => (
+                RejectionCode::CanisterReject,
+                format!("Canister {} did not accept the message", self.id()),
+            ),
+            Completion::Panicked(m) => (RejectionCode::CanisterError, m),
+        };
+
+        Err(CallReply::Reject {
+            rejection_code,
+            rejection_message,
+            cycles_refunded: env.cycles_available.get(),
+        })
+    }
+
+    /// Charge the instructions of the given system call to the current message.
+    fn count_instructions(&mut self, request: &runtime::Request) {
+        let instructions = match &self.instruction_model {
+            Some(model) => model(request),
+            None => SYSTEM_CALL_INSTRUCTIONS,
+        };
+
+        self.instructions += instructions;
+
+        if let Some(request_id) = self.request_id {
+            *self
+                .call_context_instructions
+                .entry(request_id)
+                .or_default() += instructions;
+        }
+    }
+
     /// Send the final reply for the current call if none has already been sent.
     fn maybe_final_reply(&mut self, trap_message: Option<String>, cycles: u128) {
         let id = match self.request_id {
@@ -421,8 +1131,13 @@ impl Canister {
             None => return,
         };
 
-        // There are still pending outgoing calls we have to wait for them to finish.
-        if self.pending_outgoing_requests.contains_key(&id) || !self.call_queue.is_empty() {
+        // There are still pending outgoing calls we have to wait for them to finish, except for
+        // the one-way calls.
+        let waiting = self
+            .call_queue
+            .iter()
+            .any(|(_, _, cb, ..)| !cb.is_one_way());
+        if self.pending_outgoing_requests.contains_key(&id) || waiting {
             return;
         }
 
@@ -433,204 +1148,320 @@ impl Canister {
 
         self.cycles_available_store.remove(&id);
 
-        chan.send(CallReply::Reject {
-            rejection_code: RejectionCode::CanisterError,
-            rejection_message: trap_message
-                .unwrap_or_else(|| "Canister did not reply to the call".to_string()),
-            cycles_refunded: cycles,
+        self.send_reply(
+            chan,
+            CallReply::Reject {
+                rejection_code: RejectionCode::CanisterError,
+                rejection_message: trap_message
+                    .unwrap_or_else(|| "Canister did not reply to the call".to_string()),
+                cycles_refunded: cycles,
+            },
+        );
+    }
+
+    /// Check the arguments of the given message against the Candid types of the method, if the
+    /// types of the method are known.
+    fn check_arg_types(&self, env: &Env) -> Result<(), String> {
+        let types = match self
+            .arg_types
+            .get(&env.get_entry_point_name())
+            .or_else(|| self.arg_types.get(&env.get_possible_entry_point_name()))
+        {
+            Some(types) => types,
+            None => return Ok(()),
+        };
+
+        IDLArgs::from_bytes(&env.args)
+            .and_then(|args| args.annotate_types(false, &TypeEnv::new(), types))
+            .map(|_| ())
+            .map_err(|e| {
+                format!(
+                    "The arguments do not match the Candid interface of '{}': {}",
+                    env.get_entry_point_name(),
+                    e
+                )
+            })
+    }
+
+    /// Return an error if the given system call is not available in the entry point that is being
+    /// executed, see [`is_available`].
+    fn check_entry_mode(&self, call: &'static str) -> Result<(), SystemApiError> {
+        if is_available(call, self.env.entry_mode, self.env.replicated) {
+            return Ok(());
+        }
+
+        Err(SystemApiError::WrongEntryMode {
+            call,
+            mode: self.env.get_entry_point_name(),
         })
-        .expect("ic-kit-runtime: Could not send the message reply.")
+    }
+
+    /// Return an error if the given incoming message is already replied to.
+    fn ensure_not_replied(&self, message_id: IncomingRequestId) -> Result<(), SystemApiError> {
+        // We have either replied to this message in the current task execution, so the msg_reply
+        // contains data, or we have done this in previous task execution for this incoming message
+        // so the msg_reply_sender channel is no longer available.
+        if self.msg_reply.is_none() && self.msg_reply_senders.contains_key(&message_id) {
+            return Ok(());
+        }
+
+        // A query can not defer its response, so this is a reply that came too late.
+        if self.is_query_context() {
+            return Err(SystemApiError::QueryAlreadyResponded);
+        }
+
+        Err(SystemApiError::AlreadyResponded)
+    }
+
+    /// Take the cycles that are still available to the given incoming message so they can be
+    /// refunded with its response, the cycles accepted by the canister are not included.
+    fn refund_cycles(&mut self, message_id: IncomingRequestId) -> u128 {
+        self.cycles_available_store
+            .insert(message_id, Cycles::default());
+        std::mem::take(&mut self.env.cycles_available).get()
+    }
+
+    /// Return an error if the given range is not within the current size of the stable memory.
+    fn check_stable_bounds(&mut self, offset: u64, size: u64) -> Result<(), SystemApiError> {
+        let stable_size = self.stable.stable_size() << 16;
+
+        match offset.checked_add(size) {
+            Some(end) if end <= stable_size => Ok(()),
+            _ => Err(SystemApiError::StableOutOfBounds {
+                offset,
+                size,
+                stable_size,
+            }),
+        }
+    }
+
+    /// Send the given reply to the caller and record it in the execution report.
+    fn send_reply(&mut self, chan: oneshot::Sender<CallReply>, reply: CallReply) {
+        self.report.reply = Some(reply.clone());
+
+        // The caller might have already given up on the call, for example when the deadline
+        // of a best-effort call has passed, in that case the reply is just dropped.
+        if chan.is_closed() {
+            return;
+        }
+
+        if let Err(reply) = chan.send(reply) {
+            eprintln!(
+                "ic-kit-runtime: Canister '{}' could not send the reply {:?}.",
+                self.canister_id, reply
+            );
+        }
     }
 
     fn discard_pending_call(&mut self) {
         if let Some(pending_call) = self.pending_call.take() {
-            self.env.balance += MAX_CYCLES_PER_RESPONSE + pending_call.3;
+            self.balance = self
+                .balance
+                .saturating_add(MAX_CYCLES_PER_RESPONSE)
+                .saturating_add(pending_call.3);
         }
     }
 
     fn discard_call_queue(&mut self) {
         while let Some(pending_call) = self.call_queue.pop() {
-            self.env.balance += MAX_CYCLES_PER_RESPONSE + pending_call.3;
+            let fee = self.call_cost.fee(&pending_call.1, &pending_call.4);
+            self.balance = self
+                .balance
+                .saturating_add(MAX_CYCLES_PER_RESPONSE)
+                .saturating_add(pending_call.3)
+                .saturating_add(fee);
         }
     }
 }
 
-impl Ic0CallHandlerProxy for Canister {
-    fn msg_arg_data_size(&mut self) -> Result<isize, String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Init
-            | EntryMode::Update
-            | EntryMode::Query
-            | EntryMode::ReplyCallback
-            | EntryMode::InspectMessage => Ok(self.env.args.len() as isize),
-            _ => Err(format!(
-                "msg_arg_data_size can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
-        }
-    }
-
-    fn msg_arg_data_copy(&mut self, dst: isize, offset: isize, size: isize) -> Result<(), String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Init
-            | EntryMode::PostUpgrade
-            | EntryMode::Update
-            | EntryMode::Query
-            | EntryMode::ReplyCallback
-            | EntryMode::InspectMessage => {
-                let data = self.env.args.as_slice();
-                copy_to_canister(dst, offset, size, data)?;
-                Ok(())
-            }
-            _ => Err(format!(
-                "msg_arg_data_copy can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
-        }
-    }
-
-    fn msg_caller_size(&mut self) -> Result<isize, String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Init
-            | EntryMode::PostUpgrade
-            | EntryMode::PreUpgrade
-            | EntryMode::Update
-            | EntryMode::Query
-            | EntryMode::InspectMessage => Ok(self.env.sender.as_slice().len() as isize),
-            _ => Err(format!(
-                "msg_caller_size can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
-        }
-    }
-
-    fn msg_caller_copy(&mut self, dst: isize, offset: isize, size: isize) -> Result<(), String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Init
-            | EntryMode::PostUpgrade
-            | EntryMode::PreUpgrade
-            | EntryMode::Update
-            | EntryMode::Query
-            | EntryMode::InspectMessage => {
-                let data = self.env.sender.as_slice();
-                copy_to_canister(dst, offset, size, data)?;
-                Ok(())
-            }
-            _ => Err(format!(
-                "msg_caller_copy can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
+impl Drop for Canister {
+    fn drop(&mut self) {
+        // Close every channel of the execution thread, so the thread returns once it is idle, or
+        // fails to reach the canister if it is in the middle of a task.
+        drop(std::mem::replace(&mut self.task_tx, mpsc::channel(1).0));
+        drop(std::mem::replace(&mut self.reply_tx, mpsc::channel(1).0));
+        self.request_rx.close();
+        self.task_completion_rx.close();
+
+        if let Some(handle) = self.execution_thread_handle.take() {
+            // The thread panics if it is stopped in the middle of a task, which is expected.
+            let _ = handle.join();
         }
     }
+}
 
-    fn msg_reject_code(&mut self) -> Result<i32, String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask | EntryMode::ReplyCallback | EntryMode::RejectCallback => {
-                Ok(self.env.rejection_code as i32)
-            }
-            _ => Err(format!(
-                "msg_reject_code can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
+/// Whether the given system call is available in the given entry point, this follows the
+/// availability table of the Internet Computer interface specification:
+///
+/// | System calls                                  | Entry points    |
+/// |-----------------------------------------------|-----------------|
+/// | `msg_arg_data_*`                              | I U Q CQ Ry F   |
+/// | `msg_caller_*`                                | I G U Q CQ F    |
+/// | `msg_reject_code`                             | Ry Rt           |
+/// | `msg_reject_msg_*`                            | Rt              |
+/// | `msg_reply*`, `msg_reject`, `msg_deadline`    | U Q CQ Ry Rt    |
+/// | `msg_cycles_available*`, `msg_cycles_accept*` | U RQ Ry Rt      |
+/// | `msg_cycles_refunded*`                        | Ry Rt           |
+/// | `msg_method_name_*`, `accept_message`         | F               |
+/// | `call_new`                                    | U CQ Ry Rt T    |
+/// | `global_timer_set`, `certified_data_set`      | I G U Ry Rt C T |
+///
+/// Where `I` is `canister_init` and `canister_post_upgrade`, `G` is `canister_pre_upgrade`, `U`
+/// is an update, `Q` is a query, `RQ` a query in replicated mode and `CQ` a composite query, `Ry`
+/// and `Rt` are the reply and reject callbacks, `C` is the cleanup callback, `F` is
+/// `canister_inspect_message` and `T` are the system tasks such as the heartbeat. The custom tasks
+/// can use every system call except `accept_message`.
+///
+/// The system calls that are available everywhere are not checked, so any other name is denied
+/// in every entry point.
+fn is_available(call: &str, mode: EntryMode, replicated: bool) -> bool {
+    use EntryMode::*;
+
+    let available = match call {
+        "msg_arg_data_size" | "msg_arg_data_copy" => matches!(
+            mode,
+            Init | PostUpgrade | Update | Query | CompositeQuery | ReplyCallback | InspectMessage
+        ),
+        "msg_caller_size" | "msg_caller_copy" => matches!(
+            mode,
+            Init | PostUpgrade | PreUpgrade | Update | Query | CompositeQuery | InspectMessage
+        ),
+        "msg_reject_code" => matches!(mode, ReplyCallback | RejectCallback),
+        "msg_reject_msg_size" | "msg_reject_msg_copy" => mode == RejectCallback,
+        "msg_reply_data_append" | "msg_reply" | "msg_reject" | "msg_deadline" => {
+            matches!(
+                mode,
+                Update | Query | CompositeQuery | ReplyCallback | RejectCallback
+            )
         }
-    }
-
-    fn msg_reject_msg_size(&mut self) -> Result<isize, String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask | EntryMode::RejectCallback => {
-                Ok(self.env.rejection_message.len() as isize)
-            }
-            _ => Err(format!(
-                "msg_reject_msg_size can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
+        "msg_cycles_available"
+        | "msg_cycles_available128"
+        | "msg_cycles_accept"
+        | "msg_cycles_accept128" => match mode {
+            Update | ReplyCallback | RejectCallback => true,
+            Query => replicated,
+            _ => false,
+        },
+        "msg_cycles_refunded" | "msg_cycles_refunded128" => {
+            matches!(mode, ReplyCallback | RejectCallback)
         }
+        "msg_method_name_size" | "msg_method_name_copy" | "accept_message" => {
+            mode == InspectMessage
+        }
+        "call_new" => matches!(
+            mode,
+            Update
+                | CompositeQuery
+                | ReplyCallback
+                | RejectCallback
+                | Heartbeat
+                | GlobalTimer
+                | OnLowWasmMemory
+        ),
+        "global_timer_set" | "certified_data_set" => {
+            !matches!(mode, Query | CompositeQuery | InspectMessage)
+        }
+        _ => return false,
+    };
+
+    available || (mode == CustomTask && call != "accept_message")
+}
+
+impl Ic0CallHandlerProxy for Canister {
+    fn msg_arg_data_size(&mut self) -> Result<isize, SystemApiError> {
+        self.check_entry_mode("msg_arg_data_size")?;
+        Ok(self.env.args.len() as isize)
     }
 
-    fn msg_reject_msg_copy(
+    fn msg_arg_data_copy(
         &mut self,
         dst: isize,
         offset: isize,
         size: isize,
-    ) -> Result<(), String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask | EntryMode::RejectCallback => {
-                let data = self.env.rejection_message.as_bytes();
-                copy_to_canister(dst, offset, size, data)?;
-                Ok(())
-            }
-            _ => Err(format!(
-                "msg_reject_msg_copy can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
-        }
-    }
-
-    fn msg_reply_data_append(&mut self, src: isize, size: isize) -> Result<(), String> {
-        let message_id = match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Update
-            | EntryMode::Query
-            | EntryMode::ReplyCallback
-            | EntryMode::RejectCallback => {
-                // this should always be present when processing a call.
-                self.request_id
-                    .expect("ic-kit: Unexpected canister state, request_id not set.")
-            }
-            _ => {
-                return Err(format!(
-                    "msg_reply_data_append can not be called from '{}'",
-                    self.env.get_entry_point_name()
-                ))
-            }
-        };
-
-        if !self.msg_reply_senders.contains_key(&message_id) {
-            return Err(
-                "msg_reply_data_append may only be invoked before canister responses.".to_string(),
-            );
-        }
+    ) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_arg_data_copy")?;
+        let data = self.env.args.as_slice();
+        copy_to_canister(dst, offset, size, data)?;
+        Ok(())
+    }
 
-        self.msg_reply_data
-            .extend_from_slice(copy_from_canister(src, size));
+    fn msg_caller_size(&mut self) -> Result<isize, SystemApiError> {
+        self.check_entry_mode("msg_caller_size")?;
+        Ok(self.env.sender.as_slice().len() as isize)
+    }
 
+    fn msg_caller_copy(
+        &mut self,
+        dst: isize,
+        offset: isize,
+        size: isize,
+    ) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_caller_copy")?;
+        let data = self.env.sender.as_slice();
+        copy_to_canister(dst, offset, size, data)?;
         Ok(())
     }
 
-    fn msg_reply(&mut self) -> Result<(), String> {
-        let message_id = match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Update
-            | EntryMode::Query
-            | EntryMode::ReplyCallback
-            | EntryMode::RejectCallback => {
-                // this should always be present when processing a call.
-                self.request_id
-                    .expect("ic-kit: Unexpected canister state, request_id not set.")
-            }
-            _ => {
-                return Err(format!(
-                    "msg_reply can not be called from '{}'",
-                    self.env.get_entry_point_name()
-                ))
-            }
-        };
+    fn msg_reject_code(&mut self) -> Result<i32, SystemApiError> {
+        self.check_entry_mode("msg_reject_code")?;
+        Ok(i32::from(self.env.rejection_code))
+    }
 
-        // We have either replied to this message in the current task execution, so the msg_reply
-        // contains data, or we have done this in previous task execution for this incoming message
-        // so the msg_reply_sender channel is no longer available.
-        if self.msg_reply.is_some() || !self.msg_reply_senders.contains_key(&message_id) {
-            return Err("Current call is already replied to.".to_string());
+    fn msg_reject_msg_size(&mut self) -> Result<isize, SystemApiError> {
+        self.check_entry_mode("msg_reject_msg_size")?;
+        Ok(self.env.rejection_message.len() as isize)
+    }
+
+    fn msg_reject_msg_copy(
+        &mut self,
+        dst: isize,
+        offset: isize,
+        size: isize,
+    ) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_reject_msg_copy")?;
+        let data = self.env.rejection_message.as_bytes();
+        copy_to_canister(dst, offset, size, data)?;
+        Ok(())
+    }
+
+    fn msg_reply_data_append(&mut self, src: isize, size: isize) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_reply_data_append")?;
+
+        // this should always be present when processing a call.
+        let message_id = self
+            .request_id
+            .expect("ic-kit: Unexpected canister state, request_id not set.");
+
+        // Appending to a reply that is already sent traps just like a second reply does.
+        self.ensure_not_replied(message_id)?;
+
+        let total = self.msg_reply_data.len() + size.max(0) as usize;
+        if total > self.max_message_size {
+            return Err(SystemApiError::PayloadTooLarge {
+                call: "msg_reply_data_append",
+                size: total,
+                limit: self.max_message_size,
+            });
         }
 
-        let data = self.msg_reply_data.clone();
-        self.msg_reply_data.clear();
-        let cycles_refunded = self.env.cycles_available;
-        self.env.cycles_available = 0;
+        self.msg_reply_data
+            .extend_from_slice(copy_from_canister(src, size));
+
+        Ok(())
+    }
+
+    fn msg_reply(&mut self) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_reply")?;
+
+        // this should always be present when processing a call.
+        let message_id = self
+            .request_id
+            .expect("ic-kit: Unexpected canister state, request_id not set.");
+
+        self.ensure_not_replied(message_id)?;
+
+        let data = std::mem::take(&mut self.msg_reply_data);
+        let cycles_refunded = self.refund_cycles(message_id);
         self.msg_reply = Some(CallReply::Reply {
             data,
             cycles_refunded,
@@ -639,35 +1470,20 @@ impl Ic0CallHandlerProxy for Canister {
         Ok(())
     }
 
-    fn msg_reject(&mut self, src: isize, size: isize) -> Result<(), String> {
-        let message_id = match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Update
-            | EntryMode::Query
-            | EntryMode::ReplyCallback
-            | EntryMode::RejectCallback => {
-                // this should always be present when processing a call.
-                self.request_id
-                    .expect("ic-kit: Unexpected canister state, request_id not set.")
-            }
-            _ => {
-                return Err(format!(
-                    "msg_reject can not be called from '{}'",
-                    self.env.get_entry_point_name()
-                ))
-            }
-        };
+    fn msg_reject(&mut self, src: isize, size: isize) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_reject")?;
+
+        // this should always be present when processing a call.
+        let message_id = self
+            .request_id
+            .expect("ic-kit: Unexpected canister state, request_id not set.");
 
         self.msg_reply_data.clear();
 
-        // see: msg_reply
-        if self.msg_reply.is_some() || !self.msg_reply_senders.contains_key(&message_id) {
-            return Err("Current call is already replied to.".to_string());
-        }
+        self.ensure_not_replied(message_id)?;
 
-        let cycles_refunded = self.env.cycles_available;
+        let cycles_refunded = self.refund_cycles(message_id);
         let rejection_message = String::from_utf8_lossy(copy_from_canister(src, size)).into();
-        self.env.cycles_available = 0;
         self.msg_reply = Some(CallReply::Reject {
             rejection_code: RejectionCode::CanisterReject,
             rejection_message,
@@ -677,91 +1493,68 @@ impl Ic0CallHandlerProxy for Canister {
         Ok(())
     }
 
-    fn msg_cycles_available(&mut self) -> Result<i64, String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Update
-            | EntryMode::ReplyCallback
-            | EntryMode::RejectCallback => {
-                if self.env.cycles_available > (u64::MAX as u128) {
-                    return Err("available cycles does not fit in u64".to_string());
-                }
+    fn msg_deadline(&mut self) -> Result<i64, SystemApiError> {
+        self.check_entry_mode("msg_deadline")?;
+        Ok(self.env.deadline as i64)
+    }
 
-                Ok(self.env.cycles_available as u64 as i64)
-            }
-            _ => Err(format!(
-                "msg_cycles_available can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
-        }
-    }
-
-    fn msg_cycles_available128(&mut self, dst: isize) -> Result<(), String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Update
-            | EntryMode::ReplyCallback
-            | EntryMode::RejectCallback => {
-                let data = self.env.cycles_available.to_le_bytes();
-                copy_to_canister(dst, 0, 16, &data)?;
-                Ok(())
-            }
-            _ => Err(format!(
-                "msg_cycles_available128 can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
+    fn msg_cycles_available(&mut self) -> Result<i64, SystemApiError> {
+        self.check_entry_mode("msg_cycles_available")?;
+        let cycles = self.env.cycles_available.get();
+        if cycles > (u64::MAX as u128) {
+            return Err(SystemApiError::Overflow {
+                value: "available cycles",
+                target: "u64",
+            });
         }
-    }
 
-    fn msg_cycles_refunded(&mut self) -> Result<i64, String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask | EntryMode::ReplyCallback | EntryMode::RejectCallback => {
-                if self.env.cycles_refunded > (u64::MAX as u128) {
-                    return Err("refunded cycles does not fit in u64".to_string());
-                }
+        Ok(cycles as u64 as i64)
+    }
 
-                Ok(self.env.cycles_refunded as u64 as i64)
-            }
-            _ => Err(format!(
-                "msg_cycles_refunded can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
-        }
+    fn msg_cycles_available128(&mut self, dst: isize) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_cycles_available128")?;
+        let data = self.env.cycles_available.get().to_le_bytes();
+        copy_to_canister(dst, 0, 16, &data)?;
+        Ok(())
     }
 
-    fn msg_cycles_refunded128(&mut self, dst: isize) -> Result<(), String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask | EntryMode::ReplyCallback | EntryMode::RejectCallback => {
-                let data = self.env.cycles_refunded.to_le_bytes();
-                copy_to_canister(dst, 0, 16, &data)?;
-                Ok(())
-            }
-            _ => Err(format!(
-                "msg_cycles_refunded128 can not be called from '{}'",
-                self.env.get_entry_point_name()
-            )),
+    fn msg_cycles_refunded(&mut self) -> Result<i64, SystemApiError> {
+        self.check_entry_mode("msg_cycles_refunded")?;
+        let cycles = self.env.cycles_refunded.get();
+        if cycles > (u64::MAX as u128) {
+            return Err(SystemApiError::Overflow {
+                value: "refunded cycles",
+                target: "u64",
+            });
         }
+
+        Ok(cycles as u64 as i64)
     }
 
-    fn msg_cycles_accept(&mut self, max_amount: i64) -> Result<i64, String> {
-        let message_id = match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Update
-            | EntryMode::ReplyCallback
-            | EntryMode::RejectCallback => self
-                .request_id
-                .expect("ic-kit: Unexpected canister state, request_id not set."),
-            _ => {
-                return Err(format!(
-                    "msg_cycles_accept can not be called from '{}'",
-                    self.env.get_entry_point_name()
-                ))
-            }
-        };
+    fn msg_cycles_refunded128(&mut self, dst: isize) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_cycles_refunded128")?;
+        let data = self.env.cycles_refunded.get().to_le_bytes();
+        copy_to_canister(dst, 0, 16, &data)?;
+        Ok(())
+    }
 
-        let amount = self.env.cycles_available.min(max_amount as u128);
-        self.env.cycles_available -= amount;
-        self.cycles_accepted += amount;
+    fn msg_cycles_accept(&mut self, max_amount: i64) -> Result<i64, SystemApiError> {
+        self.check_entry_mode("msg_cycles_accept")?;
+
+        let message_id = self
+            .request_id
+            .expect("ic-kit: Unexpected canister state, request_id not set.");
+
+        let amount = self.env.cycles_available.get().min(max_amount as u128);
+        self.env.cycles_available = self
+            .env
+            .cycles_available
+            .checked_sub(amount)
+            .map_err(|_| cycles_overflow("available cycles"))?;
+        self.cycles_accepted = self
+            .cycles_accepted
+            .checked_add(amount)
+            .map_err(|_| cycles_overflow("accepted cycles"))?;
         self.cycles_available_store
             .insert(message_id, self.env.cycles_available);
 
@@ -773,28 +1566,26 @@ impl Ic0CallHandlerProxy for Canister {
         max_amount_high: i64,
         max_amount_low: i64,
         dst: isize,
-    ) -> Result<(), String> {
-        let message_id = match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Update
-            | EntryMode::ReplyCallback
-            | EntryMode::RejectCallback => self
-                .request_id
-                .expect("ic-kit: Unexpected canister state, request_id not set."),
-            _ => {
-                return Err(format!(
-                    "msg_cycles_accept128 can not be called from '{}'",
-                    self.env.get_entry_point_name()
-                ))
-            }
-        };
-
-        let high = max_amount_high as u128;
-        let low = max_amount_low as u128;
-        let max_amount = high << 64 + low;
-        let amount = self.env.cycles_available.min(max_amount);
-        self.env.cycles_available -= amount;
-        self.cycles_accepted += amount;
+    ) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_cycles_accept128")?;
+
+        let message_id = self
+            .request_id
+            .expect("ic-kit: Unexpected canister state, request_id not set.");
+
+        let high = max_amount_high as u64 as u128;
+        let low = max_amount_low as u64 as u128;
+        let max_amount = (high << 64) + low;
+        let amount = self.env.cycles_available.get().min(max_amount);
+        self.env.cycles_available = self
+            .env
+            .cycles_available
+            .checked_sub(amount)
+            .map_err(|_| cycles_overflow("available cycles"))?;
+        self.cycles_accepted = self
+            .cycles_accepted
+            .checked_add(amount)
+            .map_err(|_| cycles_overflow("accepted cycles"))?;
         self.cycles_available_store
             .insert(message_id, self.env.cycles_available);
         copy_to_canister(dst, 0, 16, &amount.to_le_bytes())?;
@@ -802,53 +1593,72 @@ impl Ic0CallHandlerProxy for Canister {
         Ok(())
     }
 
-    fn canister_self_size(&mut self) -> Result<isize, String> {
+    fn canister_self_size(&mut self) -> Result<isize, SystemApiError> {
         Ok(self.canister_id.as_slice().len() as isize)
     }
 
-    fn canister_self_copy(&mut self, dst: isize, offset: isize, size: isize) -> Result<(), String> {
+    fn canister_self_copy(
+        &mut self,
+        dst: isize,
+        offset: isize,
+        size: isize,
+    ) -> Result<(), SystemApiError> {
         let data = self.canister_id.as_slice();
         copy_to_canister(dst, offset, size, data)?;
         Ok(())
     }
 
-    fn canister_cycle_balance(&mut self) -> Result<i64, String> {
-        let balance = self.env.balance + self.cycles_accepted;
+    fn canister_cycle_balance(&mut self) -> Result<i64, SystemApiError> {
+        let balance = self
+            .balance
+            .checked_add(self.cycles_accepted)
+            .map_err(|_| cycles_overflow("cycle balance"))?
+            .get();
 
         if balance > (u64::MAX as u128) {
-            return Err("refunded cycles does not fit in u64".to_string());
+            return Err(SystemApiError::Overflow {
+                value: "cycle balance",
+                target: "u64",
+            });
         }
 
         Ok(balance as u64 as i64)
     }
 
-    fn canister_cycle_balance128(&mut self, dst: isize) -> Result<(), String> {
-        let balance = self.env.balance + self.cycles_accepted;
+    fn canister_cycle_balance128(&mut self, dst: isize) -> Result<(), SystemApiError> {
+        let balance = self
+            .balance
+            .checked_add(self.cycles_accepted)
+            .map_err(|_| cycles_overflow("cycle balance"))?
+            .get();
         let data = balance.to_le_bytes();
         copy_to_canister(dst, 0, 16, &data)?;
         Ok(())
     }
 
-    fn canister_status(&mut self) -> Result<i32, String> {
-        // TODO(qti3e) support stopping canisters.
-        Ok(1)
-    }
-
-    fn msg_method_name_size(&mut self) -> Result<isize, String> {
-        let method_name = match self.env.entry_mode {
-            EntryMode::CustomTask | EntryMode::InspectMessage => self
-                .env
-                .method_name
-                .as_ref()
-                .expect("ic-kit-runtime: Method name is not set.")
-                .as_bytes(),
-            _ => {
-                return Err(format!(
-                    "msg_method_name_size can not be called from '{}'",
-                    self.env.get_entry_point_name()
-                ))
-            }
-        };
+    fn canister_status(&mut self) -> Result<i32, SystemApiError> {
+        Ok(match self.status {
+            CanisterStatus::Running => 1,
+            CanisterStatus::Stopping => 2,
+            CanisterStatus::Stopped => 3,
+        })
+    }
+
+    fn in_replicated_execution(&mut self) -> Result<i32, SystemApiError> {
+        Ok(self.env.replicated as i32)
+    }
+
+    fn msg_method_name_size(&mut self) -> Result<isize, SystemApiError> {
+        self.check_entry_mode("msg_method_name_size")?;
+
+        let method_name = self
+            .env
+            .method_name
+            .as_ref()
+            .ok_or(SystemApiError::MethodNameNotSet {
+                call: "msg_method_name_size",
+            })?
+            .as_bytes();
 
         Ok(method_name.len() as isize)
     }
@@ -858,29 +1668,31 @@ impl Ic0CallHandlerProxy for Canister {
         dst: isize,
         offset: isize,
         size: isize,
-    ) -> Result<(), String> {
-        let method_name = match self.env.entry_mode {
-            EntryMode::CustomTask | EntryMode::InspectMessage => self
-                .env
-                .method_name
-                .as_ref()
-                .expect("ic-kit-runtime: Method name is not set.")
-                .as_bytes(),
-            _ => {
-                return Err(format!(
-                    "msg_method_name_copy can not be called from '{}'",
-                    self.env.get_entry_point_name()
-                ))
-            }
-        };
+    ) -> Result<(), SystemApiError> {
+        self.check_entry_mode("msg_method_name_copy")?;
+
+        let method_name = self
+            .env
+            .method_name
+            .as_ref()
+            .ok_or(SystemApiError::MethodNameNotSet {
+                call: "msg_method_name_copy",
+            })?
+            .as_bytes();
 
         copy_to_canister(dst, offset, size, method_name)?;
         Ok(())
     }
 
-    fn accept_message(&mut self) -> Result<(), String> {
-        // TODO(qti3e) Hmm.. this has room for some thoughts.
-        todo!()
+    fn accept_message(&mut self) -> Result<(), SystemApiError> {
+        self.check_entry_mode("accept_message")?;
+
+        if self.message_accepted {
+            return Err(SystemApiError::MessageAlreadyAccepted);
+        }
+
+        self.message_accepted = true;
+        Ok(())
     }
 
     fn call_new(
@@ -893,32 +1705,22 @@ impl Ic0CallHandlerProxy for Canister {
         reply_env: isize,
         reject_fun: isize,
         reject_env: isize,
-    ) -> Result<(), String> {
-        match self.env.entry_mode {
-            EntryMode::CustomTask
-            | EntryMode::Update
-            | EntryMode::ReplyCallback
-            | EntryMode::RejectCallback
-            | EntryMode::Heartbeat => {}
-            _ => {
-                return Err(format!(
-                    "call_new can not be called from '{}'",
-                    self.env.get_entry_point_name()
-                ))
-            }
-        }
+    ) -> Result<(), SystemApiError> {
+        self.check_entry_mode("call_new")?;
 
         self.discard_pending_call();
 
-        if self.env.balance < MAX_CYCLES_PER_RESPONSE {
-            return Err("Insufficient cycles balance to process canister response.".into());
-        }
+        let callee = principal_from_canister(callee_src, callee_size)?;
 
-        self.env.balance -= MAX_CYCLES_PER_RESPONSE;
+        self.balance = self
+            .balance
+            .checked_sub(MAX_CYCLES_PER_RESPONSE)
+            .map_err(|_| SystemApiError::InsufficientCycles {
+                needed: MAX_CYCLES_PER_RESPONSE,
+                balance: self.balance.get(),
+            })?;
 
-        let callee_bytes = copy_from_canister(callee_src, callee_size);
         let name_bytes = copy_from_canister(name_src, name_size);
-        let callee = Principal::from_slice(callee_bytes);
         let name = String::from_utf8_lossy(name_bytes).to_string();
         let callbacks = RequestCallbacks {
             message_id: self
@@ -929,22 +1731,24 @@ impl Ic0CallHandlerProxy for Canister {
             cleanup: None,
         };
 
-        self.pending_call = Some((callee, name, callbacks, 0, Vec::new()));
+        self.pending_call = Some((callee, name, callbacks, 0, Vec::new(), None));
 
         Ok(())
     }
 
-    fn call_on_cleanup(&mut self, fun: isize, env: isize) -> Result<(), String> {
+    fn call_on_cleanup(&mut self, fun: isize, env: isize) -> Result<(), SystemApiError> {
         if self.pending_call.is_none() {
-            return Err(format!(
-                "call_on_cleanup cannot be called when there is no pending call."
-            ));
+            return Err(SystemApiError::NoPendingCall {
+                call: "call_on_cleanup",
+            });
         }
 
         let cleanup = &mut self.pending_call.as_mut().unwrap().2.cleanup;
 
         if cleanup.is_some() {
-            return Err(format!("call_on_cleanup cannot be invoked more than once."));
+            return Err(SystemApiError::CalledMoreThanOnce {
+                call: "call_on_cleanup",
+            });
         }
 
         *cleanup = Some((fun, env));
@@ -952,11 +1756,11 @@ impl Ic0CallHandlerProxy for Canister {
         Ok(())
     }
 
-    fn call_data_append(&mut self, src: isize, size: isize) -> Result<(), String> {
+    fn call_data_append(&mut self, src: isize, size: isize) -> Result<(), SystemApiError> {
         if self.pending_call.is_none() {
-            return Err(format!(
-                "call_data_append cannot be called when there is no pending call."
-            ));
+            return Err(SystemApiError::NoPendingCall {
+                call: "call_data_append",
+            });
         }
 
         let args = &mut self.pending_call.as_mut().unwrap().4;
@@ -966,123 +1770,206 @@ impl Ic0CallHandlerProxy for Canister {
         Ok(())
     }
 
-    fn call_cycles_add(&mut self, amount: i64) -> Result<(), String> {
+    fn call_cycles_add(&mut self, amount: i64) -> Result<(), SystemApiError> {
         if self.pending_call.is_none() {
-            return Err(format!(
-                "call_cycles_add cannot be called when there is no pending call."
-            ));
+            return Err(SystemApiError::NoPendingCall {
+                call: "call_cycles_add",
+            });
         }
 
         let amount = amount as u128;
 
-        if self.env.balance < amount {
-            return Err(format!("Insufficient cycles balance."));
+        let payment = Cycles(self.pending_call.as_ref().unwrap().3)
+            .checked_add(amount)
+            .map_err(|_| cycles_overflow("call payment"))?;
+        self.balance =
+            self.balance
+                .checked_sub(amount)
+                .map_err(|_| SystemApiError::InsufficientCycles {
+                    needed: amount,
+                    balance: self.balance.get(),
+                })?;
+        self.pending_call.as_mut().unwrap().3 = payment.get();
+
+        Ok(())
+    }
+
+    fn call_cycles_add128(
+        &mut self,
+        amount_high: i64,
+        amount_low: i64,
+    ) -> Result<(), SystemApiError> {
+        if self.pending_call.is_none() {
+            return Err(SystemApiError::NoPendingCall {
+                call: "call_cycles_add128",
+            });
         }
 
-        self.env.balance -= amount;
-        self.pending_call.as_mut().unwrap().3 += amount;
+        let high = amount_high as u64 as u128;
+        let low = amount_low as u64 as u128;
+        let amount = (high << 64) + low;
+
+        let payment = Cycles(self.pending_call.as_ref().unwrap().3)
+            .checked_add(amount)
+            .map_err(|_| cycles_overflow("call payment"))?;
+        self.balance =
+            self.balance
+                .checked_sub(amount)
+                .map_err(|_| SystemApiError::InsufficientCycles {
+                    needed: amount,
+                    balance: self.balance.get(),
+                })?;
+        self.pending_call.as_mut().unwrap().3 = payment.get();
 
         Ok(())
     }
 
-    fn call_cycles_add128(&mut self, amount_high: i64, amount_low: i64) -> Result<(), String> {
+    fn call_with_best_effort_response(
+        &mut self,
+        timeout_seconds: i32,
+    ) -> Result<(), SystemApiError> {
         if self.pending_call.is_none() {
-            return Err(format!(
-                "call_cycles_add128 cannot be called when there is no pending call."
-            ));
+            return Err(SystemApiError::NoPendingCall {
+                call: "call_with_best_effort_response",
+            });
         }
 
-        let high = amount_high as u128;
-        let low = amount_low as u128;
-        let amount = high << 64 + low;
+        let deadline = &mut self.pending_call.as_mut().unwrap().5;
 
-        if self.env.balance < amount {
-            return Err(format!("Insufficient cycles balance."));
+        if deadline.is_some() {
+            return Err(SystemApiError::CalledMoreThanOnce {
+                call: "call_with_best_effort_response",
+            });
         }
 
-        self.env.balance -= amount;
-        self.pending_call.as_mut().unwrap().3 += amount;
+        let timeout = (timeout_seconds as u32).min(MAX_CALL_TIMEOUT) as u64;
+        *deadline = Some(self.env.time + timeout * 1_000_000_000);
 
         Ok(())
     }
 
-    fn call_perform(&mut self) -> Result<i32, String> {
+    fn call_perform(&mut self) -> Result<i32, SystemApiError> {
         if self.pending_call.is_none() {
-            return Err(format!(
-                "call_cycles_add128 cannot be called when there is no pending call."
-            ));
+            return Err(SystemApiError::NoPendingCall {
+                call: "call_perform",
+            });
         }
 
         // TODO(qti3e) Implement the freezing threshold + system ability to perform call.
-        // For now all of the calls go through.
+        // For now all of the calls go through as long as the canister can pay for them.
+
+        let pending_call = self.pending_call.as_ref().unwrap();
+        // Just like the IC the method name counts towards the size of the payload.
+        let size = pending_call.1.len() + pending_call.4.len();
+        if size > self.max_message_size {
+            return Err(SystemApiError::PayloadTooLarge {
+                call: "call_perform",
+                size,
+                limit: self.max_message_size,
+            });
+        }
 
+        let fee = self.call_cost.fee(&pending_call.1, &pending_call.4);
+        self.balance =
+            self.balance
+                .checked_sub(fee)
+                .map_err(|_| SystemApiError::InsufficientCycles {
+                    needed: fee,
+                    balance: self.balance.get(),
+                })?;
         self.call_queue.push(self.pending_call.take().unwrap());
         Ok(0)
     }
 
-    fn stable_size(&mut self) -> Result<i32, String> {
-        match self.stable.stable_size().try_into() {
-            Ok(size) => Ok(size),
-            Err(_) => Err("Invalid stable size".into()),
+    fn stable_size(&mut self) -> Result<i32, SystemApiError> {
+        let size = self.stable.stable_size();
+
+        if size > MAX_STABLE32_PAGES {
+            return Err(SystemApiError::Overflow {
+                value: "stable memory size",
+                target: "the 32-bit stable memory API",
+            });
         }
+
+        Ok(size as i32)
     }
 
-    fn stable_grow(&mut self, new_pages: i32) -> Result<i32, String> {
-        let size = self.stable.stable_size() as i32;
-        let max_size = i32::max_value();
+    fn stable_grow(&mut self, new_pages: i32) -> Result<i32, SystemApiError> {
+        let size = self.stable.stable_size();
+        let new_pages = new_pages as u32 as u64;
 
-        if size + new_pages > max_size {
+        if size + new_pages > MAX_STABLE32_PAGES {
             Ok(-1)
         } else {
-            Ok(self.stable.stable_grow(new_pages as u64) as i32)
+            Ok(self.stable.stable_grow(new_pages) as i32)
         }
     }
 
-    fn stable_write(&mut self, _offset: i32, _src: isize, _size: isize) -> Result<(), String> {
+    fn stable_write(&mut self, offset: i32, src: isize, size: isize) -> Result<(), SystemApiError> {
+        let offset = offset as u32 as u64;
+        self.check_stable_bounds(offset, size as u64)?;
         self.stable
-            .stable_write(_offset as u64, copy_from_canister(_src, _size));
+            .stable_write(offset, copy_from_canister(src, size));
 
         Ok(())
     }
 
-    fn stable_read(&mut self, dst: isize, offset: i32, size: isize) -> Result<(), String> {
+    fn stable_read(&mut self, dst: isize, offset: i32, size: isize) -> Result<(), SystemApiError> {
+        let offset = offset as u32 as u64;
+        self.check_stable_bounds(offset, size as u64)?;
         let mut buf = vec![0u8; size as usize];
-        self.stable.stable_read(offset as u64, &mut buf);
-        copy_to_canister(dst, offset as isize, size, &buf)?;
+        self.stable.stable_read(offset, &mut buf);
+        copy_to_canister(dst, 0, size, &buf)?;
         Ok(())
     }
 
-    fn stable64_size(&mut self) -> Result<i64, String> {
+    fn stable64_size(&mut self) -> Result<i64, SystemApiError> {
         Ok(self.stable.stable_size() as i64)
     }
 
-    fn stable64_grow(&mut self, new_pages: i64) -> Result<i64, String> {
-        Ok(self.stable.stable_grow(new_pages as u64) as i64)
+    fn stable64_grow(&mut self, new_pages: i64) -> Result<i64, SystemApiError> {
+        Ok(self.stable.stable_grow(new_pages as u64))
     }
 
-    fn stable64_write(&mut self, offset: i64, src: i64, size: i64) -> Result<(), String> {
-        Ok(self.stable.stable_write(
-            offset as u64,
-            copy_from_canister(src as isize, size as isize),
-        ))
+    fn stable64_write(&mut self, offset: i64, src: i64, size: i64) -> Result<(), SystemApiError> {
+        let (offset, size) = (offset as u64, size as u64);
+        self.check_stable_bounds(offset, size)?;
+        let (src, size) = host_range(src as u64, size)?;
+        self.stable
+            .stable_write(offset, copy_from_canister(src, size));
+        Ok(())
     }
 
-    fn stable64_read(&mut self, dst: i64, offset: i64, size: i64) -> Result<(), String> {
+    fn stable64_read(&mut self, dst: i64, offset: i64, size: i64) -> Result<(), SystemApiError> {
+        let (offset, size) = (offset as u64, size as u64);
+        self.check_stable_bounds(offset, size)?;
+        let (dst, size) = host_range(dst as u64, size)?;
         let mut buf = vec![0u8; size as usize];
-        self.stable.stable_read(offset as u64, &mut buf);
-        copy_to_canister(dst as isize, offset as isize, size as isize, &buf)?;
+        self.stable.stable_read(offset, &mut buf);
+        copy_to_canister(dst, 0, size, &buf)?;
         Ok(())
     }
 
-    fn certified_data_set(&mut self, _src: isize, _size: isize) -> Result<(), String> {
-        todo!()
+    fn certified_data_set(&mut self, src: isize, size: isize) -> Result<(), SystemApiError> {
+        self.check_entry_mode("certified_data_set")?;
+
+        if !(0..=MAX_CERTIFIED_DATA_SIZE as isize).contains(&size) {
+            return Err(SystemApiError::PayloadTooLarge {
+                call: "certified_data_set",
+                size: size as usize,
+                limit: MAX_CERTIFIED_DATA_SIZE,
+            });
+        }
+
+        self.certified_data = copy_from_canister(src, size).to_vec();
+        Ok(())
     }
 
-    fn data_certificate_present(&mut self) -> Result<i32, String> {
+    fn data_certificate_present(&mut self) -> Result<i32, SystemApiError> {
         todo!()
     }
 
-    fn data_certificate_size(&mut self) -> Result<isize, String> {
+    fn data_certificate_size(&mut self) -> Result<isize, SystemApiError> {
         todo!()
     }
 
@@ -1091,44 +1978,66 @@ impl Ic0CallHandlerProxy for Canister {
         _dst: isize,
         _offset: isize,
         _size: isize,
-    ) -> Result<(), String> {
+    ) -> Result<(), SystemApiError> {
         todo!()
     }
 
-    fn time(&mut self) -> Result<i64, String> {
+    fn time(&mut self) -> Result<i64, SystemApiError> {
         Ok(self.env.time as i64)
     }
 
-    fn performance_counter(&mut self, _counter_type: i32) -> Result<i64, String> {
-        todo!()
+    fn global_timer_set(&mut self, timestamp: i64) -> Result<i64, SystemApiError> {
+        self.check_entry_mode("global_timer_set")?;
+        Ok(std::mem::replace(&mut self.global_timer, timestamp as u64) as i64)
+    }
+
+    fn performance_counter(&mut self, counter_type: i32) -> Result<i64, SystemApiError> {
+        match counter_type {
+            0 => Ok(self.instructions as i64),
+            1 => Ok(self
+                .request_id
+                .and_then(|id| self.call_context_instructions.get(&id).copied())
+                .unwrap_or(self.instructions) as i64),
+            _ => Err(SystemApiError::InvalidPerformanceCounter(counter_type)),
+        }
     }
 
-    fn debug_print(&mut self, src: isize, size: isize) -> Result<(), String> {
+    fn debug_print(&mut self, src: isize, size: isize) -> Result<(), SystemApiError> {
         let bytes = copy_from_canister(src, size);
         let message = String::from_utf8_lossy(bytes).to_string();
-        println!("canister: {}", message);
+        eprintln!("canister {}: {}", self.canister_id, message);
+        self.report.debug_log.push(message.clone());
+        self.debug_log.push(message);
         Ok(())
     }
 
-    fn trap(&mut self, src: isize, size: isize) -> Result<(), String> {
+    fn trap(&mut self, src: isize, size: isize) -> Result<(), SystemApiError> {
         let bytes = copy_from_canister(src, size);
-        let message = String::from_utf8_lossy(bytes).to_string();
-        Err(format!("Canister trapped: '{}'", message))
+        // The trap is a panic with this message in the canister's thread, just like any other
+        // panic in the canister's code.
+        Err(SystemApiError::Trap(
+            String::from_utf8_lossy(bytes).to_string(),
+        ))
     }
 }
 
-fn copy_to_canister(dst: isize, offset: isize, size: isize, data: &[u8]) -> Result<(), String> {
-    let dst = dst as usize;
-    let offset = offset as usize;
-    let size = size as usize;
-
-    if offset + size > data.len() {
-        return Err("Out of bound read.".into());
+/// Convert a 64-bit address and size in the canister's memory to the host's pointer size.
+fn host_range(address: u64, size: u64) -> Result<(isize, isize), SystemApiError> {
+    match (isize::try_from(address), isize::try_from(size)) {
+        (Ok(address), Ok(size)) => Ok((address, size)),
+        _ => Err(SystemApiError::Overflow {
+            value: "memory range",
+            target: "the address space of the host",
+        }),
     }
+}
 
-    let slice = unsafe { std::slice::from_raw_parts_mut(dst as *mut u8, size) };
-    slice.copy_from_slice(&data[offset..offset + size]);
-    Ok(())
+/// The error of an amount of cycles that does not fit in `u128`.
+fn cycles_overflow(value: &'static str) -> SystemApiError {
+    SystemApiError::Overflow {
+        value,
+        target: "u128",
+    }
 }
 
 fn copy_from_canister<'a>(src: isize, size: isize) -> &'a [u8] {
@@ -1138,6 +2047,16 @@ fn copy_from_canister<'a>(src: isize, size: isize) -> &'a [u8] {
     unsafe { std::slice::from_raw_parts(src as *const u8, size) }
 }
 
+/// Read a principal from the canister's memory, the principal can be at most 29 bytes long. An
+/// empty principal is valid, and is the management canister.
+fn principal_from_canister(src: isize, size: isize) -> Result<Principal, SystemApiError> {
+    if !(0..=29).contains(&size) {
+        return Err(SystemApiError::InvalidPrincipal { size });
+    }
+
+    Ok(Principal::from_slice(copy_from_canister(src, size)))
+}
+
 fn downcast_panic_payload(payload: &Box<dyn Any + Send>) -> String {
     payload
         .downcast_ref::<&'static str>()
@@ -1146,3 +2065,1850 @@ fn downcast_panic_payload(payload: &Box<dyn Any + Send>) -> String {
         .or_else(|| payload.downcast_ref::<String>().cloned())
         .unwrap_or_else(|| String::from("Box<Any>"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Replica;
+    use lazy_static::lazy_static;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Mutex};
+
+    /// Run the given function as a custom task on the canister and return the reply.
+    async fn run<F: FnOnce() + Send + RefUnwindSafe + UnwindSafe + 'static>(
+        canister: &mut Canister,
+        env: Env,
+        f: F,
+    ) -> CallReply {
+        let message = Message::CustomTask {
+            request_id: RequestId::new(),
+            task: Box::new(f),
+            env,
+        };
+
+        canister.process_message_and_wait(message).await
+    }
+
+    /// Reply to the current call with the given bytes.
+    fn reply(data: &[u8]) {
+        unsafe {
+            ic0::msg_reply_data_append(data.as_ptr() as isize, data.len() as isize);
+            ic0::msg_reply();
+        }
+    }
+
+    /// Return the argument of the current message.
+    fn arg_data() -> Vec<u8> {
+        unsafe {
+            let mut data = vec![0u8; ic0::msg_arg_data_size() as usize];
+            ic0::msg_arg_data_copy(data.as_mut_ptr() as isize, 0, data.len() as isize);
+            data
+        }
+    }
+
+    /// Reply with the raw bytes of the caller.
+    struct CallerMethod;
+
+    impl CanisterMethod for CallerMethod {
+        const EXPORT_NAME: &'static str = "canister_update caller";
+
+        fn exported_method() {
+            let mut bytes = [0u8; 29];
+            unsafe {
+                let len = ic0::msg_caller_size();
+                ic0::msg_caller_copy(bytes.as_mut_ptr() as isize, 0, len);
+                reply(&bytes[..len as usize]);
+            }
+        }
+    }
+
+    lazy_static! {
+        /// The channel that releases [`HangMethod`].
+        static ref HANG_RELEASE: (Mutex<mpsc::Sender<()>>, Mutex<mpsc::Receiver<()>>) = {
+            let (tx, rx) = mpsc::channel();
+            (Mutex::new(tx), Mutex::new(rx))
+        };
+    }
+
+    /// Block the canister until it is released through [`HANG_RELEASE`], or for at most 10
+    /// seconds so a failing test does not hang, and never reply.
+    struct HangMethod;
+
+    impl CanisterMethod for HangMethod {
+        const EXPORT_NAME: &'static str = "canister_update hang";
+
+        fn exported_method() {
+            let release = HANG_RELEASE.1.lock().unwrap();
+            let _ = release.recv_timeout(std::time::Duration::from_secs(10));
+        }
+    }
+
+    /// Call `hang` on the canister passed as the raw argument with a 10 seconds best-effort
+    /// timeout, and reply with the rejection code.
+    struct BestEffortMethod;
+
+    impl CanisterMethod for BestEffortMethod {
+        const EXPORT_NAME: &'static str = "canister_update best_effort";
+
+        fn exported_method() {
+            fn replied(_env: isize) {
+                reply(b"replied");
+            }
+
+            fn rejected(_env: isize) {
+                reply(&unsafe { ic0::msg_reject_code() }.to_le_bytes());
+            }
+
+            let id = arg_data();
+            let name = b"hang";
+            unsafe {
+                ic0::call_new(
+                    id.as_ptr() as isize,
+                    id.len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    rejected as fn(isize) as usize as isize,
+                    0,
+                );
+                ic0::call_with_best_effort_response(10);
+                ic0::call_perform();
+            }
+        }
+    }
+
+    /// Call `caller` on the canister passed as the raw argument with 5 cycles, and reply with the
+    /// refunded cycles followed by the response.
+    struct CallCallerMethod;
+
+    impl CanisterMethod for CallCallerMethod {
+        const EXPORT_NAME: &'static str = "canister_update call_caller";
+
+        fn exported_method() {
+            fn replied(_env: isize) {
+                let mut data = unsafe { ic0::msg_cycles_refunded() }.to_le_bytes().to_vec();
+                data.extend(arg_data());
+                reply(&data);
+            }
+
+            let id = arg_data();
+            let name = b"caller";
+            unsafe {
+                ic0::call_new(
+                    id.as_ptr() as isize,
+                    id.len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    -1,
+                    -1,
+                );
+                ic0::call_cycles_add128(0, 5);
+                ic0::call_perform();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn inter_canister_call() {
+        let replica = Replica::new(vec![]);
+        let callee = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        replica.add_canister(Canister::new(callee).with_method::<CallerMethod>());
+        let caller = replica
+            .add_canister(Canister::new(Principal::anonymous()).with_method::<CallCallerMethod>());
+
+        let r = caller
+            .new_call("call_caller")
+            .with_arg_raw(callee.as_slice())
+            .perform()
+            .await;
+
+        let mut expected = 5i64.to_le_bytes().to_vec();
+        expected.extend_from_slice(Principal::anonymous().as_slice());
+        assert_eq!(r.bytes().unwrap(), expected);
+    }
+
+    /// Accept up to 400 of the cycles sent with the call.
+    struct AcceptMethod;
+
+    impl CanisterMethod for AcceptMethod {
+        const EXPORT_NAME: &'static str = "canister_update accept";
+
+        fn exported_method() {
+            unsafe { ic0::msg_cycles_accept(400) };
+            reply(&[]);
+        }
+    }
+
+    /// Accept up to 400 of the cycles sent with the call, and reject the call.
+    struct AcceptRejectMethod;
+
+    impl CanisterMethod for AcceptRejectMethod {
+        const EXPORT_NAME: &'static str = "canister_update accept";
+
+        fn exported_method() {
+            let message = "rejected";
+            unsafe {
+                ic0::msg_cycles_accept(400);
+                ic0::msg_reject(message.as_ptr() as isize, message.len() as isize);
+            }
+        }
+    }
+
+    /// Send 1000 cycles to the accept method of the given canister, and reply with the refunded
+    /// cycles once the call is replied to or rejected.
+    struct SendCyclesMethod;
+
+    impl CanisterMethod for SendCyclesMethod {
+        const EXPORT_NAME: &'static str = "canister_update send_cycles";
+
+        fn exported_method() {
+            fn replied(_env: isize) {
+                reply(&unsafe { ic0::msg_cycles_refunded() }.to_le_bytes());
+            }
+
+            let id = arg_data();
+            let name = b"accept";
+            unsafe {
+                ic0::call_new(
+                    id.as_ptr() as isize,
+                    id.len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                );
+                ic0::call_cycles_add(1_000);
+                ic0::call_perform();
+            }
+        }
+    }
+
+    /// Reply with the 128-bit amount of cycles available to the call, and accept all of them.
+    struct Available128Method;
+
+    impl CanisterMethod for Available128Method {
+        const EXPORT_NAME: &'static str = "canister_update available128";
+
+        fn exported_method() {
+            let mut available = [0u8; 16];
+            unsafe {
+                ic0::msg_cycles_available128(available.as_mut_ptr() as isize);
+                ic0::msg_cycles_accept128(-1, -1, available.as_mut_ptr() as isize);
+            }
+            reply(&available);
+        }
+    }
+
+    /// Send `2^65 + 7` cycles to the available128 method of the given canister, and reply with
+    /// its reply.
+    struct SendLargeCyclesMethod;
+
+    impl CanisterMethod for SendLargeCyclesMethod {
+        const EXPORT_NAME: &'static str = "canister_update send_large_cycles";
+
+        fn exported_method() {
+            fn replied(_env: isize) {
+                reply(&arg_data());
+            }
+
+            let id = arg_data();
+            let name = b"available128";
+            unsafe {
+                ic0::call_new(
+                    id.as_ptr() as isize,
+                    id.len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    -1,
+                    -1,
+                );
+                ic0::call_cycles_add128(2, 7);
+                ic0::call_perform();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cycles_128() {
+        let amount = (2u128 << 64) + 7;
+        assert!(amount > u64::MAX as u128);
+
+        let replica = Replica::new(vec![]);
+        let callee_id = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        let callee = replica.add_canister(
+            Canister::new(callee_id)
+                .with_method::<Available128Method>()
+                .with_balance(0),
+        );
+        let caller = replica.add_canister(
+            Canister::new(Principal::anonymous())
+                .with_method::<SendLargeCyclesMethod>()
+                .with_balance(u128::MAX / 2),
+        );
+
+        let r = caller
+            .new_call("send_large_cycles")
+            .with_arg_raw(callee_id.as_slice())
+            .perform()
+            .await;
+        assert_eq!(r.bytes().unwrap(), amount.to_le_bytes());
+        assert_eq!(callee.balance().await, amount);
+        assert_eq!(caller.balance().await, u128::MAX / 2 - amount);
+
+        // The cycles sent by a user are not truncated either.
+        let r = callee
+            .new_call("available128")
+            .with_payment(amount)
+            .perform()
+            .await;
+        assert_eq!(r.bytes().unwrap(), amount.to_le_bytes());
+        assert_eq!(callee.balance().await, 2 * amount);
+    }
+
+    #[tokio::test]
+    async fn cycles_transfer() {
+        let replica = Replica::new(vec![]);
+        let callee_id = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        let callee = replica.add_canister(
+            Canister::new(callee_id)
+                .with_method::<AcceptMethod>()
+                .with_balance(0),
+        );
+        let caller = replica.add_canister(
+            Canister::new(Principal::anonymous())
+                .with_method::<SendCyclesMethod>()
+                .with_balance(10_000),
+        );
+
+        let r = caller
+            .new_call("send_cycles")
+            .with_arg_raw(callee_id.as_slice())
+            .perform()
+            .await;
+
+        assert_eq!(r.bytes().unwrap(), 600i64.to_le_bytes());
+        assert_eq!(caller.balance().await, 9_600);
+        assert_eq!(callee.balance().await, 400);
+
+        // The balance is kept across messages.
+        caller
+            .new_call("send_cycles")
+            .with_arg_raw(callee_id.as_slice())
+            .perform()
+            .await
+            .assert_ok();
+
+        assert_eq!(caller.balance().await, 9_200);
+        assert_eq!(callee.balance().await, 800);
+    }
+
+    #[tokio::test]
+    async fn last_message_cycles_accepted() {
+        let mut canister = Canister::new(Principal::anonymous()).with_balance(0);
+
+        let env = Env::default().with_cycles_available(300);
+        run(&mut canister, env, || {
+            unsafe { ic0::msg_cycles_accept(200) };
+            reply(&[]);
+        })
+        .await;
+        assert_eq!(canister.last_message_cycles_accepted(), 200);
+
+        let env = Env::default().with_cycles_available(300);
+        run(&mut canister, env, || {
+            unsafe { ic0::msg_cycles_accept(50) };
+            reply(&[]);
+        })
+        .await;
+        assert_eq!(canister.last_message_cycles_accepted(), 50);
+        assert_eq!(canister.balance(), 250);
+
+        run(&mut canister, Env::default(), || reply(&[])).await;
+        assert_eq!(canister.last_message_cycles_accepted(), 0);
+    }
+
+    #[tokio::test]
+    async fn call_context_cycles_accepted() {
+        let mut canister = Canister::new(Principal::anonymous());
+        let request_id = RequestId::new();
+        let (tx, mut rx) = oneshot::channel();
+
+        // Accept some of the cycles before the call, and some more once it is replied to.
+        let calls = canister
+            .process_message(
+                Message::CustomTask {
+                    request_id,
+                    task: Box::new(|| unsafe {
+                        fn replied(_env: isize) {
+                            let available = unsafe { ic0::msg_cycles_available() };
+                            unsafe { ic0::msg_cycles_accept(200) };
+                            reply(&available.to_le_bytes());
+                        }
+
+                        ic0::msg_cycles_accept(300);
+
+                        let id = Principal::management_canister();
+                        let name = b"raw_rand";
+                        ic0::call_new(
+                            id.as_slice().as_ptr() as isize,
+                            id.as_slice().len() as isize,
+                            name.as_ptr() as isize,
+                            name.len() as isize,
+                            replied as fn(isize) as usize as isize,
+                            0,
+                            -1,
+                            -1,
+                        );
+                        ic0::call_perform();
+                    }),
+                    env: Env::default().with_cycles_available(1_000),
+                },
+                Some(tx),
+            )
+            .await;
+        assert_eq!(calls.len(), 1);
+        assert_eq!(canister.execution_report().cycles_accepted, 300);
+        assert_eq!(
+            canister.execution_report().call_context_cycles_accepted,
+            300
+        );
+
+        canister
+            .process_message(
+                Message::Reply {
+                    reply_to: calls[0].request_id,
+                    env: Env::default().with_entry_mode(EntryMode::ReplyCallback),
+                },
+                None,
+            )
+            .await;
+        // The continuation accepts from the cycles left in the call context.
+        let r = rx.try_recv().unwrap();
+        assert_eq!(r.bytes().unwrap(), 700i64.to_le_bytes());
+        assert_eq!(canister.execution_report().cycles_accepted, 200);
+        assert_eq!(
+            canister.execution_report().call_context_cycles_accepted,
+            500
+        );
+        assert_eq!(canister.last_message_cycles_accepted(), 200);
+    }
+
+    #[tokio::test]
+    async fn cycles_refund_on_reject() {
+        let replica = Replica::new(vec![]);
+        let callee_id = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        let callee = replica.add_canister(
+            Canister::new(callee_id)
+                .with_method::<AcceptRejectMethod>()
+                .with_balance(0),
+        );
+        let caller = replica.add_canister(
+            Canister::new(Principal::anonymous())
+                .with_method::<SendCyclesMethod>()
+                .with_balance(10_000),
+        );
+
+        // The callee keeps the accepted cycles, and the rest is refunded with the reject.
+        let r = caller
+            .new_call("send_cycles")
+            .with_arg_raw(callee_id.as_slice())
+            .perform()
+            .await;
+
+        assert_eq!(r.bytes().unwrap(), 600i64.to_le_bytes());
+        assert_eq!(caller.balance().await, 9_600);
+        assert_eq!(callee.balance().await, 400);
+
+        // Trapping after the reject discards it, so every cycle is refunded.
+        let mut canister = Canister::new(Principal::anonymous()).with_balance(0);
+        let r = run(
+            &mut canister,
+            Env::default().with_cycles_available(1_000),
+            || {
+                let message = "rejected";
+                unsafe {
+                    ic0::msg_cycles_accept(400);
+                    ic0::msg_reject(message.as_ptr() as isize, message.len() as isize);
+                }
+                panic!("trap");
+            },
+        )
+        .await;
+
+        assert!(matches!(r.rejection_code(), RejectionCode::CanisterError));
+        assert_eq!(r.cycles_refunded(), 1_000);
+        assert_eq!(canister.balance(), 0);
+    }
+
+    #[tokio::test]
+    async fn mocked_method() {
+        let callee = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        let args = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sent = args.clone();
+        let replica = Replica::default()
+            .with_handler(callee, "caller", move |arg| {
+                sent.lock().unwrap().push(arg.to_vec());
+                Ok(b"stub".to_vec())
+            })
+            .with_handler(callee, "hang", |_| {
+                Err((RejectionCode::CanisterReject, "no".to_string()))
+            });
+        let caller = replica.add_canister(
+            Canister::new(Principal::anonymous())
+                .with_method::<CallCallerMethod>()
+                .with_method::<BestEffortMethod>(),
+        );
+
+        let r = caller
+            .new_call("call_caller")
+            .with_arg_raw(callee.as_slice())
+            .perform()
+            .await;
+        let mut expected = 5i64.to_le_bytes().to_vec();
+        expected.extend_from_slice(b"stub");
+        assert_eq!(r.bytes().unwrap(), expected);
+        assert_eq!(*args.lock().unwrap(), vec![Vec::<u8>::new()]);
+
+        let r = caller
+            .new_call("best_effort")
+            .with_arg_raw(callee.as_slice())
+            .perform()
+            .await;
+        assert_eq!(
+            r.bytes().unwrap(),
+            (RejectionCode::CanisterReject as i32).to_le_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn best_effort_call_timeout() {
+        let replica = Replica::new(vec![]);
+        let callee = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        replica.add_canister(Canister::new(callee).with_method::<HangMethod>());
+        let caller = replica.add_canister(
+            Canister::new(Principal::anonymous())
+                .with_method::<BestEffortMethod>()
+                .with_method::<CallerMethod>(),
+        );
+
+        replica.set_time(1_000);
+
+        let call = caller
+            .new_call("best_effort")
+            .with_arg_raw(callee.as_slice());
+
+        let (r, _) = tokio::join!(call.perform(), async {
+            // The caller executes its messages in order, so once this call returns the
+            // best-effort call is already sent to the replica.
+            caller.new_call("caller").perform().await;
+            replica.set_time(1_000 + 11_000_000_000);
+        });
+
+        assert_eq!(
+            r.bytes().unwrap(),
+            i32::from(RejectionCode::SysUnknown).to_le_bytes()
+        );
+
+        HANG_RELEASE.0.lock().unwrap().send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn double_reply() {
+        let mut canister = Canister::new(Principal::anonymous());
+        let already_replied = "Current call is already replied to.";
+
+        let r = run(&mut canister, Env::default(), || {
+            reply(b"first");
+            reply(b"second");
+        })
+        .await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert!(r.rejection_message().unwrap().contains(already_replied));
+
+        let r = run(&mut canister, Env::default(), || {
+            let message = b"rejected";
+            unsafe {
+                ic0::msg_reject(message.as_ptr() as isize, message.len() as isize);
+                ic0::msg_reply();
+            }
+        })
+        .await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert!(r.rejection_message().unwrap().contains(already_replied));
+
+        let r = run(&mut canister, Env::default(), || {
+            let data = b"late";
+            unsafe {
+                ic0::msg_reply();
+                ic0::msg_reply_data_append(data.as_ptr() as isize, data.len() as isize);
+            }
+        })
+        .await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert!(r.rejection_message().unwrap().contains(already_replied));
+    }
+
+    #[test]
+    fn system_api_errors() {
+        let mut canister = Canister::new(Principal::anonymous());
+
+        canister.env = Env::default().with_entry_mode(EntryMode::Heartbeat);
+        let e = canister.msg_arg_data_size().unwrap_err();
+        assert_eq!(
+            e,
+            SystemApiError::WrongEntryMode {
+                call: "msg_arg_data_size",
+                mode: "canister_heartbeat".into()
+            }
+        );
+        assert_eq!(
+            e.to_string(),
+            "msg_arg_data_size can not be called from 'canister_heartbeat'"
+        );
+
+        assert_eq!(
+            canister.call_perform(),
+            Err(SystemApiError::NoPendingCall {
+                call: "call_perform"
+            })
+        );
+        assert_eq!(
+            canister.performance_counter(7),
+            Err(SystemApiError::InvalidPerformanceCounter(7))
+        );
+
+        let mut buf = [0u8; 10];
+        assert_eq!(
+            canister.stable_read(buf.as_mut_ptr() as isize, 0, 10),
+            Err(SystemApiError::StableOutOfBounds {
+                offset: 0,
+                size: 10,
+                stable_size: 0
+            })
+        );
+        assert_eq!(
+            canister.canister_self_copy(0, 0, 1),
+            Err(SystemApiError::OutOfBoundsWrite)
+        );
+    }
+
+    #[tokio::test]
+    async fn max_message_size() {
+        let mut canister = Canister::new(Principal::anonymous()).with_max_message_size(10);
+
+        let r = run(&mut canister, Env::default(), || reply(&[0; 10])).await;
+        assert!(r.is_ok());
+
+        let r = run(&mut canister, Env::default(), || reply(&[0; 11])).await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert!(r.rejection_message().unwrap().contains(
+            "msg_reply_data_append: the payload of 11 bytes exceeds the limit of 10 bytes."
+        ));
+
+        // The method name counts towards the size of the call.
+        let r = run(&mut canister, Env::default(), || {
+            let callee = Principal::anonymous();
+            let callee = callee.as_slice();
+            let method = b"m";
+            let arg = [0u8; 10];
+            unsafe {
+                ic0::call_new(
+                    callee.as_ptr() as isize,
+                    callee.len() as isize,
+                    method.as_ptr() as isize,
+                    method.len() as isize,
+                    -1,
+                    -1,
+                    -1,
+                    -1,
+                );
+                ic0::call_data_append(arg.as_ptr() as isize, arg.len() as isize);
+                ic0::call_perform();
+            }
+        })
+        .await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert!(r
+            .rejection_message()
+            .unwrap()
+            .contains("call_perform: the payload of 11 bytes exceeds the limit of 10 bytes."));
+    }
+
+    #[test]
+    fn entry_mode_availability() {
+        use EntryMode::*;
+
+        /// Whether the system call did not fail because of the entry mode.
+        fn allowed<T>(r: Result<T, SystemApiError>) -> bool {
+            !matches!(r, Err(SystemApiError::WrongEntryMode { .. }))
+        }
+
+        fn call_new(c: &mut Canister) -> bool {
+            let callee = Principal::anonymous();
+            let callee = callee.as_slice();
+            let method = b"m";
+            let r = c.call_new(
+                callee.as_ptr() as isize,
+                callee.len() as isize,
+                method.as_ptr() as isize,
+                method.len() as isize,
+                -1,
+                -1,
+                -1,
+                -1,
+            );
+            allowed(r)
+        }
+
+        let modes = [
+            Init,
+            PreUpgrade,
+            PostUpgrade,
+            Heartbeat,
+            GlobalTimer,
+            OnLowWasmMemory,
+            InspectMessage,
+            Update,
+            Query,
+            ReplyCallback,
+            RejectCallback,
+            CleanupCallback,
+        ];
+
+        type Accessor = fn(&mut Canister) -> bool;
+        let arg_data: &[EntryMode] = &[
+            Init,
+            PostUpgrade,
+            Update,
+            Query,
+            ReplyCallback,
+            InspectMessage,
+        ];
+        let caller: &[EntryMode] = &[Init, PreUpgrade, PostUpgrade, Update, Query, InspectMessage];
+        let response: &[EntryMode] = &[Update, Query, ReplyCallback, RejectCallback];
+        let cycles: &[EntryMode] = &[Update, ReplyCallback, RejectCallback];
+        let callbacks: &[EntryMode] = &[ReplyCallback, RejectCallback];
+        let updates: &[EntryMode] = &[
+            Init,
+            PreUpgrade,
+            PostUpgrade,
+            Heartbeat,
+            GlobalTimer,
+            OnLowWasmMemory,
+            Update,
+            ReplyCallback,
+            RejectCallback,
+            CleanupCallback,
+        ];
+        let calls: Vec<(&str, Accessor, &[EntryMode])> = vec![
+            (
+                "msg_arg_data_size",
+                |c| allowed(c.msg_arg_data_size()),
+                arg_data,
+            ),
+            (
+                "msg_arg_data_copy",
+                |c| allowed(c.msg_arg_data_copy(0, 0, 0)),
+                arg_data,
+            ),
+            ("msg_caller_size", |c| allowed(c.msg_caller_size()), caller),
+            (
+                "msg_caller_copy",
+                |c| allowed(c.msg_caller_copy(0, 0, 0)),
+                caller,
+            ),
+            (
+                "msg_reject_code",
+                |c| allowed(c.msg_reject_code()),
+                callbacks,
+            ),
+            (
+                "msg_reject_msg_size",
+                |c| allowed(c.msg_reject_msg_size()),
+                &[RejectCallback],
+            ),
+            ("msg_reply", |c| allowed(c.msg_reply()), response),
+            ("msg_deadline", |c| allowed(c.msg_deadline()), response),
+            (
+                "msg_cycles_available",
+                |c| allowed(c.msg_cycles_available()),
+                cycles,
+            ),
+            (
+                "msg_cycles_accept",
+                |c| allowed(c.msg_cycles_accept(0)),
+                cycles,
+            ),
+            (
+                "msg_cycles_refunded",
+                |c| allowed(c.msg_cycles_refunded()),
+                callbacks,
+            ),
+            (
+                "msg_method_name_size",
+                |c| allowed(c.msg_method_name_size()),
+                &[InspectMessage],
+            ),
+            (
+                "accept_message",
+                |c| allowed(c.accept_message()),
+                &[InspectMessage],
+            ),
+            (
+                "call_new",
+                call_new,
+                &[
+                    Update,
+                    ReplyCallback,
+                    RejectCallback,
+                    Heartbeat,
+                    GlobalTimer,
+                    OnLowWasmMemory,
+                ],
+            ),
+            (
+                "global_timer_set",
+                |c| allowed(c.global_timer_set(0)),
+                updates,
+            ),
+            (
+                "certified_data_set",
+                |c| {
+                    let data = [1u8];
+                    allowed(c.certified_data_set(data.as_ptr() as isize, 1))
+                },
+                updates,
+            ),
+            (
+                "canister_self_size",
+                |c| allowed(c.canister_self_size()),
+                &modes,
+            ),
+        ];
+
+        let mut canister = Canister::new(Principal::anonymous());
+        canister.request_id = Some(RequestId::new());
+
+        for (name, call, expected) in calls {
+            for mode in modes {
+                canister.env = Env::default().with_entry_mode(mode).with_replicated(false);
+                canister.message_accepted = false;
+                let is_allowed = call(&mut canister);
+                assert_eq!(
+                    is_allowed,
+                    expected.contains(&mode),
+                    "{} in {:?}",
+                    name,
+                    mode
+                );
+            }
+
+            // The custom tasks can use everything except accept_message.
+            canister.env = Env::default().with_entry_mode(CustomTask);
+            canister.message_accepted = false;
+            assert_eq!(call(&mut canister), name != "accept_message", "{}", name);
+        }
+
+        // The cycles are only available to the queries in replicated mode.
+        canister.env = Env::default().with_entry_mode(Query);
+        assert!(allowed(canister.msg_cycles_available()));
+
+        // The system calls that are not in the table are never available.
+        assert!(!is_available("msg_unknown", Update, true));
+        assert!(!is_available("msg_unknown", CustomTask, true));
+    }
+
+    #[test]
+    fn id() {
+        let id = Principal::from_slice(&[1, 2, 3]);
+        let mut canister = Canister::new(id);
+        assert_eq!(canister.id(), id);
+
+        // The system API exposes the same id as bytes.
+        let mut bytes = [0u8; 3];
+        assert_eq!(canister.canister_self_size(), Ok(3));
+        canister
+            .canister_self_copy(bytes.as_mut_ptr() as isize, 0, 3)
+            .unwrap();
+        assert_eq!(Principal::from_slice(&bytes), id);
+    }
+
+    /// The number of execution threads that exited, it is incremented by the thread local
+    /// [`ExitGuard`] of the thread when the thread exits.
+    static EXITED_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+    struct ExitGuard;
+
+    impl Drop for ExitGuard {
+        fn drop(&mut self) {
+            EXITED_THREADS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    thread_local! {
+        static EXIT_GUARD: ExitGuard = const { ExitGuard };
+    }
+
+    #[tokio::test]
+    async fn drop_joins_execution_thread() {
+        for i in 0..1000 {
+            let mut canister = Canister::new(Principal::anonymous());
+            run(&mut canister, Env::default(), || {
+                EXIT_GUARD.with(|_| ());
+                reply(&[])
+            })
+            .await
+            .assert_ok();
+
+            // The execution thread has exited once the canister is dropped.
+            drop(canister);
+            assert_eq!(EXITED_THREADS.load(Ordering::SeqCst), i + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn copy_out_of_bounds() {
+        let mut canister = Canister::new(Principal::from_slice(&[1, 2, 3]));
+
+        let cases: [(isize, isize, isize, &str); 5] = [
+            (1, 4, 1, "Out of bound read."),
+            (1, -1, 1, "Out of bound read."),
+            (1, isize::MAX, isize::MAX, "Out of bound read."),
+            (0, 0, 2, "Out of bound write."),
+            (-1, 0, 2, "Out of bound write."),
+        ];
+
+        for (dst, offset, size, message) in cases {
+            let r = run(&mut canister, Env::default(), move || {
+                let mut buf = [0u8; 3];
+                let dst = if dst == 1 {
+                    buf.as_mut_ptr() as isize
+                } else {
+                    dst
+                };
+                unsafe { ic0::canister_self_copy(dst, offset, size) };
+            })
+            .await;
+
+            assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+            assert!(r.rejection_message().unwrap().contains(message));
+        }
+    }
+
+    #[tokio::test]
+    async fn call_new_invalid_principal() {
+        let mut canister = Canister::new(Principal::anonymous());
+
+        let r = run(&mut canister, Env::default(), || {
+            let callee = [1u8; 40];
+            let name = b"method";
+            unsafe {
+                ic0::call_new(
+                    callee.as_ptr() as isize,
+                    callee.len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    -1,
+                    -1,
+                    -1,
+                    -1,
+                );
+            }
+        })
+        .await;
+
+        assert!(matches!(r.rejection_code(), RejectionCode::CanisterError));
+        assert!(r
+            .rejection_message()
+            .unwrap()
+            .contains("invalid principal: 40 bytes long"));
+    }
+
+    #[test]
+    fn empty_principal() {
+        let bytes = [0u8; 30];
+        let src = bytes.as_ptr() as isize;
+
+        // The empty callee is the management canister.
+        assert_eq!(
+            principal_from_canister(src, 0).unwrap(),
+            Principal::management_canister()
+        );
+        assert!(matches!(
+            principal_from_canister(src, 30),
+            Err(SystemApiError::InvalidPrincipal { size: 30 })
+        ));
+    }
+
+    /// Reply with the 128-bit cycle balance of the canister.
+    struct BalanceMethod;
+
+    impl CanisterMethod for BalanceMethod {
+        const EXPORT_NAME: &'static str = "canister_query balance";
+
+        fn exported_method() {
+            let mut bytes = [0u8; 16];
+            unsafe { ic0::canister_cycle_balance128(bytes.as_mut_ptr() as isize) };
+            reply(&bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn canister_status() {
+        let replica = Replica::new(vec![]);
+        let canister = replica.add_canister(
+            Canister::new(Principal::anonymous())
+                .with_method::<CallerMethod>()
+                .with_method::<BalanceMethod>(),
+        );
+        let status = || async {
+            canister
+                .custom(
+                    || reply(&[unsafe { ic0::canister_status() } as u8]),
+                    Env::default(),
+                )
+                .await
+                .bytes()
+                .unwrap()
+                .to_vec()
+        };
+
+        assert_eq!(status().await, [1]);
+        canister.new_call("caller").perform().await.assert_ok();
+
+        canister.stop();
+        assert_eq!(status().await, [3]);
+
+        let r = canister.new_call("caller").perform().await;
+        assert!(matches!(r.rejection_code(), RejectionCode::CanisterError));
+        assert_eq!(r.rejection_message(), Some("Canister 2vxsx-fae is stopped"));
+        canister.run_env(Env::query("balance")).await.assert_ok();
+
+        canister.start();
+        assert_eq!(status().await, [1]);
+        canister.new_call("caller").perform().await.assert_ok();
+    }
+
+    #[tokio::test]
+    async fn cycle_balance() {
+        let mut canister = Canister::new(Principal::anonymous()).with_method::<BalanceMethod>();
+
+        for balance in [0, 5_000, u64::MAX as u128 + 1] {
+            let (tx, rx) = oneshot::channel();
+            canister
+                .process_message(
+                    Message::Request {
+                        request_id: RequestId::new(),
+                        env: Env::query("balance").with_balance(balance),
+                    },
+                    Some(tx),
+                )
+                .await;
+            assert_eq!(rx.await.unwrap().bytes().unwrap(), balance.to_le_bytes());
+        }
+
+        let r = run(&mut canister, Env::default().with_balance(5_000), || {
+            reply(&unsafe { ic0::canister_cycle_balance() }.to_le_bytes())
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), 5_000i64.to_le_bytes());
+
+        let env = Env::default().with_balance(u64::MAX as u128 + 1);
+        let r = run(&mut canister, env, || {
+            reply(&unsafe { ic0::canister_cycle_balance() }.to_le_bytes())
+        })
+        .await;
+        assert_eq!(
+            r.rejection_message(),
+            Some("cycle balance does not fit in u64")
+        );
+    }
+
+    #[tokio::test]
+    async fn cycles_overflow() {
+        let mut canister = Canister::new(Principal::anonymous()).with_balance(u128::MAX - 1);
+        assert!(matches!(
+            canister.add_cycles(2),
+            Err(SystemApiError::Overflow { .. })
+        ));
+        canister.add_cycles(1).unwrap();
+        assert_eq!(canister.balance(), u128::MAX);
+
+        // A refund that does not fit in the balance traps the message.
+        let env = Env::default().with_cycles_refunded(1);
+        let r = run(&mut canister, env, || reply(&[])).await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert!(r
+            .rejection_message()
+            .unwrap()
+            .contains("cycle balance does not fit in u128"));
+        assert_eq!(canister.balance(), u128::MAX);
+    }
+
+    #[tokio::test]
+    async fn cycle_balance_mid_message() {
+        let mut canister = Canister::new(Principal::anonymous());
+        let env = Env::default()
+            .with_balance(5_000)
+            .with_cycles_available(300);
+
+        let r = run(&mut canister, env, || {
+            let mut balances = Vec::new();
+            unsafe {
+                balances.push(ic0::canister_cycle_balance());
+
+                // The accepted cycles are part of the balance right away.
+                ic0::msg_cycles_accept(200);
+                balances.push(ic0::canister_cycle_balance());
+
+                // And so are the cycles reserved for the response and attached to a call.
+                let callee = Principal::anonymous();
+                let method = b"m";
+                ic0::call_new(
+                    callee.as_slice().as_ptr() as isize,
+                    callee.as_slice().len() as isize,
+                    method.as_ptr() as isize,
+                    method.len() as isize,
+                    -1,
+                    -1,
+                    -1,
+                    -1,
+                );
+                ic0::call_cycles_add(50);
+                balances.push(ic0::canister_cycle_balance());
+            }
+
+            let bytes = balances
+                .iter()
+                .flat_map(|balance| balance.to_le_bytes())
+                .collect::<Vec<_>>();
+            reply(&bytes);
+        })
+        .await;
+
+        let balances = r
+            .bytes()
+            .unwrap()
+            .chunks(8)
+            .map(|chunk| i64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            balances,
+            [5_000, 5_200, 5_200 - MAX_CYCLES_PER_RESPONSE as i64 - 50]
+        );
+    }
+
+    #[tokio::test]
+    async fn inter_canister_caller() {
+        let caller = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        let mut canister = Canister::new(Principal::anonymous()).with_method::<CallerMethod>();
+
+        let call = CanisterCall {
+            sender: caller,
+            request_id: RequestId::new(),
+            callee: canister.id(),
+            method: "caller".to_string(),
+            payment: 0,
+            arg: Vec::new(),
+            deadline: None,
+            one_way: false,
+            query: false,
+        };
+
+        let (tx, rx) = oneshot::channel();
+        canister.process_message(call.into(), Some(tx)).await;
+        assert_eq!(rx.await.unwrap().bytes().unwrap(), caller.as_slice());
+    }
+
+    /// Trap on every method except `increment`.
+    struct InspectMethod;
+
+    impl CanisterMethod for InspectMethod {
+        const EXPORT_NAME: &'static str = "canister_inspect_message";
+
+        fn exported_method() {
+            let mut name = [0u8; 32];
+            unsafe {
+                let len = ic0::msg_method_name_size();
+                ic0::msg_method_name_copy(name.as_mut_ptr() as isize, 0, len);
+
+                if &name[..len as usize] != b"increment" {
+                    let message = "method is not allowed";
+                    ic0::trap(message.as_ptr() as isize, message.len() as isize);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn inspect_message_method_name() {
+        let mut canister = Canister::new(Principal::anonymous()).with_method::<InspectMethod>();
+
+        for (method, trapped) in [("increment", false), ("decrement", true)] {
+            let (tx, _rx) = oneshot::channel();
+            canister
+                .process_message(
+                    Message::Request {
+                        request_id: RequestId::new(),
+                        env: Env::inspect_message(method),
+                    },
+                    Some(tx),
+                )
+                .await;
+
+            let report = canister.execution_report();
+            assert_eq!(report.trapped, trapped, "{}", method);
+        }
+
+        // The method name is only available to inspect_message.
+        let r = run(&mut canister, Env::update("increment"), || unsafe {
+            ic0::msg_method_name_size();
+        })
+        .await;
+        assert_eq!(
+            r.rejection_message(),
+            Some("msg_method_name_size can not be called from 'canister_update increment'")
+        );
+    }
+
+    /// Only accept the calls to `call_caller`.
+    struct AcceptCallCallerMethod;
+
+    impl CanisterMethod for AcceptCallCallerMethod {
+        const EXPORT_NAME: &'static str = "canister_inspect_message";
+
+        fn exported_method() {
+            let mut name = [0u8; 32];
+            unsafe {
+                let len = ic0::msg_method_name_size();
+                ic0::msg_method_name_copy(name.as_mut_ptr() as isize, 0, len);
+
+                if &name[..len as usize] == b"call_caller" {
+                    ic0::accept_message();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn inspect_message() {
+        let replica = Replica::new(vec![]);
+        let id = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        let canister = replica.add_canister(
+            Canister::new(id)
+                .with_method::<AcceptCallCallerMethod>()
+                .with_method::<CallerMethod>()
+                .with_method::<CallCallerMethod>(),
+        );
+        // A canister with no inspect_message accepts every message.
+        let other = replica.add_canister(
+            Canister::new(Principal::anonymous())
+                .with_method::<CallerMethod>()
+                .with_method::<CallCallerMethod>(),
+        );
+
+        let r = canister
+            .new_call("call_caller")
+            .with_arg_raw(Principal::anonymous().as_slice())
+            .perform()
+            .await;
+        let mut expected = 5i64.to_le_bytes().to_vec();
+        expected.extend_from_slice(id.as_slice());
+        assert_eq!(r.bytes().unwrap(), expected);
+
+        let r = canister.new_call("caller").perform().await;
+        assert!(matches!(r.rejection_code(), RejectionCode::CanisterReject));
+        assert_eq!(
+            r.rejection_message(),
+            Some("Canister whq4n-xiaaa-aaaam-qaazq-cai did not accept the message")
+        );
+
+        // Calls from other canisters do not go through inspect_message.
+        let r = other
+            .new_call("call_caller")
+            .with_arg_raw(id.as_slice())
+            .perform()
+            .await;
+        let mut expected = 5i64.to_le_bytes().to_vec();
+        expected.extend_from_slice(Principal::anonymous().as_slice());
+        assert_eq!(r.bytes().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn missing_method() {
+        let mut canister = Canister::new(Principal::anonymous()).with_method::<CallerMethod>();
+
+        let r = canister
+            .process_message_and_wait(Message::Request {
+                request_id: RequestId::new(),
+                env: Env::update("increment").with_cycles_available(1_000),
+            })
+            .await;
+        assert!(matches!(
+            r.rejection_code(),
+            RejectionCode::DestinationInvalid
+        ));
+        assert_eq!(
+            r.rejection_message(),
+            Some("Canister does not have a 'increment' method.")
+        );
+        assert_eq!(r.cycles_refunded(), 1_000);
+
+        // The exported methods are still dispatched.
+        let r = canister
+            .process_message_and_wait(Message::Request {
+                request_id: RequestId::new(),
+                env: Env::update("caller"),
+            })
+            .await;
+        assert!(r.is_ok());
+    }
+
+    /// Reply with the text argument repeated the given number of times and the accepted cycles.
+    struct RepeatMethod;
+
+    impl CanisterMethod for RepeatMethod {
+        const EXPORT_NAME: &'static str = "canister_update repeat";
+
+        fn exported_method() {
+            let (text, n): (String, u64) = candid::decode_args(&arg_data()).unwrap();
+            let cycles = unsafe { ic0::msg_cycles_accept(i64::MAX) } as u64;
+            reply(&candid::encode_args((text.repeat(n as usize), cycles)).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn message_builder() {
+        let alice = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        let mut canister = Canister::new(Principal::anonymous())
+            .with_method::<RepeatMethod>()
+            .with_method::<CallerMethod>();
+
+        let r = canister
+            .process_message_and_wait(
+                Message::update("repeat")
+                    .with_args(("ab".to_string(), 3u64))
+                    .with_cycles(500),
+            )
+            .await;
+        assert_eq!(
+            r.decode::<(String, u64)>().unwrap(),
+            ("ababab".to_string(), 500)
+        );
+
+        let r = canister
+            .process_message_and_wait(Message::query("caller").with_caller(alice))
+            .await;
+        assert_eq!(r.bytes().unwrap(), alice.as_slice());
+    }
+
+    #[tokio::test]
+    async fn process_message_and_wait() {
+        let mut canister = Canister::new(Principal::anonymous());
+
+        let r = canister
+            .process_message_and_wait(Message::CustomTask {
+                request_id: RequestId::new(),
+                task: Box::new(|| reply(&candid::encode_args((7u64, "seven")).unwrap())),
+                env: Env::default().with_cycles_available(100),
+            })
+            .await;
+        assert_eq!(
+            r.decode::<(u64, String)>().unwrap(),
+            (7, "seven".to_string())
+        );
+        assert_eq!(r.cycles_refunded(), 100);
+
+        let r = canister
+            .process_message_and_wait(Message::CustomTask {
+                request_id: RequestId::new(),
+                task: Box::new(|| unsafe {
+                    let message = "invalid argument";
+                    ic0::msg_reject(message.as_ptr() as isize, message.len() as isize);
+                }),
+                env: Env::default(),
+            })
+            .await;
+        assert!(matches!(r.rejection_code(), RejectionCode::CanisterReject));
+        assert_eq!(r.rejection_message(), Some("invalid argument"));
+    }
+
+    #[tokio::test]
+    async fn peek_reply_data() {
+        let mut canister = Canister::new(Principal::anonymous());
+        let request_id = RequestId::new();
+        let (tx, mut rx) = oneshot::channel();
+
+        // Append a chunk and wait for a call before replying.
+        let calls = canister
+            .process_message(
+                Message::CustomTask {
+                    request_id,
+                    task: Box::new(|| unsafe {
+                        fn replied(_env: isize) {
+                            reply(b"World!");
+                        }
+
+                        let chunk = b"Hello, ";
+                        ic0::msg_reply_data_append(chunk.as_ptr() as isize, chunk.len() as isize);
+
+                        let id = Principal::management_canister();
+                        let name = b"chunk";
+                        ic0::call_new(
+                            id.as_slice().as_ptr() as isize,
+                            id.as_slice().len() as isize,
+                            name.as_ptr() as isize,
+                            name.len() as isize,
+                            replied as fn(isize) as usize as isize,
+                            0,
+                            -1,
+                            -1,
+                        );
+                        ic0::call_perform();
+                    }),
+                    env: Env::default(),
+                },
+                Some(tx),
+            )
+            .await;
+        assert_eq!(calls.len(), 1);
+        assert_eq!(canister.peek_reply_data(request_id), Some(&b"Hello, "[..]));
+        assert!(rx.try_recv().is_err());
+
+        canister
+            .process_message(
+                Message::Reply {
+                    reply_to: calls[0].request_id,
+                    env: Env::default().with_entry_mode(EntryMode::ReplyCallback),
+                },
+                None,
+            )
+            .await;
+        // The reply data is not preserved across the await, so the chunk appended before the
+        // call is not a part of the reply.
+        assert_eq!(canister.peek_reply_data(request_id), None);
+        assert_eq!(rx.try_recv().unwrap().bytes().unwrap(), b"World!");
+    }
+
+    /// Grow the stable memory and write to it.
+    struct StableWriteMethod;
+
+    impl CanisterMethod for StableWriteMethod {
+        const EXPORT_NAME: &'static str = "canister_query write";
+
+        fn exported_method() {
+            unsafe {
+                ic0::stable_grow(1);
+                ic0::stable_write(0, [1u8, 2, 3].as_ptr() as isize, 3);
+            }
+            reply(&[]);
+        }
+    }
+
+    #[tokio::test]
+    async fn query_discards_changes() {
+        let mut canister = Canister::new(Principal::anonymous()).with_method::<StableWriteMethod>();
+
+        canister
+            .process_message_and_wait(Message::query("write"))
+            .await
+            .assert_ok();
+
+        let r = run(&mut canister, Env::default(), || {
+            reply(&unsafe { ic0::stable_size() }.to_le_bytes())
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), 0i32.to_le_bytes());
+
+        // The same method called as an update keeps the changes.
+        canister
+            .process_message_and_wait(Message::update("write"))
+            .await
+            .assert_ok();
+
+        let r = run(&mut canister, Env::default(), || {
+            reply(&unsafe { ic0::stable_size() }.to_le_bytes())
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), 1i32.to_le_bytes());
+    }
+
+    /// A composite query that replies right away.
+    struct CompositeMethod;
+
+    impl CanisterMethod for CompositeMethod {
+        const EXPORT_NAME: &'static str = "canister_composite_query composite";
+
+        fn exported_method() {
+            reply(&[]);
+        }
+    }
+
+    #[tokio::test]
+    async fn entry_mode() {
+        let mut canister = Canister::new(Principal::anonymous())
+            .with_method::<StableWriteMethod>()
+            .with_method::<AcceptMethod>()
+            .with_method::<CompositeMethod>();
+
+        canister
+            .process_message_and_wait(Message::query("write"))
+            .await
+            .assert_ok();
+        assert_eq!(canister.current_entry_mode(), EntryMode::Query);
+        assert!(canister.is_query_context());
+
+        canister
+            .process_message_and_wait(Message::update("accept"))
+            .await
+            .assert_ok();
+        assert_eq!(canister.current_entry_mode(), EntryMode::Update);
+        assert!(!canister.is_query_context());
+
+        canister
+            .process_message_and_wait(Message::query("composite"))
+            .await
+            .assert_ok();
+        assert_eq!(canister.current_entry_mode(), EntryMode::CompositeQuery);
+        assert!(canister.is_query_context());
+    }
+
+    #[tokio::test]
+    async fn stable_memory() {
+        let mut canister = Canister::new(Principal::anonymous());
+        let pattern: Vec<u8> = (0..20).collect();
+
+        let data = pattern.clone();
+        let r = run(&mut canister, Env::default(), move || unsafe {
+            assert_eq!(ic0::stable_grow(2), 0);
+            assert_eq!(ic0::stable_size(), 2);
+            // The pattern crosses the page boundary.
+            ic0::stable_write((1 << 16) - 10, data.as_ptr() as isize, data.len() as isize);
+            reply(&[]);
+        })
+        .await;
+        assert!(r.is_ok());
+
+        // Simulate an upgrade, the stable memory should survive it.
+        run(&mut canister, Env::pre_upgrade(), || unsafe {
+            assert_eq!(ic0::stable_grow(1), 2);
+        })
+        .await;
+        run(&mut canister, Env::post_upgrade(), || {}).await;
+
+        let r = run(&mut canister, Env::default(), || unsafe {
+            let mut buf = [0u8; 20];
+            ic0::stable_read(buf.as_mut_ptr() as isize, (1 << 16) - 10, 20);
+            assert_eq!(ic0::stable_size(), 3);
+            reply(&buf);
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), pattern);
+
+        let r = run(&mut canister, Env::default(), || unsafe {
+            let mut buf = [0u8; 10];
+            ic0::stable_read(buf.as_mut_ptr() as isize, (3 << 16) - 5, 10);
+        })
+        .await;
+        assert_eq!(
+            r.rejection_message(),
+            Some("stable memory out of bounds: accessing 10 bytes at offset 196603, but the stable memory is 196608 bytes")
+        );
+
+        let r = run(&mut canister, Env::default(), || unsafe {
+            reply(&ic0::stable_grow(1 << 16).to_le_bytes());
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), (-1i32).to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn stable_read_at_offset() {
+        let mut canister = Canister::new(Principal::anonymous());
+
+        // The offset is the position in the stable memory, the data is copied to the start of
+        // the destination buffer.
+        let r = run(&mut canister, Env::default(), || unsafe {
+            ic0::stable_grow(1);
+            ic0::stable_write(100, [1u8, 2, 3, 4].as_ptr() as isize, 4);
+
+            let mut buf = [0u8; 8];
+            ic0::stable_read(buf.as_mut_ptr() as isize, 101, 2);
+            ic0::stable64_read(buf[4..].as_mut_ptr() as i64, 101, 3);
+            reply(&buf);
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), vec![2, 3, 0, 0, 2, 3, 4, 0]);
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let mut canister = Canister::new(Principal::anonymous()).with_balance(1_000);
+        let snapshot = canister.snapshot();
+
+        let r = run(
+            &mut canister,
+            Env::default().with_cycles_available(500),
+            || unsafe {
+                ic0::msg_cycles_accept(500);
+                ic0::global_timer_set(1_000);
+                ic0::stable_grow(2);
+                ic0::stable_write(1 << 16, [1u8, 2, 3].as_ptr() as isize, 3);
+                reply(&[]);
+            },
+        )
+        .await;
+        r.assert_ok();
+        assert_eq!(canister.balance(), 1_500);
+        assert_ne!(canister.snapshot(), snapshot);
+
+        canister.restore(&snapshot);
+        assert_eq!(canister.balance(), 1_000);
+        assert_eq!(canister.global_timer(), 0);
+        assert_eq!(canister.snapshot(), snapshot);
+
+        // The pages grown again after the restore are zeroed.
+        let r = run(&mut canister, Env::default(), || unsafe {
+            assert_eq!(ic0::stable_grow(2), 0);
+            let mut buf = [1u8; 3];
+            ic0::stable_read(buf.as_mut_ptr() as isize, 1 << 16, 3);
+            reply(&buf);
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), [0u8; 3]);
+    }
+
+    #[tokio::test]
+    async fn performance_counter() {
+        let mut canister = Canister::new(Principal::anonymous());
+
+        let r = run(&mut canister, Env::default(), || unsafe {
+            let before = ic0::performance_counter(0);
+            ic0::canister_self_size();
+            let after = ic0::performance_counter(0);
+            assert!(after > before);
+            reply(&[]);
+        })
+        .await;
+        r.assert_ok();
+
+        // Charge one instruction for each call to `canister_self_size`, and nothing for the
+        // other system calls.
+        let mut canister =
+            Canister::new(Principal::anonymous()).with_instruction_model(|req| match req {
+                runtime::Request::canister_self_size { .. } => 1,
+                _ => 0,
+            });
+
+        for _ in 0..2 {
+            let r = run(&mut canister, Env::default(), || unsafe {
+                ic0::canister_self_size();
+                ic0::canister_self_size();
+                let counters = [ic0::performance_counter(0), ic0::performance_counter(1)];
+                reply(&[counters[0] as u8, counters[1] as u8]);
+            })
+            .await;
+            // The counter starts from zero on every message.
+            assert_eq!(r.bytes().unwrap(), [2, 2]);
+            assert_eq!(canister.report.instructions, 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn certified_data() {
+        let mut canister = Canister::new(Principal::anonymous());
+        let snapshot = canister.snapshot();
+
+        run(&mut canister, Env::default(), || {
+            unsafe { ic0::certified_data_set(b"hash".as_ptr() as isize, 4) };
+            reply(&[]);
+        })
+        .await
+        .assert_ok();
+        assert_eq!(canister.certified_data(), b"hash");
+
+        // The data is at most 32 bytes long.
+        let r = run(&mut canister, Env::default(), || {
+            let data = [0u8; 33];
+            unsafe { ic0::certified_data_set(data.as_ptr() as isize, 33) };
+        })
+        .await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert!(r.rejection_message().unwrap().contains(
+            "certified_data_set: the payload of 33 bytes exceeds the limit of 32 bytes."
+        ));
+
+        // A trap discards the data set by the message.
+        run(&mut canister, Env::default(), || unsafe {
+            ic0::certified_data_set(b"other".as_ptr() as isize, 5);
+            ic0::trap(b"no".as_ptr() as isize, 2);
+        })
+        .await;
+        assert_eq!(canister.certified_data(), b"hash");
+
+        canister.restore(&snapshot);
+        assert_eq!(canister.certified_data(), b"");
+    }
+
+    #[tokio::test]
+    async fn trap_rolls_back() {
+        let mut canister = Canister::new(Principal::anonymous());
+
+        run(&mut canister, Env::default(), || unsafe {
+            assert_eq!(ic0::stable_grow(1), 0);
+            ic0::stable_write(0, [1u8, 2, 3].as_ptr() as isize, 3);
+            reply(&[]);
+        })
+        .await
+        .assert_ok();
+
+        let r = run(&mut canister, Env::default(), || unsafe {
+            ic0::stable_write(0, [9u8, 9, 9].as_ptr() as isize, 3);
+            assert_eq!(ic0::stable_grow(2), 1);
+            ic0::stable_write(1 << 16, [9u8].as_ptr() as isize, 1);
+            ic0::global_timer_set(1_000);
+            reply(b"replied");
+
+            let message = "something went wrong";
+            ic0::trap(message.as_ptr() as isize, message.len() as isize);
+        })
+        .await;
+        assert!(matches!(r.rejection_code(), RejectionCode::CanisterError));
+        assert_eq!(r.rejection_message(), Some("something went wrong"));
+        assert_eq!(canister.global_timer(), 0);
+
+        let r = run(&mut canister, Env::default(), || unsafe {
+            assert_eq!(ic0::stable_size(), 1);
+            // The pages grown by the trapping message are zeroed.
+            assert_eq!(ic0::stable_grow(1), 1);
+            let mut buf = [0u8; 4];
+            ic0::stable_read(buf.as_mut_ptr() as isize, 0, 3);
+            ic0::stable_read(buf[3..].as_mut_ptr() as isize, 1 << 16, 1);
+            reply(&buf);
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), [1, 2, 3, 0]);
+    }
+
+    #[tokio::test]
+    async fn with_env() {
+        let alice = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+        let mut canister = Canister::new(Principal::anonymous())
+            .with_caller(alice)
+            .with_cycles(500);
+
+        let task = || unsafe {
+            let mut bytes = [0u8; 29];
+            let len = ic0::msg_caller_size();
+            ic0::msg_caller_copy(bytes.as_mut_ptr() as isize, 0, len);
+            assert_eq!(ic0::msg_cycles_available(), 500);
+            reply(&bytes[..len as usize]);
+        };
+
+        // Every custom task observes the same environment.
+        for _ in 0..2 {
+            let r = canister.custom(task).await;
+            assert_eq!(r.bytes().unwrap(), alice.as_slice());
+        }
+
+        let mut canister = Canister::new(Principal::anonymous())
+            .with_env(Env::default().with_sender(Principal::management_canister()));
+        let r = canister
+            .custom(|| unsafe {
+                assert_eq!(ic0::msg_caller_size(), 0);
+                reply(&[]);
+            })
+            .await;
+        r.assert_ok();
+    }
+
+    #[tokio::test]
+    async fn panic_location() {
+        let mut canister = Canister::new(Principal::anonymous());
+
+        let line = line!() + 2;
+        let r = run(&mut canister, Env::default(), || {
+            panic!("something went wrong");
+        })
+        .await;
+
+        assert!(matches!(r.rejection_code(), RejectionCode::CanisterError));
+        let message = r.rejection_message().unwrap();
+        assert!(
+            message.starts_with(&format!("panicked at {}:{}:", file!(), line)),
+            "{}",
+            message
+        );
+        assert!(message.ends_with(": something went wrong"), "{}", message);
+        assert_eq!(canister.report.trap_message.as_deref(), Some(message));
+    }
+
+    #[tokio::test]
+    async fn stable64_memory() {
+        // 64GiB of stable memory, the pages are only allocated when written to.
+        let mut canister = Canister::new(Principal::anonymous())
+            .with_stable(Box::new(HeapStableMemory::new(1 << 20)));
+
+        let r = run(&mut canister, Env::default(), || unsafe {
+            assert_eq!(ic0::stable64_grow(70_000), 0);
+            assert_eq!(ic0::stable64_size(), 70_000);
+            // The 32-bit API can not address more than 4GiB.
+            assert_eq!(ic0::stable_grow(1), -1);
+
+            let data: Vec<u8> = (0..100).collect();
+            ic0::stable64_write((4 << 30) + 1_000, data.as_ptr() as i64, data.len() as i64);
+            let mut buf = [0u8; 100];
+            ic0::stable64_read(buf.as_mut_ptr() as i64, (4 << 30) + 1_000, 100);
+            reply(&buf);
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), (0..100).collect::<Vec<u8>>());
+
+        let r = run(&mut canister, Env::default(), || unsafe {
+            ic0::stable_size();
+        })
+        .await;
+        assert_eq!(
+            r.rejection_message(),
+            Some("stable memory size does not fit in the 32-bit stable memory API")
+        );
+
+        let r = run(&mut canister, Env::default(), || unsafe {
+            let mut buf = [0u8; 1];
+            ic0::stable64_read(buf.as_mut_ptr() as i64, i64::MAX, 2);
+        })
+        .await;
+        assert!(r
+            .rejection_message()
+            .unwrap()
+            .starts_with("stable memory out of bounds"));
+    }
+
+    #[tokio::test]
+    async fn with_time() {
+        let time = 1_600_000_000_000_000_000;
+        let mut canister = Canister::new(Principal::anonymous()).with_time(time);
+
+        let env = Env::default().with_time(0);
+        let r = run(&mut canister, env, || {
+            reply(&unsafe { ic0::time() }.to_le_bytes())
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), (time as i64).to_le_bytes());
+
+        // moving backward is allowed.
+        canister.set_time(time - 1);
+        let r = run(&mut canister, Env::default(), || {
+            reply(&unsafe { ic0::time() }.to_le_bytes())
+        })
+        .await;
+        assert_eq!(r.bytes().unwrap(), (time as i64 - 1).to_le_bytes());
+    }
+}
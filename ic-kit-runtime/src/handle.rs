@@ -1,5 +1,5 @@
 use crate::call::{CallBuilder, CallReply};
-use crate::types::{Env, Message, RequestId};
+use crate::types::{CanisterSnapshot, Env, Message, RequestId};
 use crate::Replica;
 use ic_types::Principal;
 use std::panic::{RefUnwindSafe, UnwindSafe};
@@ -11,11 +11,67 @@ pub struct CanisterHandle<'a> {
 }
 
 impl<'a> CanisterHandle<'a> {
+    /// Return the id of the canister.
+    pub fn id(&self) -> Principal {
+        self.canister_id
+    }
+
     /// Create a new call builder to call this canister.
     pub fn new_call<S: Into<String>>(&self, method_name: S) -> CallBuilder {
         CallBuilder::new(self.replica, self.canister_id, method_name.into())
     }
 
+    /// Stop the canister, see [`Replica::stop_canister`].
+    pub fn stop(&self) {
+        self.replica.stop_canister(self.canister_id);
+    }
+
+    /// Start the canister, see [`Replica::start_canister`].
+    pub fn start(&self) {
+        self.replica.start_canister(self.canister_id);
+    }
+
+    /// Take every debug message printed by the canister so far, see
+    /// [`Canister::drain_debug_log`].
+    ///
+    /// [`Canister::drain_debug_log`]: crate::Canister::drain_debug_log
+    pub async fn drain_debug_log(&self) -> Vec<String> {
+        self.replica
+            .inspect_canister(self.canister_id, |canister| canister.drain_debug_log())
+            .await
+    }
+
+    /// Return the cycle balance of the canister, see [`Canister::balance`].
+    ///
+    /// [`Canister::balance`]: crate::Canister::balance
+    pub async fn balance(&self) -> u128 {
+        self.replica
+            .inspect_canister(self.canister_id, |canister| canister.balance())
+            .await
+    }
+
+    /// Take a snapshot of the state of the canister, see [`Canister::snapshot`].
+    ///
+    /// [`Canister::snapshot`]: crate::Canister::snapshot
+    pub async fn snapshot(&self) -> CanisterSnapshot {
+        self.replica
+            .inspect_canister(self.canister_id, |canister| canister.snapshot())
+            .await
+    }
+
+    /// Revert the canister to the state captured by the given snapshot, see
+    /// [`Canister::restore`].
+    ///
+    /// [`Canister::restore`]: crate::Canister::restore
+    pub async fn restore(&self, snapshot: &CanisterSnapshot) {
+        let snapshot = snapshot.clone();
+        self.replica
+            .inspect_canister(self.canister_id, move |canister| {
+                canister.restore(&snapshot)
+            })
+            .await
+    }
+
     /// Run the given custom function in the execution thread of the canister.
     pub async fn custom<F: FnOnce() + Send + RefUnwindSafe + UnwindSafe + 'static>(
         &self,
@@ -76,4 +132,16 @@ impl<'a> CanisterHandle<'a> {
     pub async fn heartbeat(&self) -> CallReply {
         self.run_env(Env::heartbeat()).await
     }
+
+    /// Runs the global timer of the canister. For more customization use
+    /// [`CanisterHandle::run_env`] with [`Env::global_timer()`].
+    pub async fn global_timer(&self) -> CallReply {
+        self.run_env(Env::global_timer()).await
+    }
+
+    /// Runs the on_low_wasm_memory hook of the canister. For more customization use
+    /// [`CanisterHandle::run_env`] with [`Env::on_low_wasm_memory()`].
+    pub async fn on_low_wasm_memory(&self) -> CallReply {
+        self.run_env(Env::on_low_wasm_memory()).await
+    }
 }
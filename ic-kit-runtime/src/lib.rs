@@ -8,10 +8,12 @@ cfg_if::cfg_if! {
         pub mod call;
         pub mod canister;
         pub mod replica;
+        pub mod rng;
         pub mod stable;
         pub mod types;
         pub mod users;
         pub mod handle;
+        pub mod management;
 
         pub use canister::{Canister, CanisterMethod};
         pub use replica::Replica;
@@ -0,0 +1,80 @@
+//! The Candid types of the methods of the management canister that are implemented by the
+//! [`Replica`](crate::Replica).
+
+use candid::{CandidType, Nat};
+use ic_types::Principal;
+use serde::Deserialize;
+
+/// The argument of `create_canister`.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CreateCanisterArgument {
+    pub settings: Option<CanisterSettings>,
+}
+
+/// The settings of a canister that is being created, only the controllers are used by the
+/// runtime.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CanisterSettings {
+    pub controllers: Option<Vec<Principal>>,
+    pub compute_allocation: Option<Nat>,
+    pub memory_allocation: Option<Nat>,
+    pub freezing_threshold: Option<Nat>,
+}
+
+/// The argument of the methods that only take a canister id, and the reply of
+/// `create_canister`.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CanisterIdRecord {
+    pub canister_id: Principal,
+}
+
+/// How `install_code` treats the code that is already installed on the canister.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallMode {
+    #[serde(rename = "install")]
+    Install,
+    #[serde(rename = "reinstall")]
+    Reinstall,
+    #[serde(rename = "upgrade")]
+    Upgrade,
+}
+
+/// The argument of `install_code`.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct InstallCodeArgument {
+    pub mode: InstallMode,
+    pub canister_id: Principal,
+    pub wasm_module: Vec<u8>,
+    pub arg: Vec<u8>,
+}
+
+/// The status of a canister as reported by `canister_status`.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanisterStatusType {
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "stopping")]
+    Stopping,
+    #[serde(rename = "stopped")]
+    Stopped,
+}
+
+/// The settings of an existing canister.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct DefiniteCanisterSettings {
+    pub controllers: Vec<Principal>,
+    pub compute_allocation: Nat,
+    pub memory_allocation: Nat,
+    pub freezing_threshold: Nat,
+}
+
+/// The reply of `canister_status`.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CanisterStatusResponse {
+    pub status: CanisterStatusType,
+    pub settings: DefiniteCanisterSettings,
+    pub module_hash: Option<Vec<u8>>,
+    pub memory_size: Nat,
+    pub cycles: Nat,
+    pub idle_cycles_burned_per_day: Nat,
+}
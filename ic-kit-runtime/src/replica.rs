@@ -16,19 +16,81 @@
 use crate::call::{CallBuilder, CallReply};
 use crate::canister::Canister;
 use crate::handle::CanisterHandle;
+use crate::management::{
+    CanisterIdRecord, CanisterStatusResponse, CanisterStatusType, CreateCanisterArgument,
+    DefiniteCanisterSettings, InstallCodeArgument, InstallMode,
+};
+use crate::rng::ChaChaRng;
 use crate::types::*;
-use ic_kit_sys::types::RejectionCode;
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Nat};
+use futures::future::{ready, BoxFuture, FutureExt};
+use ic_kit_sys::ic0::runtime::Request;
+use ic_kit_sys::types::{RejectionCode, SystemApiError};
 use ic_types::Principal;
-use std::collections::HashMap;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::future::Future;
 use std::panic::{RefUnwindSafe, UnwindSafe};
-use tokio::sync::{mpsc, oneshot};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, oneshot, Notify};
 
 /// A local replica that contains one or several canisters.
 pub struct Replica {
     // The current implementation uses a `tokio::spawn` to run an event loop for the replica,
     // the state of the replica is store in that event loop.
-    sender: mpsc::UnboundedSender<ReplicaMessage>,
+    sender: TrackedSender<ReplicaMessage>,
+    /// The index of the next canister id returned by [`Replica::new_canister_id`].
+    next_canister_index: Arc<AtomicU64>,
+    /// The time that passes in each round executed by [`Replica::run_rounds`].
+    round_duration: Duration,
+    /// The messages recorded when tracing is enabled, see [`Replica::with_tracing`].
+    trace: Arc<Mutex<Vec<TraceEvent>>>,
+    /// The traps of the heartbeats executed by [`Replica::run_rounds`].
+    heartbeat_errors: Mutex<Vec<(Principal, String)>>,
+    /// The id of every canister added to the replica, in the order they were added.
+    canister_ids: Arc<Mutex<Vec<Principal>>>,
+    /// The caller of the calls built by [`Replica::new_call`] that do not set one.
+    default_caller: Principal,
+}
+
+/// The amount of work in the replica that is not done yet, this includes the messages that are
+/// not processed yet and the inter-canister calls that are waiting for a response.
+#[derive(Default)]
+struct Pending {
+    count: AtomicUsize,
+    notify: Notify,
+    /// The number of tasks forwarding a response that are woken up and did not run yet, see
+    /// [`spawn_forwarder`].
+    forwarding: AtomicUsize,
+    forwarded: Notify,
+}
+
+/// The future of a task that forwards a response, see [`spawn_forwarder`].
+struct Forwarder {
+    future: BoxFuture<'static, ()>,
+    waker: Arc<ForwarderWaker>,
+}
+
+/// The waker of a [`Forwarder`], the task counts as forwarding from the moment it is woken up
+/// until it runs.
+struct ForwarderWaker {
+    pending: Arc<Pending>,
+    woken: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+/// A sender that counts every sent message as pending work, the receiver must call
+/// [`Pending::done`] once the message is processed.
+struct TrackedSender<T> {
+    sender: mpsc::UnboundedSender<T>,
+    pending: Arc<Pending>,
 }
 
 /// The state of the replica, it does not live inside the replica itself, but an instance of it
@@ -37,29 +99,192 @@ pub struct Replica {
 #[derive(Default)]
 struct ReplicaState {
     /// Map each of the current canisters to the receiver of that canister's event loop.
-    canisters: HashMap<Principal, mpsc::UnboundedSender<ReplicaCanisterRequest>>,
+    canisters: HashMap<Principal, TrackedSender<ReplicaCanisterRequest>>,
+    /// The time set on the replica's clock, this is applied to every canister on the replica
+    /// including the ones added later.
+    time: Option<u64>,
+    /// The settings that are applied to every canister on the replica including the ones added
+    /// later, see [`ReplicaState::broadcast`].
+    broadcasts: Vec<(&'static str, CanisterBroadcastFn)>,
+    /// Where the messages are recorded, only set when tracing is enabled.
+    trace: Option<Arc<Mutex<Vec<TraceEvent>>>>,
+    /// The handlers of the mocked methods, the calls to these methods never reach a canister.
+    handlers: HashMap<(Principal, String), MethodHandler>,
+    /// The hook invoked at every await point, see [`Replica::with_interleave_hook`].
+    interleave_hook: Option<InterleaveHook>,
+    /// The deadlines of the best-effort messages that are not responded to yet, and the channel
+    /// used to notify the message that its deadline has passed.
+    deadlines: Vec<(u64, oneshot::Sender<()>)>,
+    /// The number of rounds after which an inter-canister call that is not responded to is
+    /// rejected, see [`Replica::with_call_timeout`].
+    call_timeout: Option<u64>,
+    /// The number of rounds executed by [`Replica::run_rounds`] so far.
+    round: u64,
+    /// The random number generator behind the `raw_rand` method of the management canister,
+    /// see [`Replica::with_rng_seed`].
+    rng: ChaChaRng,
+    /// The channel of the replica's own event loop, the management canister uses it to execute
+    /// the steps of its methods.
+    sender: Option<TrackedSender<ReplicaMessage>>,
+    /// The index of the next canister id, shared with [`Replica::new_canister_id`].
+    next_canister_index: Arc<AtomicU64>,
+    /// The id of every canister on the replica, shared with [`Replica::canister_ids`].
+    canister_ids: Arc<Mutex<Vec<Principal>>>,
+    /// The templates installed by the management canister, keyed by their module hash.
+    modules: HashMap<Vec<u8>, CanisterTemplate>,
+    /// The controllers of each canister, see [`Canister::with_controllers`].
+    controllers: HashMap<Principal, Vec<Principal>>,
+    /// The hash of the module installed on each canister created by the management canister.
+    module_hashes: HashMap<Principal, Vec<u8>>,
+    /// The round in which each inter-canister call that is not responded to yet times out, and
+    /// the channel used to notify the call that it has timed out.
+    timeouts: Vec<(u64, oneshot::Sender<()>)>,
+    /// The inter-canister calls whose response is awaited by the caller, with the caller and the
+    /// cycles attached to the call.
+    outgoing: HashMap<RequestId, (Principal, u128)>,
+    /// The calls that are rejected by [`Replica::reject_outgoing`] before the callee responded,
+    /// the response of the callee is dropped once it arrives.
+    rejected: HashSet<RequestId>,
+    /// The scheduler that chooses the next message, when it is not set the messages are
+    /// delivered to the canisters right away.
+    scheduler: Option<Scheduler>,
+    /// The messages that are waiting to be delivered by the scheduler, in the order they are
+    /// enqueued.
+    queue: VecDeque<(Principal, ReplicaCanisterRequest)>,
+    /// The messages enqueued since the last message was delivered, they are added to the queue
+    /// sorted by the canister id.
+    incoming: Vec<(Principal, ReplicaCanisterRequest)>,
+    /// The number of messages that are delivered to the canisters and are not executed yet.
+    in_flight: usize,
+    /// The pending work of the replica, the messages waiting in the queue are part of it.
+    pending: Arc<Pending>,
+}
+
+/// The order in which the replica delivers the messages to the canisters, see
+/// [`Replica::with_deterministic_scheduler`] and [`Replica::with_scheduler_seed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scheduler {
+    /// Deliver the messages in the order they are enqueued.
+    Fifo,
+    /// Deliver the oldest message of a canister chosen at random, the value is the state of
+    /// the random number generator.
+    Seeded(u64),
 }
 
+/// A function that can directly mutate a canister's state from the canister's event loop, outside
+/// of any message execution.
+type CanisterUpdateFn = Box<dyn FnOnce(&mut Canister) + Send>;
+
+/// A function that is applied to every canister on the replica from their event loops.
+type CanisterBroadcastFn = Arc<dyn Fn(&mut Canister) + Send + Sync>;
+
 /// A message that Replica wants to send to a canister to be processed.
-struct ReplicaCanisterRequest {
-    message: Message,
-    reply_sender: Option<oneshot::Sender<CallReply>>,
+enum ReplicaCanisterRequest {
+    /// A message that should be executed by the canister.
+    Message {
+        message: Box<Message>,
+        reply_sender: Option<oneshot::Sender<CallReply>>,
+        report_sender: Option<oneshot::Sender<ExecutionReport>>,
+    },
+    /// Update the canister's state.
+    Update(CanisterUpdateFn),
 }
 
+/// A function that creates a new instance of a canister, see [`Replica::with_module`].
+type CanisterTemplate = Arc<dyn Fn() -> Canister + Send + Sync>;
+
+/// The reply data of a call to the management canister, or its rejection.
+type ManagementResult = Result<Vec<u8>, (RejectionCode, String)>;
+
+/// A hook that is invoked at every await point of the canisters and returns the messages that
+/// are injected into the waiting canister.
+type InterleaveHook = Box<dyn FnMut(&AwaitPoint) -> Vec<Message> + Send>;
+
+/// A handler that responds to the calls to a mocked method, it receives the raw argument of the
+/// call and returns either the raw reply data or the rejection.
+type MethodHandler = Box<dyn FnMut(&[u8]) -> Result<Vec<u8>, (RejectionCode, String)> + Send>;
+
 enum ReplicaMessage {
     CanisterAdded {
         canister_id: Principal,
-        channel: mpsc::UnboundedSender<ReplicaCanisterRequest>,
+        channel: TrackedSender<ReplicaCanisterRequest>,
+        controllers: Vec<Principal>,
     },
     CanisterRequest {
         canister_id: Principal,
         message: Message,
         reply_sender: Option<oneshot::Sender<CallReply>>,
+        report_sender: Option<oneshot::Sender<ExecutionReport>>,
     },
     CanisterReply {
         canister_id: Principal,
         message: Message,
     },
+    /// A canister is done executing one of the messages delivered to it.
+    CanisterIdle,
+    /// A canister made inter-canister calls and is waiting for their responses.
+    AwaitPoint {
+        point: AwaitPoint,
+    },
+    CanisterUpdate {
+        canister_id: Principal,
+        f: CanisterUpdateFn,
+    },
+    /// A canister is deleted by the management canister.
+    CanisterDeleted {
+        canister_id: Principal,
+    },
+    SetControllers {
+        canister_id: Principal,
+        controllers: Vec<Principal>,
+    },
+    /// A module is installed on the canister by the management canister.
+    SetModuleHash {
+        canister_id: Principal,
+        module_hash: Vec<u8>,
+    },
+    RejectOutgoing {
+        request_id: RequestId,
+        rejection_code: RejectionCode,
+        rejection_message: String,
+    },
+    SetTime {
+        time: u64,
+    },
+    /// Apply a setting to every canister on the replica, see [`ReplicaState::broadcast`].
+    Broadcast {
+        key: &'static str,
+        f: CanisterBroadcastFn,
+    },
+    SetScheduler {
+        scheduler: Scheduler,
+    },
+    SetInterleaveHook {
+        hook: InterleaveHook,
+    },
+    SetCallTimeout {
+        rounds: u64,
+    },
+    SetRngSeed {
+        seed: u64,
+    },
+    AddModule {
+        hash: Vec<u8>,
+        template: CanisterTemplate,
+    },
+    /// A new round has started.
+    Round,
+    SetTrace {
+        trace: Option<Arc<Mutex<Vec<TraceEvent>>>>,
+    },
+    AddHandler {
+        canister_id: Principal,
+        method: String,
+        handler: MethodHandler,
+    },
+    GlobalTimers {
+        sender: oneshot::Sender<(u64, Vec<(Principal, u64)>)>,
+    },
 }
 
 impl Replica {
@@ -74,9 +299,212 @@ impl Replica {
         tmp
     }
 
-    /// Add the given canister to this replica.
-    pub fn add_canister(&self, canister: Canister) -> CanisterHandle {
+    /// Start the clock of this replica at the given time in nanoseconds since the UNIX epoch,
+    /// the clock only moves when [`Replica::set_time`] or [`Replica::advance_time`] is called.
+    pub fn with_time(self, time: u64) -> Self {
+        self.set_time(time);
+        self
+    }
+
+    /// Set the time that passes in each round executed by [`Replica::run_rounds`], the default is
+    /// one second.
+    pub fn with_round_duration(mut self, duration: Duration) -> Self {
+        self.round_duration = duration;
+        self
+    }
+
+    /// Set the caller of the calls built by [`Replica::new_call`] that do not set a caller with
+    /// [`CallBuilder::with_caller`], the default is the anonymous principal. This is useful when
+    /// most of the calls of a test come from the same user, such as a controller.
+    pub fn with_default_caller<I: Into<Principal>>(mut self, caller: I) -> Self {
+        self.default_caller = caller.into();
+        self
+    }
+
+    /// Return the caller of the calls that do not set one, see [`Replica::with_default_caller`].
+    pub fn default_caller(&self) -> Principal {
+        self.default_caller
+    }
+
+    /// Use the given model to count the instructions executed by every canister on this replica,
+    /// see [`Canister::with_instruction_model`].
+    pub fn with_instruction_model<F: Fn(&Request) -> u64 + Send + Sync + 'static>(
+        self,
+        model: F,
+    ) -> Self {
+        let model: InstructionModel = Arc::new(model);
+        self.broadcast("instruction_model", move |canister| {
+            canister.set_instruction_model(model.clone())
+        });
+        self
+    }
+
+    /// Charge every canister on this replica a base fee plus a fee for each byte of the method
+    /// name and the argument for each inter-canister call, see [`Canister::with_call_cost`].
+    pub fn with_call_cost(self, base: u128, per_byte: u128) -> Self {
+        let cost = CallCost { base, per_byte };
+        self.broadcast("call_cost", move |canister| canister.set_call_cost(cost));
+        self
+    }
+
+    /// Check the arguments of the messages sent to every canister on this replica against the
+    /// Candid types of the method before executing it, see [`Canister::with_strict_typing`]. By
+    /// default the arguments are not checked.
+    pub fn with_strict_typing(self, strict: bool) -> Self {
+        self.broadcast("strict_typing", move |canister| {
+            canister.set_strict_typing(strict)
+        });
+        self
+    }
+
+    /// Set the maximum size in bytes of the messages of every canister on this replica, see
+    /// [`Canister::with_max_message_size`]. The default is the limit of the IC which is 2MiB.
+    pub fn with_max_message_size(self, bytes: usize) -> Self {
+        self.broadcast("max_message_size", move |canister| {
+            canister.set_max_message_size(bytes)
+        });
+        self
+    }
+
+    /// Execute one message at a time and deliver the messages in the order they are enqueued,
+    /// the messages enqueued by the same execution are ordered by the id of the receiving
+    /// canister. This makes the interleaving of the inter-canister calls reproducible, by
+    /// default the canisters execute their messages concurrently.
+    ///
+    /// The order is only guaranteed on a current thread runtime, which is what `#[tokio::test]`
+    /// uses by default.
+    pub fn with_deterministic_scheduler(self) -> Self {
+        self.set_scheduler(Scheduler::Fifo)
+    }
+
+    /// Execute one message at a time just like [`Replica::with_deterministic_scheduler`], but
+    /// deliver the oldest message of a canister chosen at random instead. The choices only
+    /// depend on the seed, so a seed that results in a faulty interleaving reproduces it.
+    pub fn with_scheduler_seed(self, seed: u64) -> Self {
+        self.set_scheduler(Scheduler::Seeded(seed))
+    }
+
+    fn set_scheduler(self, scheduler: Scheduler) -> Self {
+        self.sender
+            .send(ReplicaMessage::SetScheduler { scheduler })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+        self
+    }
+
+    /// Reject the inter-canister calls that are not responded to within the given number of
+    /// rounds executed by [`Replica::run_rounds`], so a callee that never replies does not
+    /// leave the caller waiting forever. By default the calls never time out.
+    ///
+    /// The caller's reject callback receives a `SysUnknown` rejection and no refund, the
+    /// response of the callee is dropped if it arrives later.
+    pub fn with_call_timeout(self, rounds: u64) -> Self {
+        self.sender
+            .send(ReplicaMessage::SetCallTimeout { rounds })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+        self
+    }
+
+    /// Seed the random bytes returned by the `raw_rand` method of the management canister, the
+    /// replica serves this method itself unless a canister is added under the management
+    /// canister's id. The bytes are the ChaCha20 keystream keyed by the seed, so they only depend
+    /// on the seed and the number of calls made so far, the default seed is zero.
+    pub fn with_rng_seed(self, seed: u64) -> Self {
+        self.sender
+            .send(ReplicaMessage::SetRngSeed { seed })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+        self
+    }
+
+    /// Register a canister template under the given module hash, the management canister
+    /// installs a fresh instance created by the template when `install_code` is called with the
+    /// module hash as the `wasm_module`. The id of the instance is replaced with the id of the
+    /// canister it is installed on.
+    ///
+    /// The management canister is served by the replica itself unless a canister is added under
+    /// its id, it implements `create_canister`, `install_code`, `start_canister`,
+    /// `stop_canister`, `delete_canister`, `canister_status` and `raw_rand`. Only the controllers
+    /// of a canister can manage it, see [`Canister::with_controllers`], by default the controller
+    /// of a canister created by `create_canister` is its caller, and the cycles attached to the
+    /// call are the balance of the new canister.
+    pub fn with_module<H, F>(self, hash: H, template: F) -> Self
+    where
+        H: Into<Vec<u8>>,
+        F: Fn() -> Canister + Send + Sync + 'static,
+    {
+        self.sender
+            .send(ReplicaMessage::AddModule {
+                hash: hash.into(),
+                template: Arc::new(template),
+            })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+        self
+    }
+
+    /// Record every call made on this replica along with its response, including the calls made
+    /// by the users and the inter-canister calls, the recorded messages are returned by
+    /// [`Replica::trace`]. Nothing is recorded by default.
+    pub fn with_tracing(self, enabled: bool) -> Self {
+        let trace = if enabled {
+            Some(self.trace.clone())
+        } else {
+            None
+        };
+
+        self.sender
+            .send(ReplicaMessage::SetTrace { trace })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+        self
+    }
+
+    /// Return the messages recorded so far in the order they were sent, see
+    /// [`Replica::with_tracing`].
+    pub fn trace(&self) -> Vec<TraceEvent> {
+        self.trace.lock().unwrap().clone()
+    }
+
+    /// Mock the given method, every call to the method is responded to by the handler and never
+    /// reaches the canister, the canister does not even need to exist on the replica. The handler
+    /// receives the raw argument of the call, and returns the raw reply data or the rejection
+    /// code and message. The cycles sent with the call are always refunded.
+    pub fn with_handler<S, F>(self, canister_id: Principal, method: S, handler: F) -> Self
+    where
+        S: Into<String>,
+        F: FnMut(&[u8]) -> Result<Vec<u8>, (RejectionCode, String)> + Send + 'static,
+    {
+        self.sender
+            .send(ReplicaMessage::AddHandler {
+                canister_id,
+                method: method.into(),
+                handler: Box::new(handler),
+            })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+        self
+    }
+
+    /// Invoke the given hook every time a canister makes inter-canister calls and starts waiting
+    /// for their responses, the messages returned by the hook are sent to the waiting canister
+    /// before it receives any of the responses. The replies to the injected messages are
+    /// dropped.
+    ///
+    /// This exercises the reentrancy of the canisters, a canister that checks its state before
+    /// an await point and updates it after is exposed to the messages injected in between.
+    pub fn with_interleave_hook<F>(self, hook: F) -> Self
+    where
+        F: FnMut(&AwaitPoint) -> Vec<Message> + Send + 'static,
+    {
+        self.sender
+            .send(ReplicaMessage::SetInterleaveHook {
+                hook: Box::new(hook),
+            })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+        self
+    }
+
+    /// Add the given canister to this replica, the messages sent to the canister's id are routed
+    /// to it from now on.
+    pub fn add_canister(&self, mut canister: Canister) -> CanisterHandle {
         let canister_id = canister.id();
+        self.canister_ids.lock().unwrap().push(canister_id);
 
         // Create a execution queue for the canister so we can send messages to the canister
         // asynchronously
@@ -86,7 +514,8 @@ impl Replica {
         replica
             .send(ReplicaMessage::CanisterAdded {
                 canister_id,
-                channel: tx,
+                channel: TrackedSender::new(tx, replica.pending.clone()),
+                controllers: canister.take_controllers(),
             })
             .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
 
@@ -99,6 +528,115 @@ impl Replica {
         }
     }
 
+    /// Add the given canister to this replica and run its `canister_init` with the given candid
+    /// encoded arguments, use `()` for a canister whose init does not take any arguments. A
+    /// canister without an init method is just added to the replica.
+    ///
+    /// # Panics
+    ///
+    /// If the init method of the canister traps.
+    pub async fn install_canister<T: ArgumentEncoder>(
+        &self,
+        canister: Canister,
+        init_args: T,
+    ) -> CanisterHandle {
+        let handle = self.add_canister(canister);
+        let message = Message::Request {
+            request_id: RequestId::new(),
+            env: Env::init().with_args(init_args),
+        };
+
+        let report = self.run_message_detailed(handle.id(), message).await;
+        if report.trapped {
+            panic!(
+                "ic-kit-runtime: The init method of canister {} trapped: {}",
+                handle.id(),
+                report.trap_message.unwrap_or_default()
+            );
+        }
+
+        handle
+    }
+
+    /// Upgrade the given canister to the new instance, the `pre_upgrade` hook runs on the old
+    /// instance, then the new instance replaces it and runs the `post_upgrade` hook. Just like
+    /// the IC the heap memory of the old instance is dropped and the stable memory is passed to
+    /// the new instance, the id, the cycle balance and the status of the canister are preserved
+    /// as well, but the global timer is not.
+    ///
+    /// The canister should not have any outstanding calls when it is upgraded.
+    ///
+    /// # Panics
+    ///
+    /// If either of the upgrade hooks trap.
+    pub async fn upgrade_canister(&self, canister_id: Principal, canister: Canister) {
+        self.run_upgrade_hook(canister_id, Env::pre_upgrade()).await;
+        self.update_canister(canister_id, move |old| {
+            old.upgrade(canister);
+        });
+        self.run_upgrade_hook(canister_id, Env::post_upgrade())
+            .await;
+    }
+
+    /// Run one of the upgrade hooks of the canister and panic if it traps.
+    async fn run_upgrade_hook(&self, canister_id: Principal, env: Env) {
+        let hook = env.get_entry_point_name();
+        let message = Message::Request {
+            request_id: RequestId::new(),
+            env,
+        };
+
+        let report = self.run_message_detailed(canister_id, message).await;
+        if report.trapped {
+            panic!(
+                "ic-kit-runtime: The {} hook of canister {} trapped: {}",
+                hook,
+                canister_id,
+                report.trap_message.unwrap_or_default()
+            );
+        }
+    }
+
+    /// Return a fresh canister id, the ids are allocated sequentially from the start of the
+    /// canister id range, just like a new local replica does, so they are deterministic.
+    ///
+    /// The replica does not check these ids against the canisters that were added with an
+    /// explicit id.
+    pub fn new_canister_id(&self) -> Principal {
+        new_canister_id(&self.next_canister_index)
+    }
+
+    /// Add the given canister to this replica under a fresh canister id, see
+    /// [`Replica::new_canister_id`], the id the canister was created with is replaced.
+    pub fn new_canister(&self, mut canister: Canister) -> CanisterHandle {
+        canister.set_id(self.new_canister_id());
+        self.add_canister(canister)
+    }
+
+    /// Run the given function on the canister in the canister's event loop and return its
+    /// result, this can be used to inspect or mutate the state of a canister in tests. The
+    /// function runs after the messages that are already enqueued for the canister.
+    pub async fn inspect_canister<R, F>(&self, canister_id: Principal, f: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Canister) -> R + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        self.update_canister(canister_id, move |canister| {
+            let _ = tx.send(f(canister));
+        });
+
+        rx.await
+            .expect("ic-kit-runtime: Could not inspect the canister.")
+    }
+
+    /// Return the id of every canister on this replica, in the order they were added. The calls
+    /// to any other principal are rejected with [`RejectionCode::DestinationInvalid`].
+    pub fn canister_ids(&self) -> Vec<Principal> {
+        self.canister_ids.lock().unwrap().clone()
+    }
+
     /// Return the handle to a canister.
     pub fn get_canister(&self, canister_id: Principal) -> CanisterHandle {
         CanisterHandle {
@@ -119,15 +657,36 @@ impl Replica {
                 canister_id,
                 message,
                 reply_sender,
+                report_sender: None,
             })
             .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
     }
 
-    /// Perform the given call in this replica and return a future that will be resolved once the
-    /// call is executed.
+    /// Execute the given message on the canister and return a report of what happened during
+    /// the execution of this single message, this includes the reply if the message was responded
+    /// to in this execution, the cycles accounting, the debug logs and the outgoing calls made by
+    /// the canister.
+    ///
+    /// The outgoing calls are performed as usual, but their responses are not part of the report.
+    pub async fn run_message_detailed(
+        &self,
+        canister_id: Principal,
+        message: Message,
+    ) -> ExecutionReport {
+        run_message_detailed(&self.sender, canister_id, message).await
+    }
+
+    /// Perform the given call in this replica as an ingress message and return a future that will
+    /// be resolved once the call is executed.
     pub(crate) fn perform_call(&self, call: CanisterCall) -> impl Future<Output = CallReply> {
         let canister_id = call.callee;
-        let message = Message::from(call);
+        let message = match Message::from(call) {
+            Message::Request { request_id, env } => Message::Request {
+                request_id,
+                env: env.with_ingress(true),
+            },
+            message => message,
+        };
         let (tx, rx) = oneshot::channel();
         self.enqueue_request(canister_id, message, Some(tx));
         async {
@@ -141,45 +700,405 @@ impl Replica {
     pub fn new_call<S: Into<String>>(&self, id: Principal, method: S) -> CallBuilder {
         CallBuilder::new(&self, id, method.into())
     }
+
+    /// Send an ingress update call to the given canister from the caller, just like an agent
+    /// would, and return the reply once the call is executed, this includes every
+    /// inter-canister call made by the canister before it replies.
+    pub async fn update<S: Into<String>, T: ArgumentEncoder>(
+        &self,
+        canister_id: Principal,
+        caller: Principal,
+        method: S,
+        arguments: T,
+    ) -> CallReply {
+        self.new_call(canister_id, method)
+            .with_caller(caller)
+            .with_args(arguments)
+            .perform()
+            .await
+    }
+
+    /// Send a query call to the given canister from the caller and return the reply.
+    ///
+    /// The query leaves the [`Canister::snapshot`] of the canister as it was, the changes made to
+    /// the stable memory are discarded and the balance, the global timer and the certified data
+    /// can not be changed by a query. The heap memory lives in the execution thread of the canister and the values
+    /// kept there are not required to be cloneable, so it is not part of the snapshot and can not
+    /// be rolled back.
+    pub async fn query<S: Into<String>, T: ArgumentEncoder>(
+        &self,
+        canister_id: Principal,
+        caller: Principal,
+        method: S,
+        arguments: T,
+    ) -> CallReply {
+        self.run_query(canister_id, caller, Message::query(method), arguments)
+            .await
+    }
+
+    /// Send a query call to the given canister from the caller and return the reply, just like
+    /// [`Replica::query`], except that the query is executed in replicated mode.
+    pub async fn replicated_query<S: Into<String>, T: ArgumentEncoder>(
+        &self,
+        canister_id: Principal,
+        caller: Principal,
+        method: S,
+        arguments: T,
+    ) -> CallReply {
+        let message = Message::Request {
+            request_id: RequestId::new(),
+            env: Env::query(method).with_replicated(true),
+        };
+
+        self.run_query(canister_id, caller, message, arguments)
+            .await
+    }
+
+    async fn run_query<T: ArgumentEncoder>(
+        &self,
+        canister_id: Principal,
+        caller: Principal,
+        message: Message,
+        arguments: T,
+    ) -> CallReply {
+        let (tx, rx) = oneshot::channel();
+        let message = message.with_caller(caller).with_args(arguments);
+        self.enqueue_request(canister_id, message, Some(tx));
+
+        rx.await
+            .expect("ic-kit-runtime: Could not retrieve the response from the call.")
+    }
+
+    /// Stop the given canister, once stopped the canister rejects every incoming call.
+    pub fn stop_canister(&self, canister_id: Principal) {
+        self.update_canister(canister_id, |canister| {
+            canister.set_status(CanisterStatus::Stopped)
+        });
+    }
+
+    /// Start the given canister again so it can accept calls.
+    pub fn start_canister(&self, canister_id: Principal) {
+        self.update_canister(canister_id, |canister| {
+            canister.set_status(CanisterStatus::Running)
+        });
+    }
+
+    /// Replace the controllers of the canister, the controllers are the only principals that can
+    /// manage the canister through the management canister, see [`Canister::with_controllers`].
+    pub fn set_controllers(&self, canister_id: Principal, controllers: Vec<Principal>) {
+        self.sender
+            .send(ReplicaMessage::SetControllers {
+                canister_id,
+                controllers,
+            })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+    }
+
+    /// Reject the inter-canister call with the given request id as if the callee failed, so the
+    /// reject callback of the caller is executed with the given rejection code and message. The
+    /// request id of a call can be found in [`ExecutionReport::outgoing_calls`] or in the
+    /// [`AwaitPoint`] passed to the interleave hook.
+    ///
+    /// The cycles attached to the call are refunded, and the reply of the callee is dropped once
+    /// it responds. Does nothing if the call is already responded to.
+    pub fn reject_outgoing<S: Into<String>>(
+        &self,
+        request_id: RequestId,
+        rejection_code: RejectionCode,
+        rejection_message: S,
+    ) {
+        self.sender
+            .send(ReplicaMessage::RejectOutgoing {
+                request_id,
+                rejection_code,
+                rejection_message: rejection_message.into(),
+            })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+    }
+
+    /// Add the given cycles to the balance of the canister, this is how a canister that is
+    /// frozen is topped up, see [`Canister::with_freezing_threshold`].
+    ///
+    /// Fails if the balance of the canister would not fit in `u128`.
+    pub async fn add_cycles(
+        &self,
+        canister_id: Principal,
+        cycles: u128,
+    ) -> Result<(), SystemApiError> {
+        self.inspect_canister(canister_id, move |canister| canister.add_cycles(cycles))
+            .await
+    }
+
+    /// Run the given function on the canister's state in the canister's event loop, the update
+    /// is ordered with the messages that are already enqueued for the canister.
+    pub(crate) fn update_canister<F: FnOnce(&mut Canister) + Send + 'static>(
+        &self,
+        canister_id: Principal,
+        f: F,
+    ) {
+        self.sender
+            .send(ReplicaMessage::CanisterUpdate {
+                canister_id,
+                f: Box::new(f),
+            })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+    }
+
+    /// Run the given function on every canister on this replica, including the canisters added
+    /// later, the last function sent with a key replaces the previous ones for those canisters.
+    fn broadcast<F: Fn(&mut Canister) + Send + Sync + 'static>(&self, key: &'static str, f: F) {
+        self.sender
+            .send(ReplicaMessage::Broadcast {
+                key,
+                f: Arc::new(f),
+            })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+    }
+
+    /// Set the clock of every canister on this replica to the given time in nanoseconds since
+    /// the UNIX epoch, see [`Canister::set_time`]. Messages enqueued after this call observe
+    /// the new time.
+    pub fn set_time(&self, time: u64) {
+        self.sender
+            .send(ReplicaMessage::SetTime { time })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+    }
+
+    /// Move the clock of the replica forward by the given duration, and run the global timer of
+    /// every canister whose timer is due within this interval, the timers fire one at a time in
+    /// the order of their timestamp and the clock is set to the timestamp of each timer before
+    /// it fires. Timers that are set by a global timer and are due within the interval fire as
+    /// well, but a canister fires at most once per timestamp, so a timer that is set to a time
+    /// that has already come fires once the clock moves again.
+    ///
+    /// If the replica's clock is not set, it starts from the current system time.
+    pub async fn advance_time(&self, duration: Duration) {
+        let (mut time, _) = self.global_timers().await;
+        let target = time.saturating_add(u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX));
+        // The canisters that already fired at the current time.
+        let mut fired = HashSet::new();
+
+        loop {
+            let (_, timers) = self.global_timers().await;
+            let next = timers
+                .into_iter()
+                .filter(|(_, timer)| *timer != 0 && *timer <= target)
+                .filter(|(canister_id, timer)| *timer > time || !fired.contains(canister_id))
+                .min_by_key(|(canister_id, timer)| (*timer, *canister_id));
+
+            let (canister_id, timer) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            if timer > time {
+                time = timer;
+                fired.clear();
+            }
+
+            fired.insert(canister_id);
+            self.set_time(time);
+            self.get_canister(canister_id).global_timer().await;
+        }
+
+        self.set_time(target);
+    }
+
+    /// Execute the given number of rounds, in each round the clock moves forward by the round
+    /// duration, see [`Replica::with_round_duration`], firing the global timers that are due just
+    /// like [`Replica::advance_time`], and then the heartbeat of every canister that has one runs.
+    ///
+    /// A heartbeat that traps does not stop the round, the trap is recorded and returned by
+    /// [`Replica::heartbeat_errors`].
+    ///
+    /// The inter-canister calls time out at the start of a round, see
+    /// [`Replica::with_call_timeout`].
+    pub async fn run_rounds(&self, n: usize) {
+        for _ in 0..n {
+            self.sender
+                .send(ReplicaMessage::Round)
+                .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+
+            self.advance_time(self.round_duration).await;
+
+            let mut canisters = self.canister_ids();
+            canisters.sort();
+
+            for canister_id in canisters {
+                let has_heartbeat = self
+                    .inspect_canister(canister_id, |canister| {
+                        canister.has_method("canister_heartbeat")
+                    })
+                    .await;
+
+                if has_heartbeat {
+                    let message = Message::Request {
+                        request_id: RequestId::new(),
+                        env: Env::heartbeat(),
+                    };
+
+                    let report = self.run_message_detailed(canister_id, message).await;
+                    if report.trapped {
+                        self.heartbeat_errors
+                            .lock()
+                            .unwrap()
+                            .push((canister_id, report.trap_message.unwrap_or_default()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return the canister and the trap message of every heartbeat that trapped so far during
+    /// [`Replica::run_rounds`], in the order they were executed.
+    pub fn heartbeat_errors(&self) -> Vec<(Principal, String)> {
+        self.heartbeat_errors.lock().unwrap().clone()
+    }
+
+    /// Run the global timer of the given canister right away, regardless of the time the timer
+    /// is set to.
+    pub async fn trigger_global_timer(&self, canister_id: Principal) -> CallReply {
+        self.get_canister(canister_id).global_timer().await
+    }
+
+    /// Run the on_low_wasm_memory hook of the given canister.
+    pub async fn trigger_low_wasm_memory(&self, canister_id: Principal) -> CallReply {
+        self.get_canister(canister_id).on_low_wasm_memory().await
+    }
+
+    /// Wait until the replica is idle, that is when every message sent to the canisters on this
+    /// replica is processed, including the inter-canister calls made in response to them and
+    /// the calls made by those calls.
+    pub async fn process_until_quiescent(&self) {
+        self.sender.pending.wait().await;
+    }
+
+    /// Return the time of the replica along with the global timer of every canister.
+    async fn global_timers(&self) -> (u64, Vec<(Principal, u64)>) {
+        let (tx, rx) = oneshot::channel();
+
+        self.sender
+            .send(ReplicaMessage::GlobalTimers { sender: tx })
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+
+        rx.await
+            .expect("ic-kit-runtime: Could not retrieve the global timers.")
+    }
 }
 
 impl Default for Replica {
     /// Create an empty replica and run the start the event loop.
     fn default() -> Self {
         let (sender, rx) = mpsc::unbounded_channel::<ReplicaMessage>();
-        tokio::spawn(replica_worker(rx));
-        Replica { sender }
+        let pending = Arc::new(Pending::default());
+        let sender = TrackedSender::new(sender, pending.clone());
+        let next_canister_index = Arc::new(AtomicU64::new(0));
+        let canister_ids = Arc::new(Mutex::new(Vec::new()));
+
+        tokio::spawn(replica_worker(
+            rx,
+            ReplicaState {
+                sender: Some(sender.clone()),
+                pending,
+                next_canister_index: next_canister_index.clone(),
+                canister_ids: canister_ids.clone(),
+                ..ReplicaState::default()
+            },
+        ));
+
+        Replica {
+            sender,
+            next_canister_index,
+            round_duration: Duration::from_secs(1),
+            trace: Arc::new(Mutex::new(Vec::new())),
+            heartbeat_errors: Mutex::new(Vec::new()),
+            canister_ids,
+            default_caller: Principal::anonymous(),
+        }
     }
 }
 
 /// Run replica's event loop, gets ReplicaMessages and performs the state transition accordingly.
-async fn replica_worker(mut rx: mpsc::UnboundedReceiver<ReplicaMessage>) {
-    let mut state = ReplicaState::default();
+async fn replica_worker(mut rx: mpsc::UnboundedReceiver<ReplicaMessage>, mut state: ReplicaState) {
+    let pending = state.pending.clone();
 
     while let Some(message) = rx.recv().await {
-        match message {
-            ReplicaMessage::CanisterAdded {
-                canister_id,
-                channel,
-            } => state.canister_added(canister_id, channel),
-            ReplicaMessage::CanisterRequest {
-                canister_id,
-                message,
-                reply_sender,
-            } => state.canister_request(canister_id, message, reply_sender),
-            ReplicaMessage::CanisterReply {
-                canister_id,
-                message,
-            } => state.canister_reply(canister_id, message),
+        state.handle(message);
+        pending.done();
+
+        // The responses that are already sent are enqueued before the scheduler chooses the next
+        // message, so the choice does not depend on the order in which the tasks that forward
+        // them happen to run.
+        while state.is_idle() {
+            pending.wait_forwarded().await;
+
+            let mut received = false;
+            while let Ok(message) = rx.try_recv() {
+                state.handle(message);
+                pending.done();
+                received = true;
+            }
+
+            if !received {
+                break;
+            }
         }
+
+        state.deliver_next();
     }
 }
 
+/// Return the canister id at the given index and move the index forward, see
+/// [`Replica::new_canister_id`].
+fn new_canister_id(next_canister_index: &AtomicU64) -> Principal {
+    let index = next_canister_index.fetch_add(1, Ordering::SeqCst);
+    let mut bytes = index.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&[0x01, 0x01]);
+    Principal::from_slice(&bytes)
+}
+
+/// Execute the given message on the canister and return its execution report, see
+/// [`Replica::run_message_detailed`].
+async fn run_message_detailed(
+    sender: &TrackedSender<ReplicaMessage>,
+    canister_id: Principal,
+    message: Message,
+) -> ExecutionReport {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let (report_tx, report_rx) = oneshot::channel();
+
+    sender
+        .send(ReplicaMessage::CanisterRequest {
+            canister_id,
+            message,
+            reply_sender: Some(reply_tx),
+            report_sender: Some(report_tx),
+        })
+        .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+
+    // The reply might be sent after the execution we're reporting, keep the channel open so
+    // the canister can still respond to the call.
+    tokio::spawn(reply_rx);
+
+    report_rx
+        .await
+        .expect("ic-kit-runtime: Could not retrieve the execution report.")
+}
+
+/// Advance the given state of a SplitMix64 generator and return the next random number.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
 /// Start a dedicated event loop for a canister, this will get CanisterMessage messages from a tokio
 /// channel and perform
 async fn canister_worker(
     mut rx: mpsc::UnboundedReceiver<ReplicaCanisterRequest>,
-    mut replica: mpsc::UnboundedSender<ReplicaMessage>,
+    mut replica: TrackedSender<ReplicaMessage>,
     mut canister: Canister,
 ) {
     let canister_id = canister.id();
@@ -187,13 +1106,56 @@ async fn canister_worker(
     let mut rx = rx;
     let mut canister = canister;
 
-    while let Some(message) = rx.recv().await {
+    while let Some(request) = rx.recv().await {
+        let (message, reply_sender, report_sender) = match request {
+            ReplicaCanisterRequest::Message {
+                message,
+                reply_sender,
+                report_sender,
+            } => (*message, reply_sender, report_sender),
+            ReplicaCanisterRequest::Update(f) => {
+                f(&mut canister);
+                replica.pending.done();
+                continue;
+            }
+        };
+
+        let entry_point = match &message {
+            Message::CustomTask { env, .. }
+            | Message::Request { env, .. }
+            | Message::Reply { env, .. } => env.get_entry_point_name(),
+        };
+
         // Perform the message on the canister's thread, the result containing a list of
         // inter-canister call requests is returned here, so we can send each call back to
         // replica.
-        let canister_requested_calls = canister
-            .process_message(message.message, message.reply_sender)
-            .await;
+        let canister_requested_calls = canister.process_message(message, reply_sender).await;
+
+        if let Some(report_sender) = report_sender {
+            // The receiver only goes away if the test is no longer interested in the report.
+            let _ = report_sender.send(canister.take_execution_report());
+        }
+
+        // The messages injected at the await point must reach the canister before the responses
+        // to the calls, so the await point is sent before the calls themselves. The canister
+        // does not wait for the one-way calls.
+        let awaited_calls = canister_requested_calls
+            .iter()
+            .filter(|call| !call.one_way)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !awaited_calls.is_empty() {
+            let point = AwaitPoint {
+                canister_id,
+                entry_point,
+                calls: awaited_calls,
+            };
+
+            replica
+                .send(ReplicaMessage::AwaitPoint { point })
+                .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+        }
 
         for call in canister_requested_calls {
             // For each call a oneshot channel is created that is used to receive the response
@@ -205,6 +1167,7 @@ async fn canister_worker(
             // TODO(qti3e) Do the optimization - we don't need to send the result to the replica
             // just so that it queues to our own `rx`.
             let request_id = call.request_id;
+            let one_way = call.one_way;
             let (tx, rx) = oneshot::channel();
 
             replica
@@ -212,12 +1175,22 @@ async fn canister_worker(
                     canister_id: call.callee,
                     message: call.into(),
                     reply_sender: Some(tx),
+                    report_sender: None,
                 })
                 .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
 
+            // The response of a one-way call is dropped.
+            if one_way {
+                tokio::spawn(rx);
+                continue;
+            }
+
             let rs = replica.clone();
 
-            tokio::spawn(async move {
+            // The call is not done until the response is delivered to this canister.
+            rs.pending.start();
+
+            spawn_forwarder(rs.pending.clone(), async move {
                 let replica = rs;
 
                 // wait for the response from the destination canister.
@@ -236,16 +1209,149 @@ async fn canister_worker(
                     .unwrap_or_else(|_| {
                         panic!("ic-kit-runtime: could not send message to replica")
                     });
+
+                replica.pending.done();
             });
         }
+
+        replica
+            .send(ReplicaMessage::CanisterIdle)
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+
+        replica.pending.done();
     }
 }
 
 impl ReplicaState {
+    /// Perform the state transition for the given message.
+    fn handle(&mut self, message: ReplicaMessage) {
+        let state = self;
+
+        match message {
+            ReplicaMessage::CanisterAdded {
+                canister_id,
+                channel,
+                controllers,
+            } => {
+                state.canister_added(canister_id, channel);
+                state.controllers.insert(canister_id, controllers);
+            }
+            ReplicaMessage::CanisterRequest {
+                canister_id,
+                message,
+                reply_sender,
+                report_sender,
+            } => state.canister_request(canister_id, message, reply_sender, report_sender),
+            ReplicaMessage::CanisterReply {
+                canister_id,
+                message,
+            } => state.canister_reply(canister_id, message),
+            ReplicaMessage::CanisterIdle => state.in_flight = state.in_flight.saturating_sub(1),
+            ReplicaMessage::AwaitPoint { point } => state.await_point(point),
+            ReplicaMessage::CanisterUpdate { canister_id, f } => {
+                state.canister_update(canister_id, f)
+            }
+            ReplicaMessage::CanisterDeleted { canister_id } => state.canister_deleted(canister_id),
+            ReplicaMessage::SetControllers {
+                canister_id,
+                controllers,
+            } => state.set_controllers(canister_id, controllers),
+            ReplicaMessage::SetModuleHash {
+                canister_id,
+                module_hash,
+            } => {
+                state.module_hashes.insert(canister_id, module_hash);
+            }
+            ReplicaMessage::RejectOutgoing {
+                request_id,
+                rejection_code,
+                rejection_message,
+            } => state.reject_outgoing(request_id, rejection_code, rejection_message),
+            ReplicaMessage::SetTime { time } => state.set_time(time),
+            ReplicaMessage::Broadcast { key, f } => state.broadcast(key, f),
+            ReplicaMessage::SetScheduler { scheduler } => state.scheduler = Some(scheduler),
+            ReplicaMessage::SetInterleaveHook { hook } => state.interleave_hook = Some(hook),
+            ReplicaMessage::SetCallTimeout { rounds } => state.call_timeout = Some(rounds),
+            ReplicaMessage::SetRngSeed { seed } => state.rng = ChaChaRng::new(seed),
+            ReplicaMessage::AddModule { hash, template } => {
+                state.modules.insert(hash, template);
+            }
+            ReplicaMessage::Round => state.next_round(),
+            ReplicaMessage::SetTrace { trace } => state.trace = trace,
+            ReplicaMessage::AddHandler {
+                canister_id,
+                method,
+                handler,
+            } => {
+                state.handlers.insert((canister_id, method), handler);
+            }
+            ReplicaMessage::GlobalTimers { sender } => state.global_timers(sender),
+        }
+    }
+
+    /// Send the given message to the canister, or enqueue it when there is a scheduler.
+    fn deliver(&mut self, canister_id: Principal, request: ReplicaCanisterRequest) {
+        if self.scheduler.is_some() {
+            self.pending.start();
+            self.incoming.push((canister_id, request));
+            return;
+        }
+
+        self.send_to_canister(canister_id, request);
+    }
+
+    fn send_to_canister(&mut self, canister_id: Principal, request: ReplicaCanisterRequest) {
+        let chan = self.canisters.get(&canister_id).unwrap();
+        chan.send(request)
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: Could not enqueue the request."));
+        self.in_flight += 1;
+    }
+
+    /// Whether the scheduler is waiting to deliver the next message, that is when no canister is
+    /// executing a message.
+    fn is_idle(&self) -> bool {
+        self.scheduler.is_some() && self.in_flight == 0
+    }
+
+    /// Whether the scheduler should deliver the next message, that is when no canister is
+    /// executing a message and there are messages waiting.
+    fn is_ready_to_deliver(&self) -> bool {
+        self.is_idle() && !(self.queue.is_empty() && self.incoming.is_empty())
+    }
+
+    /// Deliver the message chosen by the scheduler.
+    fn deliver_next(&mut self) {
+        if !self.is_ready_to_deliver() {
+            return;
+        }
+
+        let mut incoming = std::mem::take(&mut self.incoming);
+        incoming.sort_by_key(|(canister_id, _)| *canister_id);
+        self.queue.extend(incoming);
+
+        let index = match self.scheduler.as_mut().unwrap() {
+            Scheduler::Fifo => 0,
+            Scheduler::Seeded(rng) => {
+                let mut canisters = self.queue.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+                canisters.sort();
+                canisters.dedup();
+                let canister_id = canisters[(next_random(rng) % canisters.len() as u64) as usize];
+                self.queue
+                    .iter()
+                    .position(|(id, _)| *id == canister_id)
+                    .unwrap()
+            }
+        };
+
+        let (canister_id, request) = self.queue.remove(index).unwrap();
+        self.send_to_canister(canister_id, request);
+        self.pending.done();
+    }
+
     pub fn canister_added(
         &mut self,
         canister_id: Principal,
-        channel: mpsc::UnboundedSender<ReplicaCanisterRequest>,
+        channel: TrackedSender<ReplicaCanisterRequest>,
     ) {
         if self.canisters.contains_key(&canister_id) {
             panic!(
@@ -254,6 +1360,15 @@ impl ReplicaState {
             )
         }
 
+        for (_, f) in &self.broadcasts {
+            let f = f.clone();
+            channel
+                .send(ReplicaCanisterRequest::Update(Box::new(move |canister| {
+                    f(canister)
+                })))
+                .unwrap_or_else(|_| panic!("ic-kit-runtime: Could not enqueue the request."));
+        }
+
         self.canisters.insert(canister_id, channel);
     }
 
@@ -262,37 +1377,2864 @@ impl ReplicaState {
         canister_id: Principal,
         message: Message,
         reply_sender: Option<oneshot::Sender<CallReply>>,
+        report_sender: Option<oneshot::Sender<ExecutionReport>>,
     ) {
-        if let Some(chan) = self.canisters.get(&canister_id) {
-            chan.send(ReplicaCanisterRequest {
-                message,
-                reply_sender,
-            })
-            .unwrap_or_else(|_| panic!("ic-kit-runtime: Could not enqueue the request."));
+        let reply_sender = self.trace_request(canister_id, &message, reply_sender);
+
+        let deadline = match &message {
+            Message::Request { env, .. } => env.deadline,
+            _ => 0,
+        };
+
+        let is_inter_canister_call = match &message {
+            Message::Request { env, .. } => {
+                !env.ingress && self.canisters.contains_key(&env.sender)
+            }
+            _ => false,
+        };
+
+        if deadline != 0 && self.time.map(|time| time >= deadline).unwrap_or(false) {
+            let cycles_refunded = match message {
+                Message::Request { env, .. } => env.cycles_available.get(),
+                _ => 0,
+            };
+
+            reply_sender
+                .unwrap()
+                .send(deadline_expired_reply(cycles_refunded))
+                .expect("ic-kit-runtime: Could not send the response.");
+
+            return;
+        }
+
+        if let Message::Request { env, .. } = &message {
+            let handler = env
+                .method_name
+                .clone()
+                .and_then(|method| self.handlers.get_mut(&(canister_id, method)));
+
+            if let Some(handler) = handler {
+                let reply = match handler(&env.args) {
+                    Ok(data) => CallReply::Reply {
+                        data,
+                        cycles_refunded: env.cycles_available.get(),
+                    },
+                    Err((rejection_code, rejection_message)) => CallReply::Reject {
+                        rejection_code,
+                        rejection_message,
+                        cycles_refunded: env.cycles_available.get(),
+                    },
+                };
+
+                respond(reply, reply_sender, report_sender);
+                return;
+            }
+        }
+
+        if canister_id == Principal::management_canister()
+            && !self.canisters.contains_key(&canister_id)
+        {
+            if let Message::Request { env, .. } = message {
+                self.management_request(env, reply_sender, report_sender);
+                return;
+            }
+        }
+
+        if self.canisters.contains_key(&canister_id) {
+            let reply_sender = match reply_sender {
+                Some(sender) if deadline != 0 => Some(self.watch_deadline(deadline, sender)),
+                sender => sender,
+            };
+
+            let reply_sender = match (reply_sender, self.call_timeout) {
+                (Some(sender), Some(rounds)) if is_inter_canister_call => {
+                    Some(self.watch_timeout(rounds, sender))
+                }
+                (sender, _) => sender,
+            };
+
+            self.deliver(
+                canister_id,
+                ReplicaCanisterRequest::Message {
+                    message: Box::new(message),
+                    reply_sender,
+                    report_sender,
+                },
+            );
         } else {
             let cycles_refunded = match message {
-                Message::CustomTask { env, .. } => env.cycles_available,
-                Message::Request { env, .. } => env.cycles_refunded,
+                Message::CustomTask { env, .. } => env.cycles_available.get(),
+                Message::Request { env, .. } => env.cycles_available.get(),
                 Message::Reply { .. } => 0,
             };
 
+            let reply = CallReply::Reject {
+                rejection_code: RejectionCode::DestinationInvalid,
+                rejection_message: format!("Canister '{}' does not exists", canister_id),
+                cycles_refunded,
+            };
+
+            if let Some(report_sender) = report_sender {
+                let _ = report_sender.send(ExecutionReport {
+                    reply: Some(reply.clone()),
+                    ..ExecutionReport::default()
+                });
+            }
+
             reply_sender
                 .unwrap()
-                .send(CallReply::Reject {
-                    rejection_code: RejectionCode::DestinationInvalid,
-                    rejection_message: format!("Canister '{}' does not exists", canister_id),
-                    cycles_refunded,
-                })
+                .send(reply)
                 .expect("ic-kit-runtime: Could not send the response.");
         }
     }
 
+    /// Execute the given call to the management canister, see [`Replica::with_module`].
+    fn management_request(
+        &mut self,
+        env: Env,
+        reply_sender: Option<oneshot::Sender<CallReply>>,
+        report_sender: Option<oneshot::Sender<ExecutionReport>>,
+    ) {
+        let caller = env.sender;
+        let cycles = env.cycles_available.get();
+        let method = env.method_name.unwrap_or_default();
+        let args = env.args;
+
+        let result: BoxFuture<'static, ManagementResult> = match method.as_str() {
+            "raw_rand" => ready(Ok(self.raw_rand())).boxed(),
+            "create_canister" => ready(self.create_canister(caller, &args, cycles)).boxed(),
+            "start_canister" => {
+                let status = CanisterStatus::Running;
+                ready(self.set_canister_status(&method, caller, &args, status)).boxed()
+            }
+            "stop_canister" => {
+                let status = CanisterStatus::Stopped;
+                ready(self.set_canister_status(&method, caller, &args, status)).boxed()
+            }
+            "install_code" => self.install_code(caller, &args),
+            "canister_status" => self.canister_status(caller, &args),
+            "delete_canister" => self.delete_canister(caller, &args),
+            _ => ready(Err((
+                RejectionCode::DestinationInvalid,
+                format!(
+                    "The management canister does not have a '{}' method.",
+                    method
+                ),
+            )))
+            .boxed(),
+        };
+
+        // The work of the management canister is done once the response is sent.
+        let pending = self.pending.clone();
+        pending.start();
+
+        spawn_forwarder(self.pending.clone(), async move {
+            let reply = match result.await {
+                // The cycles sent to create_canister are the balance of the new canister.
+                Ok(data) => CallReply::Reply {
+                    data,
+                    cycles_refunded: if method == "create_canister" {
+                        0
+                    } else {
+                        cycles
+                    },
+                },
+                Err((rejection_code, rejection_message)) => CallReply::Reject {
+                    rejection_code,
+                    rejection_message,
+                    cycles_refunded: cycles,
+                },
+            };
+
+            respond(reply, reply_sender, report_sender);
+            pending.done();
+        });
+    }
+
+    /// Check that the caller is a controller of the given canister.
+    fn check_controller(
+        &self,
+        caller: Principal,
+        canister_id: Principal,
+    ) -> Result<(), (RejectionCode, String)> {
+        if !self.canisters.contains_key(&canister_id) {
+            return Err((
+                RejectionCode::DestinationInvalid,
+                format!("Canister {} not found.", canister_id),
+            ));
+        }
+
+        match self.controllers.get(&canister_id) {
+            Some(controllers) if controllers.contains(&caller) => Ok(()),
+            _ => Err((
+                RejectionCode::CanisterReject,
+                format!(
+                    "Only the controllers of the canister {} can control it.",
+                    canister_id
+                ),
+            )),
+        }
+    }
+
+    /// Create a new empty canister with the given cycles as its balance, and return its id.
+    fn create_canister(
+        &mut self,
+        caller: Principal,
+        args: &[u8],
+        cycles: u128,
+    ) -> ManagementResult {
+        let arg: CreateCanisterArgument = decode_management_argument("create_canister", args)?;
+        let controllers = arg
+            .settings
+            .and_then(|settings| settings.controllers)
+            .unwrap_or_else(|| vec![caller]);
+
+        let canister_id = new_canister_id(&self.next_canister_index);
+        let canister = Canister::new(canister_id).with_balance(cycles);
+        let sender = self.sender.clone().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        self.canister_added(canister_id, TrackedSender::new(tx, self.pending.clone()));
+        tokio::spawn(canister_worker(rx, sender, canister));
+
+        self.canister_ids.lock().unwrap().push(canister_id);
+        self.controllers.insert(canister_id, controllers);
+
+        Ok(encode_management_reply(CanisterIdRecord { canister_id }))
+    }
+
+    /// Change the status of the canister, the status changes right away.
+    fn set_canister_status(
+        &mut self,
+        method: &str,
+        caller: Principal,
+        args: &[u8],
+        status: CanisterStatus,
+    ) -> ManagementResult {
+        let arg: CanisterIdRecord = decode_management_argument(method, args)?;
+        self.check_controller(caller, arg.canister_id)?;
+        self.canister_update(
+            arg.canister_id,
+            Box::new(move |canister| canister.set_status(status)),
+        );
+
+        Ok(encode_management_reply(()))
+    }
+
+    /// Install a new instance of the template registered under the module hash on the canister
+    /// and run its `canister_init`, or its upgrade hooks in the upgrade mode.
+    ///
+    /// If the `canister_init` or the `canister_post_upgrade` traps, the previous instance of the
+    /// canister is put back and the module hash is not changed.
+    fn install_code(
+        &mut self,
+        caller: Principal,
+        args: &[u8],
+    ) -> BoxFuture<'static, ManagementResult> {
+        let prepare = || {
+            let arg: InstallCodeArgument = decode_management_argument("install_code", args)?;
+            self.check_controller(caller, arg.canister_id)?;
+
+            let template = self.modules.get(&arg.wasm_module).cloned().ok_or_else(|| {
+                (
+                    RejectionCode::CanisterReject,
+                    format!(
+                        "No canister is registered under the module hash {}.",
+                        hex(&arg.wasm_module)
+                    ),
+                )
+            })?;
+
+            let installed = self.module_hashes.contains_key(&arg.canister_id);
+            match arg.mode {
+                InstallMode::Install if installed => Err((
+                    RejectionCode::CanisterReject,
+                    format!(
+                        "Canister {} already has a module installed.",
+                        arg.canister_id
+                    ),
+                )),
+                InstallMode::Upgrade if !installed => Err((
+                    RejectionCode::CanisterReject,
+                    format!(
+                        "Canister {} does not have a module to upgrade.",
+                        arg.canister_id
+                    ),
+                )),
+                _ => Ok((arg, template)),
+            }
+        };
+
+        let (arg, template) = match prepare() {
+            Ok(prepared) => prepared,
+            Err(rejection) => return ready(Err(rejection)).boxed(),
+        };
+
+        let sender = self.sender.clone().unwrap();
+        let canister_id = arg.canister_id;
+        let mode = arg.mode;
+
+        async move {
+            let (previous_tx, previous_rx) = oneshot::channel();
+            let replace = move |canister: &mut Canister| {
+                let previous = match mode {
+                    InstallMode::Upgrade => canister.upgrade(template()),
+                    _ => canister.reinstall(template()),
+                };
+
+                let _ = previous_tx.send(previous);
+            };
+
+            if arg.mode == InstallMode::Upgrade {
+                run_install_hook(&sender, canister_id, Env::pre_upgrade().with_sender(caller))
+                    .await?;
+            }
+
+            sender
+                .send(ReplicaMessage::CanisterUpdate {
+                    canister_id,
+                    f: Box::new(replace),
+                })
+                .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+
+            let env = match mode {
+                InstallMode::Upgrade => Env::post_upgrade(),
+                _ => Env::init(),
+            };
+
+            let installed = run_install_hook(
+                &sender,
+                canister_id,
+                env.with_sender(caller).with_raw_args(arg.arg),
+            )
+            .await;
+
+            if let Err(rejection) = installed {
+                // The replacement is done before the hook runs, so the previous instance is ready.
+                let previous = previous_rx.await.unwrap();
+                let restore = move |canister: &mut Canister| {
+                    match mode {
+                        InstallMode::Upgrade => canister.upgrade(previous),
+                        _ => canister.reinstall(previous),
+                    };
+                };
+
+                sender
+                    .send(ReplicaMessage::CanisterUpdate {
+                        canister_id,
+                        f: Box::new(restore),
+                    })
+                    .unwrap_or_else(|_| {
+                        panic!("ic-kit-runtime: could not send message to replica")
+                    });
+
+                return Err(rejection);
+            }
+
+            sender
+                .send(ReplicaMessage::SetModuleHash {
+                    canister_id,
+                    module_hash: arg.wasm_module,
+                })
+                .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+
+            Ok(encode_management_reply(()))
+        }
+        .boxed()
+    }
+
+    /// Return the status of the canister.
+    fn canister_status(
+        &mut self,
+        caller: Principal,
+        args: &[u8],
+    ) -> BoxFuture<'static, ManagementResult> {
+        let arg: CanisterIdRecord = match decode_management_argument("canister_status", args)
+            .and_then(|arg: CanisterIdRecord| {
+                self.check_controller(caller, arg.canister_id)?;
+                Ok(arg)
+            }) {
+            Ok(arg) => arg,
+            Err(rejection) => return ready(Err(rejection)).boxed(),
+        };
+
+        let controllers = self.controllers[&arg.canister_id].clone();
+        let module_hash = self.module_hashes.get(&arg.canister_id).cloned();
+        let (tx, rx) = oneshot::channel();
+        self.canister_update(
+            arg.canister_id,
+            Box::new(move |canister| {
+                let _ = tx.send((
+                    canister.status(),
+                    canister.balance(),
+                    canister.freezing_threshold(),
+                ));
+            }),
+        );
+
+        async move {
+            let (status, cycles, freezing_threshold) = rx
+                .await
+                .expect("ic-kit-runtime: Could not inspect the canister.");
+
+            let status = match status {
+                CanisterStatus::Running => CanisterStatusType::Running,
+                CanisterStatus::Stopping => CanisterStatusType::Stopping,
+                CanisterStatus::Stopped => CanisterStatusType::Stopped,
+            };
+
+            Ok(encode_management_reply(CanisterStatusResponse {
+                status,
+                settings: DefiniteCanisterSettings {
+                    controllers,
+                    compute_allocation: Nat::from(0u64),
+                    memory_allocation: Nat::from(0u64),
+                    freezing_threshold: Nat::from(freezing_threshold),
+                },
+                module_hash,
+                memory_size: Nat::from(0u64),
+                cycles: Nat::from(cycles),
+                idle_cycles_burned_per_day: Nat::from(0u64),
+            }))
+        }
+        .boxed()
+    }
+
+    /// Delete the canister, the canister must be stopped first.
+    fn delete_canister(
+        &mut self,
+        caller: Principal,
+        args: &[u8],
+    ) -> BoxFuture<'static, ManagementResult> {
+        let arg: CanisterIdRecord = match decode_management_argument("delete_canister", args)
+            .and_then(|arg: CanisterIdRecord| {
+                self.check_controller(caller, arg.canister_id)?;
+                Ok(arg)
+            }) {
+            Ok(arg) => arg,
+            Err(rejection) => return ready(Err(rejection)).boxed(),
+        };
+
+        let canister_id = arg.canister_id;
+        let (tx, rx) = oneshot::channel();
+        self.canister_update(
+            canister_id,
+            Box::new(move |canister| {
+                let _ = tx.send(canister.status());
+            }),
+        );
+
+        let sender = self.sender.clone().unwrap();
+
+        async move {
+            let status = rx
+                .await
+                .expect("ic-kit-runtime: Could not inspect the canister.");
+
+            if status != CanisterStatus::Stopped {
+                return Err((
+                    RejectionCode::CanisterReject,
+                    format!(
+                        "Canister {} must be stopped before it is deleted.",
+                        canister_id
+                    ),
+                ));
+            }
+
+            sender
+                .send(ReplicaMessage::CanisterDeleted { canister_id })
+                .unwrap_or_else(|_| panic!("ic-kit-runtime: could not send message to replica"));
+
+            Ok(encode_management_reply(()))
+        }
+        .boxed()
+    }
+
+    /// Replace the controllers of the canister.
+    fn set_controllers(&mut self, canister_id: Principal, controllers: Vec<Principal>) {
+        match self.controllers.get_mut(&canister_id) {
+            Some(current) => *current = controllers,
+            None => panic!("Canister '{}' does not exists", canister_id),
+        }
+    }
+
+    /// Remove the canister from the replica, its event loop stops once the messages that are
+    /// already enqueued for it are processed.
+    fn canister_deleted(&mut self, canister_id: Principal) {
+        self.canisters.remove(&canister_id);
+        self.controllers.remove(&canister_id);
+        self.module_hashes.remove(&canister_id);
+        self.canister_ids
+            .lock()
+            .unwrap()
+            .retain(|id| *id != canister_id);
+    }
+
+    /// Return the Candid encoded reply of the `raw_rand` method, 32 bytes from the replica's
+    /// random number generator.
+    fn raw_rand(&mut self) -> Vec<u8> {
+        let mut bytes = vec![0u8; 32];
+        self.rng.fill_bytes(&mut bytes);
+        encode_management_reply(bytes)
+    }
+
+    /// Record the given request in the trace if tracing is enabled, and return the channel that
+    /// records the response before sending it to the given channel.
+    fn trace_request(
+        &mut self,
+        canister_id: Principal,
+        message: &Message,
+        reply_sender: Option<oneshot::Sender<CallReply>>,
+    ) -> Option<oneshot::Sender<CallReply>> {
+        let (trace, env) = match (&self.trace, message) {
+            (Some(trace), Message::Request { env, .. }) => (trace.clone(), env),
+            _ => return reply_sender,
+        };
+
+        let caller = env.sender;
+        let method = env.method_name.clone().unwrap_or_default();
+        trace.lock().unwrap().push(TraceEvent::Request {
+            sender: caller,
+            receiver: canister_id,
+            method: method.clone(),
+            cycles: env.cycles_available.get(),
+        });
+
+        let sender = reply_sender?;
+        let (tx, rx) = oneshot::channel();
+
+        spawn_forwarder(self.pending.clone(), async move {
+            if let Ok(reply) = rx.await {
+                let event = TraceEvent::response(canister_id, caller, method, &reply);
+                trace.lock().unwrap().push(event);
+                let _ = sender.send(reply);
+            }
+        });
+
+        Some(tx)
+    }
+
+    /// Send the messages returned by the interleave hook to the waiting canister.
+    fn await_point(&mut self, point: AwaitPoint) {
+        for call in &point.calls {
+            self.outgoing
+                .insert(call.request_id, (point.canister_id, call.payment));
+        }
+
+        let messages = match &mut self.interleave_hook {
+            Some(hook) => hook(&point),
+            None => return,
+        };
+
+        for message in messages {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            tokio::spawn(reply_rx);
+            self.canister_request(point.canister_id, message, Some(reply_tx), None);
+        }
+    }
+
     fn canister_reply(&mut self, canister_id: Principal, message: Message) {
-        let chan = self.canisters.get(&canister_id).unwrap();
-        chan.send(ReplicaCanisterRequest {
-            message,
-            reply_sender: None,
-        })
-        .unwrap_or_else(|_| panic!("ic-kit-runtime: Could not enqueue the response request."));
+        if let Message::Reply { reply_to, .. } = &message {
+            // The caller already received the rejection injected by `reject_outgoing`.
+            if self.rejected.remove(reply_to) {
+                return;
+            }
+
+            self.outgoing.remove(reply_to);
+        }
+
+        self.deliver(
+            canister_id,
+            ReplicaCanisterRequest::Message {
+                message: Box::new(message),
+                reply_sender: None,
+                report_sender: None,
+            },
+        );
+    }
+
+    fn canister_update(&mut self, canister_id: Principal, f: CanisterUpdateFn) {
+        let chan = self
+            .canisters
+            .get(&canister_id)
+            .unwrap_or_else(|| panic!("Canister '{}' does not exists", canister_id));
+
+        chan.send(ReplicaCanisterRequest::Update(f))
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: Could not enqueue the request."));
+    }
+
+    /// Wrap the reply channel of a best-effort message, so the call is rejected once the replica's
+    /// clock passes the deadline.
+    fn watch_deadline(
+        &mut self,
+        deadline: u64,
+        sender: oneshot::Sender<CallReply>,
+    ) -> oneshot::Sender<CallReply> {
+        // Forget the deadlines of the messages that are already responded to.
+        self.deadlines
+            .retain(|(_, expired_tx)| !expired_tx.is_closed());
+
+        let (expired_tx, expired_rx) = oneshot::channel();
+        self.deadlines.push((deadline, expired_tx));
+        watch_expiry(&self.pending, sender, expired_rx, deadline_expired_reply(0))
+    }
+
+    /// Wrap the reply channel of an inter-canister call, so the call is rejected once the given
+    /// number of rounds have started.
+    fn watch_timeout(
+        &mut self,
+        rounds: u64,
+        sender: oneshot::Sender<CallReply>,
+    ) -> oneshot::Sender<CallReply> {
+        let (expired_tx, expired_rx) = oneshot::channel();
+        self.timeouts.push((self.round + rounds, expired_tx));
+        watch_expiry(
+            &self.pending,
+            sender,
+            expired_rx,
+            call_timeout_reply(rounds),
+        )
+    }
+
+    /// Reject the inter-canister call with the given request id, the cycles attached to the call
+    /// are refunded. Does nothing if the call is already responded to.
+    fn reject_outgoing(
+        &mut self,
+        request_id: RequestId,
+        rejection_code: RejectionCode,
+        rejection_message: String,
+    ) {
+        let (caller, cycles_refunded) = match self.outgoing.remove(&request_id) {
+            Some(call) => call,
+            None => return,
+        };
+
+        self.rejected.insert(request_id);
+
+        let reply = CallReply::Reject {
+            rejection_code,
+            rejection_message,
+            cycles_refunded,
+        };
+
+        self.deliver(
+            caller,
+            ReplicaCanisterRequest::Message {
+                message: Box::new(reply.to_message(request_id)),
+                reply_sender: None,
+                report_sender: None,
+            },
+        );
+    }
+
+    /// Start the next round and reject the inter-canister calls that time out in it.
+    fn next_round(&mut self) {
+        self.round += 1;
+
+        let round = self.round;
+        let (expired, pending) = std::mem::take(&mut self.timeouts)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(timeout, _)| *timeout <= round);
+
+        self.timeouts = pending;
+
+        for (_, expired_tx) in expired {
+            let _ = expired_tx.send(());
+        }
+    }
+
+    /// Apply the given function to every canister on the replica, the function is recorded under
+    /// the given key and applied to the canisters added later as well, only the last function of
+    /// each key is kept.
+    fn broadcast(&mut self, key: &'static str, f: CanisterBroadcastFn) {
+        self.broadcasts.retain(|(k, _)| *k != key);
+        self.broadcasts.push((key, f.clone()));
+
+        for chan in self.canisters.values() {
+            let f = f.clone();
+            chan.send(ReplicaCanisterRequest::Update(Box::new(move |canister| {
+                f(canister)
+            })))
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: Could not enqueue the request."));
+        }
+    }
+
+    fn set_time(&mut self, time: u64) {
+        self.time = Some(time);
+
+        let (expired, pending) = std::mem::take(&mut self.deadlines)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= time);
+
+        self.deadlines = pending;
+
+        for (_, expired_tx) in expired {
+            let _ = expired_tx.send(());
+        }
+
+        self.broadcast("time", Arc::new(move |canister| canister.set_time(time)));
+    }
+
+    /// Send the replica's time and the global timer of every canister to the given channel.
+    fn global_timers(&mut self, sender: oneshot::Sender<(u64, Vec<(Principal, u64)>)>) {
+        let time = self.time.unwrap_or_else(now);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        for chan in self.canisters.values() {
+            let tx = tx.clone();
+            chan.send(ReplicaCanisterRequest::Update(Box::new(move |canister| {
+                let _ = tx.send((canister.id(), canister.global_timer()));
+            })))
+            .unwrap_or_else(|_| panic!("ic-kit-runtime: Could not enqueue the request."));
+        }
+
+        // The channel is closed once every canister has processed the update.
+        drop(tx);
+
+        tokio::spawn(async move {
+            let mut timers = Vec::new();
+            while let Some(timer) = rx.recv().await {
+                timers.push(timer);
+            }
+
+            let _ = sender.send((time, timers));
+        });
+    }
+}
+
+impl Pending {
+    /// Add one unit of work.
+    fn start(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Mark one unit of work as done.
+    fn done(&self) {
+        if self.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Wait until there is no more pending work.
+    async fn wait(&self) {
+        loop {
+            let notified = self.notify.notified();
+
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Wait until every task forwarding a response that is woken up has run, the responses they
+    /// forward to the replica are then enqueued in its channel.
+    async fn wait_forwarded(&self) {
+        loop {
+            let notified = self.forwarded.notified();
+
+            if self.forwarding.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    fn start_forwarding(&self) {
+        self.forwarding.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn done_forwarding(&self) {
+        if self.forwarding.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.forwarded.notify_waiters();
+        }
+    }
+}
+
+/// Spawn a task that forwards a response from one channel to another, the task is counted from
+/// the moment the response wakes it up until it runs, so the scheduler can wait for the responses
+/// that are already sent before it chooses the next message, see [`Pending::wait_forwarded`].
+fn spawn_forwarder<F>(pending: Arc<Pending>, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    // The task is counted until its first run, the response may already be sent by then.
+    pending.start_forwarding();
+
+    tokio::spawn(Forwarder {
+        future: future.boxed(),
+        waker: Arc::new(ForwarderWaker {
+            pending,
+            woken: AtomicBool::new(true),
+            waker: Mutex::new(None),
+        }),
+    });
+}
+
+impl Future for Forwarder {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        *self.waker.waker.lock().unwrap() = Some(cx.waker().clone());
+        let woken = self.waker.woken.swap(false, Ordering::SeqCst);
+
+        let waker = Waker::from(self.waker.clone());
+        let poll = self.future.as_mut().poll(&mut Context::from_waker(&waker));
+
+        if woken {
+            self.waker.pending.done_forwarding();
+        }
+
+        poll
+    }
+}
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        // Leave the flag set so the wakes that arrive after the task is gone are not counted.
+        if self.waker.woken.swap(true, Ordering::SeqCst) {
+            self.waker.pending.done_forwarding();
+        }
+
+        self.waker.waker.lock().unwrap().take();
+    }
+}
+
+impl Wake for ForwarderWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.woken.swap(true, Ordering::SeqCst) {
+            self.pending.start_forwarding();
+        }
+
+        if let Some(waker) = &*self.waker.lock().unwrap() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+impl<T> TrackedSender<T> {
+    fn new(sender: mpsc::UnboundedSender<T>, pending: Arc<Pending>) -> Self {
+        Self { sender, pending }
+    }
+
+    fn send(&self, message: T) -> Result<(), SendError<T>> {
+        self.pending.start();
+        self.sender
+            .send(message)
+            .inspect_err(|_| self.pending.done())
+    }
+}
+
+impl<T> Clone for TrackedSender<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.sender.clone(), self.pending.clone())
+    }
+}
+
+/// Decode the Candid encoded argument of the given method of the management canister.
+fn decode_management_argument<T>(method: &str, args: &[u8]) -> Result<T, (RejectionCode, String)>
+where
+    T: for<'a> Deserialize<'a> + CandidType,
+{
+    candid::decode_one(args).map_err(|e| {
+        (
+            RejectionCode::CanisterReject,
+            format!("Invalid argument for {}: {}", method, e),
+        )
+    })
+}
+
+/// Encode the reply of a method of the management canister.
+fn encode_management_reply<T: CandidType>(reply: T) -> Vec<u8> {
+    candid::encode_one(reply).expect("ic-kit-runtime: Could not encode the reply.")
+}
+
+/// Run the init or one of the upgrade hooks of a canister installed by the management canister,
+/// and reject the installation if it traps.
+async fn run_install_hook(
+    sender: &TrackedSender<ReplicaMessage>,
+    canister_id: Principal,
+    env: Env,
+) -> Result<(), (RejectionCode, String)> {
+    let hook = env.get_entry_point_name();
+    let message = Message::Request {
+        request_id: RequestId::new(),
+        env,
+    };
+
+    let report = run_message_detailed(sender, canister_id, message).await;
+    if report.trapped {
+        return Err((
+            RejectionCode::CanisterError,
+            format!(
+                "The {} hook of canister {} trapped: {}",
+                hook,
+                canister_id,
+                report.trap_message.unwrap_or_default()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Format the given bytes as a hex string.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Send the given reply of a message that is not executed by a canister to the caller, and its
+/// report to the test if it is waiting for one.
+fn respond(
+    reply: CallReply,
+    reply_sender: Option<oneshot::Sender<CallReply>>,
+    report_sender: Option<oneshot::Sender<ExecutionReport>>,
+) {
+    if let Some(report_sender) = report_sender {
+        let _ = report_sender.send(ExecutionReport {
+            reply: Some(reply.clone()),
+            ..ExecutionReport::default()
+        });
+    }
+
+    if let Some(reply_sender) = reply_sender {
+        let _ = reply_sender.send(reply);
+    }
+}
+
+/// Return a reply channel that forwards the reply to the given channel, unless the expiry is
+/// notified first in which case the given rejection is sent instead. The watch stops as soon as
+/// the message is answered or dropped, which closes the expiry channel.
+fn watch_expiry(
+    pending: &Arc<Pending>,
+    sender: oneshot::Sender<CallReply>,
+    expired_rx: oneshot::Receiver<()>,
+    expired_reply: CallReply,
+) -> oneshot::Sender<CallReply> {
+    let (tx, rx) = oneshot::channel();
+
+    spawn_forwarder(pending.clone(), async move {
+        let reply = select! {
+            biased;
+            reply = rx => match reply {
+                Ok(reply) => reply,
+                // The message was dropped without a response, there is nothing to wait for.
+                Err(_) => return,
+            },
+            Ok(()) = expired_rx => expired_reply,
+        };
+
+        let _ = sender.send(reply);
+    });
+
+    tx
+}
+
+/// The rejection of an inter-canister call that is not responded to in the given number of
+/// rounds.
+fn call_timeout_reply(rounds: u64) -> CallReply {
+    CallReply::Reject {
+        rejection_code: RejectionCode::SysUnknown,
+        rejection_message: format!("The call timed out after {} round(s).", rounds),
+        cycles_refunded: 0,
+    }
+}
+
+/// The rejection of a best-effort message whose deadline has passed.
+fn deadline_expired_reply(cycles_refunded: u128) -> CallReply {
+    CallReply::Reject {
+        rejection_code: RejectionCode::SysUnknown,
+        rejection_message: "The deadline of the message has passed.".to_string(),
+        cycles_refunded,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users;
+    use crate::CanisterMethod;
+    use ic_kit_sys::ic0;
+    use lazy_static::lazy_static;
+    use std::cell::Cell;
+    use std::sync::Mutex;
+
+    const SECOND: u64 = 1_000_000_000;
+    const START: u64 = 1_600_000_000 * SECOND;
+
+    lazy_static! {
+        /// The canister and the time of each global timer execution.
+        static ref FIRED: Mutex<Vec<(Principal, u64)>> = Mutex::new(Vec::new());
+        /// The canister of each forward call, along with the rejection code of its response,
+        /// `None` when the call is received and `Some(0)` for a reply.
+        static ref HOPS: Mutex<Vec<(Principal, Option<i32>)>> = Mutex::new(Vec::new());
+        /// The number of heartbeats executed on the replica.
+        static ref HEARTBEATS: Mutex<u64> = Mutex::new(0);
+        /// The canister of each heartbeat executed by [`BeatMethod`].
+        static ref BEATS: Mutex<Vec<Principal>> = Mutex::new(Vec::new());
+        /// The funds held by [`WithdrawMethod`].
+        static ref VAULT: Mutex<i64> = Mutex::new(100);
+        /// The number of transfers executed by [`TransferMethod`].
+        static ref TRANSFERS: Mutex<u64> = Mutex::new(0);
+        /// Whether [`HangMethod`] is executing.
+        static ref HANGING: Mutex<bool> = Mutex::new(false);
+        /// Releases [`HangMethod`] once [`CallHangMethod`] is rejected.
+        static ref HANG_RELEASE: Release = Release::new();
+        /// The rejection code and message received by [`CallHangMethod`].
+        static ref TIMEOUTS: Mutex<Vec<(i32, String)>> = Mutex::new(Vec::new());
+        /// The caller of each call received by [`ObserveMethod`].
+        static ref NOTIFIED: Mutex<Vec<Principal>> = Mutex::new(Vec::new());
+        /// The canister created by [`DeployMethod`].
+        static ref CHILD: Mutex<Option<Principal>> = Mutex::new(None);
+        /// Whether [`StallMethod`] is executing.
+        static ref STALLED: Mutex<bool> = Mutex::new(false);
+        /// Releases [`StallMethod`] once [`CallStallMethod`] is rejected.
+        static ref STALL_RELEASE: Release = Release::new();
+        /// The rejection code, message and refunded cycles received by [`CallStallMethod`].
+        static ref INJECTED: Mutex<Vec<(i32, String, u128)>> = Mutex::new(Vec::new());
+        /// The argument of each call received by [`RecordMethod`].
+        static ref RECORDED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+    }
+
+    fn canister_self() -> Principal {
+        let mut id = [0u8; 29];
+        unsafe {
+            let len = ic0::canister_self_size();
+            ic0::canister_self_copy(id.as_mut_ptr() as isize, 0, len);
+            Principal::from_slice(&id[..len as usize])
+        }
+    }
+
+    /// Record the execution, and set the timer again to 15 seconds later on the first run.
+    struct GlobalTimerMethod;
+
+    impl CanisterMethod for GlobalTimerMethod {
+        const EXPORT_NAME: &'static str = "canister_global_timer";
+
+        fn exported_method() {
+            let (id, time) = (canister_self(), unsafe { ic0::time() as u64 });
+
+            let mut fired = FIRED.lock().unwrap();
+            if !fired.iter().any(|(canister_id, _)| *canister_id == id) {
+                unsafe { ic0::global_timer_set((time + 15 * SECOND) as i64) };
+            }
+            fired.push((id, time));
+        }
+    }
+
+    static REARMED: AtomicU64 = AtomicU64::new(0);
+
+    /// A global timer that always sets the timer to the current time again.
+    struct RearmTimerMethod;
+
+    impl CanisterMethod for RearmTimerMethod {
+        const EXPORT_NAME: &'static str = "canister_global_timer";
+
+        fn exported_method() {
+            REARMED.fetch_add(1, Ordering::SeqCst);
+            unsafe { ic0::global_timer_set(ic0::time()) };
+        }
+    }
+
+    #[tokio::test]
+    async fn advance_time_rearmed_timer() {
+        let replica = Replica::default();
+        replica.set_time(START);
+
+        let id = Principal::from_slice(&[9]);
+        let canister = replica.add_canister(Canister::new(id).with_method::<RearmTimerMethod>());
+        canister
+            .custom(
+                || unsafe {
+                    ic0::global_timer_set((START + 5 * SECOND) as i64);
+                },
+                Env::default(),
+            )
+            .await;
+
+        replica.advance_time(Duration::from_secs(10)).await;
+        assert_eq!(REARMED.load(Ordering::SeqCst), 1);
+
+        let (time, timers) = replica.global_timers().await;
+        assert_eq!(time, START + 10 * SECOND);
+        assert_eq!(timers, vec![(id, START + 5 * SECOND)]);
+
+        // The timer that is already due fires once the clock moves again.
+        replica.advance_time(Duration::from_secs(10)).await;
+        assert_eq!(REARMED.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn advance_time() {
+        let replica = Replica::default();
+        replica.set_time(START);
+
+        let ids = [1u8, 2, 3].map(|i| Principal::from_slice(&[i]));
+        for (i, id) in ids.iter().enumerate() {
+            let canister =
+                replica.add_canister(Canister::new(*id).with_method::<GlobalTimerMethod>());
+
+            let timer = START + (i as u64 + 1) * 10 * SECOND;
+            canister
+                .custom(
+                    move || unsafe {
+                        ic0::global_timer_set(timer as i64);
+                    },
+                    Env::default(),
+                )
+                .await;
+        }
+
+        replica.advance_time(Duration::from_secs(40)).await;
+
+        assert_eq!(
+            *FIRED.lock().unwrap(),
+            vec![
+                (ids[0], START + 10 * SECOND),
+                (ids[1], START + 20 * SECOND),
+                (ids[0], START + 25 * SECOND),
+                (ids[2], START + 30 * SECOND),
+                (ids[1], START + 35 * SECOND),
+            ]
+        );
+
+        let (time, mut timers) = replica.global_timers().await;
+        timers.sort();
+        assert_eq!(time, START + 40 * SECOND);
+        assert_eq!(
+            timers,
+            vec![(ids[0], 0), (ids[1], 0), (ids[2], START + 45 * SECOND)]
+        );
+    }
+
+    /// Reply with the current time.
+    struct TimeMethod;
+
+    impl CanisterMethod for TimeMethod {
+        const EXPORT_NAME: &'static str = "canister_query time";
+
+        fn exported_method() {
+            let time = unsafe { ic0::time() }.to_le_bytes();
+            unsafe {
+                ic0::msg_reply_data_append(time.as_ptr() as isize, time.len() as isize);
+                ic0::msg_reply();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn with_time() {
+        let replica = Replica::default().with_time(START);
+        let canister = replica
+            .add_canister(Canister::new(Principal::from_slice(&[1])).with_method::<TimeMethod>());
+
+        let r = canister.new_call("time").perform().await;
+        assert_eq!(r.bytes().unwrap(), START.to_le_bytes());
+
+        // The clock does not move between messages.
+        let r = canister.new_call("time").perform().await;
+        assert_eq!(r.bytes().unwrap(), START.to_le_bytes());
+
+        replica.advance_time(Duration::from_secs(3600)).await;
+
+        let r = canister.new_call("time").perform().await;
+        assert_eq!(r.bytes().unwrap(), (START + 3600 * SECOND).to_le_bytes());
+    }
+
+    /// Print a line, call the `time` method of the canister itself and print another line in the
+    /// callback before replying.
+    struct PrintMethod;
+
+    impl CanisterMethod for PrintMethod {
+        const EXPORT_NAME: &'static str = "canister_update print";
+
+        fn exported_method() {
+            fn print(message: &str) {
+                unsafe { ic0::debug_print(message.as_ptr() as isize, message.len() as isize) };
+            }
+
+            fn replied(_env: isize) {
+                print("after call");
+                unsafe { ic0::msg_reply() };
+            }
+
+            print("before call");
+
+            let id = canister_self();
+            let name = b"time";
+            unsafe {
+                ic0::call_new(
+                    id.as_slice().as_ptr() as isize,
+                    id.as_slice().len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    -1,
+                    -1,
+                );
+                ic0::call_perform();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn drain_debug_log() {
+        let replica = Replica::default();
+        let canister = replica.add_canister(
+            Canister::new(Principal::from_slice(&[1]))
+                .with_method::<PrintMethod>()
+                .with_method::<TimeMethod>(),
+        );
+
+        canister.new_call("print").perform().await.assert_ok();
+        assert_eq!(
+            canister.drain_debug_log().await,
+            vec!["before call".to_string(), "after call".to_string()]
+        );
+        assert!(canister.drain_debug_log().await.is_empty());
+    }
+
+    /// Reply right away, and forward the call to the first one-byte canister id in the argument
+    /// with the rest of the argument.
+    struct ForwardMethod;
+
+    impl CanisterMethod for ForwardMethod {
+        const EXPORT_NAME: &'static str = "canister_update forward";
+
+        fn exported_method() {
+            fn replied(_env: isize) {
+                HOPS.lock().unwrap().push((canister_self(), Some(0)));
+            }
+
+            fn rejected(_env: isize) {
+                let code = unsafe { ic0::msg_reject_code() };
+                HOPS.lock().unwrap().push((canister_self(), Some(code)));
+            }
+
+            HOPS.lock().unwrap().push((canister_self(), None));
+
+            let mut arg = [0u8; 8];
+            let name = b"forward";
+            unsafe {
+                let len = ic0::msg_arg_data_size();
+                ic0::msg_arg_data_copy(arg.as_mut_ptr() as isize, 0, len);
+                ic0::msg_reply();
+
+                if len > 0 {
+                    ic0::call_new(
+                        arg.as_ptr() as isize,
+                        1,
+                        name.as_ptr() as isize,
+                        name.len() as isize,
+                        replied as fn(isize) as usize as isize,
+                        0,
+                        rejected as fn(isize) as usize as isize,
+                        0,
+                    );
+                    ic0::call_data_append(arg[1..].as_ptr() as isize, len - 1);
+                    ic0::call_perform();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn process_until_quiescent() {
+        let replica = Replica::default();
+        let ids = [1u8, 2, 3].map(|i| Principal::from_slice(&[i]));
+        for id in ids {
+            replica.add_canister(Canister::new(id).with_method::<ForwardMethod>());
+        }
+
+        // 1 -> 2 -> 3 -> 4, but there is no canister 4.
+        replica
+            .new_call(ids[0], "forward")
+            .with_arg_raw(vec![2, 3, 4])
+            .perform()
+            .await
+            .assert_ok();
+
+        replica.process_until_quiescent().await;
+
+        let mut hops = HOPS.lock().unwrap().clone();
+        hops.sort();
+        assert_eq!(
+            hops,
+            vec![
+                (ids[0], None),
+                (ids[0], Some(0)),
+                (ids[1], None),
+                (ids[1], Some(0)),
+                (ids[2], None),
+                (ids[2], Some(RejectionCode::DestinationInvalid as i32)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn new_canister() {
+        let replica = Replica::default();
+        let a = replica.new_canister(Canister::new(Principal::anonymous()));
+        let b = replica.new_canister(Canister::new(Principal::anonymous()).with_balance(5));
+
+        assert_eq!(a.id().to_text(), "rwlgt-iiaaa-aaaaa-aaaaa-cai");
+        assert_eq!(b.id().to_text(), "rrkah-fqaaa-aaaaa-aaaaq-cai");
+
+        // The canisters run under their new ids.
+        for canister in [&a, &b] {
+            let r = canister
+                .custom(
+                    || {
+                        let id = canister_self();
+                        unsafe {
+                            ic0::msg_reply_data_append(
+                                id.as_slice().as_ptr() as isize,
+                                id.as_slice().len() as isize,
+                            );
+                            ic0::msg_reply();
+                        }
+                    },
+                    Env::default(),
+                )
+                .await;
+            assert_eq!(r.bytes().unwrap(), canister.id().as_slice());
+        }
+
+        let (id, balance) = replica
+            .inspect_canister(b.id(), |canister| (canister.id(), canister.balance()))
+            .await;
+        assert_eq!(id, b.id());
+        assert_eq!(balance, 5);
+    }
+
+    #[tokio::test]
+    async fn canister_ids() {
+        let replica = Replica::default();
+        assert!(replica.canister_ids().is_empty());
+
+        let ids = (0..3)
+            .map(|_| {
+                let canister = Canister::new(Principal::anonymous());
+                replica
+                    .new_canister(canister.with_method::<IncrementMethod>())
+                    .id()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(replica.canister_ids(), ids);
+
+        // Each canister has its own state.
+        for id in replica.canister_ids() {
+            assert_eq!(increment(&replica.get_canister(id)).await, 1);
+        }
+
+        let unknown = replica.new_canister_id();
+        let r = replica.new_call(unknown, "increment").perform().await;
+        assert_eq!(r.rejection_code(), RejectionCode::DestinationInvalid);
+    }
+
+    thread_local! {
+        /// The heap memory of the canister executed on the current thread.
+        static COUNTER: Cell<u64> = const { Cell::new(0) };
+    }
+
+    /// Increment the counter and reply with its new value.
+    struct IncrementMethod;
+
+    impl CanisterMethod for IncrementMethod {
+        const EXPORT_NAME: &'static str = "canister_update increment";
+
+        fn exported_method() {
+            let value = COUNTER.with(|c| c.get() + 1);
+            COUNTER.with(|c| c.set(value));
+            let bytes = value.to_le_bytes();
+            unsafe {
+                ic0::msg_reply_data_append(bytes.as_ptr() as isize, bytes.len() as isize);
+                ic0::msg_reply();
+            }
+        }
+    }
+
+    /// Set the counter to the `nat64` passed to the init.
+    struct InitCounterMethod;
+
+    impl CanisterMethod for InitCounterMethod {
+        const EXPORT_NAME: &'static str = "canister_init";
+
+        fn exported_method() {
+            let data = unsafe {
+                let mut data = vec![0u8; ic0::msg_arg_data_size() as usize];
+                ic0::msg_arg_data_copy(data.as_mut_ptr() as isize, 0, data.len() as isize);
+                data
+            };
+            let start: u64 = candid::decode_one(&data).unwrap();
+            COUNTER.with(|c| c.set(start));
+        }
+    }
+
+    #[tokio::test]
+    async fn install_canister_args() {
+        let replica = Replica::default();
+        let canister = replica
+            .install_canister(
+                Canister::new(Principal::from_slice(&[1]))
+                    .with_method::<InitCounterMethod>()
+                    .with_method::<IncrementMethod>(),
+                (10u64,),
+            )
+            .await;
+
+        let r = canister.new_call("increment").perform().await;
+        assert_eq!(r.bytes().unwrap(), 11u64.to_le_bytes());
+    }
+
+    /// Save the counter to the stable memory.
+    struct PreUpgradeMethod;
+
+    impl CanisterMethod for PreUpgradeMethod {
+        const EXPORT_NAME: &'static str = "canister_pre_upgrade";
+
+        fn exported_method() {
+            let bytes = COUNTER.with(|c| c.get()).to_le_bytes();
+            unsafe {
+                if ic0::stable_size() == 0 {
+                    ic0::stable_grow(1);
+                }
+                ic0::stable_write(0, bytes.as_ptr() as isize, bytes.len() as isize);
+            }
+        }
+    }
+
+    /// Restore the counter from the stable memory.
+    struct PostUpgradeMethod;
+
+    impl CanisterMethod for PostUpgradeMethod {
+        const EXPORT_NAME: &'static str = "canister_post_upgrade";
+
+        fn exported_method() {
+            let mut bytes = [0u8; 8];
+            unsafe { ic0::stable_read(bytes.as_mut_ptr() as isize, 0, bytes.len() as isize) };
+            COUNTER.with(|c| c.set(u64::from_le_bytes(bytes)));
+        }
+    }
+
+    async fn increment(canister: &CanisterHandle<'_>) -> u64 {
+        let r = canister.new_call("increment").perform().await;
+        u64::from_le_bytes(r.bytes().unwrap().try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn upgrade_canister() {
+        let replica = Replica::default();
+        let counter = || Canister::new(Principal::anonymous()).with_method::<IncrementMethod>();
+
+        let canister = replica.add_canister(counter().with_method::<PreUpgradeMethod>());
+        assert_eq!(increment(&canister).await, 1);
+        assert_eq!(increment(&canister).await, 2);
+
+        // The counter is restored from the stable memory by the new instance.
+        replica
+            .upgrade_canister(
+                canister.id(),
+                counter()
+                    .with_method::<PreUpgradeMethod>()
+                    .with_method::<PostUpgradeMethod>(),
+            )
+            .await;
+        assert_eq!(increment(&canister).await, 3);
+
+        // The heap memory does not survive the upgrade.
+        replica.upgrade_canister(canister.id(), counter()).await;
+        assert_eq!(increment(&canister).await, 1);
+    }
+
+    #[tokio::test]
+    async fn call_cost() {
+        let callee = Principal::management_canister();
+        let replica =
+            Replica::default()
+                .with_call_cost(1_000, 10)
+                .with_handler(callee, "echo", |arg| Ok(arg.to_vec()));
+        let canister =
+            replica.add_canister(Canister::new(Principal::from_slice(&[1])).with_balance(10_000));
+
+        let r = canister
+            .custom(
+                move || unsafe {
+                    fn replied(_env: isize) {
+                        unsafe { ic0::msg_reply() };
+                    }
+
+                    let name = b"echo";
+                    let arg = [7u8; 100];
+                    ic0::call_new(
+                        callee.as_slice().as_ptr() as isize,
+                        callee.as_slice().len() as isize,
+                        name.as_ptr() as isize,
+                        name.len() as isize,
+                        replied as fn(isize) as usize as isize,
+                        0,
+                        -1,
+                        -1,
+                    );
+                    ic0::call_data_append(arg.as_ptr() as isize, arg.len() as isize);
+                    ic0::call_perform();
+                },
+                Env::default(),
+            )
+            .await;
+        assert!(r.is_ok());
+
+        // The fee covers the 4 bytes of the method name and the 100 bytes of the argument.
+        assert_eq!(canister.balance().await, 10_000 - (1_000 + 10 * 104));
+    }
+
+    /// Count the heartbeats.
+    struct HeartbeatMethod;
+
+    impl CanisterMethod for HeartbeatMethod {
+        const EXPORT_NAME: &'static str = "canister_heartbeat";
+
+        fn exported_method() {
+            *HEARTBEATS.lock().unwrap() += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn run_rounds() {
+        let replica = Replica::default().with_time(START);
+        replica.add_canister(
+            Canister::new(Principal::from_slice(&[1])).with_method::<HeartbeatMethod>(),
+        );
+        // A canister without a heartbeat is skipped.
+        replica.add_canister(Canister::new(Principal::from_slice(&[2])));
+
+        replica.run_rounds(5).await;
+
+        assert_eq!(*HEARTBEATS.lock().unwrap(), 5);
+        assert_eq!(replica.global_timers().await.0, START + 5 * SECOND);
+    }
+
+    /// Record the canister of the heartbeat.
+    struct BeatMethod;
+
+    impl CanisterMethod for BeatMethod {
+        const EXPORT_NAME: &'static str = "canister_heartbeat";
+
+        fn exported_method() {
+            BEATS.lock().unwrap().push(canister_self());
+        }
+    }
+
+    /// A heartbeat that always traps.
+    struct TrappingHeartbeatMethod;
+
+    impl CanisterMethod for TrappingHeartbeatMethod {
+        const EXPORT_NAME: &'static str = "canister_heartbeat";
+
+        fn exported_method() {
+            let message = b"heartbeat failed";
+            unsafe { ic0::trap(message.as_ptr() as isize, message.len() as isize) };
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeat_errors() {
+        let replica = Replica::default();
+        let (failing, healthy) = (Principal::from_slice(&[30]), Principal::from_slice(&[31]));
+        replica.add_canister(Canister::new(failing).with_method::<TrappingHeartbeatMethod>());
+        replica.add_canister(Canister::new(healthy).with_method::<BeatMethod>());
+
+        replica.run_rounds(3).await;
+
+        assert_eq!(BEATS.lock().unwrap().clone(), vec![healthy; 3]);
+
+        let errors = replica.heartbeat_errors();
+        assert_eq!(errors.len(), 3);
+        for (canister_id, message) in errors {
+            assert_eq!(canister_id, failing);
+            assert!(message.contains("heartbeat failed"));
+        }
+    }
+
+    /// Reply with whether the query is executed in replicated mode.
+    struct ReplicatedMethod;
+
+    impl CanisterMethod for ReplicatedMethod {
+        const EXPORT_NAME: &'static str = "canister_query replicated";
+
+        fn exported_method() {
+            let replicated = unsafe { ic0::in_replicated_execution() };
+            let bytes = replicated.to_le_bytes();
+            unsafe {
+                ic0::msg_reply_data_append(bytes.as_ptr() as isize, bytes.len() as isize);
+                ic0::msg_reply();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn replicated_query() {
+        let caller = Principal::anonymous();
+        let replica = Replica::default();
+        let canister = replica.add_canister(
+            Canister::new(Principal::from_slice(&[1])).with_method::<ReplicatedMethod>(),
+        );
+
+        let r = replica.query(canister.id(), caller, "replicated", ()).await;
+        assert_eq!(r.bytes().unwrap(), 0i32.to_le_bytes());
+
+        let r = replica
+            .replicated_query(canister.id(), caller, "replicated", ())
+            .await;
+        assert_eq!(r.bytes().unwrap(), 1i32.to_le_bytes());
+
+        // A query method called as an update is always replicated.
+        let r = replica
+            .update(canister.id(), caller, "replicated", ())
+            .await;
+        assert_eq!(r.bytes().unwrap(), 1i32.to_le_bytes());
+    }
+
+    /// Call the relay method of the one-byte canister id in the argument with the rest of the
+    /// argument and reply once it replies, or reply right away if the argument is empty.
+    struct RelayMethod;
+
+    impl CanisterMethod for RelayMethod {
+        const EXPORT_NAME: &'static str = "canister_update relay";
+
+        fn exported_method() {
+            fn replied(_env: isize) {
+                unsafe { ic0::msg_reply() };
+            }
+
+            let mut arg = [0u8; 8];
+            let name = b"relay";
+            unsafe {
+                let len = ic0::msg_arg_data_size();
+                ic0::msg_arg_data_copy(arg.as_mut_ptr() as isize, 0, len);
+
+                if len == 0 {
+                    ic0::msg_reply();
+                    return;
+                }
+
+                ic0::call_new(
+                    arg.as_ptr() as isize,
+                    1,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    -1,
+                    -1,
+                );
+                ic0::call_data_append(arg[1..].as_ptr() as isize, len - 1);
+                ic0::call_cycles_add(100);
+                ic0::call_perform();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn tracing() {
+        let replica = Replica::default().with_tracing(true);
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        replica.add_canister(Canister::new(a).with_method::<RelayMethod>());
+        replica.add_canister(Canister::new(b).with_method::<RelayMethod>());
+
+        replica
+            .new_call(a, "relay")
+            .with_arg_raw(vec![2])
+            .perform()
+            .await
+            .assert_ok();
+
+        let user = Principal::anonymous();
+        let method = String::from("relay");
+        assert_eq!(
+            replica.trace(),
+            vec![
+                TraceEvent::Request {
+                    sender: user,
+                    receiver: a,
+                    method: method.clone(),
+                    cycles: 0
+                },
+                TraceEvent::Request {
+                    sender: a,
+                    receiver: b,
+                    method: method.clone(),
+                    cycles: 100
+                },
+                TraceEvent::Reply {
+                    sender: b,
+                    receiver: a,
+                    method: method.clone(),
+                    cycles_refunded: 100
+                },
+                TraceEvent::Reply {
+                    sender: a,
+                    receiver: user,
+                    method,
+                    cycles_refunded: 0
+                },
+            ]
+        );
+
+        // Nothing is recorded once the tracing is disabled.
+        let replica = replica.with_tracing(false);
+        replica
+            .new_call(b, "relay")
+            .with_arg_raw(vec![])
+            .perform()
+            .await
+            .assert_ok();
+        assert_eq!(replica.trace().len(), 4);
+    }
+
+    /// Run three concurrent chains of relay calls on a traced replica with the given scheduler.
+    async fn scheduled_trace(seed: Option<u64>) -> Vec<TraceEvent> {
+        let replica = Replica::default().with_tracing(true);
+        let replica = match seed {
+            Some(seed) => replica.with_scheduler_seed(seed),
+            None => replica.with_deterministic_scheduler(),
+        };
+
+        let ids = [1, 2, 3].map(|i| Principal::from_slice(&[i]));
+        for id in ids {
+            replica.add_canister(Canister::new(id).with_method::<RelayMethod>());
+        }
+
+        let x = replica.new_call(ids[0], "relay").with_arg_raw(vec![2, 3]);
+        let y = replica.new_call(ids[1], "relay").with_arg_raw(vec![3, 1]);
+        let z = replica.new_call(ids[2], "relay").with_arg_raw(vec![1, 2]);
+        let (x, y, z) = futures::join!(x.perform(), y.perform(), z.perform());
+        x.assert_ok();
+        y.assert_ok();
+        z.assert_ok();
+
+        replica.trace()
+    }
+
+    #[tokio::test]
+    async fn scheduler() {
+        let trace = scheduled_trace(None).await;
+        // 3 calls from the users and 6 inter-canister calls, each with a request and a reply.
+        assert_eq!(trace.len(), 18);
+        assert_eq!(scheduled_trace(None).await, trace);
+
+        for seed in [0, 7, 42] {
+            let trace = scheduled_trace(Some(seed)).await;
+            assert_eq!(trace.len(), 18);
+            assert_eq!(scheduled_trace(Some(seed)).await, trace);
+        }
+    }
+
+    #[tokio::test]
+    async fn scheduler_seed() {
+        // The chains interleave differently with another seed.
+        let trace = scheduled_trace(Some(0)).await;
+        assert_eq!(scheduled_trace(Some(0)).await, trace);
+        assert_ne!(scheduled_trace(Some(1)).await, trace);
+    }
+
+    /// Make three calls to the record method of the canister `[61]`, with the argument of this
+    /// call plus 0, 1 and 2, and reply right away.
+    struct FanOutMethod;
+
+    impl CanisterMethod for FanOutMethod {
+        const EXPORT_NAME: &'static str = "canister_update fan_out";
+
+        fn exported_method() {
+            fn replied(_env: isize) {}
+
+            let callee = [61u8];
+            let name = b"record";
+            let mut base = 0u8;
+            unsafe {
+                ic0::msg_arg_data_copy(&mut base as *mut u8 as isize, 0, 1);
+
+                for i in 0..3 {
+                    ic0::call_new(
+                        callee.as_ptr() as isize,
+                        1,
+                        name.as_ptr() as isize,
+                        name.len() as isize,
+                        replied as fn(isize) as usize as isize,
+                        0,
+                        -1,
+                        -1,
+                    );
+                    ic0::call_data_append(&(base + i) as *const u8 as isize, 1);
+                    ic0::call_perform();
+                }
+
+                ic0::msg_reply();
+            }
+        }
+    }
+
+    /// Record the argument of the call.
+    struct RecordMethod;
+
+    impl CanisterMethod for RecordMethod {
+        const EXPORT_NAME: &'static str = "canister_update record";
+
+        fn exported_method() {
+            let mut arg = 0u8;
+            unsafe {
+                ic0::msg_arg_data_copy(&mut arg as *mut u8 as isize, 0, 1);
+                RECORDED.lock().unwrap().push(arg);
+                ic0::msg_reply();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn scheduler_fifo_per_caller() {
+        let (a, b) = (Principal::from_slice(&[60]), Principal::from_slice(&[62]));
+        let callee = Principal::from_slice(&[61]);
+
+        for seed in 0..8 {
+            RECORDED.lock().unwrap().clear();
+
+            let replica = Replica::default().with_scheduler_seed(seed);
+            replica.add_canister(Canister::new(a).with_method::<FanOutMethod>());
+            replica.add_canister(Canister::new(b).with_method::<FanOutMethod>());
+            replica.add_canister(Canister::new(callee).with_method::<RecordMethod>());
+
+            let x = replica.new_call(a, "fan_out").with_arg_raw(vec![0]);
+            let y = replica.new_call(b, "fan_out").with_arg_raw(vec![10]);
+            let (x, y) = futures::join!(x.perform(), y.perform());
+            x.assert_ok();
+            y.assert_ok();
+            replica.process_until_quiescent().await;
+
+            // The calls of the two callers interleave, but the calls from one caller are
+            // delivered in the order they are made.
+            let recorded = RECORDED.lock().unwrap().clone();
+            let (from_a, from_b): (Vec<u8>, Vec<u8>) = recorded.iter().partition(|n| **n < 10);
+            assert_eq!(from_a, [0, 1, 2]);
+            assert_eq!(from_b, [10, 11, 12]);
+        }
+    }
+
+    /// Withdraw 100 from the vault, the funds are checked before the transfer but only debited
+    /// once the transfer is done.
+    struct WithdrawMethod;
+
+    impl CanisterMethod for WithdrawMethod {
+        const EXPORT_NAME: &'static str = "canister_update withdraw";
+
+        fn exported_method() {
+            fn debit(_env: isize) {
+                *VAULT.lock().unwrap() -= 100;
+                unsafe { ic0::msg_reply() };
+            }
+
+            if *VAULT.lock().unwrap() < 100 {
+                let message = b"Insufficient funds.";
+                unsafe { ic0::msg_reject(message.as_ptr() as isize, message.len() as isize) };
+                return;
+            }
+
+            let ledger = [51u8];
+            let name = b"transfer";
+            unsafe {
+                ic0::call_new(
+                    ledger.as_ptr() as isize,
+                    ledger.len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    debit as fn(isize) as usize as isize,
+                    0,
+                    -1,
+                    -1,
+                );
+                ic0::call_perform();
+            }
+        }
+    }
+
+    /// Count the transfers.
+    struct TransferMethod;
+
+    impl CanisterMethod for TransferMethod {
+        const EXPORT_NAME: &'static str = "canister_update transfer";
+
+        fn exported_method() {
+            *TRANSFERS.lock().unwrap() += 1;
+            unsafe { ic0::msg_reply() };
+        }
+    }
+
+    #[tokio::test]
+    async fn interleave_hook() {
+        let (bank, ledger) = (Principal::from_slice(&[50]), Principal::from_slice(&[51]));
+        let mut injected = false;
+        let replica = Replica::default().with_interleave_hook(move |point| {
+            assert_eq!(point.canister_id, bank);
+            assert_eq!(point.calls[0].callee, ledger);
+            assert_eq!(point.calls[0].method, "transfer");
+
+            // Withdraw again while the first withdrawal waits for the transfer.
+            if point.entry_point == "canister_update withdraw" && !injected {
+                injected = true;
+                vec![Message::update("withdraw")]
+            } else {
+                vec![]
+            }
+        });
+        replica.add_canister(Canister::new(bank).with_method::<WithdrawMethod>());
+        replica.add_canister(Canister::new(ledger).with_method::<TransferMethod>());
+
+        replica
+            .new_call(bank, "withdraw")
+            .perform()
+            .await
+            .assert_ok();
+        replica.process_until_quiescent().await;
+
+        // Both withdrawals passed the check, the funds are spent twice.
+        assert_eq!(*TRANSFERS.lock().unwrap(), 2);
+        assert_eq!(*VAULT.lock().unwrap(), -100);
+    }
+
+    /// Releases a test method that blocks its canister, the method gives up after 10 seconds so
+    /// a failing test does not hang.
+    struct Release {
+        tx: Mutex<std::sync::mpsc::Sender<()>>,
+        rx: Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl Release {
+        fn new() -> Self {
+            let (tx, rx) = std::sync::mpsc::channel();
+            Self {
+                tx: Mutex::new(tx),
+                rx: Mutex::new(rx),
+            }
+        }
+
+        /// Block the current thread until it is released.
+        fn wait(&self) {
+            let _ = self
+                .rx
+                .lock()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(10));
+        }
+
+        fn release(&self) {
+            let _ = self.tx.lock().unwrap().send(());
+        }
+    }
+
+    /// Block the canister until [`CallHangMethod`] is rejected, and never reply.
+    struct HangMethod;
+
+    impl CanisterMethod for HangMethod {
+        const EXPORT_NAME: &'static str = "canister_update hang";
+
+        fn exported_method() {
+            *HANGING.lock().unwrap() = true;
+            HANG_RELEASE.wait();
+        }
+    }
+
+    /// Call the `hang` method of the canister 61, and record the rejection.
+    struct CallHangMethod;
+
+    impl CanisterMethod for CallHangMethod {
+        const EXPORT_NAME: &'static str = "canister_update call_hang";
+
+        fn exported_method() {
+            fn rejected(_env: isize) {
+                let code = unsafe { ic0::msg_reject_code() };
+                let mut message = vec![0u8; unsafe { ic0::msg_reject_msg_size() } as usize];
+                unsafe {
+                    ic0::msg_reject_msg_copy(
+                        message.as_mut_ptr() as isize,
+                        0,
+                        message.len() as isize,
+                    );
+                }
+
+                TIMEOUTS
+                    .lock()
+                    .unwrap()
+                    .push((code, String::from_utf8(message).unwrap()));
+                HANG_RELEASE.release();
+                unsafe { ic0::msg_reply() };
+            }
+
+            let callee = [61u8];
+            let name = b"hang";
+            unsafe {
+                ic0::call_new(
+                    callee.as_ptr() as isize,
+                    callee.len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    -1,
+                    0,
+                    rejected as fn(isize) as usize as isize,
+                    0,
+                );
+                ic0::call_perform();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn call_timeout() {
+        let (caller, callee) = (Principal::from_slice(&[60]), Principal::from_slice(&[61]));
+        let replica = Replica::default().with_call_timeout(1);
+        replica.add_canister(Canister::new(caller).with_method::<CallHangMethod>());
+        replica.add_canister(Canister::new(callee).with_method::<HangMethod>());
+
+        let call = replica.new_call(caller, "call_hang");
+        let rounds = async {
+            while !*HANGING.lock().unwrap() {
+                tokio::task::yield_now().await;
+            }
+
+            // The call does not time out until a round starts.
+            assert!(TIMEOUTS.lock().unwrap().is_empty());
+            replica.run_rounds(1).await;
+        };
+
+        let (reply, _) = futures::join!(call.perform(), rounds);
+        reply.assert_ok();
+        replica.process_until_quiescent().await;
+
+        assert_eq!(
+            *TIMEOUTS.lock().unwrap(),
+            vec![(
+                i32::from(RejectionCode::SysUnknown),
+                "The call timed out after 1 round(s).".to_string()
+            )]
+        );
+    }
+
+    /// Block the canister until [`CallStallMethod`] is rejected, and then reply.
+    struct StallMethod;
+
+    impl CanisterMethod for StallMethod {
+        const EXPORT_NAME: &'static str = "canister_update stall";
+
+        fn exported_method() {
+            *STALLED.lock().unwrap() = true;
+            STALL_RELEASE.wait();
+
+            unsafe { ic0::msg_reply() };
+        }
+    }
+
+    /// Call the `stall` method of the canister 131 with 1000 cycles, and record the rejection.
+    struct CallStallMethod;
+
+    impl CanisterMethod for CallStallMethod {
+        const EXPORT_NAME: &'static str = "canister_update call_stall";
+
+        fn exported_method() {
+            fn replied(_env: isize) {
+                let message = "The call should be rejected.";
+                unsafe { ic0::trap(message.as_ptr() as isize, message.len() as isize) };
+            }
+
+            fn rejected(_env: isize) {
+                let code = unsafe { ic0::msg_reject_code() };
+                let mut message = vec![0u8; unsafe { ic0::msg_reject_msg_size() } as usize];
+                let refunded = unsafe {
+                    ic0::msg_reject_msg_copy(
+                        message.as_mut_ptr() as isize,
+                        0,
+                        message.len() as isize,
+                    );
+                    ic0::msg_cycles_refunded() as u128
+                };
+
+                INJECTED.lock().unwrap().push((
+                    code,
+                    String::from_utf8(message).unwrap(),
+                    refunded,
+                ));
+                STALL_RELEASE.release();
+                unsafe { ic0::msg_reply() };
+            }
+
+            let callee = [131u8];
+            let name = b"stall";
+            unsafe {
+                ic0::call_new(
+                    callee.as_ptr() as isize,
+                    callee.len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    rejected as fn(isize) as usize as isize,
+                    0,
+                );
+                ic0::call_cycles_add(1_000);
+                ic0::call_perform();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_outgoing() {
+        let (caller, callee) = (Principal::from_slice(&[130]), Principal::from_slice(&[131]));
+        let request_id = Arc::new(Mutex::new(None));
+        let replica = Replica::default().with_interleave_hook({
+            let request_id = request_id.clone();
+            move |point| {
+                if point.canister_id == caller {
+                    *request_id.lock().unwrap() = Some(point.calls[0].request_id);
+                }
+
+                vec![]
+            }
+        });
+        replica.add_canister(Canister::new(caller).with_method::<CallStallMethod>());
+        replica.add_canister(Canister::new(callee).with_method::<StallMethod>());
+
+        let call = replica.new_call(caller, "call_stall");
+        let reject = async {
+            while !*STALLED.lock().unwrap() {
+                tokio::task::yield_now().await;
+            }
+
+            let request_id = request_id.lock().unwrap().unwrap();
+            replica.reject_outgoing(request_id, RejectionCode::CanisterReject, "Injected.");
+        };
+
+        let (reply, _) = futures::join!(call.perform(), reject);
+        reply.assert_ok();
+        replica.process_until_quiescent().await;
+
+        // The late reply of the callee is dropped, and the attached cycles are refunded.
+        assert_eq!(
+            *INJECTED.lock().unwrap(),
+            vec![(
+                RejectionCode::CanisterReject as i32,
+                "Injected.".to_string(),
+                1_000
+            )]
+        );
+    }
+
+    /// Notify the `observe` method of the canister 71 with a one-way call.
+    struct NotifyMethod;
+
+    impl CanisterMethod for NotifyMethod {
+        const EXPORT_NAME: &'static str = "canister_update notify";
+
+        fn exported_method() {
+            let callee = [71u8];
+            let name = b"observe";
+            unsafe {
+                ic0::call_new(
+                    callee.as_ptr() as isize,
+                    callee.len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    -1,
+                    -1,
+                    -1,
+                    -1,
+                );
+                ic0::call_perform();
+            }
+        }
+    }
+
+    /// Record the caller, and reject the call.
+    struct ObserveMethod;
+
+    impl CanisterMethod for ObserveMethod {
+        const EXPORT_NAME: &'static str = "canister_update observe";
+
+        fn exported_method() {
+            let mut bytes = [0u8; 29];
+            unsafe {
+                let len = ic0::msg_caller_size();
+                ic0::msg_caller_copy(bytes.as_mut_ptr() as isize, 0, len);
+                NOTIFIED
+                    .lock()
+                    .unwrap()
+                    .push(Principal::from_slice(&bytes[..len as usize]));
+
+                let message = b"Not interested.";
+                ic0::msg_reject(message.as_ptr() as isize, message.len() as isize);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn one_way_call() {
+        let (notifier, observer) = (Principal::from_slice(&[70]), Principal::from_slice(&[71]));
+        let replica = Replica::default();
+        replica.add_canister(Canister::new(notifier).with_method::<NotifyMethod>());
+        replica.add_canister(Canister::new(observer).with_method::<ObserveMethod>());
+
+        // The notifier does not reply, but it is not waiting for any response either, so the
+        // call is finished right away.
+        let report = replica
+            .run_message_detailed(notifier, Message::update("notify"))
+            .await;
+        assert_eq!(report.outgoing_calls.len(), 1);
+        assert!(report.outgoing_calls[0].one_way);
+        assert_eq!(
+            report.reply.unwrap().rejection_message(),
+            Some("Canister did not reply to the call")
+        );
+
+        // The rejection of the observer is dropped.
+        replica.process_until_quiescent().await;
+        assert_eq!(*NOTIFIED.lock().unwrap(), vec![notifier]);
+    }
+
+    /// Call `raw_rand` on the management canister, and reply with its response.
+    struct RandMethod;
+
+    impl CanisterMethod for RandMethod {
+        const EXPORT_NAME: &'static str = "canister_update rand";
+
+        fn exported_method() {
+            fn replied(_env: isize) {
+                unsafe {
+                    let mut data = vec![0u8; ic0::msg_arg_data_size() as usize];
+                    ic0::msg_arg_data_copy(data.as_mut_ptr() as isize, 0, data.len() as isize);
+                    ic0::msg_reply_data_append(data.as_ptr() as isize, data.len() as isize);
+                    ic0::msg_reply();
+                }
+            }
+
+            let callee = Principal::management_canister();
+            let name = b"raw_rand";
+            unsafe {
+                ic0::call_new(
+                    callee.as_slice().as_ptr() as isize,
+                    callee.as_slice().len() as isize,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    -1,
+                    -1,
+                );
+                ic0::call_perform();
+            }
+        }
+    }
+
+    /// Return the random bytes of two `raw_rand` calls on a replica with the given seed.
+    async fn random_bytes(seed: u64) -> Vec<Vec<u8>> {
+        let replica = Replica::default().with_rng_seed(seed);
+        let canister = replica
+            .add_canister(Canister::new(Principal::from_slice(&[80])).with_method::<RandMethod>());
+
+        let mut bytes = Vec::new();
+        for _ in 0..2 {
+            let r = canister.new_call("rand").perform().await;
+            bytes.push(r.decode_one::<Vec<u8>>().unwrap());
+        }
+
+        bytes
+    }
+
+    #[tokio::test]
+    async fn rng_seed() {
+        // The first block of the ChaCha20 keystream with an all zero key.
+        let bytes = random_bytes(0).await;
+        assert_eq!(
+            bytes[0],
+            [
+                0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90, 0x40, 0x5d, 0x6a, 0xe5, 0x53, 0x86,
+                0xbd, 0x28, 0xbd, 0xd2, 0x19, 0xb8, 0xa0, 0x8d, 0xed, 0x1a, 0xa8, 0x36, 0xef, 0xcc,
+                0x8b, 0x77, 0x0d, 0xc7
+            ]
+        );
+        assert_eq!(
+            bytes[1],
+            [
+                0xda, 0x41, 0x59, 0x7c, 0x51, 0x57, 0x48, 0x8d, 0x77, 0x24, 0xe0, 0x3f, 0xb8, 0xd8,
+                0x4a, 0x37, 0x6a, 0x43, 0xb8, 0xf4, 0x15, 0x18, 0xa1, 0x1c, 0xc3, 0x87, 0xb6, 0x69,
+                0xb2, 0xee, 0x65, 0x86
+            ]
+        );
+
+        let bytes = random_bytes(7).await;
+        assert_eq!(random_bytes(7).await, bytes);
+        assert_ne!(random_bytes(8).await, bytes);
+    }
+
+    /// Create a canister, install the `counter` module on it, and reply with the response of
+    /// its `increment` method.
+    struct DeployMethod;
+
+    impl DeployMethod {
+        /// Perform a call with the given argument, and forward a rejection to the caller.
+        fn call(callee: Principal, method: &str, arg: Vec<u8>, cycles: i64, replied: fn(isize)) {
+            fn rejected(_env: isize) {
+                unsafe {
+                    let mut message = vec![0u8; ic0::msg_reject_msg_size() as usize];
+                    ic0::msg_reject_msg_copy(
+                        message.as_mut_ptr() as isize,
+                        0,
+                        message.len() as isize,
+                    );
+                    ic0::msg_reject(message.as_ptr() as isize, message.len() as isize);
+                }
+            }
+
+            unsafe {
+                ic0::call_new(
+                    callee.as_slice().as_ptr() as isize,
+                    callee.as_slice().len() as isize,
+                    method.as_ptr() as isize,
+                    method.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    rejected as fn(isize) as usize as isize,
+                    0,
+                );
+                ic0::call_data_append(arg.as_ptr() as isize, arg.len() as isize);
+                ic0::call_cycles_add(cycles);
+                ic0::call_perform();
+            }
+        }
+
+        /// Return the argument of the current message.
+        fn arg_data() -> Vec<u8> {
+            unsafe {
+                let mut data = vec![0u8; ic0::msg_arg_data_size() as usize];
+                ic0::msg_arg_data_copy(data.as_mut_ptr() as isize, 0, data.len() as isize);
+                data
+            }
+        }
+    }
+
+    impl CanisterMethod for DeployMethod {
+        const EXPORT_NAME: &'static str = "canister_update deploy";
+
+        fn exported_method() {
+            fn created(_env: isize) {
+                let record: CanisterIdRecord =
+                    candid::decode_one(&DeployMethod::arg_data()).unwrap();
+                *CHILD.lock().unwrap() = Some(record.canister_id);
+
+                let arg = InstallCodeArgument {
+                    mode: InstallMode::Install,
+                    canister_id: record.canister_id,
+                    wasm_module: b"counter".to_vec(),
+                    arg: vec![],
+                };
+
+                DeployMethod::call(
+                    Principal::management_canister(),
+                    "install_code",
+                    candid::encode_one(arg).unwrap(),
+                    0,
+                    installed,
+                );
+            }
+
+            fn installed(_env: isize) {
+                let child = CHILD.lock().unwrap().unwrap();
+                DeployMethod::call(child, "increment", vec![], 0, incremented);
+            }
+
+            fn incremented(_env: isize) {
+                let data = DeployMethod::arg_data();
+                unsafe {
+                    ic0::msg_reply_data_append(data.as_ptr() as isize, data.len() as isize);
+                    ic0::msg_reply();
+                }
+            }
+
+            let arg = CreateCanisterArgument { settings: None };
+            DeployMethod::call(
+                Principal::management_canister(),
+                "create_canister",
+                candid::encode_one(arg).unwrap(),
+                1_000,
+                created,
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn management_canister() {
+        let factory = Principal::from_slice(&[90]);
+        let replica = Replica::default().with_module("counter", || {
+            Canister::new(Principal::anonymous()).with_method::<IncrementMethod>()
+        });
+        replica.add_canister(
+            Canister::new(factory)
+                .with_balance(10_000)
+                .with_method::<DeployMethod>(),
+        );
+
+        let reply = replica.new_call(factory, "deploy").perform().await;
+        assert_eq!(reply.bytes().unwrap(), &1u64.to_le_bytes()[..]);
+
+        let child = CHILD.lock().unwrap().unwrap();
+        assert_eq!(replica.canister_ids(), vec![factory, child]);
+        assert_eq!(replica.get_canister(child).balance().await, 1_000);
+
+        let management = Principal::management_canister();
+        let record = CanisterIdRecord { canister_id: child };
+
+        let status = replica
+            .new_call(management, "canister_status")
+            .with_caller(factory)
+            .with_arg(record.clone())
+            .perform()
+            .await
+            .decode_one::<CanisterStatusResponse>()
+            .unwrap();
+        assert_eq!(status.status, CanisterStatusType::Running);
+        assert_eq!(status.settings.controllers, vec![factory]);
+        assert_eq!(status.module_hash, Some(b"counter".to_vec()));
+        assert_eq!(status.cycles, Nat::from(1_000u64));
+
+        // Only the factory controls the child.
+        let reply = replica
+            .new_call(management, "stop_canister")
+            .with_arg(record.clone())
+            .perform()
+            .await;
+        assert_eq!(reply.rejection_code(), RejectionCode::CanisterReject);
+
+        // A running canister can not be deleted.
+        let delete = || {
+            replica
+                .new_call(management, "delete_canister")
+                .with_caller(factory)
+                .with_arg(record.clone())
+        };
+        assert_eq!(
+            delete().perform().await.rejection_code(),
+            RejectionCode::CanisterReject
+        );
+
+        replica
+            .new_call(management, "stop_canister")
+            .with_caller(factory)
+            .with_arg(record.clone())
+            .perform()
+            .await
+            .assert_ok();
+        delete().perform().await.assert_ok();
+
+        assert_eq!(replica.canister_ids(), vec![factory]);
+        let reply = replica.new_call(child, "increment").perform().await;
+        assert_eq!(reply.rejection_code(), RejectionCode::DestinationInvalid);
+    }
+
+    /// Trap in the `canister_init`.
+    struct TrapInitMethod;
+
+    impl CanisterMethod for TrapInitMethod {
+        const EXPORT_NAME: &'static str = "canister_init";
+
+        fn exported_method() {
+            panic!("Not ready.");
+        }
+    }
+
+    #[tokio::test]
+    async fn install_code_trap() {
+        let canister_id = Principal::from_slice(&[91]);
+        let replica = Replica::default()
+            .with_module("counter", || {
+                Canister::new(Principal::anonymous()).with_method::<IncrementMethod>()
+            })
+            .with_module("broken", || {
+                Canister::new(Principal::anonymous()).with_method::<TrapInitMethod>()
+            });
+        replica.add_canister(Canister::new(canister_id).with_controllers(vec![users::alice()]));
+
+        let install = |mode: InstallMode, module: &str| {
+            let arg = InstallCodeArgument {
+                mode,
+                canister_id,
+                wasm_module: module.as_bytes().to_vec(),
+                arg: vec![],
+            };
+
+            replica
+                .new_call(Principal::management_canister(), "install_code")
+                .with_caller(users::alice())
+                .with_arg(arg)
+        };
+
+        let module_hash = || async {
+            replica
+                .new_call(Principal::management_canister(), "canister_status")
+                .with_caller(users::alice())
+                .with_arg(CanisterIdRecord { canister_id })
+                .perform()
+                .await
+                .decode_one::<CanisterStatusResponse>()
+                .unwrap()
+                .module_hash
+        };
+
+        // A failed install leaves the canister empty, so it can still be installed.
+        let r = install(InstallMode::Install, "broken").perform().await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert!(r.rejection_message().unwrap().contains("Not ready."));
+        assert_eq!(module_hash().await, None);
+
+        install(InstallMode::Install, "counter")
+            .perform()
+            .await
+            .assert_ok();
+
+        // A failed reinstall keeps the previous instance and its module hash.
+        let r = install(InstallMode::Reinstall, "broken").perform().await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert_eq!(module_hash().await, Some(b"counter".to_vec()));
+        replica
+            .new_call(canister_id, "increment")
+            .perform()
+            .await
+            .assert_ok();
+    }
+
+    #[tokio::test]
+    async fn freezing_threshold() {
+        let replica = Replica::default().with_time(START);
+        let canister = replica.add_canister(
+            Canister::new(Principal::from_slice(&[100]))
+                .with_balance(1_000)
+                .with_freezing_threshold(500)
+                .with_call_cost(600, 0)
+                .with_method::<NotifyMethod>()
+                .with_method::<TimeMethod>(),
+        );
+
+        // The fee of the call drains the canister below the threshold.
+        let r = canister.new_call("notify").perform().await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert_eq!(canister.balance().await, 400);
+
+        let r = canister.new_call("notify").perform().await;
+        assert_eq!(r.rejection_code(), RejectionCode::SysTransient);
+        assert_eq!(
+            r.rejection_message().unwrap(),
+            format!(
+                "Canister {} is out of cycles: its balance 400 is below the freezing threshold 500",
+                canister.id()
+            )
+        );
+
+        // Queries still work on a frozen canister.
+        let r = replica
+            .query(canister.id(), Principal::anonymous(), "time", ())
+            .await;
+        assert_eq!(r.bytes().unwrap(), START.to_le_bytes());
+
+        replica.add_cycles(canister.id(), 1_000).await.unwrap();
+        let r = canister.new_call("notify").perform().await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert_eq!(canister.balance().await, 800);
+    }
+
+    /// Reply with the caller.
+    struct CallerMethod;
+
+    impl CanisterMethod for CallerMethod {
+        const EXPORT_NAME: &'static str = "canister_update caller";
+
+        fn exported_method() {
+            let mut bytes = [0u8; 29];
+            unsafe {
+                let len = ic0::msg_caller_size();
+                ic0::msg_caller_copy(bytes.as_mut_ptr() as isize, 0, len);
+                ic0::msg_reply_data_append(bytes.as_ptr() as isize, len);
+                ic0::msg_reply();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn default_caller() {
+        let canister_id = Principal::from_slice(&[110]);
+        let canister = || Canister::new(canister_id).with_method::<CallerMethod>();
+
+        let replica = Replica::default();
+        replica.add_canister(canister());
+        assert_eq!(replica.default_caller(), Principal::anonymous());
+        let r = replica.new_call(canister_id, "caller").perform().await;
+        assert_eq!(r.bytes().unwrap(), Principal::anonymous().as_slice());
+
+        let controller = users::alice();
+        let replica = Replica::default().with_default_caller(controller);
+        replica.add_canister(canister());
+        let r = replica.new_call(canister_id, "caller").perform().await;
+        assert_eq!(r.bytes().unwrap(), controller.as_slice());
+
+        // A call can still set its own caller.
+        let r = replica
+            .new_call(canister_id, "caller")
+            .with_caller(users::bob())
+            .perform()
+            .await;
+        assert_eq!(r.bytes().unwrap(), users::bob().as_slice());
+    }
+
+    #[tokio::test]
+    async fn controllers() {
+        let canister_id = Principal::from_slice(&[120]);
+        let replica = Replica::default();
+        replica.add_canister(Canister::new(canister_id).with_controllers(vec![users::alice()]));
+
+        let management = Principal::management_canister();
+        let record = CanisterIdRecord { canister_id };
+        let manage = |method: &'static str, caller: Principal| {
+            replica
+                .new_call(management, method)
+                .with_caller(caller)
+                .with_arg(record.clone())
+        };
+
+        let r = manage("stop_canister", users::bob()).perform().await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterReject);
+        assert_eq!(
+            r.rejection_message().unwrap(),
+            format!(
+                "Only the controllers of the canister {} can control it.",
+                canister_id
+            )
+        );
+
+        manage("stop_canister", users::alice())
+            .perform()
+            .await
+            .assert_ok();
+        let status = replica
+            .inspect_canister(canister_id, |canister| canister.status())
+            .await;
+        assert_eq!(status, CanisterStatus::Stopped);
+
+        // Bob takes over the canister.
+        replica.set_controllers(canister_id, vec![users::bob()]);
+        let r = manage("start_canister", users::alice()).perform().await;
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterReject);
+        manage("start_canister", users::bob())
+            .perform()
+            .await
+            .assert_ok();
+
+        let status = manage("canister_status", users::bob())
+            .perform()
+            .await
+            .decode_one::<CanisterStatusResponse>()
+            .unwrap();
+        assert_eq!(status.settings.controllers, vec![users::bob()]);
+    }
+
+    /// Reply to any argument, declares a single `nat` argument.
+    struct NatMethod;
+
+    impl CanisterMethod for NatMethod {
+        const EXPORT_NAME: &'static str = "canister_update nat";
+
+        fn exported_method() {
+            unsafe { ic0::msg_reply() };
+        }
+
+        fn arg_types() -> Option<Vec<candid::types::Type>> {
+            Some(vec![candid::types::Type::Nat])
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_typing() {
+        let caller = Principal::anonymous();
+
+        // The arguments are not checked by default.
+        let replica = Replica::default();
+        let canister = replica
+            .add_canister(Canister::new(Principal::from_slice(&[1])).with_method::<NatMethod>());
+        let r = replica
+            .update(canister.id(), caller, "nat", ("text",))
+            .await;
+        assert!(r.is_ok());
+
+        let replica = Replica::default().with_strict_typing(true);
+        let canister = replica
+            .add_canister(Canister::new(Principal::from_slice(&[1])).with_method::<NatMethod>());
+
+        let r = replica
+            .update(canister.id(), caller, "nat", (candid::Nat::from(1u64),))
+            .await;
+        assert!(r.is_ok());
+
+        let r = replica
+            .update(canister.id(), caller, "nat", ("text",))
+            .await;
+        assert!(matches!(r.rejection_code(), RejectionCode::CanisterReject));
+        assert!(r.rejection_message().unwrap().starts_with(
+            "The arguments do not match the Candid interface of 'canister_update nat'"
+        ));
+    }
+
+    #[tokio::test]
+    async fn max_message_size() {
+        let replica = Replica::default().with_max_message_size(100);
+        let canister = replica
+            .add_canister(Canister::new(Principal::from_slice(&[1])).with_method::<NatMethod>());
+
+        let r = replica
+            .new_call(canister.id(), "nat")
+            .with_arg_raw(vec![0; 100])
+            .perform()
+            .await;
+        assert!(r.is_ok());
+
+        let r = replica
+            .new_call(canister.id(), "nat")
+            .with_arg_raw(vec![0; 101])
+            .perform()
+            .await;
+        assert_eq!(r.rejection_code(), RejectionCode::SysFatal);
+        assert_eq!(
+            r.rejection_message(),
+            Some("The message of 101 bytes exceeds the limit of 100 bytes.")
+        );
+    }
+
+    /// Grow the stable memory by a page and reply with the new number of pages.
+    struct GrowMethod;
+
+    impl CanisterMethod for GrowMethod {
+        const EXPORT_NAME: &'static str = "canister_query grow";
+
+        fn exported_method() {
+            unsafe {
+                let pages = ic0::stable_grow(1) + 1;
+                ic0::msg_reply_data_append(pages.to_le_bytes().as_ptr() as isize, 4);
+                ic0::msg_reply();
+            }
+        }
+    }
+
+    /// Call the grow query of the canister `[2]`, and once it replies grow the stable memory of
+    /// this canister as well and reply with both numbers of pages.
+    struct CompositeGrowMethod;
+
+    impl CanisterMethod for CompositeGrowMethod {
+        const EXPORT_NAME: &'static str = "canister_composite_query composite_grow";
+
+        fn exported_method() {
+            fn replied(_env: isize) {
+                let mut data = [0u8; 8];
+                unsafe {
+                    ic0::msg_arg_data_copy(data.as_mut_ptr() as isize, 0, 4);
+                    let pages = ic0::stable_grow(1) + 1;
+                    data[4..].copy_from_slice(&pages.to_le_bytes());
+                    ic0::msg_reply_data_append(data.as_ptr() as isize, 8);
+                    ic0::msg_reply();
+                }
+            }
+
+            let callee = [2u8];
+            let name = b"grow";
+            unsafe {
+                ic0::call_new(
+                    callee.as_ptr() as isize,
+                    1,
+                    name.as_ptr() as isize,
+                    name.len() as isize,
+                    replied as fn(isize) as usize as isize,
+                    0,
+                    -1,
+                    -1,
+                );
+                ic0::call_perform();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn query_keeps_snapshot() {
+        let caller = Principal::anonymous();
+        let replica = Replica::default();
+        let canister = replica
+            .add_canister(Canister::new(Principal::from_slice(&[1])).with_method::<GrowMethod>());
+        let snapshot = replica
+            .inspect_canister(canister.id(), |canister| canister.snapshot())
+            .await;
+
+        for _ in 0..2 {
+            let r = replica.query(canister.id(), caller, "grow", ()).await;
+            assert_eq!(r.bytes().unwrap(), 1i32.to_le_bytes());
+        }
+
+        let after = replica
+            .inspect_canister(canister.id(), |canister| canister.snapshot())
+            .await;
+        assert_eq!(after, snapshot);
+
+        // The same query executed as an update keeps its changes.
+        replica
+            .update(canister.id(), caller, "grow", ())
+            .await
+            .assert_ok();
+        let after = replica
+            .inspect_canister(canister.id(), |canister| canister.snapshot())
+            .await;
+        assert_ne!(after, snapshot);
+    }
+
+    #[tokio::test]
+    async fn composite_query() {
+        let caller = Principal::anonymous();
+        let replica = Replica::default();
+        let canister = replica.add_canister(
+            Canister::new(Principal::from_slice(&[1])).with_method::<CompositeGrowMethod>(),
+        );
+        replica
+            .add_canister(Canister::new(Principal::from_slice(&[2])).with_method::<GrowMethod>());
+
+        // The callee runs the call as a query and the callback runs as part of the composite
+        // query, so the stable memory of both canisters is rolled back every time.
+        for _ in 0..2 {
+            let r = replica
+                .query(canister.id(), caller, "composite_grow", ())
+                .await;
+            assert_eq!(r.bytes().unwrap(), [1, 0, 0, 0, 1, 0, 0, 0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_canister_refund() {
+        let replica = Replica::default();
+
+        // The payment is the cycles available to the callee, since the call never reaches a
+        // canister none of them are accepted and they are all refunded.
+        let r = replica
+            .new_call(Principal::from_slice(&[1]), "increment")
+            .with_payment(1_000)
+            .perform()
+            .await;
+        assert!(matches!(
+            r.rejection_code(),
+            RejectionCode::DestinationInvalid
+        ));
+        assert_eq!(r.cycles_refunded(), 1_000);
     }
 }
@@ -0,0 +1,130 @@
+/// The first four words of the ChaCha state, `"expand 32-byte k"` in little endian.
+const CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+/// The ChaCha20 keystream as a random number generator, the key is derived from a seed and the
+/// nonce is always zero, so the generated bytes only depend on the seed.
+///
+/// This is only meant to make the tests reproducible, the generator is not used for anything
+/// where security matters.
+#[derive(Clone)]
+pub struct ChaChaRng {
+    /// The key of the stream.
+    key: [u32; 8],
+    /// The counter of the next block.
+    counter: u64,
+    /// The current block.
+    block: [u8; 64],
+    /// The number of bytes of the current block that are already used.
+    used: usize,
+}
+
+impl ChaChaRng {
+    /// Create a new generator, the key is the seed in little endian followed by zeros.
+    pub fn new(seed: u64) -> Self {
+        let mut key = [0; 8];
+        key[0] = seed as u32;
+        key[1] = (seed >> 32) as u32;
+
+        Self {
+            key,
+            counter: 0,
+            block: [0; 64],
+            used: 64,
+        }
+    }
+
+    /// Fill the given buffer with the next bytes of the stream.
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for byte in dest {
+            if self.used == self.block.len() {
+                self.block = self.next_block();
+                self.used = 0;
+            }
+
+            *byte = self.block[self.used];
+            self.used += 1;
+        }
+    }
+
+    /// Compute the block of the current counter and move the counter forward.
+    fn next_block(&mut self) -> [u8; 64] {
+        let mut state = [0u32; 16];
+        state[..4].copy_from_slice(&CONSTANTS);
+        state[4..12].copy_from_slice(&self.key);
+        state[12] = self.counter as u32;
+        state[13] = (self.counter >> 32) as u32;
+        self.counter = self.counter.wrapping_add(1);
+
+        let mut working = state;
+        for _ in 0..10 {
+            quarter_round(&mut working, 0, 4, 8, 12);
+            quarter_round(&mut working, 1, 5, 9, 13);
+            quarter_round(&mut working, 2, 6, 10, 14);
+            quarter_round(&mut working, 3, 7, 11, 15);
+            quarter_round(&mut working, 0, 5, 10, 15);
+            quarter_round(&mut working, 1, 6, 11, 12);
+            quarter_round(&mut working, 2, 7, 8, 13);
+            quarter_round(&mut working, 3, 4, 9, 14);
+        }
+
+        let mut block = [0u8; 64];
+        for (i, chunk) in block.chunks_exact_mut(4).enumerate() {
+            chunk.copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+        }
+
+        block
+    }
+}
+
+impl Default for ChaChaRng {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode the given hex string.
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn keystream() {
+        // The test vectors #1 and #2 of the ChaCha20 block function in RFC 8439, with an all
+        // zero key and nonce, and the block counter 0 and 1.
+        let mut expected = hex(
+            "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+             da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586",
+        );
+        expected.extend(hex(
+            "9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed\
+             29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f",
+        ));
+
+        // The stream is the same regardless of how it is split.
+        let mut rng = ChaChaRng::new(0);
+        let mut bytes = vec![0u8; 128];
+        for chunk in bytes.chunks_mut(24) {
+            rng.fill_bytes(chunk);
+        }
+
+        assert_eq!(bytes, expected);
+    }
+}
@@ -1,4 +1,8 @@
 use memmap::MmapMut;
+use std::collections::HashMap;
+
+/// The size of a WebAssembly page.
+const PAGE_SIZE: u64 = 1 << 16;
 
 /// A dynamic backend that can be used to handle stable storage. An implementation can decide
 /// where to store the data as long as it provides the given functionalities.
@@ -30,17 +34,17 @@ impl StableMemoryBackend for FileSystemStableMemory {
 }
 
 /// An stable storage backend that stores everything in the heap. By default it has a 128MB limit.
+///
+/// The pages are only allocated once they are written to, so growing the memory is cheap.
 pub struct HeapStableMemory {
-    pages: Vec<[u8; 1 << 16]>,
+    pages: HashMap<u64, Box<[u8]>>,
+    size: u64,
     max_pages: u64,
 }
 
 impl Default for HeapStableMemory {
     fn default() -> Self {
-        Self {
-            pages: Vec::new(),
-            max_pages: 128 << 20 >> 16,
-        }
+        Self::new(128 << 20 >> 16)
     }
 }
 
@@ -48,7 +52,8 @@ impl HeapStableMemory {
     /// Create a stable storage backend with the provided max page.
     pub fn new(max_pages: u64) -> Self {
         Self {
-            pages: Vec::new(),
+            pages: HashMap::new(),
+            size: 0,
             max_pages,
         }
     }
@@ -56,38 +61,186 @@ impl HeapStableMemory {
 
 impl StableMemoryBackend for HeapStableMemory {
     fn stable_size(&mut self) -> u64 {
-        self.pages.len() as u64
+        self.size
     }
 
     fn stable_grow(&mut self, new_pages: u64) -> i64 {
-        let size = self.pages.len() as u64;
-        if new_pages + size > self.max_pages {
-            -1
-        } else {
-            for _ in 0..new_pages {
-                self.pages.push([0; 1 << 16]);
+        let size = self.size;
+        match size.checked_add(new_pages) {
+            Some(new_size) if new_size <= self.max_pages => {
+                self.size = new_size;
+                size as i64
             }
-            size as i64
+            _ => -1,
         }
     }
 
     fn stable_read(&mut self, offset: u64, buf: &mut [u8]) {
-        // TODO(qti3e) This can be optimized.
-        for i in 0..buf.len() {
-            let offset = offset + i as u64;
-            let page = offset >> 16;
-            let byte = offset - (page << 16);
-            buf[i] = self.pages[page as usize][byte as usize];
+        let mut read = 0;
+        while read < buf.len() {
+            let offset = offset + read as u64;
+            let (page, byte) = (offset / PAGE_SIZE, (offset % PAGE_SIZE) as usize);
+            let len = (PAGE_SIZE as usize - byte).min(buf.len() - read);
+            let dst = &mut buf[read..read + len];
+
+            match self.pages.get(&page) {
+                Some(data) => dst.copy_from_slice(&data[byte..byte + len]),
+                None => dst.fill(0),
+            }
+
+            read += len;
         }
     }
 
     fn stable_write(&mut self, offset: u64, buf: &[u8]) {
-        // TODO(qti3e) This can be optimized.
-        for i in 0..buf.len() {
-            let offset = offset + i as u64;
-            let page = offset >> 16;
-            let byte = offset - (page << 16);
-            self.pages[page as usize][byte as usize] = buf[i];
+        let mut written = 0;
+        while written < buf.len() {
+            let offset = offset + written as u64;
+            let (page, byte) = (offset / PAGE_SIZE, (offset % PAGE_SIZE) as usize);
+            let len = (PAGE_SIZE as usize - byte).min(buf.len() - written);
+
+            let data = self
+                .pages
+                .entry(page)
+                .or_insert_with(|| vec![0; PAGE_SIZE as usize].into_boxed_slice());
+            data[byte..byte + len].copy_from_slice(&buf[written..written + len]);
+
+            written += len;
+        }
+    }
+}
+
+/// A wrapper around a stable storage backend that keeps the changes made during a message in
+/// memory, so they can be discarded if the message traps.
+///
+/// The backend is grown eagerly so running out of memory is reported right away, the pages that
+/// are grown by a trapping message are hidden from the canister and reused by the next grow.
+pub(crate) struct TransactionalStableMemory {
+    backend: Box<dyn StableMemoryBackend + Send>,
+    /// The size of the stable memory at the beginning of the current message.
+    committed: u64,
+    size: u64,
+    /// The pages written to during the current message.
+    dirty: HashMap<u64, Box<[u8]>>,
+}
+
+impl TransactionalStableMemory {
+    pub fn new(mut backend: Box<dyn StableMemoryBackend + Send>) -> Self {
+        let size = backend.stable_size();
+
+        Self {
+            backend,
+            committed: size,
+            size,
+            dirty: HashMap::new(),
+        }
+    }
+
+    /// Write the changes made during the current message to the backend.
+    pub fn commit(&mut self) {
+        for (page, data) in std::mem::take(&mut self.dirty) {
+            self.backend.stable_write(page * PAGE_SIZE, &data);
+        }
+
+        self.committed = self.size;
+    }
+
+    /// Discard the changes made during the current message.
+    pub fn rollback(&mut self) {
+        self.dirty.clear();
+        self.size = self.committed;
+    }
+
+    /// Return the entire content of the stable memory.
+    pub fn read_all(&mut self) -> Vec<u8> {
+        let mut data = vec![0; (self.size * PAGE_SIZE) as usize];
+        self.stable_read(0, &mut data);
+        data
+    }
+
+    /// Replace the content of the stable memory with the given data, the size of the data must be
+    /// a multiple of the page size. The changes made during the current message are discarded.
+    pub fn restore(&mut self, data: &[u8]) {
+        assert_eq!(data.len() as u64 % PAGE_SIZE, 0);
+        let size = data.len() as u64 / PAGE_SIZE;
+
+        self.dirty.clear();
+        let backend_size = self.backend.stable_size();
+        if size > backend_size && self.backend.stable_grow(size - backend_size) == -1 {
+            panic!(
+                "ic-kit-runtime: Could not grow the stable memory to {} pages.",
+                size
+            );
+        }
+
+        self.backend.stable_write(0, data);
+
+        // The pages past the restored size are hidden, but they are handed out again by the next
+        // grow, so they must be zeroed.
+        let zero = vec![0; PAGE_SIZE as usize];
+        for page in size..backend_size {
+            self.backend.stable_write(page * PAGE_SIZE, &zero);
+        }
+
+        self.committed = size;
+        self.size = size;
+    }
+}
+
+impl StableMemoryBackend for TransactionalStableMemory {
+    fn stable_size(&mut self) -> u64 {
+        self.size
+    }
+
+    fn stable_grow(&mut self, new_pages: u64) -> i64 {
+        let size = self.size;
+        let new_size = match size.checked_add(new_pages) {
+            Some(new_size) => new_size,
+            None => return -1,
+        };
+
+        let backend_size = self.backend.stable_size();
+        if new_size > backend_size && self.backend.stable_grow(new_size - backend_size) == -1 {
+            return -1;
+        }
+
+        self.size = new_size;
+        size as i64
+    }
+
+    fn stable_read(&mut self, offset: u64, buf: &mut [u8]) {
+        let mut read = 0;
+        while read < buf.len() {
+            let offset = offset + read as u64;
+            let (page, byte) = (offset / PAGE_SIZE, (offset % PAGE_SIZE) as usize);
+            let len = (PAGE_SIZE as usize - byte).min(buf.len() - read);
+            let dst = &mut buf[read..read + len];
+
+            match self.dirty.get(&page) {
+                Some(data) => dst.copy_from_slice(&data[byte..byte + len]),
+                None => self.backend.stable_read(offset, dst),
+            }
+
+            read += len;
+        }
+    }
+
+    fn stable_write(&mut self, offset: u64, buf: &[u8]) {
+        let mut written = 0;
+        while written < buf.len() {
+            let offset = offset + written as u64;
+            let (page, byte) = (offset / PAGE_SIZE, (offset % PAGE_SIZE) as usize);
+            let len = (PAGE_SIZE as usize - byte).min(buf.len() - written);
+
+            let backend = &mut self.backend;
+            let data = self.dirty.entry(page).or_insert_with(|| {
+                let mut data = vec![0; PAGE_SIZE as usize].into_boxed_slice();
+                backend.stable_read(page * PAGE_SIZE, &mut data);
+                data
+            });
+            data[byte..byte + len].copy_from_slice(&buf[written..written + len]);
+
+            written += len;
         }
     }
 }
@@ -1,9 +1,13 @@
+use crate::call::CallReply;
 use candid::utils::ArgumentEncoder;
 use candid::{encode_args, encode_one, CandidType};
+use ic_kit_sys::ic0::runtime::Request;
 use ic_kit_sys::types::{RejectionCode, CANDID_EMPTY_ARG};
 use ic_types::Principal;
+use std::fmt;
 use std::panic::{RefUnwindSafe, UnwindSafe};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
@@ -31,19 +35,87 @@ pub enum EntryMode {
     PreUpgrade,
     PostUpgrade,
     Heartbeat,
+    GlobalTimer,
+    OnLowWasmMemory,
     InspectMessage,
     Update,
     Query,
+    /// A query that can call the query methods of other canisters, the callbacks of its calls
+    /// are executed in the [`EntryMode::ReplyCallback`] and [`EntryMode::RejectCallback`] modes.
+    CompositeQuery,
     ReplyCallback,
     RejectCallback,
     CleanupCallback,
     CustomTask,
 }
 
+/// The status of a canister, only a running canister accepts new calls.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum CanisterStatus {
+    Running,
+    Stopping,
+    Stopped,
+}
+
+/// An amount of cycles. The arithmetic on the cycles is checked, so a violated accounting
+/// invariant is reported as an error instead of silently wrapping around.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
+pub struct Cycles(pub u128);
+
+impl Cycles {
+    /// Return the amount of cycles as an integer.
+    pub fn get(self) -> u128 {
+        self.0
+    }
+
+    /// Add the given amount of cycles, return an error if the result does not fit in `u128`.
+    pub fn checked_add<C: Into<Cycles>>(self, other: C) -> Result<Cycles, String> {
+        let other = other.into();
+        self.0
+            .checked_add(other.0)
+            .map(Cycles)
+            .ok_or_else(|| format!("cycles overflow: {} + {}", self, other))
+    }
+
+    /// Add the given amount of cycles, capped at `u128::MAX`. This is only used to return the
+    /// cycles that were taken out of a balance before, which can not overflow in practice.
+    pub fn saturating_add<C: Into<Cycles>>(self, other: C) -> Cycles {
+        Cycles(self.0.saturating_add(other.into().0))
+    }
+
+    /// Subtract the given amount of cycles, return an error if there are not enough cycles.
+    pub fn checked_sub<C: Into<Cycles>>(self, other: C) -> Result<Cycles, String> {
+        let other = other.into();
+        self.0
+            .checked_sub(other.0)
+            .map(Cycles)
+            .ok_or_else(|| format!("cycles underflow: {} - {}", self, other))
+    }
+}
+
+impl From<u128> for Cycles {
+    fn from(cycles: u128) -> Self {
+        Cycles(cycles)
+    }
+}
+
+impl From<Cycles> for u128 {
+    fn from(cycles: Cycles) -> Self {
+        cycles.0
+    }
+}
+
+impl fmt::Display for Cycles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 /// The canister's environment that should be used during a message.
+#[derive(Clone)]
 pub struct Env {
-    /// Determines the canister' balance.
-    pub balance: u128,
+    /// When set, overrides the canister's cycle balance before the message is executed.
+    pub balance: Option<u128>,
     /// The type of the entry point that should be simulated, this enables trapping when a the
     /// method is calling a system api call that it should not be able to call during the
     /// execution of that entry point.
@@ -53,9 +125,9 @@ pub struct Env {
     /// The method to call. Only applies to update/query calls.
     pub method_name: Option<String>,
     /// The cycles provided to the canister during this call.
-    pub cycles_available: u128,
+    pub cycles_available: Cycles,
     /// The amount of refunded cycles.
-    pub cycles_refunded: u128,
+    pub cycles_refunded: Cycles,
     /// The arguments provided to the canister during this call.
     pub args: Vec<u8>,
     /// The reply rejection code. Default to `0`
@@ -64,10 +136,62 @@ pub struct Env {
     pub rejection_message: String,
     /// The current time in nanoseconds.
     pub time: u64,
+    /// The deadline of a best-effort message in nanoseconds, zero for guaranteed response
+    /// messages.
+    pub deadline: u64,
+    /// Whether this is an ingress message sent by a user rather than a canister. Ingress update
+    /// calls must be accepted by the canister's inspect_message before they are executed.
+    pub ingress: bool,
+    /// Whether the message is executed in replicated mode, the queries sent by users are
+    /// executed in non-replicated mode by a single replica.
+    pub replicated: bool,
 }
 
 pub type TaskFn = Box<dyn FnOnce() + Send + RefUnwindSafe + UnwindSafe>;
 
+/// The number of instructions that is charged for a system call, since the actual instructions
+/// executed by the canister can not be measured when it is not running as WebAssembly, this is
+/// used to compute the performance counters.
+pub type InstructionModel = Arc<dyn Fn(&Request) -> u64 + Send + Sync>;
+
+/// The cycles charged to the caller for each inter-canister call, a base fee plus a fee for each
+/// byte of the method name and the argument of the call. The default is free calls.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct CallCost {
+    /// The fee charged for every call.
+    pub base: u128,
+    /// The fee charged for each byte of the method name and the argument.
+    pub per_byte: u128,
+}
+
+impl CallCost {
+    /// Return the fee of a call to the given method with the given argument, the fee saturates
+    /// at `u128::MAX` which no canister can afford.
+    pub fn fee(&self, method: &str, arg: &[u8]) -> u128 {
+        let bytes = (method.len() + arg.len()) as u128;
+
+        self.per_byte
+            .checked_mul(bytes)
+            .map_or(u128::MAX, |fee| fee.saturating_add(self.base))
+    }
+}
+
+/// A checkpoint of the state of a canister that can be restored later, see
+/// [`Canister::snapshot`].
+///
+/// [`Canister::snapshot`]: crate::Canister::snapshot
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CanisterSnapshot {
+    /// The cycle balance of the canister.
+    pub balance: u128,
+    /// The time at which the global timer of the canister fires, zero if the timer is not set.
+    pub global_timer: u64,
+    /// The data certified by the canister.
+    pub certified_data: Vec<u8>,
+    /// The entire content of the stable memory.
+    pub stable_memory: Vec<u8>,
+}
+
 /// A message sent to a canister that trigger execution of a task on the canister's execution thread
 /// based on the type of the message.
 pub enum Message {
@@ -98,8 +222,116 @@ pub enum Message {
     },
 }
 
+/// A message recorded in the trace of a replica, see [`Replica::with_tracing`].
+///
+/// [`Replica::with_tracing`]: crate::Replica::with_tracing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// A call to a method of a canister, made either by a user or by another canister.
+    Request {
+        sender: Principal,
+        receiver: Principal,
+        method: String,
+        /// The cycles sent with the call.
+        cycles: u128,
+    },
+    /// A reply from a canister to one of its callers.
+    Reply {
+        sender: Principal,
+        receiver: Principal,
+        method: String,
+        cycles_refunded: u128,
+    },
+    /// A rejection of a call, sent on behalf of the called canister.
+    Reject {
+        sender: Principal,
+        receiver: Principal,
+        method: String,
+        rejection_code: RejectionCode,
+        rejection_message: String,
+        cycles_refunded: u128,
+    },
+}
+
+impl TraceEvent {
+    /// Create the event of the given response of the canister to the caller.
+    pub(crate) fn response(
+        sender: Principal,
+        receiver: Principal,
+        method: String,
+        reply: &CallReply,
+    ) -> Self {
+        match reply {
+            CallReply::Reply {
+                cycles_refunded, ..
+            } => TraceEvent::Reply {
+                sender,
+                receiver,
+                method,
+                cycles_refunded: *cycles_refunded,
+            },
+            CallReply::Reject {
+                rejection_code,
+                rejection_message,
+                cycles_refunded,
+            } => TraceEvent::Reject {
+                sender,
+                receiver,
+                method,
+                rejection_code: *rejection_code,
+                rejection_message: rejection_message.clone(),
+                cycles_refunded: *cycles_refunded,
+            },
+        }
+    }
+}
+
+/// A summary of what happened during the execution of a single message on a canister.
+#[derive(Debug, Default)]
+pub struct ExecutionReport {
+    /// The response to the message if it was replied to or rejected during this execution.
+    pub reply: Option<CallReply>,
+    /// Whether the execution trapped.
+    pub trapped: bool,
+    /// The trap message, only present if the execution trapped.
+    pub trap_message: Option<String>,
+    /// The canister's balance when the execution started.
+    pub balance_before: u128,
+    /// The canister's balance after the execution.
+    pub balance_after: u128,
+    /// The amount of cycles accepted by the canister during this execution.
+    pub cycles_accepted: u128,
+    /// The amount of cycles accepted by the call context of the message so far, including the
+    /// cycles accepted by the previous executions of its callbacks.
+    pub call_context_cycles_accepted: u128,
+    /// The amount of cycles refunded to the canister, only applies to reply/reject callbacks.
+    pub cycles_refunded: u128,
+    /// The instructions executed during this execution, as counted by the instruction model of
+    /// the canister.
+    pub instructions: u64,
+    /// The messages printed by the canister using `debug_print` during this execution.
+    pub debug_log: Vec<String>,
+    /// The inter-canister calls made during this execution.
+    pub outgoing_calls: Vec<CanisterCall>,
+}
+
+/// A point where a canister waits for the responses of the inter-canister calls it made, the
+/// other messages to the canister can be executed before the responses arrive, see
+/// [`Replica::with_interleave_hook`].
+///
+/// [`Replica::with_interleave_hook`]: crate::Replica::with_interleave_hook
+#[derive(Debug, Clone)]
+pub struct AwaitPoint {
+    /// The canister that is waiting.
+    pub canister_id: Principal,
+    /// The entry point that made the calls, such as `canister_update transfer`.
+    pub entry_point: String,
+    /// The calls made by the execution.
+    pub calls: Vec<CanisterCall>,
+}
+
 /// A call that has made to another canister.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CanisterCall {
     pub sender: Principal,
     pub request_id: RequestId,
@@ -107,6 +339,12 @@ pub struct CanisterCall {
     pub method: String,
     pub payment: u128,
     pub arg: Vec<u8>,
+    /// The deadline of the call in nanoseconds, `None` for guaranteed response calls.
+    pub deadline: Option<u64>,
+    /// Whether this is a one-way call, the caller does not wait for its response.
+    pub one_way: bool,
+    /// Whether the call is made by a composite query, it is executed as a query by the callee.
+    pub query: bool,
 }
 
 impl From<CanisterCall> for Message {
@@ -114,28 +352,96 @@ impl From<CanisterCall> for Message {
         Message::Request {
             request_id: call.request_id,
             env: Env::default()
-                .with_entry_mode(EntryMode::Update)
+                .with_entry_mode(if call.query {
+                    EntryMode::Query
+                } else {
+                    EntryMode::Update
+                })
                 .with_sender(call.sender)
                 .with_method_name(call.method)
                 .with_cycles_available(call.payment)
-                .with_raw_args(call.arg),
+                .with_raw_args(call.arg)
+                .with_deadline(call.deadline.unwrap_or(0)),
+        }
+    }
+}
+
+impl Message {
+    /// Create a new request to the given update method.
+    pub fn update<S: Into<String>>(method_name: S) -> Self {
+        Message::Request {
+            request_id: RequestId::new(),
+            env: Env::update(method_name),
         }
     }
+
+    /// Create a new non-replicated request to the given query method.
+    pub fn query<S: Into<String>>(method_name: S) -> Self {
+        Message::Request {
+            request_id: RequestId::new(),
+            env: Env::query(method_name),
+        }
+    }
+
+    /// Create a new ingress request to the given update method, ingress messages must be
+    /// accepted by the canister's inspect_message before they are executed.
+    pub fn ingress<S: Into<String>>(method_name: S) -> Self {
+        Message::Request {
+            request_id: RequestId::new(),
+            env: Env::update(method_name).with_ingress(true),
+        }
+    }
+
+    /// Return a mutable reference to the env of this message.
+    fn env_mut(&mut self) -> &mut Env {
+        match self {
+            Message::CustomTask { env, .. } => env,
+            Message::Request { env, .. } => env,
+            Message::Reply { env, .. } => env,
+        }
+    }
+
+    /// Use the given argument tuple as the candid encoded argument of this message.
+    pub fn with_args<T: ArgumentEncoder>(mut self, arguments: T) -> Self {
+        self.env_mut().args = encode_args(arguments).unwrap();
+        self
+    }
+
+    /// Use the given value as the only candid encoded argument of this message.
+    pub fn with_arg<T: CandidType>(mut self, argument: T) -> Self {
+        self.env_mut().args = encode_one(argument).unwrap();
+        self
+    }
+
+    /// Send this message from the given caller.
+    pub fn with_caller<I: Into<Principal>>(mut self, caller: I) -> Self {
+        self.env_mut().sender = caller.into();
+        self
+    }
+
+    /// Send the given amount of cycles with this message.
+    pub fn with_cycles(mut self, cycles: u128) -> Self {
+        self.env_mut().cycles_available = cycles.into();
+        self
+    }
 }
 
 impl Default for Env {
     fn default() -> Self {
         Env {
-            balance: 100_000_000_000_000,
+            balance: None,
             entry_mode: EntryMode::CustomTask,
             sender: Principal::anonymous(),
             method_name: None,
-            cycles_available: 0,
-            cycles_refunded: 0,
+            cycles_available: Cycles::default(),
+            cycles_refunded: Cycles::default(),
             args: CANDID_EMPTY_ARG.to_vec(),
             rejection_code: RejectionCode::NoError,
             rejection_message: String::new(),
             time: now(),
+            deadline: 0,
+            ingress: false,
+            replicated: true,
         }
     }
 }
@@ -148,11 +454,20 @@ impl Env {
             .with_method_name(method_name)
     }
 
-    /// Create a new env for a query call.
+    /// Create a new env for a non-replicated query call.
     pub fn query<S: Into<String>>(method_name: S) -> Self {
         Self::default()
             .with_entry_mode(EntryMode::Query)
             .with_method_name(method_name)
+            .with_replicated(false)
+    }
+
+    /// Create a new env for a call to the inspect_message function, for an ingress message to
+    /// the given method.
+    pub fn inspect_message<S: Into<String>>(method_name: S) -> Self {
+        Self::default()
+            .with_entry_mode(EntryMode::InspectMessage)
+            .with_method_name(method_name)
     }
 
     /// Create a new env for a call to the init function.
@@ -175,9 +490,19 @@ impl Env {
         Self::default().with_entry_mode(EntryMode::Heartbeat)
     }
 
-    /// Determines the canister's cycle balance for this call.
+    /// Create a new env for a call to the global timer function.
+    pub fn global_timer() -> Self {
+        Self::default().with_entry_mode(EntryMode::GlobalTimer)
+    }
+
+    /// Create a new env for a call to the on_low_wasm_memory function.
+    pub fn on_low_wasm_memory() -> Self {
+        Self::default().with_entry_mode(EntryMode::OnLowWasmMemory)
+    }
+
+    /// Set the canister's cycle balance to the given amount before executing this call.
     pub fn with_balance(mut self, balance: u128) -> Self {
-        self.balance = balance;
+        self.balance = Some(balance);
         self
     }
 
@@ -187,6 +512,12 @@ impl Env {
         self
     }
 
+    /// Use the provided deadline for this env, making it a best-effort message.
+    pub fn with_deadline(mut self, deadline: u64) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
     /// Use the given entry mode in this env.
     pub fn with_entry_mode(mut self, mode: EntryMode) -> Self {
         self.entry_mode = mode;
@@ -207,14 +538,14 @@ impl Env {
 
     /// Provide the current env with the given amount of cycles to execute.
     pub fn with_cycles_available(mut self, cycles: u128) -> Self {
-        self.cycles_available = cycles;
+        self.cycles_available = cycles.into();
         self
     }
 
     /// Provide the current env with the given amount of refunded cycles, only applicable
     /// if this is reply/reject callback.
     pub fn with_cycles_refunded(mut self, cycles: u128) -> Self {
-        self.cycles_refunded = cycles;
+        self.cycles_refunded = cycles.into();
         self
     }
 
@@ -237,6 +568,18 @@ impl Env {
         self
     }
 
+    /// Mark this env as an ingress message sent by a user.
+    pub fn with_ingress(mut self, ingress: bool) -> Self {
+        self.ingress = ingress;
+        self
+    }
+
+    /// Set whether the message is executed in replicated mode.
+    pub fn with_replicated(mut self, replicated: bool) -> Self {
+        self.replicated = replicated;
+        self
+    }
+
     /// Set this environment's rejection code the provided value, you must also set a rejection
     /// message if this is not equal to NoError.
     pub fn with_rejection_code(mut self, rejection_code: RejectionCode) -> Self {
@@ -249,9 +592,29 @@ impl Env {
         self.rejection_message = rejection_message.into();
         self
     }
+
+    /// Turn this env into the env of a reject callback with the given rejection code and message.
+    pub fn with_rejection<S: Into<String>>(
+        self,
+        rejection_code: RejectionCode,
+        rejection_message: S,
+    ) -> Self {
+        self.with_entry_mode(EntryMode::RejectCallback)
+            .with_rejection_code(rejection_code)
+            .with_rejection_message(rejection_message)
+    }
 }
 
 impl Env {
+    /// Return true if this env is for a query or a composite query call, the changes made by a
+    /// query are discarded once it is executed.
+    pub fn is_query_context(&self) -> bool {
+        matches!(
+            self.entry_mode,
+            EntryMode::Query | EntryMode::CompositeQuery
+        )
+    }
+
     /// Return a name we can use to get the method from the symbol table.
     pub fn get_entry_point_name(&self) -> String {
         match &self.entry_mode {
@@ -259,6 +622,8 @@ impl Env {
             EntryMode::PreUpgrade => "canister_pre_upgrade".to_string(),
             EntryMode::PostUpgrade => "canister_post_upgrade".to_string(),
             EntryMode::Heartbeat => "canister_heartbeat".to_string(),
+            EntryMode::GlobalTimer => "canister_global_timer".to_string(),
+            EntryMode::OnLowWasmMemory => "canister_on_low_wasm_memory".to_string(),
             EntryMode::InspectMessage => "canister_inspect_message".to_string(),
             EntryMode::Update => {
                 format!(
@@ -270,6 +635,10 @@ impl Env {
                 "canister_query {}",
                 self.method_name.as_ref().unwrap_or(&String::new())
             ),
+            EntryMode::CompositeQuery => format!(
+                "canister_composite_query {}",
+                self.method_name.as_ref().unwrap_or(&String::new())
+            ),
             EntryMode::ReplyCallback => "reply callback".to_string(),
             EntryMode::RejectCallback => "reject callback".to_string(),
             EntryMode::CleanupCallback => "cleanup callback".to_string(),
@@ -295,10 +664,48 @@ impl Env {
     }
 }
 
-fn now() -> u64 {
+pub(crate) fn now() -> u64 {
     let now = SystemTime::now();
     let unix = now
         .duration_since(UNIX_EPOCH)
         .expect("ic-kit-runtime: could not retrieve unix time.");
     unix.as_nanos() as u64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_cycles() {
+        let max = Cycles(u128::MAX);
+
+        assert_eq!(max.checked_sub(1u128), Ok(Cycles(u128::MAX - 1)));
+        assert_eq!(Cycles(u128::MAX - 1).checked_add(1u128), Ok(max));
+        assert_eq!(
+            max.checked_add(1u128),
+            Err(format!("cycles overflow: {} + 1", u128::MAX))
+        );
+        assert_eq!(
+            Cycles(0).checked_sub(1u128),
+            Err("cycles underflow: 0 - 1".to_string())
+        );
+        assert_eq!(max.checked_sub(max), Ok(Cycles(0)));
+    }
+
+    #[test]
+    fn call_cost_fee() {
+        let cost = CallCost {
+            base: 10,
+            per_byte: 2,
+        };
+        assert_eq!(cost.fee("get", &[0; 5]), 26);
+
+        let cost = CallCost {
+            base: 1,
+            per_byte: u128::MAX,
+        };
+        assert_eq!(cost.fee("", &[]), 1);
+        assert_eq!(cost.fee("get", &[]), u128::MAX);
+    }
+}
@@ -2,6 +2,7 @@
 
 use ic_types::Principal;
 use lazy_static::lazy_static;
+use std::collections::HashSet;
 
 lazy_static! {
     pub static ref ALICE: Principal = Principal::self_authenticating("ALICE");
@@ -10,3 +11,160 @@ lazy_static! {
     pub static ref PARSA: Principal = Principal::self_authenticating("PARSA");
     pub static ref OZ: Principal = Principal::self_authenticating("OZ");
 }
+
+/// The principal of Alice, same as [`struct@ALICE`].
+pub fn alice() -> Principal {
+    *ALICE
+}
+
+/// The principal of Bob, same as [`struct@BOB`].
+pub fn bob() -> Principal {
+    *BOB
+}
+
+/// The anonymous principal, this is the caller of the calls that are not signed.
+pub fn anonymous() -> Principal {
+    Principal::anonymous()
+}
+
+/// Generate a self-authenticating principal from the given seed, the same seed always results
+/// in the same principal and different seeds result in different principals, this is useful to
+/// create any number of users in the tests.
+pub fn generate(seed: u64) -> Principal {
+    Principal::self_authenticating(seed.to_be_bytes())
+}
+
+/// A set of principals, such as the admins of a canister, that can be used to authorize the
+/// callers in the tests.
+///
+/// # Example
+///
+/// ```
+/// use ic_kit_runtime::users::{self, Group};
+///
+/// let admins = Group::new().with_user(users::alice());
+/// assert!(admins.contains(&users::alice()));
+/// assert!(!admins.contains(&users::bob()));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Group {
+    users: HashSet<Principal>,
+}
+
+impl Group {
+    /// Create an empty group.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the given principal to the group.
+    pub fn with_user(mut self, user: Principal) -> Self {
+        self.add(user);
+        self
+    }
+
+    /// Add the given principal to the group, returns false if it was already a member.
+    pub fn add(&mut self, user: Principal) -> bool {
+        self.users.insert(user)
+    }
+
+    /// Remove the given principal from the group, returns false if it was not a member.
+    pub fn remove(&mut self, user: &Principal) -> bool {
+        self.users.remove(user)
+    }
+
+    /// Return true if the given principal is a member of the group.
+    pub fn contains(&self, user: &Principal) -> bool {
+        self.users.contains(user)
+    }
+
+    /// Return an iterator over the members of the group in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Principal> {
+        self.users.iter()
+    }
+}
+
+impl FromIterator<Principal> for Group {
+    fn from_iter<I: IntoIterator<Item = Principal>>(iter: I) -> Self {
+        Self {
+            users: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Canister, CanisterMethod, Replica};
+    use ic_kit_sys::ic0;
+
+    lazy_static! {
+        static ref ADMINS: Group = Group::new().with_user(alice());
+    }
+
+    /// Only allow the admins to call this method.
+    struct AdminMethod;
+
+    impl CanisterMethod for AdminMethod {
+        const EXPORT_NAME: &'static str = "canister_update admin";
+
+        fn exported_method() {
+            let mut caller = [0u8; 29];
+            let caller = unsafe {
+                let len = ic0::msg_caller_size();
+                ic0::msg_caller_copy(caller.as_mut_ptr() as isize, 0, len);
+                Principal::from_slice(&caller[..len as usize])
+            };
+
+            if !ADMINS.contains(&caller) {
+                let message = "Only the admins can call this method.";
+                unsafe { ic0::msg_reject(message.as_ptr() as isize, message.len() as isize) };
+                return;
+            }
+
+            unsafe { ic0::msg_reply() };
+        }
+    }
+
+    #[tokio::test]
+    async fn group_guard() {
+        let replica = Replica::default();
+        let canister = replica.add_canister(
+            Canister::new(Principal::management_canister()).with_method::<AdminMethod>(),
+        );
+
+        let r = replica.update(canister.id(), alice(), "admin", ()).await;
+        assert!(r.is_ok());
+
+        let r = replica.update(canister.id(), bob(), "admin", ()).await;
+        assert_eq!(
+            r.rejection_message(),
+            Some("Only the admins can call this method.")
+        );
+    }
+
+    #[test]
+    fn group() {
+        let mut group: Group = [alice(), bob()].into_iter().collect();
+        assert!(group.contains(&bob()));
+        assert!(group.remove(&bob()));
+        assert!(!group.remove(&bob()));
+        assert!(!group.contains(&bob()));
+        assert!(!group.add(alice()));
+        assert_eq!(group, Group::new().with_user(alice()));
+    }
+
+    #[test]
+    fn users() {
+        assert_ne!(alice(), bob());
+        assert_eq!(alice(), *ALICE);
+        assert_eq!(anonymous().to_text(), "2vxsx-fae");
+    }
+
+    #[test]
+    fn generate_is_deterministic() {
+        assert_eq!(generate(5), generate(5));
+        assert_ne!(generate(5), generate(6));
+        assert_ne!(generate(0), alice());
+    }
+}
@@ -1,6 +1,45 @@
 #[cfg(not(target_family = "wasm"))]
 thread_local!(static HANDLER: std::cell::RefCell<Option<Box<dyn Ic0CallHandler>>> = std::cell::RefCell::new(None));
 
+/// Copy `size` bytes of the data starting at `offset` to the memory of the canister at `dst`,
+/// this is used by the handlers to write the result of a system call.
+///
+/// The canister shares the memory of the host when it is not running as WebAssembly, so the
+/// destination can not be checked against the heap of the canister, only the ranges that can
+/// never be valid are rejected.
+#[cfg(not(target_family = "wasm"))]
+pub fn copy_to_canister(
+    dst: isize,
+    offset: isize,
+    size: isize,
+    data: &[u8],
+) -> Result<(), crate::types::SystemApiError> {
+    use crate::types::SystemApiError;
+
+    let (offset, size) = match (usize::try_from(offset), usize::try_from(size)) {
+        (Ok(offset), Ok(size)) => (offset, size),
+        _ => return Err(SystemApiError::OutOfBoundsRead),
+    };
+
+    let end = match offset.checked_add(size) {
+        Some(end) if end <= data.len() => end,
+        _ => return Err(SystemApiError::OutOfBoundsRead),
+    };
+
+    let dst = match usize::try_from(dst) {
+        Ok(dst) if size == 0 || (dst != 0 && dst.checked_add(size).is_some()) => dst,
+        _ => return Err(SystemApiError::OutOfBoundsWrite),
+    };
+
+    if size == 0 {
+        return Ok(());
+    }
+
+    let slice = unsafe { std::slice::from_raw_parts_mut(dst as *mut u8, size) };
+    slice.copy_from_slice(&data[offset..end]);
+    Ok(())
+}
+
 /// Register a handler to be used for handling the canister call in non-wasm environments.
 ///
 /// # Panics
@@ -45,6 +84,28 @@ macro_rules! ic0_module {
             )*
         }
 
+        /// A mock handler that only supports some of the ic0 WASM API calls, every method panics
+        /// by default. Every mock handler is also an [`Ic0CallHandler`].
+        #[cfg(not(target_family = "wasm"))]
+        pub trait Ic0MockHandler {
+            $(
+            fn $name(&mut self, $($argname: $argtype,)*) -> _ic0_module_ret!($rettype) {
+                let _ = ($($argname,)*);
+                panic!("ic0::{} is not supported by this handler.", stringify!($name))
+            }
+            )*
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        impl<H: Ic0MockHandler> Ic0CallHandler for H {
+            $(
+            #[inline(always)]
+            fn $name(&mut self, $($argname: $argtype,)*) -> _ic0_module_ret!($rettype) {
+                Ic0MockHandler::$name(self, $($argname,)*)
+            }
+            )*
+        }
+
         /// The runtime module provides the tools to have the canister in one thread and communicate
         /// with another handler on another thread.
         #[cfg(not(target_family = "wasm"))]
@@ -53,6 +114,9 @@ macro_rules! ic0_module {
             use super::Ic0CallHandler;
 
             /// A response from the runtime to the canister.
+            ///
+            /// Converting a [`Response::Trap`] unwinds the canister's thread with the trap message,
+            /// without invoking the panic hook, since a trap is not a panic in the canister code.
             #[derive(Debug)]
             pub enum Response {
                 None,
@@ -74,7 +138,7 @@ macro_rules! ic0_module {
                 fn into(self) -> () {
                     match self {
                         Response::None => (),
-                        Response::Trap(m) => panic!("Canister trapped: {}", m),
+                        Response::Trap(m) => std::panic::resume_unwind(Box::new(m)),
                         _ => panic!("unexpected type cast."),
                     }
                 }
@@ -92,7 +156,7 @@ macro_rules! ic0_module {
                 fn into(self) -> isize {
                     match self {
                         Response::Isize(n) => n,
-                        Response::Trap(m) => panic!("Canister trapped: {}", m),
+                        Response::Trap(m) => std::panic::resume_unwind(Box::new(m)),
                         _ => panic!("unexpected type cast."),
                     }
                 }
@@ -110,7 +174,7 @@ macro_rules! ic0_module {
                 fn into(self) -> i32 {
                     match self {
                         Response::I32(n) => n,
-                        Response::Trap(m) => panic!("Canister trapped: {}", m),
+                        Response::Trap(m) => std::panic::resume_unwind(Box::new(m)),
                         _ => panic!("unexpected type cast."),
                     }
                 }
@@ -128,7 +192,7 @@ macro_rules! ic0_module {
                 fn into(self) -> i64 {
                     match self {
                         Response::I64(n) => n,
-                        Response::Trap(m) => panic!("Canister trapped: {}", m),
+                        Response::Trap(m) => std::panic::resume_unwind(Box::new(m)),
                         _ => panic!("unexpected type cast."),
                     }
                 }
@@ -141,10 +205,17 @@ macro_rules! ic0_module {
                 }
             }
 
-            /// The Ic0CallHandler on the main thread.
+            impl From<crate::types::SystemApiError> for Response {
+                #[inline(always)]
+                fn from(e: crate::types::SystemApiError) -> Self {
+                    Response::Trap(e.to_string())
+                }
+            }
+
+            /// The Ic0CallHandler on the main thread, an error traps the canister.
             pub trait Ic0CallHandlerProxy {
                 $(
-                fn $name(&mut self, $($argname: $argtype,)*) -> Result<_ic0_module_ret!($rettype), String>;
+                fn $name(&mut self, $($argname: $argtype,)*) -> Result<_ic0_module_ret!($rettype), crate::types::SystemApiError>;
                 )*
             }
 
@@ -237,7 +308,8 @@ macro_rules! ic0_module {
 // s: the (start) module initialization function
 // F: from canister_inspect_message
 // H: from canister_heartbeat
-// * = I G U Q Ry Rt C F H (NB: Not (start))
+// T: from canister_global_timer
+// * = I G U Q Ry Rt C F H T (NB: Not (start))
 ic0_module! {
     ic0.msg_arg_data_size : () -> isize;                                               // I U Q Ry F
     ic0.msg_arg_data_copy : (dst : isize, offset : isize, size : isize) -> ();         // I U Q Ry F
@@ -250,6 +322,7 @@ ic0_module! {
     ic0.msg_reply_data_append : (src : isize, size : isize) -> ();                     // U Q Ry Rt
     ic0.msg_reply : () -> ();                                                          // U Q Ry Rt
     ic0.msg_reject : (src : isize, size : isize) -> ();                                // U Q Ry Rt
+    ic0.msg_deadline : () -> i64;                                                      // U Q Ry Rt
 
     ic0.msg_cycles_available : () -> i64;                                              // U Rt Ry
     ic0.msg_cycles_available128 : (dst : isize) -> ();                                 // U Rt Ry
@@ -264,6 +337,7 @@ ic0_module! {
     ic0.canister_cycle_balance : () -> i64;                                            // *
     ic0.canister_cycle_balance128 : (dst : isize) -> ();                               // *
     ic0.canister_status : () -> i32;                                                   // *
+    ic0.in_replicated_execution : () -> i32;                                           // *
 
     ic0.msg_method_name_size : () -> isize;                                            // F
     ic0.msg_method_name_copy : (dst : isize, offset : isize, size : isize) -> ();      // F
@@ -283,6 +357,7 @@ ic0_module! {
     ic0.call_data_append : (src : isize, size : isize) -> ();                          // U Ry Rt H
     ic0.call_cycles_add : (amount : i64) -> ();                                        // U Ry Rt H
     ic0.call_cycles_add128 : (amount_high : i64, amount_low: i64) -> ();               // U Ry Rt H
+    ic0.call_with_best_effort_response : (timeout_seconds : i32) -> ();                // U Ry Rt H
     ic0.call_perform : () -> ( err_code : i32 );                                       // U Ry Rt H
 
     ic0.stable_size : () -> (page_count : i32);                                        // *
@@ -300,6 +375,7 @@ ic0_module! {
     ic0.data_certificate_copy : (dst: isize, offset: isize, size: isize) -> ();        // *
 
     ic0.time : () -> (timestamp : i64);                                                // *
+    ic0.global_timer_set : (timestamp : i64) -> i64;                                   // I G U Ry Rt C H T
     ic0.performance_counter : (counter_type : i32) -> (counter : i64);                 // * s
 
     ic0.debug_print : (src : isize, size : isize) -> ();                               // * s
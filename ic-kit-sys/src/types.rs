@@ -7,7 +7,7 @@ pub const CANDID_EMPTY_ARG: &[u8] = &[68, 73, 68, 76, 0, 0];
 /// Rejection code from calling another canister.
 #[allow(missing_docs)]
 #[repr(i32)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RejectionCode {
     NoError = 0,
     SysFatal = 1,
@@ -16,6 +16,9 @@ pub enum RejectionCode {
     CanisterReject = 4,
     CanisterError = 5,
     Unknown,
+    /// The code 6 of the IC, it comes after [`RejectionCode::Unknown`] so the discriminants of the
+    /// other variants do not change, use `i32::from` to get the code.
+    SysUnknown,
 }
 
 impl From<i32> for RejectionCode {
@@ -27,6 +30,7 @@ impl From<i32> for RejectionCode {
             3 => RejectionCode::DestinationInvalid,
             4 => RejectionCode::CanisterReject,
             5 => RejectionCode::CanisterError,
+            6 => RejectionCode::SysUnknown,
             _ => RejectionCode::Unknown,
         }
     }
@@ -38,6 +42,30 @@ impl From<u32> for RejectionCode {
     }
 }
 
+impl From<RejectionCode> for i32 {
+    fn from(code: RejectionCode) -> Self {
+        match code {
+            RejectionCode::SysUnknown => 6,
+            code => code as i32,
+        }
+    }
+}
+
+impl fmt::Display for RejectionCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RejectionCode::NoError => "NO_ERROR",
+            RejectionCode::SysFatal => "SYS_FATAL",
+            RejectionCode::SysTransient => "SYS_TRANSIENT",
+            RejectionCode::DestinationInvalid => "DESTINATION_INVALID",
+            RejectionCode::CanisterReject => "CANISTER_REJECT",
+            RejectionCode::CanisterError => "CANISTER_ERROR",
+            RejectionCode::SysUnknown => "SYS_UNKNOWN",
+            RejectionCode::Unknown => "UNKNOWN",
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum CallError {
     /// Indicates that the `ic0::call_perform` failed and the call is not queued.
@@ -65,6 +93,141 @@ impl fmt::Display for CallError {
 
 impl error::Error for CallError {}
 
+/// An error returned by a system API handler, it traps the canister with the error message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SystemApiError {
+    /// The system call is not available in the entry point that is being executed.
+    WrongEntryMode {
+        /// The name of the system call.
+        call: &'static str,
+        /// The name of the entry point that is being executed.
+        mode: String,
+    },
+    /// The system call needs a call that is under construction, but there is none.
+    NoPendingCall {
+        /// The name of the system call.
+        call: &'static str,
+    },
+    /// The system call may only be invoked once for each call under construction.
+    CalledMoreThanOnce {
+        /// The name of the system call.
+        call: &'static str,
+    },
+    /// The call is already replied to or rejected.
+    AlreadyResponded,
+    /// The query is already replied to or rejected, a query can not defer its response.
+    QueryAlreadyResponded,
+    /// The message is already accepted by inspect_message.
+    MessageAlreadyAccepted,
+    /// The method name of the message is not known.
+    MethodNameNotSet {
+        /// The name of the system call.
+        call: &'static str,
+    },
+    /// Reading past the end of the data that is copied to the canister.
+    OutOfBoundsRead,
+    /// Writing to a range of the canister's memory that can not be valid.
+    OutOfBoundsWrite,
+    /// Accessing a range past the end of the stable memory.
+    StableOutOfBounds {
+        /// The offset in bytes at which the stable memory is accessed.
+        offset: u64,
+        /// The number of bytes that are accessed.
+        size: u64,
+        /// The size of the stable memory in bytes.
+        stable_size: u64,
+    },
+    /// A value does not fit in the type used by the system call.
+    Overflow {
+        /// The value that does not fit, for example `"cycle balance"`.
+        value: &'static str,
+        /// Where the value does not fit, for example `"u64"`.
+        target: &'static str,
+    },
+    /// The canister does not have enough cycles.
+    InsufficientCycles {
+        /// The cycles that are needed.
+        needed: u128,
+        /// The cycle balance of the canister.
+        balance: u128,
+    },
+    /// The principal passed to the system call is invalid.
+    InvalidPrincipal {
+        /// The size of the principal in bytes.
+        size: isize,
+    },
+    /// The performance counter type is not known.
+    InvalidPerformanceCounter(i32),
+    /// The payload of a call or a reply is larger than the message size limit.
+    PayloadTooLarge {
+        /// The name of the system call.
+        call: &'static str,
+        /// The size of the payload in bytes.
+        size: usize,
+        /// The maximum size of a message in bytes.
+        limit: usize,
+    },
+    /// The canister trapped explicitly with the given message.
+    Trap(String),
+}
+
+impl fmt::Display for SystemApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SystemApiError::WrongEntryMode { call, mode } => {
+                write!(f, "{} can not be called from '{}'", call, mode)
+            }
+            SystemApiError::NoPendingCall { call } => write!(
+                f,
+                "{} cannot be called when there is no pending call.",
+                call
+            ),
+            SystemApiError::CalledMoreThanOnce { call } => {
+                write!(f, "{} cannot be invoked more than once.", call)
+            }
+            SystemApiError::AlreadyResponded => f.write_str("Current call is already replied to."),
+            SystemApiError::QueryAlreadyResponded => f.write_str("query already responded"),
+            SystemApiError::MessageAlreadyAccepted => f.write_str("Message is already accepted."),
+            SystemApiError::MethodNameNotSet { call } => {
+                write!(f, "{}: the method name is not set on the env.", call)
+            }
+            SystemApiError::OutOfBoundsRead => f.write_str("Out of bound read."),
+            SystemApiError::OutOfBoundsWrite => f.write_str("Out of bound write."),
+            SystemApiError::StableOutOfBounds {
+                offset,
+                size,
+                stable_size,
+            } => write!(
+                f,
+                "stable memory out of bounds: accessing {} bytes at offset {}, but the stable memory is {} bytes",
+                size, offset, stable_size
+            ),
+            SystemApiError::Overflow { value, target } => {
+                write!(f, "{} does not fit in {}", value, target)
+            }
+            SystemApiError::InsufficientCycles { needed, balance } => write!(
+                f,
+                "Insufficient cycles balance: {} cycles are needed but the balance is {}.",
+                needed, balance
+            ),
+            SystemApiError::InvalidPrincipal { size } => {
+                write!(f, "invalid principal: {} bytes long", size)
+            }
+            SystemApiError::InvalidPerformanceCounter(counter_type) => {
+                write!(f, "Invalid performance counter type '{}'.", counter_type)
+            }
+            SystemApiError::PayloadTooLarge { call, size, limit } => write!(
+                f,
+                "{}: the payload of {} bytes exceeds the limit of {} bytes.",
+                call, size, limit
+            ),
+            SystemApiError::Trap(m) => f.write_str(m),
+        }
+    }
+}
+
+impl error::Error for SystemApiError {}
+
 /// A possible error value when dealing with stable memory.
 #[derive(Debug, Eq, PartialEq)]
 pub enum StableMemoryError {
@@ -84,3 +247,20 @@ impl fmt::Display for StableMemoryError {
 }
 
 impl error::Error for StableMemoryError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejection_code() {
+        for code in 0..=6 {
+            assert_eq!(i32::from(RejectionCode::from(code)), code);
+        }
+
+        assert_eq!(RejectionCode::from(4), RejectionCode::CanisterReject);
+        assert_eq!(RejectionCode::from(42), RejectionCode::Unknown);
+        assert_eq!(RejectionCode::from(-1), RejectionCode::Unknown);
+        assert_eq!(RejectionCode::CanisterReject.to_string(), "CANISTER_REJECT");
+    }
+}
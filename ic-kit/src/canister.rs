@@ -12,4 +12,63 @@ pub trait KitCanister {
 
     /// The candid description of the canister.
     fn candid() -> String;
+
+    /// The names of the arguments of each method in the candid description, sorted by the name
+    /// of the method. The arguments that are not a plain identifier are named `arg_N`, and the
+    /// methods are not listed by default.
+    fn candid_arg_names() -> Vec<(String, Vec<String>)> {
+        Vec::new()
+    }
+}
+
+/// Return the candid interface of the given canister, assembled from the methods exported by its
+/// entry point macros, see [`KitCanister::candid`].
+pub fn candid_interface<C: KitCanister>() -> String {
+    C::candid()
+}
+
+/// A canister method that knows its Candid signature, this is implemented for the methods
+/// exported by the entry point macros such as `#[update]` and `#[query]`.
+pub trait CandidMethod {
+    /// Return the Candid types of the arguments and of the return values of the method.
+    fn candid_signature() -> (Vec<candid::types::Type>, Vec<candid::types::Type>);
+
+    /// Return the names of the arguments of the method, in the same order as their Candid types.
+    /// The arguments that are not a plain identifier are named `arg_N`, and the arguments are
+    /// unnamed by default.
+    fn candid_arg_names() -> Vec<&'static str> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macros::update;
+    use crate::Principal;
+    use candid::types::Type;
+
+    #[update(hidden = true)]
+    fn check(_number: u64, _name: String) -> bool {
+        true
+    }
+
+    #[update(hidden = true)]
+    fn transfer(to: Principal, amount: u64) -> bool {
+        to != Principal::anonymous() && amount > 0
+    }
+
+    #[test]
+    fn candid_signature() {
+        assert_eq!(
+            check::candid_signature(),
+            (vec![Type::Nat64, Type::Text], vec![Type::Bool])
+        );
+    }
+
+    #[test]
+    fn candid_arg_names() {
+        assert_eq!(transfer::candid_arg_names(), vec!["to", "amount"]);
+        assert_eq!(check::candid_arg_names(), vec!["number", "name"]);
+    }
 }
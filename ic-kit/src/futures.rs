@@ -9,9 +9,9 @@
 // needs.
 use candid::Principal;
 use ic_kit_sys::ic0;
+use std::cell::Cell;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Context, Poll, Waker};
 
 #[cfg(target_family = "wasm")]
@@ -231,9 +231,9 @@ fn cleanup(state_ptr: *const InnerCell<CallFutureState>) {
     if let Some(waker) = w {
         // Flag that we do not want to actually wake the task - we
         // want to drop it *without* executing it.
-        CLEANUP.store(true, Ordering::Relaxed);
+        CLEANUP.with(|c| c.set(true));
         waker.wake();
-        CLEANUP.store(false, Ordering::Relaxed);
+        CLEANUP.with(|c| c.set(false));
     }
 }
 
@@ -272,7 +272,11 @@ pub fn spawn<F: 'static + Future<Output = ()>>(future: F) {
     }
 }
 
-pub(crate) static CLEANUP: AtomicBool = AtomicBool::new(false);
+thread_local! {
+    // When running in the IC-Kit runtime each canister is executed on its own thread, so this flag
+    // must be thread local, otherwise a trap in one canister could drop the futures of another.
+    pub(crate) static CLEANUP: Cell<bool> = const { Cell::new(false) };
+}
 
 // This module contains the implementation of a waker we're using for waking
 // top-level futures (the ones returned by canister methods). The waker polls
@@ -282,10 +286,7 @@ pub(crate) static CLEANUP: AtomicBool = AtomicBool::new(false);
 // waker was used as intended.
 mod waker {
     use super::*;
-    use std::{
-        sync::atomic::Ordering,
-        task::{RawWaker, RawWakerVTable, Waker},
-    };
+    use std::task::{RawWaker, RawWakerVTable, Waker};
     type FuturePtr = *mut dyn Future<Output = ()>;
 
     static MY_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
@@ -312,7 +313,7 @@ mod waker {
         let future_ptr: FuturePtr = *boxed_future_ptr_ptr;
         let boxed_future = Box::from_raw(future_ptr);
         let mut pinned_future = Pin::new_unchecked(&mut *future_ptr);
-        if !CLEANUP.load(Ordering::Relaxed)
+        if !CLEANUP.with(|c| c.get())
             && pinned_future
                 .as_mut()
                 .poll(&mut Context::from_waker(&waker::waker(ptr)))
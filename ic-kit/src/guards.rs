@@ -0,0 +1,89 @@
+//! Ready-made guards that can be used with the `guard` option of the entry point macros.
+
+use crate::ic::caller;
+use candid::Principal;
+
+/// Reject the calls from the anonymous principal, the caller of the messages that are not
+/// signed.
+///
+/// # Example
+///
+/// ```
+/// use ic_kit::prelude::*;
+///
+/// #[update(guard = "not_anonymous")]
+/// fn vote(proposal: u64) {
+///     // Every vote is cast by an authenticated user.
+/// }
+/// ```
+pub fn not_anonymous() -> Result<(), String> {
+    if caller() == Principal::anonymous() {
+        return Err("The anonymous principal is not allowed.".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macros::update;
+    use crate::rt::{users, Canister, Replica};
+    use crate::test_utils::block_on;
+    use ic_kit_sys::types::RejectionCode;
+
+    #[update(hidden = true, guard = "not_anonymous")]
+    fn vote() {}
+
+    /// Fail every call rather than rejecting it.
+    fn is_closed() -> Result<(), (RejectionCode, String)> {
+        Err((
+            RejectionCode::CanisterError,
+            "The ballot is closed.".to_string(),
+        ))
+    }
+
+    #[update(hidden = true, guard = "is_closed")]
+    fn late_vote() {}
+
+    #[test]
+    fn anonymous_caller() {
+        block_on(async {
+            let replica = Replica::default();
+            let canister = replica
+                .add_canister(Canister::new(Principal::from_slice(&[1])).with_method::<vote>());
+
+            let r = canister
+                .new_call("vote")
+                .with_caller(users::anonymous())
+                .perform()
+                .await;
+            assert_eq!(r.rejection_code(), RejectionCode::CanisterReject);
+            assert_eq!(
+                r.rejection_message(),
+                Some("The anonymous principal is not allowed.")
+            );
+
+            canister
+                .new_call("vote")
+                .with_caller(users::alice())
+                .perform()
+                .await
+                .assert_ok();
+        });
+    }
+
+    #[test]
+    fn guard_rejection_code() {
+        block_on(async {
+            let replica = Replica::default();
+            let canister = replica.add_canister(
+                Canister::new(Principal::from_slice(&[2])).with_method::<late_vote>(),
+            );
+
+            let r = canister.new_call("late_vote").perform().await;
+            assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+            assert_eq!(r.rejection_message(), Some("The ballot is closed."));
+        });
+    }
+}
@@ -1,14 +1,13 @@
 use crate::futures;
 use crate::futures::CallFuture;
-use crate::ic::Cycles;
+use crate::ic::{Cycles, CANDID_EMPTY_ARG};
 use crate::utils::arg_data_raw;
 use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use candid::{decode_args, decode_one, encode_args, encode_one, CandidType, Principal};
 use ic_kit_sys::ic0;
 use serde::de::DeserializeOwned;
 
-use ic_kit_sys::types::RejectionCode;
-pub use ic_kit_sys::types::{CallError, CANDID_EMPTY_ARG};
+pub use ic_kit_sys::types::{CallError, RejectionCode};
 
 /// A call builder that let's you create an inter-canister call which can be then sent to the
 /// destination.
@@ -17,6 +16,7 @@ pub struct CallBuilder {
     method_name: String,
     payment: Cycles,
     arg: Option<Vec<u8>>,
+    timeout: Option<u32>,
 }
 
 impl CallBuilder {
@@ -28,6 +28,7 @@ impl CallBuilder {
             method_name: method_name.into(),
             payment: 0,
             arg: None,
+            timeout: None,
         }
     }
 
@@ -102,6 +103,13 @@ impl CallBuilder {
         self
     }
 
+    /// Make this a best-effort call, if the callee does not respond within the given number of
+    /// seconds the call is rejected with `SysUnknown`. The timeout is capped at 300 seconds.
+    pub fn with_best_effort_response(mut self, timeout_seconds: u32) -> Self {
+        self.timeout = Some(timeout_seconds);
+        self
+    }
+
     /// Should be called after the `ic0::call_new` to set the call arguments.
     #[inline(always)]
     unsafe fn ic0_internal_call_perform(&self) -> i32 {
@@ -113,10 +121,14 @@ impl CallBuilder {
             ic0::call_cycles_add(self.payment as i64);
         } else if self.payment > 0 {
             let high = (self.payment >> 64) as u64 as i64;
-            let low = (self.payment & (1 << 64)) as u64 as i64;
+            let low = self.payment as u64 as i64;
             ic0::call_cycles_add128(high, low);
         }
 
+        if let Some(timeout_seconds) = self.timeout {
+            ic0::call_with_best_effort_response(timeout_seconds as i32);
+        }
+
         let args_raw = self.arg.as_deref().unwrap_or(CANDID_EMPTY_ARG);
 
         if !args_raw.is_empty() {
@@ -269,3 +281,67 @@ impl CallBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic::{msg_cycles_refunded, spawn};
+    use crate::rt::types::Env;
+    use crate::rt::{Canister, Replica};
+    use crate::test_utils::block_on;
+    use crate::utils::reply;
+    use serde::Deserialize;
+
+    #[derive(CandidType, Deserialize, Debug, PartialEq, Clone)]
+    struct Point {
+        x: i64,
+        label: String,
+    }
+
+    #[test]
+    fn typed_call() {
+        block_on(async {
+            let callee = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+            // Echo the argument back to the caller.
+            let replica = Replica::default().with_handler(callee, "echo", |arg| Ok(arg.to_vec()));
+            let canister = replica.add_canister(Canister::new(Principal::anonymous()));
+
+            let r = canister
+                .custom(
+                    move || {
+                        spawn(async move {
+                            let point = Point {
+                                x: -7,
+                                label: "origin".to_string(),
+                            };
+
+                            let (echo, n): (Point, u8) = CallBuilder::new(callee, "echo")
+                                .with_args((point.clone(), 42u8))
+                                .with_payment(1_000)
+                                .perform()
+                                .await
+                                .unwrap();
+
+                            assert_eq!(echo, point);
+                            assert_eq!(n, 42);
+                            // The payment is refunded by the mocked method.
+                            assert_eq!(msg_cycles_refunded(), 1_000);
+
+                            let echo = CallBuilder::new(callee, "echo")
+                                .with_arg(point.clone())
+                                .perform_one::<Point>()
+                                .await
+                                .unwrap();
+
+                            assert_eq!(echo, point);
+                            reply(&encode_one(echo).unwrap());
+                        })
+                    },
+                    Env::default(),
+                )
+                .await;
+
+            assert_eq!(r.decode_one::<Point>().unwrap().label, "origin");
+        });
+    }
+}
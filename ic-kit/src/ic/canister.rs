@@ -1,5 +1,7 @@
 use crate::ic::{with, Cycles};
-use candid::Principal;
+use crate::utils;
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, Principal};
 use ic_kit_sys::ic0;
 use std::convert::TryFrom;
 
@@ -28,6 +30,18 @@ pub fn trap(message: &str) -> ! {
     unreachable!()
 }
 
+/// Reply to the current call with the given candid tuple value, this is meant to be used in the
+/// methods with `manual_reply = true`, every other method replies with its return value.
+///
+/// # Panics
+///
+/// If the value can not be encoded.
+#[inline(always)]
+pub fn reply<T: ArgumentEncoder>(reply: T) {
+    let bytes = encode_args(reply).expect("Could not encode the reply.");
+    utils::reply(&bytes);
+}
+
 /// Print a debug message from the canister that can be viewed during local development.
 #[inline(always)]
 pub fn print<S: AsRef<str>>(s: S) {
@@ -51,6 +65,21 @@ pub fn time() -> u64 {
     unsafe { ic0::time() as u64 }
 }
 
+/// The deadline of the current message in nanoseconds, zero if the message is a guaranteed
+/// response message.
+#[inline(always)]
+pub fn msg_deadline() -> u64 {
+    unsafe { ic0::msg_deadline() as u64 }
+}
+
+/// Set the global timer of the canister to the given time in nanoseconds, the timer fires once
+/// the time is reached. Setting the timer to zero deactivates it. Returns the previous value of
+/// the timer.
+#[inline(always)]
+pub fn set_global_timer(timestamp: u64) -> u64 {
+    unsafe { ic0::global_timer_set(timestamp as i64) as u64 }
+}
+
 /// The balance of the canister.
 #[inline(always)]
 pub fn balance() -> Cycles {
@@ -67,6 +96,13 @@ pub fn balance() -> Cycles {
     }
 }
 
+/// Whether the current message is executed in replicated mode, the queries sent by users are
+/// executed by a single replica in non-replicated mode.
+#[inline(always)]
+pub fn in_replicated_execution() -> bool {
+    unsafe { ic0::in_replicated_execution() == 1 }
+}
+
 /// The caller who has invoked this method on the canister.
 ///
 /// # Panics
@@ -74,12 +110,14 @@ pub fn balance() -> Cycles {
 /// If called after a reply/reject callback.
 #[inline(always)]
 pub fn caller() -> Principal {
+    // A principal is at most 29 bytes, so there is no need to allocate a buffer on the heap.
+    let mut bytes = [0u8; 29];
     let len = unsafe { ic0::msg_caller_size() as usize };
-    let mut bytes = vec![0u8; len];
+    assert!(len <= bytes.len(), "Invalid caller principal.");
     unsafe {
         ic0::msg_caller_copy(bytes.as_mut_ptr() as isize, 0, len as isize);
     }
-    Principal::try_from(&bytes).unwrap()
+    Principal::try_from(&bytes[..len]).unwrap()
 }
 
 /// Set the certified data of the canister, this method traps if data.len > 32.
@@ -102,3 +140,78 @@ pub fn data_certificate() -> Option<Vec<u8>> {
     }
     Some(buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic::CallBuilder;
+    use crate::macros::update;
+    use crate::rt::{Canister, Replica};
+    use crate::test_utils::block_on;
+    use crate::MockContext;
+
+    /// Reply with the caller, which is read twice to check that reading it has no side effect.
+    #[update(hidden = true)]
+    fn whoami() -> Principal {
+        let caller = super::caller();
+        assert_eq!(super::caller(), caller);
+        caller
+    }
+
+    /// Ask the given canister who its caller is.
+    #[update(hidden = true)]
+    async fn ask_whoami(other: Principal) -> Principal {
+        CallBuilder::new(other, "whoami")
+            .perform_one()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn caller() {
+        let caller = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        MockContext::new().with_caller(caller).inject();
+        assert_eq!(super::caller(), caller);
+    }
+
+    #[test]
+    fn inter_canister_caller() {
+        block_on(async {
+            let alice = Principal::from_slice(&[1]);
+            let bob = Principal::from_slice(&[2]);
+
+            let replica = Replica::default();
+            replica.add_canister(Canister::new(alice).with_method::<ask_whoami>());
+            replica.add_canister(Canister::new(bob).with_method::<whoami>());
+
+            let r = replica
+                .new_call(alice, "ask_whoami")
+                .with_arg(bob)
+                .perform()
+                .await;
+
+            assert_eq!(r.decode_one::<Principal>().unwrap(), alice);
+        });
+    }
+
+    #[test]
+    fn id() {
+        let id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        MockContext::new().with_id(id).inject();
+        assert_eq!(super::id(), id);
+    }
+
+    #[test]
+    fn time() {
+        MockContext::new()
+            .with_time(1_650_000_000_000_000_000)
+            .inject();
+        assert_eq!(super::time(), 1_650_000_000_000_000_000);
+    }
+
+    #[test]
+    fn balance() {
+        MockContext::new().with_balance(5_000).inject();
+        assert_eq!(super::balance(), 5_000);
+    }
+}
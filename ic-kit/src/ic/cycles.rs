@@ -18,11 +18,16 @@ pub fn msg_cycles_available() -> Cycles {
     }
 
     #[cfg(feature = "experimental-cycles128")]
-    {
-        let mut recv = 0u128;
-        unsafe { ic0::msg_cycles_available128(&mut recv as *mut u128 as isize) }
-        u128::from_le(recv)
-    }
+    msg_cycles_available128()
+}
+
+/// Return the number of available cycles that is sent by the caller as a 128-bit value,
+/// regardless of the `experimental-cycles128` feature.
+#[inline(always)]
+pub fn msg_cycles_available128() -> u128 {
+    let mut recv = 0u128;
+    unsafe { ic0::msg_cycles_available128(&mut recv as *mut u128 as isize) }
+    u128::from_le(recv)
 }
 
 /// Accept the given amount of cycles, returns the actual amount of accepted cycles.
@@ -34,19 +39,20 @@ pub fn msg_cycles_accept(max_amount: Cycles) -> Cycles {
     }
 
     #[cfg(feature = "experimental-cycles128")]
-    {
-        if max_amount < (u64::MAX as u128) {
-            return unsafe { ic0::msg_cycles_accept(max_amount as i64) as u128 };
-        }
-
-        let high = (max_amount >> 64) as u64 as i64;
-        let low = (max_amount & (1 << 64)) as u64 as i64;
-        let mut recv = 0u128;
-        unsafe {
-            ic0::msg_cycles_accept128(high, low, &mut recv as *mut u128 as isize);
-        }
-        u128::from_le(recv)
+    msg_cycles_accept128(max_amount)
+}
+
+/// Accept the given amount of cycles as a 128-bit value, returns the actual amount of accepted
+/// cycles, this is available regardless of the `experimental-cycles128` feature.
+#[inline(always)]
+pub fn msg_cycles_accept128(max_amount: u128) -> u128 {
+    let high = (max_amount >> 64) as u64 as i64;
+    let low = max_amount as u64 as i64;
+    let mut recv = 0u128;
+    unsafe {
+        ic0::msg_cycles_accept128(high, low, &mut recv as *mut u128 as isize);
     }
+    u128::from_le(recv)
 }
 
 /// Return the cycles that were sent back by the canister that was just called.
@@ -59,9 +65,48 @@ pub fn msg_cycles_refunded() -> Cycles {
     }
 
     #[cfg(feature = "experimental-cycles128")]
-    {
-        let mut recv = 0u128;
-        unsafe { ic0::msg_cycles_refunded128(&mut recv as *mut u128 as isize) }
-        u128::from_le(recv)
+    msg_cycles_refunded128()
+}
+
+/// Return the cycles that were sent back by the canister that was just called as a 128-bit
+/// value, regardless of the `experimental-cycles128` feature.
+#[inline(always)]
+pub fn msg_cycles_refunded128() -> u128 {
+    let mut recv = 0u128;
+    unsafe { ic0::msg_cycles_refunded128(&mut recv as *mut u128 as isize) }
+    u128::from_le(recv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::types::Env;
+    use crate::test_utils::run_custom_with_env;
+    use crate::utils::reply;
+
+    #[test]
+    fn cycles128() {
+        let available = u64::MAX as u128 * 3;
+        let refunded = u64::MAX as u128 + 7;
+
+        let env = Env::default()
+            .with_cycles_available(available)
+            .with_cycles_refunded(refunded);
+
+        run_custom_with_env(env, move || {
+            assert_eq!(msg_cycles_available128(), available);
+            assert_eq!(msg_cycles_refunded128(), refunded);
+
+            let amount = u64::MAX as u128 + 5;
+            assert_eq!(msg_cycles_accept128(amount), amount);
+            assert_eq!(msg_cycles_available128(), available - amount);
+
+            // Only the available cycles can be accepted.
+            assert_eq!(msg_cycles_accept128(u128::MAX), available - amount);
+            assert_eq!(msg_cycles_available128(), 0);
+
+            reply(&[]);
+        })
+        .assert_ok();
     }
 }
@@ -0,0 +1,35 @@
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+
+pub use ic_kit_sys::types::CANDID_EMPTY_ARG;
+
+/// Encode the given tuple of values as the Candid arguments of a message, the empty tuple is
+/// encoded as [`CANDID_EMPTY_ARG`].
+#[inline(always)]
+pub fn encode<T: ArgumentEncoder>(arguments: T) -> candid::Result<Vec<u8>> {
+    candid::encode_args(arguments)
+}
+
+/// Decode the Candid arguments of a message as the given tuple of values.
+#[inline(always)]
+pub fn decode<'a, T: ArgumentDecoder<'a>>(bytes: &'a [u8]) -> candid::Result<T> {
+    candid::decode_args(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_arg() {
+        let () = decode(CANDID_EMPTY_ARG).unwrap();
+        assert_eq!(encode(()).unwrap(), CANDID_EMPTY_ARG);
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = encode((17u64, "hello".to_string())).unwrap();
+        let (n, s): (u64, String) = decode(&bytes).unwrap();
+        assert_eq!(n, 17);
+        assert_eq!(s, "hello");
+    }
+}
@@ -1,13 +1,17 @@
 mod call;
 mod canister;
 mod cycles;
+mod encoding;
 mod spawn;
 mod stable;
 mod storage;
+mod timers;
 
 pub use call::*;
 pub use canister::*;
 pub use cycles::*;
+pub use encoding::*;
 pub use spawn::*;
 pub use stable::*;
 pub use storage::*;
+pub use timers::*;
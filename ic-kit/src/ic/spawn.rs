@@ -6,3 +6,114 @@ use crate::futures;
 pub fn spawn<F: 'static + std::future::Future<Output = ()>>(future: F) {
     futures::spawn(future)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic::CallBuilder;
+    use crate::rt::types::{EntryMode, Env, Message};
+    use crate::rt::{Canister, CanisterMethod, Replica};
+    use crate::test_utils::block_on;
+    use crate::utils::{arg_data_raw, reply};
+    use candid::{decode_args, encode_one, Principal};
+
+    /// Returns `2^n` by asking the other canister for `2^(n-1)`, so the update only replies after
+    /// awaiting a chain of inter-canister calls.
+    struct PowMethod;
+
+    impl CanisterMethod for PowMethod {
+        const EXPORT_NAME: &'static str = "canister_update pow";
+
+        fn exported_method() {
+            let (n, other): (u64, Principal) = decode_args(&arg_data_raw()).unwrap();
+
+            spawn(async move {
+                let result = if n == 0 {
+                    1
+                } else {
+                    let id = crate::ic::id();
+                    let half: u64 = CallBuilder::new(other, "pow")
+                        .with_args((n - 1, id))
+                        .perform_one()
+                        .await
+                        .unwrap();
+                    half * 2
+                };
+
+                reply(&encode_one(result).unwrap());
+            });
+        }
+    }
+
+    #[test]
+    fn async_update() {
+        block_on(async {
+            let alice = Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap();
+            let bob = Principal::from_text("lj532-6iaaa-aaaah-qcc7a-cai").unwrap();
+
+            let replica = Replica::default();
+            replica.add_canister(Canister::new(alice).with_method::<PowMethod>());
+            replica.add_canister(Canister::new(bob).with_method::<PowMethod>());
+
+            let r = replica
+                .new_call(alice, "pow")
+                .with_args((10u64, bob))
+                .perform()
+                .await;
+
+            assert_eq!(r.decode_one::<u64>().unwrap(), 1024);
+        });
+    }
+
+    /// Reply right away, and reply again once the query call to the canister `[2]` returns.
+    struct LateReplyMethod;
+
+    impl CanisterMethod for LateReplyMethod {
+        const EXPORT_NAME: &'static str = "canister_composite_query late_reply";
+
+        fn exported_method() {
+            reply(b"early");
+
+            spawn(async {
+                let callee = Principal::from_slice(&[2]);
+                let _ = CallBuilder::new(callee, "echo").perform_raw().await;
+                reply(b"late");
+            });
+        }
+    }
+
+    #[test]
+    fn query_late_reply() {
+        block_on(async {
+            let mut canister =
+                Canister::new(Principal::from_slice(&[1])).with_method::<LateReplyMethod>();
+
+            let r = canister
+                .process_message_and_wait(Message::query("late_reply"))
+                .await;
+            assert_eq!(r.bytes().unwrap(), b"early");
+
+            let call = &canister.execution_report().outgoing_calls[0];
+            assert!(call.query);
+            let reply_to = call.request_id;
+
+            canister
+                .process_message(
+                    Message::Reply {
+                        reply_to,
+                        env: Env::default().with_entry_mode(EntryMode::ReplyCallback),
+                    },
+                    None,
+                )
+                .await;
+
+            let report = canister.execution_report();
+            assert!(report.trapped);
+            assert!(report
+                .trap_message
+                .as_ref()
+                .unwrap()
+                .contains("query already responded"));
+        });
+    }
+}
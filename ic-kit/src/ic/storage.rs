@@ -8,7 +8,8 @@ thread_local! {
 ///
 /// If no value is currently associated to the type `T`, this method will insert the default
 /// value in its place before invoking the callback. Use `maybe_with` if you don't want the
-/// default value to be inserted or if your type does not implement the [`Default`] trait.
+/// default value to be inserted, or `with_or` if your type does not implement the [`Default`]
+/// trait.
 ///
 /// This is a safe replacement for the previously known `ic_kit::ic::get` API, and you can use it
 /// instead of `lazy_static` or `local_thread`.
@@ -16,6 +17,14 @@ pub fn with<T: 'static + Default, U, F: FnOnce(&T) -> U>(callback: F) -> U {
     STORAGE.with(|storage| storage.with(callback))
 }
 
+/// Like [`with`], but initializes the data with the value returned by `init` instead of the
+/// default value, so it can be used with types that do not implement the [`Default`] trait.
+///
+/// The `init` function is only called if there is no value associated with the type `T`.
+pub fn with_or<T: 'static, U, I: FnOnce() -> T, F: FnOnce(&T) -> U>(init: I, callback: F) -> U {
+    STORAGE.with(|storage| storage.with_or(init, callback))
+}
+
 /// Like [`with`], but does not initialize the data with the default value and simply returns None,
 /// if there is no value associated with the type.
 pub fn maybe_with<T: 'static, U, F: FnOnce(&T) -> U>(callback: F) -> Option<U> {
@@ -26,7 +35,8 @@ pub fn maybe_with<T: 'static, U, F: FnOnce(&T) -> U>(callback: F) -> Option<U> {
 ///
 /// If no value is currently associated to the type `T`, this method will insert the default
 /// value in its place before invoking the callback. Use `maybe_with_mut` if you don't want the
-/// default value to be inserted or if your type does not implement the [`Default`] trait.
+/// default value to be inserted, or `with_mut_or` if your type does not implement the [`Default`]
+/// trait.
 ///
 /// This is a safe replacement for the previously known `ic_kit::ic::get` API, and you can use it
 /// instead of `lazy_static` or `local_thread`.
@@ -34,12 +44,32 @@ pub fn with_mut<T: 'static + Default, U, F: FnOnce(&mut T) -> U>(callback: F) ->
     STORAGE.with(|storage| storage.with_mut(callback))
 }
 
+/// Like [`with_mut`], but initializes the data with the value returned by `init` instead of the
+/// default value, so it can be used with types that do not implement the [`Default`] trait.
+///
+/// The `init` function is only called if there is no value associated with the type `T`.
+pub fn with_mut_or<T: 'static, U, I: FnOnce() -> T, F: FnOnce(&mut T) -> U>(
+    init: I,
+    callback: F,
+) -> U {
+    STORAGE.with(|storage| storage.with_mut_or(init, callback))
+}
+
 /// Like [`with_mut`], but does not initialize the data with the default value and simply returns
 /// None, if there is no value associated with the type.
 pub fn maybe_with_mut<T: 'static, U, F: FnOnce(&mut T) -> U>(callback: F) -> Option<U> {
     STORAGE.with(|storage| storage.maybe_with_mut(callback))
 }
 
+/// Pass the ownership of the value associated with the given type to the closure and store
+/// the returned value in its place.
+///
+/// If no value is currently associated to the type `T`, the default value is passed to the
+/// closure instead.
+pub fn update<T: 'static + Default, F: FnOnce(T) -> T>(f: F) {
+    STORAGE.with(|storage| storage.update(f))
+}
+
 /// Remove the current value associated with the type and return it.
 pub fn take<T: 'static>() -> Option<T> {
     STORAGE.with(|storage| storage.take::<T>())
@@ -50,6 +80,30 @@ pub fn swap<T: 'static>(value: T) -> Option<T> {
     STORAGE.with(|storage| storage.swap(value))
 }
 
+/// Returns true if there is a value associated with the type `T`.
+pub fn contains<T: 'static>() -> bool {
+    STORAGE.with(|storage| storage.contains::<T>())
+}
+
+/// Returns the number of values currently stored.
+pub fn len() -> usize {
+    STORAGE.with(|storage| storage.len())
+}
+
+/// Returns true if there are no values stored.
+pub fn is_empty() -> bool {
+    STORAGE.with(|storage| storage.is_empty())
+}
+
+/// Drop all of the stored values, this can be used to reset the state between tests.
+///
+/// # Panics
+///
+/// If any of the values is currently borrowed, i.e when called inside of a [`with`] closure.
+pub fn clear() {
+    STORAGE.with(|storage| storage.clear())
+}
+
 /// Like [`crate::ic::with`] but passes the immutable reference of multiple variables to the
 /// closure as a tuple.
 ///
@@ -102,3 +156,53 @@ pub fn with_many<A: BorrowMany, U, F: FnOnce(A) -> U>(callback: F) -> U {
 pub fn with_many_mut<A: BorrowMutMany, U, F: FnOnce(A) -> U>(callback: F) -> U {
     STORAGE.with(|storage| storage.with_many_mut(callback))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic::CANDID_EMPTY_ARG;
+    use crate::rt::types::Env;
+    use crate::rt::{Canister, Replica};
+    use crate::test_utils::block_on;
+    use crate::utils::reply;
+    use candid::Principal;
+
+    #[test]
+    fn independent_canisters() {
+        block_on(async {
+            let replica = Replica::default();
+            let canisters = [
+                replica.add_canister(Canister::new(Principal::anonymous())),
+                replica.add_canister(Canister::new(Principal::management_canister())),
+            ];
+
+            for (i, canister) in canisters.iter().enumerate() {
+                canister
+                    .custom(
+                        move || {
+                            assert!(is_empty());
+                            swap(i);
+                            reply(CANDID_EMPTY_ARG);
+                        },
+                        Env::default(),
+                    )
+                    .await
+                    .assert_ok();
+            }
+
+            for (i, canister) in canisters.iter().enumerate() {
+                canister
+                    .custom(
+                        move || {
+                            assert_eq!(len(), 1);
+                            assert_eq!(maybe_with(|v: &usize| *v), Some(i));
+                            reply(CANDID_EMPTY_ARG);
+                        },
+                        Env::default(),
+                    )
+                    .await
+                    .assert_ok();
+            }
+        });
+    }
+}
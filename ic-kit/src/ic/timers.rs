@@ -0,0 +1,288 @@
+use crate::ic::{set_global_timer, time, with_mut};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// The id of a timer, which can be used to cancel the timer with [`clear_timer`].
+#[derive(Hash, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Debug)]
+pub struct TimerId(u64);
+
+enum Task {
+    Once(Box<dyn FnOnce()>),
+    Repeated(Box<dyn FnMut()>, u64),
+}
+
+/// The timers of the canister, they are all multiplexed on the global timer which is always set
+/// to the earliest deadline.
+#[derive(Default)]
+struct Timers {
+    next_id: u64,
+    /// The task of each active timer, the task is taken out while it is running.
+    tasks: HashMap<TimerId, Option<Task>>,
+    /// The deadline of each active timer, sorted by the deadline.
+    deadlines: BTreeSet<(u64, TimerId)>,
+}
+
+impl Timers {
+    fn insert(&mut self, deadline: u64, task: Task) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        self.tasks.insert(id, Some(task));
+        self.deadlines.insert((deadline, id));
+        id
+    }
+
+    /// Set the global timer to the earliest deadline, or deactivate it if there are no timers.
+    fn schedule(&self) {
+        let next = self
+            .deadlines
+            .iter()
+            .next()
+            .map(|(deadline, _)| *deadline)
+            .unwrap_or(0);
+
+        set_global_timer(next);
+    }
+}
+
+/// Run the given closure once after the given delay, the closure runs as part of the global
+/// timer of the canister, so this can not be used along with [`set_global_timer`].
+///
+/// When testing with the runtime, the timer fires once the clock of the replica is moved past
+/// its deadline, see `Replica::advance_time` and `Replica::run_rounds`.
+pub fn set_timer<F: FnOnce() + 'static>(delay: Duration, f: F) -> TimerId {
+    let deadline = deadline_after(nanos(delay));
+
+    with_mut(|timers: &mut Timers| {
+        let id = timers.insert(deadline, Task::Once(Box::new(f)));
+        timers.schedule();
+        id
+    })
+}
+
+/// Run the given closure every time the given interval passes, until the timer is cancelled with
+/// [`clear_timer`]. The first run happens once the interval passes for the first time.
+pub fn set_timer_interval<F: FnMut() + 'static>(interval: Duration, f: F) -> TimerId {
+    let interval = nanos(interval);
+    let deadline = deadline_after(interval);
+
+    with_mut(|timers: &mut Timers| {
+        let id = timers.insert(deadline, Task::Repeated(Box::new(f), interval));
+        timers.schedule();
+        id
+    })
+}
+
+/// The given duration in nanoseconds, saturated to `u64::MAX`.
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// The deadline that is the given number of nanoseconds from now, saturated to `u64::MAX`.
+fn deadline_after(nanos: u64) -> u64 {
+    time().saturating_add(nanos)
+}
+
+/// Cancel the given timer, does nothing if the timer has already fired or is cancelled.
+pub fn clear_timer(id: TimerId) {
+    with_mut(|timers: &mut Timers| {
+        timers.tasks.remove(&id);
+        timers.deadlines.retain(|(_, timer)| *timer != id);
+        timers.schedule();
+    })
+}
+
+/// Run every timer whose deadline has passed, in the order of their deadline. This is called
+/// by the global timer of the canister.
+///
+/// The due timers are collected before any of them runs, so a timer that is set or re-armed
+/// by a task only runs on the next call, even if it is already due.
+#[doc(hidden)]
+pub fn run_timers() {
+    let now = time();
+
+    let due = with_mut(|timers: &mut Timers| {
+        let due: Vec<_> = timers
+            .deadlines
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .copied()
+            .collect();
+
+        for entry in &due {
+            timers.deadlines.remove(entry);
+        }
+
+        due
+    });
+
+    for (_, id) in due {
+        // The task is taken out of the storage while it is running, so it can set and clear
+        // timers itself.
+        let task = with_mut(|timers: &mut Timers| timers.tasks.get_mut(&id).and_then(Option::take));
+
+        let task = match task {
+            Some(task) => task,
+            None => continue,
+        };
+
+        match task {
+            Task::Once(f) => {
+                with_mut(|timers: &mut Timers| timers.tasks.remove(&id));
+                f();
+            }
+            Task::Repeated(mut f, interval) => {
+                f();
+
+                // Unless the task cleared its own timer, it runs again after the interval.
+                with_mut(|timers: &mut Timers| {
+                    if let Some(slot) = timers.tasks.get_mut(&id) {
+                        *slot = Some(Task::Repeated(f, interval));
+                        timers.deadlines.insert((deadline_after(interval), id));
+                    }
+                });
+            }
+        }
+    }
+
+    with_mut(|timers: &mut Timers| timers.schedule());
+}
+
+/// The global timer of a canister that runs its timers, it is added by the `KitCanister` derive
+/// when the canister does not have a `#[global_timer]`.
+#[cfg(not(target_family = "wasm"))]
+#[doc(hidden)]
+pub struct TimersMethod;
+
+#[cfg(not(target_family = "wasm"))]
+impl ic_kit_runtime::CanisterMethod for TimersMethod {
+    const EXPORT_NAME: &'static str = "canister_global_timer";
+
+    fn exported_method() {
+        run_timers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic::with;
+    use crate::rt::{Canister, CanisterMethod, Replica};
+    use crate::test_utils::{block_on, run_custom};
+    use crate::utils::reply;
+    use crate::Principal;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const SECOND: u64 = 1_000_000_000;
+
+    static ONCE: AtomicU64 = AtomicU64::new(0);
+    static REPEATED: AtomicU64 = AtomicU64::new(0);
+
+    /// Set a timer that fires in 10 seconds, and a timer that is cancelled right away.
+    struct ScheduleMethod;
+
+    impl CanisterMethod for ScheduleMethod {
+        const EXPORT_NAME: &'static str = "canister_update schedule";
+
+        fn exported_method() {
+            set_timer(Duration::from_secs(10), || {
+                ONCE.fetch_add(1, Ordering::SeqCst);
+            });
+
+            let cancelled = set_timer(Duration::from_secs(5), || {
+                ONCE.fetch_add(100, Ordering::SeqCst);
+            });
+            clear_timer(cancelled);
+
+            reply(crate::ic::CANDID_EMPTY_ARG);
+        }
+    }
+
+    /// Count every 10 seconds, and stop after the third time.
+    struct RepeatMethod;
+
+    impl CanisterMethod for RepeatMethod {
+        const EXPORT_NAME: &'static str = "canister_update repeat";
+
+        fn exported_method() {
+            let id = set_timer_interval(Duration::from_secs(10), || {
+                if REPEATED.fetch_add(1, Ordering::SeqCst) == 2 {
+                    clear_timer(with(|id: &Option<TimerId>| id.unwrap()));
+                }
+            });
+            with_mut(|slot: &mut Option<TimerId>| *slot = Some(id));
+
+            reply(crate::ic::CANDID_EMPTY_ARG);
+        }
+    }
+
+    #[test]
+    fn zero_interval_and_long_delay() {
+        run_custom(|| {
+            set_timer_interval(Duration::ZERO, || with_mut(|n: &mut u64| *n += 1));
+            let id = set_timer(Duration::MAX, || {});
+
+            // The interval is due right away, but it only runs once per call.
+            run_timers();
+            assert_eq!(with(|n: &u64| *n), 1);
+            run_timers();
+            assert_eq!(with(|n: &u64| *n), 2);
+
+            // The deadline of the long delay saturates instead of overflowing.
+            with(|timers: &Timers| {
+                assert!(timers.deadlines.contains(&(u64::MAX, id)));
+            });
+
+            reply(crate::ic::CANDID_EMPTY_ARG);
+        })
+        .assert_ok();
+    }
+
+    #[test]
+    fn one_shot_timer() {
+        block_on(async {
+            let canister_id = Principal::from_slice(&[1]);
+            let replica = Replica::default();
+            replica.set_time(1_000 * SECOND);
+            replica.add_canister(
+                Canister::new(canister_id)
+                    .with_method::<ScheduleMethod>()
+                    .with_method::<TimersMethod>(),
+            );
+
+            replica.new_call(canister_id, "schedule").perform().await;
+
+            replica.advance_time(Duration::from_secs(9)).await;
+            assert_eq!(ONCE.load(Ordering::SeqCst), 0);
+
+            replica.advance_time(Duration::from_secs(1)).await;
+            assert_eq!(ONCE.load(Ordering::SeqCst), 1);
+
+            // The timer does not fire again.
+            replica.advance_time(Duration::from_secs(60)).await;
+            assert_eq!(ONCE.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn interval_timer() {
+        block_on(async {
+            let canister_id = Principal::from_slice(&[2]);
+            let replica = Replica::default();
+            replica.set_time(1_000 * SECOND);
+            replica.add_canister(
+                Canister::new(canister_id)
+                    .with_method::<RepeatMethod>()
+                    .with_method::<TimersMethod>(),
+            );
+
+            replica.new_call(canister_id, "repeat").perform().await;
+
+            replica.advance_time(Duration::from_secs(25)).await;
+            assert_eq!(REPEATED.load(Ordering::SeqCst), 2);
+
+            replica.advance_time(Duration::from_secs(60)).await;
+            assert_eq!(REPEATED.load(Ordering::SeqCst), 3);
+        });
+    }
+}
@@ -1,11 +1,21 @@
+// Allow the macros to refer to this crate as `ic_kit` in the tests.
+#[cfg(test)]
+extern crate self as ic_kit;
+
 mod canister;
 mod futures;
+#[cfg(not(target_family = "wasm"))]
+mod mock;
 mod setup;
 mod storage;
+#[cfg(test)]
+mod test_utils;
 
 /// System APIs for the Internet Computer.
 pub mod ic;
 
+pub mod guards;
+
 /// Helper methods around the stable storage.
 pub mod stable;
 
@@ -16,18 +26,25 @@ pub mod utils;
 pub use candid::{self, CandidType, Nat, Principal};
 pub use ic_kit_macros as macros;
 pub use setup::setup_hooks;
+#[cfg(not(target_family = "wasm"))]
+pub use setup::{random_bytes, set_random_seed, setup_hooks_with_seed};
 
 // The KitCanister derive macro.
-pub use canister::KitCanister;
+pub use canister::{candid_interface, CandidMethod, KitCanister};
 pub use ic_kit_macros::KitCanister;
 
 /// The IC-kit runtime, which can be used for testing the canister in non-wasm environments.
 #[cfg(not(target_family = "wasm"))]
 pub use ic_kit_runtime as rt;
 
+/// A lightweight mock of the system API for unit tests that do not need the runtime.
+#[cfg(not(target_family = "wasm"))]
+pub use mock::MockContext;
+
 /// The famous prelude module which re exports the most useful methods.
 pub mod prelude {
     pub use super::canister::KitCanister;
+    pub use super::guards::not_anonymous;
     pub use super::ic;
     pub use super::ic::CallBuilder;
     pub use super::ic::{balance, caller, id, spawn};
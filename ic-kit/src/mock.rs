@@ -0,0 +1,277 @@
+use candid::Principal;
+use ic_kit_sys::ic0;
+use ic_kit_sys::ic0::Ic0MockHandler;
+use std::cell::RefCell;
+
+thread_local! {
+    /// The debug messages printed since the mock was injected in the current thread.
+    static DEBUG_LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A lightweight mock of the system API that can be used to unit test the business logic of a
+/// canister without spinning up a [`Replica`](crate::rt::Replica).
+///
+/// Once injected, the mock handles the system calls made from the current thread. It supports
+/// reading the caller, the canister id, the time, the balance and the cycles sent with the
+/// message, accepting cycles and printing debug messages, any other system call panics. The
+/// printed messages are captured, see [`MockContext::drain_debug_log`].
+///
+/// # Example
+///
+/// ```
+/// use ic_kit::prelude::*;
+/// use ic_kit::MockContext;
+///
+/// MockContext::new()
+///     .with_caller(Principal::management_canister())
+///     .with_balance(1000)
+///     .inject();
+///
+/// assert_eq!(caller(), Principal::management_canister());
+/// assert_eq!(balance(), 1000);
+/// ```
+pub struct MockContext {
+    id: Principal,
+    caller: Principal,
+    time: u64,
+    balance: u128,
+    msg_cycles: u128,
+    data: Vec<Box<dyn FnOnce()>>,
+}
+
+impl Default for MockContext {
+    fn default() -> Self {
+        Self {
+            id: Principal::anonymous(),
+            caller: Principal::anonymous(),
+            time: 0,
+            balance: 0,
+            msg_cycles: 0,
+            data: Vec::new(),
+        }
+    }
+}
+
+impl MockContext {
+    /// Create a new mock context with an anonymous caller and canister id and no cycles.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the id of the canister.
+    pub fn with_id(mut self, id: Principal) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Set the caller of the message.
+    pub fn with_caller(mut self, caller: Principal) -> Self {
+        self.caller = caller;
+        self
+    }
+
+    /// Set the time in nanoseconds.
+    pub fn with_time(mut self, time: u64) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Set the balance of the canister.
+    pub fn with_balance(mut self, balance: u128) -> Self {
+        self.balance = balance;
+        self
+    }
+
+    /// Set the amount of cycles sent with the message, the accepted cycles are added to the
+    /// balance of the canister.
+    pub fn with_msg_cycles(mut self, cycles: u128) -> Self {
+        self.msg_cycles = cycles;
+        self
+    }
+
+    /// Store the given value in the storage once the context is injected, the value can then be
+    /// accessed using [`crate::ic::with`] and the other storage methods.
+    pub fn with_data<T: 'static>(mut self, value: T) -> Self {
+        self.data.push(Box::new(move || {
+            crate::ic::swap(value);
+        }));
+        self
+    }
+
+    /// Install the mock as the system API handler of the current thread, this replaces any
+    /// previously injected context and clears the storage before storing the provided data.
+    pub fn inject(mut self) {
+        crate::ic::clear();
+        DEBUG_LOG.with(|log| log.borrow_mut().clear());
+
+        for store in self.data.drain(..) {
+            store();
+        }
+
+        ic0::register_handler(self);
+    }
+
+    /// Take every debug message printed with [`crate::ic::print`] on the current thread since
+    /// the mock was injected or since the last call to this method.
+    pub fn drain_debug_log() -> Vec<String> {
+        DEBUG_LOG.with(|log| std::mem::take(&mut *log.borrow_mut()))
+    }
+}
+
+/// Copy the given slice of the data to the memory of the canister, and trap just like the runtime
+/// if the copy is out of bounds.
+fn copy_to_canister(dst: isize, offset: isize, size: isize, data: &[u8]) {
+    if let Err(e) = ic0::copy_to_canister(dst, offset, size, data) {
+        panic!("{}", e);
+    }
+}
+
+impl Ic0MockHandler for MockContext {
+    fn msg_caller_size(&mut self) -> isize {
+        self.caller.as_slice().len() as isize
+    }
+
+    fn msg_caller_copy(&mut self, dst: isize, offset: isize, size: isize) {
+        copy_to_canister(dst, offset, size, self.caller.as_slice());
+    }
+
+    fn msg_cycles_available(&mut self) -> i64 {
+        assert!(
+            self.msg_cycles <= u64::MAX as u128,
+            "available cycles does not fit in u64"
+        );
+        self.msg_cycles as u64 as i64
+    }
+
+    fn msg_cycles_available128(&mut self, dst: isize) {
+        copy_to_canister(dst, 0, 16, &self.msg_cycles.to_le_bytes());
+    }
+
+    fn msg_cycles_accept(&mut self, max_amount: i64) -> i64 {
+        let amount = self.msg_cycles.min(max_amount as u64 as u128);
+        self.msg_cycles -= amount;
+        self.balance += amount;
+        amount as i64
+    }
+
+    fn msg_cycles_accept128(&mut self, max_amount_high: i64, max_amount_low: i64, dst: isize) {
+        let high = max_amount_high as u64 as u128;
+        let low = max_amount_low as u64 as u128;
+        let amount = self.msg_cycles.min((high << 64) + low);
+        self.msg_cycles -= amount;
+        self.balance += amount;
+        copy_to_canister(dst, 0, 16, &amount.to_le_bytes());
+    }
+
+    fn canister_self_size(&mut self) -> isize {
+        self.id.as_slice().len() as isize
+    }
+
+    fn canister_self_copy(&mut self, dst: isize, offset: isize, size: isize) {
+        copy_to_canister(dst, offset, size, self.id.as_slice());
+    }
+
+    fn canister_cycle_balance(&mut self) -> i64 {
+        assert!(
+            self.balance <= u64::MAX as u128,
+            "balance does not fit in u64"
+        );
+        self.balance as u64 as i64
+    }
+
+    fn canister_cycle_balance128(&mut self, dst: isize) {
+        copy_to_canister(dst, 0, 16, &self.balance.to_le_bytes());
+    }
+
+    fn time(&mut self) -> i64 {
+        self.time as i64
+    }
+
+    fn debug_print(&mut self, src: isize, size: isize) {
+        let bytes = unsafe { std::slice::from_raw_parts(src as *const u8, size as usize) };
+        let message = String::from_utf8_lossy(bytes).to_string();
+        DEBUG_LOG.with(|log| log.borrow_mut().push(message));
+    }
+
+    fn trap(&mut self, src: isize, size: isize) {
+        let bytes = unsafe { std::slice::from_raw_parts(src as *const u8, size as usize) };
+        // The runtime rejects the message with the trap message as it is.
+        panic!("{}", String::from_utf8_lossy(bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic;
+
+    #[derive(Debug, PartialEq)]
+    struct Config {
+        owner: Principal,
+    }
+
+    #[test]
+    fn caller() {
+        let alice = Principal::management_canister();
+
+        MockContext::new().with_caller(alice).inject();
+        assert_eq!(ic::caller(), alice);
+
+        MockContext::new().inject();
+        assert_eq!(ic::caller(), Principal::anonymous());
+    }
+
+    #[test]
+    fn cycles() {
+        MockContext::new()
+            .with_balance(1000)
+            .with_msg_cycles(500)
+            .inject();
+
+        assert_eq!(ic::msg_cycles_available(), 500);
+        assert_eq!(ic::msg_cycles_accept(200), 200);
+        assert_eq!(ic::msg_cycles_available(), 300);
+        assert_eq!(ic::balance(), 1200);
+    }
+
+    #[test]
+    fn print() {
+        MockContext::new().inject();
+        ic::print("hello");
+        ic::print(format!("balance: {}", ic::balance()));
+
+        assert_eq!(MockContext::drain_debug_log(), vec!["hello", "balance: 0"]);
+        assert!(MockContext::drain_debug_log().is_empty());
+
+        // Injecting a new context drops the messages that are not drained.
+        ic::print("dropped");
+        MockContext::new().inject();
+        assert!(MockContext::drain_debug_log().is_empty());
+    }
+
+    #[test]
+    fn trap() {
+        MockContext::new().inject();
+
+        // The trap message is the same as the rejection message of a trap in the runtime.
+        let payload = std::panic::catch_unwind(|| ic::trap("out of tokens")).unwrap_err();
+        assert_eq!(payload.downcast_ref::<String>().unwrap(), "out of tokens");
+    }
+
+    #[test]
+    fn data() {
+        let owner = Principal::management_canister();
+
+        MockContext::new()
+            .with_id(owner)
+            .with_data(Config { owner })
+            .inject();
+
+        assert_eq!(ic::id(), owner);
+        assert_eq!(ic::maybe_with(|c: &Config| c.owner), Some(owner));
+
+        // Injecting a new context resets the storage.
+        MockContext::new().inject();
+        assert!(!ic::contains::<Config>());
+    }
+}
@@ -1,4 +1,6 @@
 use crate::ic;
+#[cfg(not(target_family = "wasm"))]
+use ic_kit_runtime::rng::ChaChaRng;
 use std::panic;
 
 #[cfg(target_family = "wasm")]
@@ -21,6 +23,33 @@ pub fn setup_hooks() {
     set_panic_hook();
 }
 
+/// Seed the random number generator of the canister behind [`random_bytes`], so every run of a
+/// test with the same seed sees the same random bytes. This is meant to be called from the
+/// `#[init]` of the canister in the tests.
+///
+/// Unlike [`setup_hooks`] this does not replace the process wide panic hook, the runtime already
+/// reports the panics of every canister from the hook of its execution thread.
+#[cfg(not(target_family = "wasm"))]
+pub fn setup_hooks_with_seed(seed: u64) {
+    set_random_seed(seed);
+}
+
+/// Seed the random number generator of the canister behind [`random_bytes`], the generator is
+/// seeded with zero until this is called.
+#[cfg(not(target_family = "wasm"))]
+pub fn set_random_seed(seed: u64) {
+    ic::with_mut(|rng: &mut ChaChaRng| *rng = ChaChaRng::new(seed));
+}
+
+/// Return the next 32 bytes of the random number generator of the canister, this stands in for
+/// the `raw_rand` method of the management canister in the tests, see [`set_random_seed`].
+#[cfg(not(target_family = "wasm"))]
+pub fn random_bytes() -> [u8; 32] {
+    let mut bytes = [0; 32];
+    ic::with_mut(|rng: &mut ChaChaRng| rng.fill_bytes(&mut bytes));
+    bytes
+}
+
 /// Sets a custom panic hook, uses debug.trace
 fn set_panic_hook() {
     panic::set_hook(Box::new(|info| {
@@ -41,3 +70,94 @@ fn set_panic_hook() {
         ic::trap(&err_info);
     }));
 }
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+    use super::*;
+    use crate::ic::RejectionCode;
+    use crate::rt::{Canister, CanisterMethod, Replica};
+    use crate::test_utils::{block_on, run_custom};
+    use crate::utils::reply;
+    use crate::Principal;
+
+    /// Seed the random number generator of the canister with 7.
+    struct SeededInitMethod;
+
+    impl CanisterMethod for SeededInitMethod {
+        const EXPORT_NAME: &'static str = "canister_init";
+
+        fn exported_method() {
+            setup_hooks_with_seed(7);
+        }
+    }
+
+    /// Reply with the first 32 random bytes of the canister.
+    struct RandomMethod;
+
+    impl CanisterMethod for RandomMethod {
+        const EXPORT_NAME: &'static str = "canister_update random";
+
+        fn exported_method() {
+            reply(&random_bytes());
+        }
+    }
+
+    /// Panic in the middle of the message.
+    struct PanicMethod;
+
+    impl CanisterMethod for PanicMethod {
+        const EXPORT_NAME: &'static str = "canister_update panic";
+
+        fn exported_method() {
+            panic!("Oops.");
+        }
+    }
+
+    /// Return the first 64 random bytes of a new canister seeded with the given seed.
+    fn seeded_bytes(seed: u64) -> Vec<u8> {
+        let r = run_custom(move || {
+            set_random_seed(seed);
+            let mut bytes = random_bytes().to_vec();
+            bytes.extend_from_slice(&random_bytes());
+            reply(&bytes);
+        });
+
+        r.bytes().unwrap().to_vec()
+    }
+
+    #[test]
+    fn random_seed() {
+        let bytes = seeded_bytes(7);
+        assert_eq!(bytes.len(), 64);
+        assert_ne!(bytes[..32], bytes[32..]);
+
+        assert_eq!(seeded_bytes(7), bytes);
+        assert_ne!(seeded_bytes(8), bytes);
+    }
+
+    #[test]
+    fn seeded_init() {
+        let expected = seeded_bytes(7);
+
+        block_on(async {
+            let replica = Replica::default();
+            let canister = replica
+                .install_canister(
+                    Canister::new(Principal::from_slice(&[2]))
+                        .with_method::<SeededInitMethod>()
+                        .with_method::<RandomMethod>()
+                        .with_method::<PanicMethod>(),
+                    (),
+                )
+                .await;
+
+            // The panic is reported as a trap of the canister, and the canister keeps running.
+            let r = canister.new_call("panic").perform().await;
+            assert!(matches!(r.rejection_code(), RejectionCode::CanisterError));
+            assert!(r.rejection_message().unwrap().contains("Oops."));
+
+            let r = canister.new_call("random").perform().await;
+            assert_eq!(r.bytes().unwrap(), &expected[..32]);
+        });
+    }
+}
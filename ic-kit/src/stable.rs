@@ -53,8 +53,12 @@ impl StableWriter {
     ///
     /// The only condition where this will error out is if it cannot grow the memory.
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, StableMemoryError> {
-        if self.offset + (buf.len() as StableSize) > (self.capacity << 16) {
-            self.grow((buf.len() >> 16) as StableSize + 1)?;
+        let end = self.offset + (buf.len() as StableSize);
+        if end > (self.capacity << 16) {
+            // Grow just enough pages to fit the entire buffer, the writer may start past the
+            // current capacity, so the size of the buffer alone is not enough.
+            let required_pages = (end + 0xffff) >> 16;
+            self.grow(required_pages - self.capacity)?;
         }
 
         stable_write(self.offset, buf);
@@ -138,3 +142,266 @@ where
     let res = ArgumentDecoder::decode(&mut de).map_err(|e| format!("{:?}", e))?;
     Ok(res)
 }
+
+/// A [`Memory`](ic_stable_structures::Memory) backed by the canister's stable storage, this
+/// allows the collections from `ic-stable-structures` to be used in a canister and be tested
+/// using the IC-Kit runtime.
+#[cfg(feature = "stable-structures")]
+#[derive(Copy, Clone, Default)]
+pub struct StableMemory;
+
+#[cfg(feature = "stable-structures")]
+impl ic_stable_structures::Memory for StableMemory {
+    fn size(&self) -> u64 {
+        stable_size() as u64
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        match stable_grow(pages as StableSize) {
+            Ok(old_page_count) => old_page_count as i64,
+            Err(_) => -1,
+        }
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        stable_read(offset as StableSize, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        stable_write(offset as StableSize, src)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ic::{swap, with, CANDID_EMPTY_ARG};
+    use crate::rt::types::Env;
+    use crate::rt::{Canister, Replica};
+    use crate::test_utils::block_on;
+    use crate::utils::reply;
+    use candid::Principal;
+    use std::collections::HashMap;
+
+    #[test]
+    #[allow(deprecated)]
+    fn stable_store_upgrade() {
+        block_on(async {
+            let replica = Replica::default();
+            let canister = replica.add_canister(Canister::new(Principal::anonymous()));
+
+            canister
+                .custom(
+                    || {
+                        let store: HashMap<String, u64> =
+                            (0..10).map(|i| (format!("key{}", i), i)).collect();
+                        stable_store((store,)).unwrap();
+                    },
+                    Env::pre_upgrade(),
+                )
+                .await;
+
+            canister
+                .custom(
+                    || {
+                        let (store,): (HashMap<String, u64>,) = stable_restore().unwrap();
+                        swap(store);
+                    },
+                    Env::post_upgrade(),
+                )
+                .await;
+
+            canister
+                .custom(
+                    || {
+                        with(|store: &HashMap<String, u64>| {
+                            assert_eq!(store.len(), 10);
+                            for i in 0..10 {
+                                assert_eq!(store[&format!("key{}", i)], i);
+                            }
+                        });
+
+                        reply(CANDID_EMPTY_ARG);
+                    },
+                    Env::default(),
+                )
+                .await
+                .assert_ok();
+
+            // Every canister starts with an empty stable storage.
+            let other = replica.add_canister(Canister::new(Principal::management_canister()));
+            other
+                .custom(
+                    || {
+                        assert!(stable_restore::<(HashMap<String, u64>,)>().is_err());
+                        reply(CANDID_EMPTY_ARG);
+                    },
+                    Env::default(),
+                )
+                .await
+                .assert_ok();
+        });
+    }
+
+    #[test]
+    fn stable_writer_reader() {
+        use std::io::{Read, Write};
+
+        const SIZE: usize = 1 << 20;
+
+        block_on(async {
+            let replica = Replica::default();
+            let canister = replica.add_canister(Canister::new(Principal::anonymous()));
+
+            canister
+                .custom(
+                    || {
+                        let data = (0..SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+                        // Use a chunk size that does not align with the page size, so that the
+                        // writer has to grow the memory in the middle of a chunk.
+                        let mut writer = StableWriter::default();
+                        for chunk in data.chunks(1000) {
+                            writer.write_all(chunk).unwrap();
+                        }
+
+                        assert_eq!(writer.offset(), SIZE as StableSize);
+                        assert_eq!(stable_size(), 16);
+
+                        reply(CANDID_EMPTY_ARG);
+                    },
+                    Env::default(),
+                )
+                .await
+                .assert_ok();
+
+            canister
+                .custom(
+                    || {
+                        // Append to the end of the previously written data.
+                        let mut writer = StableWriter::new(SIZE as StableSize);
+                        writer.write_all(&[0xff; 10]).unwrap();
+                        assert_eq!(stable_size(), 17);
+
+                        let mut data = Vec::new();
+                        StableReader::default()
+                            .take(SIZE as u64 + 10)
+                            .read_to_end(&mut data)
+                            .unwrap();
+
+                        assert_eq!(data.len(), SIZE + 10);
+                        for (i, byte) in data[..SIZE].iter().enumerate() {
+                            assert_eq!(*byte, (i % 251) as u8);
+                        }
+                        assert_eq!(&data[SIZE..], &[0xff; 10]);
+
+                        reply(CANDID_EMPTY_ARG);
+                    },
+                    Env::default(),
+                )
+                .await
+                .assert_ok();
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "stable-structures")]
+    fn stable_btree_map() {
+        use ic_stable_structures::StableBTreeMap;
+
+        block_on(async {
+            let replica = Replica::default();
+            let canister = replica.add_canister(Canister::new(Principal::anonymous()));
+
+            canister
+                .custom(
+                    || {
+                        let mut map: StableBTreeMap<u64, u64, _> =
+                            StableBTreeMap::init(StableMemory);
+
+                        for i in 0..100 {
+                            map.insert(i, i * i);
+                        }
+
+                        reply(CANDID_EMPTY_ARG);
+                    },
+                    Env::default(),
+                )
+                .await
+                .assert_ok();
+
+            // The stable storage outlives the message, so the map can be loaded again.
+            canister
+                .custom(
+                    || {
+                        let map: StableBTreeMap<u64, u64, _> = StableBTreeMap::init(StableMemory);
+
+                        assert_eq!(map.len(), 100);
+                        for i in 0..100 {
+                            assert_eq!(map.get(&i), Some(i * i));
+                        }
+
+                        reply(CANDID_EMPTY_ARG);
+                    },
+                    Env::default(),
+                )
+                .await
+                .assert_ok();
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "stable-structures")]
+    fn stable_btree_map_upgrade() {
+        use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+        use ic_stable_structures::StableBTreeMap;
+
+        /// Load the map stored in the first virtual memory of the stable storage.
+        fn balances() -> StableBTreeMap<u64, u64, VirtualMemory<StableMemory>> {
+            let manager = MemoryManager::init(StableMemory);
+            StableBTreeMap::init(manager.get(MemoryId::new(0)))
+        }
+
+        block_on(async {
+            let canister_id = Principal::anonymous();
+            let replica = Replica::default();
+            let canister = replica.add_canister(Canister::new(canister_id));
+
+            canister
+                .custom(
+                    || {
+                        let mut map = balances();
+                        for i in 0..10 {
+                            map.insert(i, i * 100);
+                        }
+
+                        reply(CANDID_EMPTY_ARG);
+                    },
+                    Env::default(),
+                )
+                .await
+                .assert_ok();
+
+            // The new instance only inherits the stable storage of the old one.
+            replica
+                .upgrade_canister(canister_id, Canister::new(canister_id))
+                .await;
+
+            canister
+                .custom(
+                    || {
+                        let map = balances();
+                        assert_eq!(map.len(), 10);
+                        for i in 0..10 {
+                            assert_eq!(map.get(&i), Some(i * 100));
+                        }
+
+                        reply(CANDID_EMPTY_ARG);
+                    },
+                    Env::default(),
+                )
+                .await
+                .assert_ok();
+        });
+    }
+}
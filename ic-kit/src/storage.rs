@@ -19,10 +19,22 @@ impl Storage {
     /// Ensure the default value exists on the map.
     #[inline(always)]
     fn ensure_default<T: 'static + Default>(&self, tid: TypeId) {
+        self.ensure_with(tid, T::default);
+    }
+
+    /// Ensure a value exists on the map, calling the `init` function only if there is none.
+    #[inline(always)]
+    fn ensure_with<T: 'static, I: FnOnce() -> T>(&self, tid: TypeId, init: I) {
+        if self.storage.borrow().contains_key(&tid) {
+            return;
+        }
+
+        // The initializer may itself access the storage, so it must run without a borrow held.
+        let value = init();
         self.storage
             .borrow_mut()
             .entry(tid)
-            .or_insert_with(|| RefCell::new(Box::new(T::default())));
+            .or_insert_with(|| RefCell::new(Box::new(value)));
     }
 
     /// Pass an immutable reference to the stored data of the type `T` to the closure,
@@ -41,6 +53,26 @@ impl Storage {
         callback(borrow)
     }
 
+    /// Pass an immutable reference to the stored data of the type `T` to the closure,
+    /// if there is no data associated with the type, store the value returned by `init` and
+    /// then perform the operation.
+    #[inline]
+    pub fn with_or<T: 'static, U, I: FnOnce() -> T, F: FnOnce(&T) -> U>(
+        &self,
+        init: I,
+        callback: F,
+    ) -> U {
+        let tid = TypeId::of::<T>();
+        self.ensure_with(tid, init);
+        let cell = unsafe { self.storage.try_borrow_unguarded() }
+            .unwrap()
+            .get(&tid)
+            .unwrap()
+            .borrow();
+        let borrow = cell.downcast_ref::<T>().unwrap();
+        callback(borrow)
+    }
+
     /// Pass an immutable reference to the stored data of the type `T` to the closure,
     /// if there is no data associated with the type, just return None.
     #[inline]
@@ -67,6 +99,24 @@ impl Storage {
         callback(borrow)
     }
 
+    /// Like [`Self::with_or`] but passes a mutable reference.
+    #[inline]
+    pub fn with_mut_or<T: 'static, U, I: FnOnce() -> T, F: FnOnce(&mut T) -> U>(
+        &self,
+        init: I,
+        callback: F,
+    ) -> U {
+        let tid = TypeId::of::<T>();
+        self.ensure_with(tid, init);
+        let mut cell = unsafe { self.storage.try_borrow_unguarded() }
+            .unwrap()
+            .get(&tid)
+            .unwrap()
+            .borrow_mut();
+        let borrow = cell.downcast_mut::<T>().unwrap();
+        callback(borrow)
+    }
+
     /// Like [`Self::maybe_with`] but passes a mutable reference.
     #[inline]
     pub fn maybe_with_mut<T: 'static, U, F: FnOnce(&mut T) -> U>(&self, callback: F) -> Option<U> {
@@ -78,6 +128,17 @@ impl Storage {
             .map(|mut c| callback(c.borrow_mut().downcast_mut::<T>().unwrap()))
     }
 
+    /// Pass the ownership of the stored data of the type `T` to the closure and store the value
+    /// it returns in its place, if there is no data associated with the type, the `Default` is
+    /// passed instead.
+    #[inline]
+    pub fn update<T: 'static + Default, F: FnOnce(T) -> T>(&self, f: F) {
+        self.with_mut(|value: &mut T| {
+            let old = std::mem::take(value);
+            *value = f(old);
+        })
+    }
+
     /// Remove the data associated with the type `T`, and returns it if any.
     #[inline]
     pub fn take<T: 'static>(&self) -> Option<T> {
@@ -106,6 +167,39 @@ impl Storage {
         }
     }
 
+    /// Returns true if there is a value associated with the type `T`.
+    #[inline]
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.storage.borrow().contains_key(&TypeId::of::<T>())
+    }
+
+    /// Returns the number of values currently stored.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.storage.borrow().len()
+    }
+
+    /// Returns true if there are no values stored.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.storage.borrow().is_empty()
+    }
+
+    /// Drop all of the stored values.
+    ///
+    /// # Panics
+    ///
+    /// If any of the values is currently borrowed, i.e when called inside of a `with` closure.
+    #[inline]
+    pub fn clear(&self) {
+        let mut storage = self.storage.borrow_mut();
+        assert!(
+            storage.values().all(|cell| cell.try_borrow_mut().is_ok()),
+            "Cannot clear the storage while a value is borrowed."
+        );
+        storage.clear();
+    }
+
     /// Just like `.with` but can pass the immutable reference to many items in one closure.
     #[inline]
     pub fn with_many<A: BorrowMany, U, F: FnOnce(A) -> U>(&self, callback: F) -> U {
@@ -216,3 +310,123 @@ implement_borrow_many!(
     (A0 A1 A3 A4 A5 A6 A7 A8)
     (A0 A1 A3 A4 A5 A6 A7 A8 A9)
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A type without a [`Default`] implementation.
+    struct Counter {
+        count: u64,
+    }
+
+    impl Counter {
+        fn new(count: u64) -> Self {
+            Self { count }
+        }
+    }
+
+    #[test]
+    fn with_or() {
+        let storage = Storage::default();
+        let mut init_calls = 0;
+
+        assert!(storage.maybe_with(|c: &Counter| c.count).is_none());
+
+        let count = storage.with_or(
+            || {
+                init_calls += 1;
+                Counter::new(10)
+            },
+            |c: &Counter| c.count,
+        );
+        assert_eq!(count, 10);
+
+        storage.with_mut_or(
+            || {
+                init_calls += 1;
+                Counter::new(0)
+            },
+            |c: &mut Counter| c.count += 1,
+        );
+
+        let count = storage.with_or(
+            || {
+                init_calls += 1;
+                Counter::new(0)
+            },
+            |c: &Counter| c.count,
+        );
+        assert_eq!(count, 11);
+        assert_eq!(init_calls, 1);
+
+        assert_eq!(storage.take::<Counter>().map(|c| c.count), Some(11));
+    }
+
+    #[test]
+    fn with_or_nested_init() {
+        let storage = Storage::default();
+        storage.swap(5u64);
+
+        let count = storage.with_or(
+            || Counter::new(storage.with(|n: &u64| *n)),
+            |c: &Counter| c.count,
+        );
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn with_mut_or() {
+        let storage = Storage::default();
+
+        storage.with_mut_or(|| Counter::new(5), |c: &mut Counter| c.count *= 2);
+        storage.with_mut_or(|| Counter::new(0), |c: &mut Counter| c.count *= 2);
+
+        assert_eq!(storage.maybe_with(|c: &Counter| c.count), Some(20));
+    }
+
+    #[test]
+    fn update() {
+        let storage = Storage::default();
+
+        storage.update(|v: Vec<u64>| {
+            assert!(v.is_empty());
+            vec![1, 2]
+        });
+
+        storage.update(|mut v: Vec<u64>| {
+            assert_eq!(v, vec![1, 2]);
+            v.push(3);
+            v
+        });
+
+        assert_eq!(storage.take::<Vec<u64>>(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn introspection() {
+        let storage = Storage::default();
+        assert!(storage.is_empty());
+
+        storage.with(|_: &u64| ());
+        storage.swap(Counter::new(0));
+
+        assert_eq!(storage.len(), 2);
+        assert!(storage.contains::<u64>());
+        assert!(storage.contains::<Counter>());
+        assert!(!storage.contains::<String>());
+
+        storage.clear();
+
+        assert!(storage.is_empty());
+        assert!(!storage.contains::<u64>());
+        assert!(!storage.contains::<Counter>());
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot clear the storage while a value is borrowed.")]
+    fn clear_while_borrowed() {
+        let storage = Storage::default();
+        storage.with(|_: &u64| storage.clear());
+    }
+}
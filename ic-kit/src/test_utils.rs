@@ -0,0 +1,33 @@
+//! Helpers to run the canister code of the unit tests on the runtime.
+
+use crate::rt::call::CallReply;
+use crate::rt::types::Env;
+use crate::rt::{Canister, Replica, TokioRuntimeBuilder};
+use candid::Principal;
+use std::future::Future;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+
+/// Run the given future to completion on a new single threaded runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    TokioRuntimeBuilder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+/// Run the given task on a new canister with the default environment, and return its reply.
+pub fn run_custom<F: FnOnce() + Send + RefUnwindSafe + UnwindSafe + 'static>(f: F) -> CallReply {
+    run_custom_with_env(Env::default(), f)
+}
+
+/// Run the given task on a new canister with the given environment, and return its reply.
+pub fn run_custom_with_env<F: FnOnce() + Send + RefUnwindSafe + UnwindSafe + 'static>(
+    env: Env,
+    f: F,
+) -> CallReply {
+    block_on(async {
+        let replica = Replica::default();
+        let canister = replica.add_canister(Canister::new(Principal::anonymous()));
+        canister.custom(f, env).await
+    })
+}
@@ -1,4 +1,7 @@
+use candid::ser::IDLBuilder;
+use candid::CandidType;
 use ic_kit_sys::ic0;
+use ic_kit_sys::types::RejectionCode;
 
 /// Return the size of the raw argument to this entry point.
 pub fn arg_data_size() -> usize {
@@ -30,11 +33,166 @@ pub fn reply(buf: &[u8]) {
     }
 }
 
+/// Build the Candid reply of the current call one value at a time, and send it once with
+/// [`ReplyBuilder::flush`]. This is meant for the methods with `manual_reply = true` that
+/// assemble a large response, without having to collect all of it in a single tuple first.
+///
+/// ```no_run
+/// use ic_kit::utils::ReplyBuilder;
+///
+/// let mut reply = ReplyBuilder::new();
+/// reply.append(&10u64).unwrap();
+/// reply.append(&vec!["a", "b"]).unwrap();
+/// reply.flush().unwrap();
+/// ```
+pub struct ReplyBuilder {
+    builder: IDLBuilder,
+    flushed: bool,
+}
+
+impl ReplyBuilder {
+    /// Create a new empty reply.
+    pub fn new() -> Self {
+        Self {
+            builder: IDLBuilder::new(),
+            flushed: false,
+        }
+    }
+
+    /// Append the next value of the reply.
+    ///
+    /// # Panics
+    ///
+    /// If the reply is already flushed.
+    pub fn append<T: CandidType + ?Sized>(&mut self, value: &T) -> candid::Result<&mut Self> {
+        assert!(
+            !self.flushed,
+            "Can not append to a reply that is already flushed."
+        );
+        self.builder.arg(value)?;
+        Ok(self)
+    }
+
+    /// Encode the values that are appended so far and reply to the current call with them.
+    ///
+    /// # Panics
+    ///
+    /// If the reply is already flushed.
+    pub fn flush(&mut self) -> candid::Result<()> {
+        assert!(!self.flushed, "The reply is already flushed.");
+        let bytes = self.builder.serialize_to_vec()?;
+        self.flushed = true;
+        reply(&bytes);
+        Ok(())
+    }
+
+    /// Return true if the reply is already sent.
+    pub fn is_flushed(&self) -> bool {
+        self.flushed
+    }
+}
+
+impl Default for ReplyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Reject the current call.
 pub fn reject(message: &str) {
     unsafe { ic0::msg_reject(message.as_ptr() as isize, message.len() as isize) }
 }
 
+/// Reject the current call with the given rejection code, a canister can either reject a call
+/// with [`RejectionCode::CanisterReject`], just like [`reject`], or fail it with
+/// [`RejectionCode::CanisterError`] by trapping, in which case the changes made by the current
+/// message are rolled back.
+///
+/// # Panics
+///
+/// If the code is any other rejection code, those are only used by the system.
+pub fn reject_with(code: RejectionCode, message: &str) {
+    match code {
+        RejectionCode::CanisterReject => reject(message),
+        RejectionCode::CanisterError => crate::ic::trap(message),
+        code => panic!("A canister can not reject a call with {:?}.", code),
+    }
+}
+
+/// The error returned by a guard, the guard can return either a message to reject the call with
+/// [`RejectionCode::CanisterReject`], or a rejection code along with the message, see
+/// [`reject_with`].
+pub trait GuardError {
+    /// Reject the current call with this error.
+    fn reject(self);
+}
+
+impl GuardError for String {
+    fn reject(self) {
+        reject(&self);
+    }
+}
+
+impl GuardError for (RejectionCode, String) {
+    fn reject(self) {
+        reject_with(self.0, &self.1);
+    }
+}
+
+/// Reject the current call because its arguments could not be decoded. The rejection message
+/// tells how many arguments the method expects and whether the data was truncated or did not
+/// match the expected types, which helps to find out if the caller sent the wrong thing or the
+/// message was corrupted.
+pub fn reject_decode_error(expected: usize, error: &candid::Error) {
+    let message = format!(
+        "Could not decode arguments, expected {} argument{} ({}): {}",
+        expected,
+        if expected == 1 { "" } else { "s" },
+        decode_error_kind(expected, error),
+        error
+    );
+    reject(&message);
+}
+
+/// Classify a candid decoding error as either truncated data or a type mismatch.
+fn decode_error_kind(expected: usize, error: &candid::Error) -> &'static str {
+    // Candid does not have a dedicated error for a message that has fewer values than expected,
+    // so the values are counted.
+    let missing = count_values(&arg_data_raw())
+        .map(|count| count < expected)
+        .unwrap_or(false);
+
+    let truncated = match error {
+        // The binary parser of the message header reports an I/O error, such as reading past the
+        // end of the data, without any label.
+        candid::Error::Binread(labels) => labels.is_empty(),
+        candid::Error::Custom(e) => e
+            .downcast_ref::<std::io::Error>()
+            .map(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+            .unwrap_or(false),
+        _ => false,
+    };
+
+    if missing || truncated {
+        "truncated data"
+    } else {
+        "wrong type"
+    }
+}
+
+/// Return the number of values in the given Candid message, or None if it is malformed.
+fn count_values(bytes: &[u8]) -> Option<usize> {
+    let mut de = candid::de::IDLDeserialize::new(bytes).ok()?;
+    let mut count = 0;
+
+    while !de.is_done() {
+        de.get_value::<candid::Reserved>().ok()?;
+        count += 1;
+    }
+
+    Some(count)
+}
+
 /// Accept the incoming message.
 pub fn accept() {
     unsafe {
@@ -61,3 +219,132 @@ pub fn method_name() -> String {
 pub fn performance_counter(counter_type: u32) -> u64 {
     unsafe { ic0::performance_counter(counter_type as i32) as u64 }
 }
+
+/// Save the candid interface of a canister to the given file, this is called by the test that
+/// the `KitCanister` derive generates for a `#[candid_path]`.
+///
+/// When the `IC_KIT_CHECK_CANDID` environment variable is set, the file is not written and this
+/// panics if the file does not match the interface, see [`check_candid`].
+#[cfg(not(target_family = "wasm"))]
+pub fn save_candid(path: &std::path::Path, candid: &str) {
+    use std::fs;
+
+    if std::env::var_os("IC_KIT_CHECK_CANDID").is_some() {
+        if let Err(e) = check_candid(path, candid) {
+            panic!("{}", e);
+        }
+        return;
+    }
+
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).unwrap_or_else(|e| {
+        panic!(
+            "Failed to create the directory '{}': {}",
+            dir.as_os_str().to_string_lossy(),
+            e
+        )
+    });
+
+    fs::write(path, candid).unwrap_or_else(|e| {
+        panic!(
+            "Failed to write to the file '{}': {}",
+            path.as_os_str().to_string_lossy(),
+            e
+        )
+    });
+
+    println!("Saved candid to: {}", path.as_os_str().to_string_lossy());
+}
+
+/// Check that the given file contains the candid interface, this is meant to be used in CI to
+/// catch the outdated candid files.
+#[cfg(not(target_family = "wasm"))]
+pub fn check_candid(path: &std::path::Path, candid: &str) -> Result<(), String> {
+    let current = std::fs::read_to_string(path).unwrap_or_default();
+    if current == candid {
+        return Ok(());
+    }
+
+    Err(format!(
+        "The candid interface in '{}' is out of date, run the tests without \
+        IC_KIT_CHECK_CANDID to update it.\n\nExpected:\n{}",
+        path.as_os_str().to_string_lossy(),
+        candid
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::run_custom;
+
+    #[test]
+    fn reject_with_code() {
+        for code in [
+            RejectionCode::CanisterReject,
+            RejectionCode::CanisterError,
+            RejectionCode::DestinationInvalid,
+        ] {
+            let r = run_custom(move || reject_with(code, "denied"));
+
+            match code {
+                RejectionCode::DestinationInvalid => {
+                    assert!(matches!(r.rejection_code(), RejectionCode::CanisterError));
+                    assert!(r
+                        .rejection_message()
+                        .unwrap()
+                        .ends_with("A canister can not reject a call with DestinationInvalid."));
+                }
+                code => {
+                    assert_eq!(r.rejection_code() as i32, code as i32);
+                    assert_eq!(r.rejection_message(), Some("denied"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_candid_file() {
+        let path = std::env::temp_dir().join(format!("ic-kit-{}.did", std::process::id()));
+        let candid = "service : {\n  get : () -> (nat64) query;\n}";
+
+        std::fs::write(&path, candid).unwrap();
+        assert_eq!(check_candid(&path, candid), Ok(()));
+
+        let outdated = candid.replace("nat64", "nat32");
+        std::fs::write(&path, outdated).unwrap();
+        let error = check_candid(&path, candid).unwrap_err();
+        assert!(error.contains("is out of date"));
+        assert!(error.ends_with(candid));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reply_builder() {
+        let r = run_custom(|| {
+            let mut reply = ReplyBuilder::new();
+            reply.append(&17u64).unwrap();
+            reply.append("page").unwrap();
+            assert!(!reply.is_flushed());
+            reply.flush().unwrap();
+            assert!(reply.is_flushed());
+        });
+
+        let (n, s): (u64, String) = r.decode().unwrap();
+        assert_eq!(n, 17);
+        assert_eq!(s, "page");
+
+        let r = run_custom(|| {
+            let mut reply = ReplyBuilder::new();
+            reply.flush().unwrap();
+            reply.append(&17u64).unwrap();
+        });
+
+        assert_eq!(r.rejection_code(), RejectionCode::CanisterError);
+        assert!(r
+            .rejection_message()
+            .unwrap()
+            .contains("Can not append to a reply that is already flushed."));
+    }
+}